@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use crate::btree::BTree;
+use crate::consts::{SnapshotHeader, SnapshotStock, UserMeta};
+use crate::reader::DatabaseReader;
+use crate::state::Portfolio;
+
+// --- ACCOUNTS (accounts.idx) ---
+// The B-Tree is the real home of every balance. RAM only keeps a write-back cache:
+// accounts touched since the last checkpoint, plus accounts with resting orders
+// (their reservations are derived from the book and never hit the disk).
+//
+// One entry per user: key = user_id (big-endian, so keys sort by id),
+// value = the full portfolio record (same layout as in the snapshots).
+fn account_key(user_id: u64) -> [u8; 8] {
+    user_id.to_be_bytes()
+}
+
+/// [SnapshotHeader | SnapshotStock * num_stocks]
+pub fn encode_portfolio(user_id: u64, portfolio: &Portfolio) -> Vec<u8> {
+    let header = SnapshotHeader {
+        user_id,
+        cash: portfolio.cash,
+        num_stocks: portfolio.stocks.len() as u32,
+        _padding: [0; 4],
+    };
+
+    let mut record = bytemuck::bytes_of(&header).to_vec();
+    for (symbol_id, qty) in &portfolio.stocks {
+        let stock = SnapshotStock {
+            symbol_id: *symbol_id,
+            _padding: [0; 4],
+            quantity: *qty,
+        };
+        record.extend_from_slice(bytemuck::bytes_of(&stock));
+    }
+    record
+}
+
+fn decode_portfolio(record: &[u8]) -> Portfolio {
+    let header: SnapshotHeader = bytemuck::pod_read_unaligned(&record[..size_of::<SnapshotHeader>()]);
+    let stocks = record[size_of::<SnapshotHeader>()..]
+        .chunks_exact(size_of::<SnapshotStock>())
+        .take(header.num_stocks as usize)
+        .map(|chunk| {
+            let stock: SnapshotStock = bytemuck::pod_read_unaligned(chunk);
+            (stock.symbol_id, stock.quantity)
+        })
+        .collect();
+
+    Portfolio { cash: header.cash, stocks, ..Default::default() }
+}
+
+pub struct Accounts {
+    cache: HashMap<u64, Portfolio>,
+    dirty: HashSet<u64>,
+    // Mutex because even a read may pull pages into the pager cache.
+    // Shared with Checkpoint, which writes to it without holding the AppState lock.
+    tree: Arc<Mutex<BTree>>,
+}
+
+impl Accounts {
+    pub fn open(filename: &str, frames: usize) -> io::Result<Self> {
+        Ok(Self {
+            cache: HashMap::new(),
+            dirty: HashSet::new(),
+            tree: Arc::new(Mutex::new(BTree::open(filename, frames)?)),
+        })
+    }
+
+    /// The log index the tree holds balances for (its WAL was already replayed by open).
+    pub fn synced_index(&self) -> u64 {
+        self.tree.lock().unwrap().applied_index()
+    }
+
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.discard();
+        self.tree.lock().unwrap().reset()
+    }
+
+    /// Forgets every change since the last checkpoint (the tree is left as it is)
+    pub fn discard(&mut self) {
+        self.cache.clear();
+        self.dirty.clear();
+    }
+
+    pub fn insert(&mut self, user_id: u64, portfolio: Portfolio) {
+        self.cache.insert(user_id, portfolio);
+        self.dirty.insert(user_id);
+    }
+
+    /// An account that was never funded (or was emptied) reads as all zeros.
+    pub fn get(&self, user_id: &u64) -> io::Result<Portfolio> {
+        match self.cache.get(user_id) {
+            Some(p) => Ok(p.clone()),
+            None => Ok(self.load(*user_id)?.unwrap_or_default()),
+        }
+    }
+
+    fn load(&self, user_id: u64) -> io::Result<Option<Portfolio>> {
+        let record = self.tree.lock().unwrap().get(&account_key(user_id))?;
+        Ok(record.map(|record| decode_portfolio(&record)))
+    }
+
+    /// Like HashMap::entry().or_default(): loads the account into the cache
+    /// (creating it if needed) and marks it for write-back.
+    pub fn entry(&mut self, user_id: u64) -> io::Result<&mut Portfolio> {
+        if !self.cache.contains_key(&user_id) {
+            let loaded = self.load(user_id)?.unwrap_or_default();
+            self.cache.insert(user_id, loaded);
+        }
+        Ok(self.cached_mut(user_id))
+    }
+
+    /// An account entry() already loaded. Matching and settling can't stop halfway for a
+    /// read error, so requests load theirs first (AppState::load_accounts); accounts with
+    /// resting orders never leave the cache. Panics if it isn't there.
+    pub fn cached(&self, user_id: u64) -> &Portfolio {
+        self.cache.get(&user_id).expect("account loaded before the request changes anything")
+    }
+
+    /// Same, marked for write-back
+    pub fn cached_mut(&mut self, user_id: u64) -> &mut Portfolio {
+        self.dirty.insert(user_id);
+        self.cache.get_mut(&user_id).expect("account loaded before the request changes anything")
+    }
+
+    /// Copies every account changed since the last checkpoint, as of `log_index`.
+    /// Cheap, so it can run under the AppState lock; the slow part is Checkpoint::write.
+    pub fn freeze(&mut self, users: &UserIndex, log_index: u64) -> Checkpoint {
+        let changed = self.dirty.drain().map(|user_id| (user_id, self.cache[&user_id].clone())).collect();
+        Checkpoint {
+            log_index,
+            changed,
+            accounts: self.tree.clone(),
+            users: users.tree.clone(),
+            users_seen: users.next_id,
+        }
+    }
+
+    /// After a successful Checkpoint::write: drops accounts RAM no longer needs
+    /// (unless they changed again meanwhile, or hold reservations).
+    pub fn release(&mut self, checkpoint: &Checkpoint) {
+        for user_id in checkpoint.changed.keys() {
+            let keep = self.dirty.contains(user_id) || self.cache.get(user_id).is_some_and(|p| p.has_reservations());
+            if !keep {
+                self.cache.remove(user_id);
+            }
+        }
+    }
+
+    /// After a failed Checkpoint::write: the accounts are dirty again, for the next one.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) {
+        self.dirty.extend(checkpoint.changed.keys());
+    }
+}
+
+// --- CHECKPOINTS ---
+// Both trees, brought up to one log index. Captured by Accounts::freeze under the AppState lock,
+// written outside it: trading only waits for the tree mutex, and only on a cache miss.
+// The accounts tree only ever changes here, so between checkpoints it's stable.
+pub struct Checkpoint {
+    pub log_index: u64,
+    changed: HashMap<u64, Portfolio>, // Accounts changed since the last checkpoint, as of log_index
+    accounts: Arc<Mutex<BTree>>,
+    users: Arc<Mutex<BTree>>,
+    users_seen: u64, // users.bin records in users.idx
+}
+
+impl Checkpoint {
+    /// Writes the changed accounts into the tree as one transaction stamped with
+    /// `log_index`, flushes it to disk, then does the same for users.idx.
+    pub fn write(&self) -> io::Result<()> {
+        let mut tree = self.accounts.lock().unwrap();
+        tree.begin();
+
+        for (user_id, p) in &self.changed {
+            // Empty accounts aren't stored at all
+            if p.cash == 0 && p.stocks.values().all(|q| *q == 0) {
+                tree.delete(&account_key(*user_id))?;
+            } else {
+                tree.insert(&account_key(*user_id), &encode_portfolio(*user_id, p))?;
+            }
+        }
+
+        tree.set_applied_index(self.log_index)?;
+        tree.commit()?;
+        tree.checkpoint()?;
+        drop(tree);
+
+        let mut tree = self.users.lock().unwrap();
+        tree.begin();
+        tree.set_applied_index(self.users_seen)?;
+        tree.commit()?;
+        tree.checkpoint()
+    }
+
+    /// Visits every account once, as of log_index (tree contents, overridden by the changed ones).
+    pub fn for_each(&self, mut f: impl FnMut(u64, &Portfolio)) -> io::Result<()> {
+        let mut seen = HashSet::new();
+
+        self.accounts.lock().unwrap().range(&[], None, |key, record| {
+            let user_id = u64::from_be_bytes(key.try_into().unwrap());
+            seen.insert(user_id);
+            match self.changed.get(&user_id) {
+                Some(changed) => f(user_id, changed),
+                None => f(user_id, &decode_portfolio(record)),
+            }
+        })?;
+
+        for (user_id, p) in &self.changed {
+            if !seen.contains(user_id) {
+                f(*user_id, p);
+            }
+        }
+        Ok(())
+    }
+}
+
+// --- USER INDEX (users.idx) ---
+// username -> the user's UserMeta record, whose user_id is its position in users.bin.
+// Keeping the whole record here lets login see users registered since startup.
+pub struct UserIndex {
+    tree: Arc<Mutex<BTree>>,
+    next_id: u64, // == number of records in users.bin
+}
+
+impl UserIndex {
+    /// Opens the index and catches it up with users.bin, which is the source of truth.
+    /// The tree's applied_index counts how many users.bin records it has seen.
+    /// Only the records it hasn't seen are read.
+    pub fn open(filename: &str, frames: usize, users: &DatabaseReader) -> io::Result<Self> {
+        let mut tree = BTree::open(filename, frames)?;
+
+        let indexed = tree.applied_index();
+        tree.begin();
+        for user_id in indexed..users.num_users() {
+            let Some(user) = users.user(user_id)? else { break };
+            let name = user_name(&user);
+            if !name.is_empty() {
+                tree.insert(name.as_bytes(), bytemuck::bytes_of(&user))?;
+            }
+        }
+        tree.set_applied_index(users.num_users())?;
+        tree.commit()?;
+
+        Ok(Self { tree: Arc::new(Mutex::new(tree)), next_id: users.num_users() })
+    }
+
+    pub fn get(&self, username: &str) -> io::Result<Option<u64>> {
+        Ok(self.get_meta(username)?.map(|user| user.user_id))
+    }
+
+    pub fn get_meta(&self, username: &str) -> io::Result<Option<UserMeta>> {
+        let value = self.tree.lock().unwrap().get(username.as_bytes())?;
+        Ok(value.and_then(|value| bytemuck::try_pod_read_unaligned(&value).ok()))
+    }
+
+    pub fn contains(&self, username: &str) -> io::Result<bool> {
+        Ok(self.get(username)?.is_some())
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    pub fn insert(&mut self, user: &UserMeta) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+        tree.insert(user_name(user).as_bytes(), bytemuck::bytes_of(user))?;
+        tree.commit()?;
+        self.next_id = self.next_id.max(user.user_id + 1);
+        Ok(())
+    }
+
+    /// Back to what users.bin holds, after the persister lost a write: registrations it
+    /// never wrote are dropped, and flags go back to their users.bin values
+    /// (replaying the log's SetFlags entries brings the durable changes back).
+    pub fn rollback(&mut self, users: &DatabaseReader) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+        let mut changed = Vec::new();
+        let mut failed = None;
+        tree.range(&[], None, |key, value| {
+            let Ok(user) = bytemuck::try_pod_read_unaligned::<UserMeta>(value) else { return };
+            match users.user(user.user_id) {
+                Ok(Some(on_disk)) if on_disk.flags == user.flags => {}
+                Ok(Some(on_disk)) => changed.push((key.to_vec(), Some(UserMeta { flags: on_disk.flags, ..user }))),
+                Ok(None) => changed.push((key.to_vec(), None)),
+                Err(e) => { failed.get_or_insert(e); }
+            }
+        })?;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+
+        tree.begin();
+        for (key, user) in changed {
+            match user {
+                Some(user) => tree.insert(&key, bytemuck::bytes_of(&user))?,
+                None => tree.delete(&key)?,
+            };
+        }
+        tree.set_applied_index(users.num_users())?;
+        tree.commit()?;
+        self.next_id = users.num_users();
+        Ok(())
+    }
+}
+
+// The username field without its zero padding
+pub fn user_name(user: &UserMeta) -> &str {
+    user_name_bytes(&user.username)
+}
+
+pub fn user_name_bytes(username: &[u8; 32]) -> &str {
+    std::str::from_utf8(username)
+        .unwrap_or("")
+        .trim_matches('\0')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::consts::PAGE_SIZE;
+
+    fn portfolio(user_id: u64) -> Portfolio {
+        Portfolio { cash: 1_000 + user_id as i64, stocks: HashMap::from([(7, user_id as i64)]), ..Default::default() }
+    }
+
+    #[test]
+    fn read_errors_reach_the_caller() {
+        let dir = std::env::temp_dir().join(format!("jdb-accounts-read-error-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("accounts.idx").to_str().unwrap().to_string();
+
+        // 1. Enough accounts for a few levels of pages, all on disk
+        let mut tree = BTree::open(&file, 8).unwrap();
+        for user_id in 0..300 {
+            tree.insert(&account_key(user_id), &encode_portfolio(user_id, &portfolio(user_id))).unwrap();
+        }
+        tree.commit().unwrap();
+        tree.checkpoint().unwrap();
+        drop(tree);
+
+        // 2. Most of the file gone from under the pager: an error for the request, not an exit
+        let mut accounts = Accounts::open(&file, 8).unwrap();
+        let bytes = fs::read(&file).unwrap();
+        fs::OpenOptions::new().write(true).open(&file).unwrap().set_len(2 * PAGE_SIZE as u64).unwrap();
+        assert_eq!(accounts.get(&299).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(accounts.entry(299).is_err());
+        assert!(!accounts.cache.contains_key(&299) && !accounts.dirty.contains(&299));
+
+        // 3. Nothing was cached from it, so once the disk is back the next request reads it fine
+        fs::write(&file, &bytes).unwrap();
+        assert_eq!(accounts.get(&299).unwrap().cash, portfolio(299).cash);
+        assert_eq!(accounts.entry(299).unwrap().stocks, portfolio(299).stocks);
+        assert_eq!(accounts.cached(299).cash, portfolio(299).cash);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use axum::body::{to_bytes, Body};
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::auth::AuthUser;
+use crate::consts::{sealed_size, ApiKeyRecord, API_SIGNATURE_WINDOW_SECS, FILE_HEADER_SIZE};
+use crate::crypto::Keyring;
+use crate::format::{self, API_KEYS};
+use crate::writer::make_string;
+use crate::SharedState;
+
+// --- API KEYS (for bots) ---
+// A key is (key_id, secret). Bots sign every request instead of logging in:
+//
+//   X-Api-Key:       key_id (16 hex chars)
+//   X-Api-Timestamp: unix seconds, within API_SIGNATURE_WINDOW_SECS of the server clock
+//   X-Api-Nonce:     any string (max 64 bytes), never reused within the window
+//   X-Api-Signature: hex(HMAC-SHA256(secret, METHOD \n /path?query \n timestamp \n nonce \n body))
+//
+// The HMAC key is the secret exactly as handed out (its 64 hex characters).
+// Nonces are only remembered in RAM, so timestamps from before startup are refused:
+// a request captured before a restart can't be replayed after it.
+// apikeys.bin holds the secrets, so it's sealed record by record like users.bin (crypto.rs).
+
+const MAX_BODY: usize = 1 << 20;
+const MAX_NONCE: usize = 64;
+
+pub struct ApiKeys {
+    file: File,
+    records: u64, // In the file, i.e. the index of the next one
+    sealer: Arc<Keyring>,
+    keys: HashMap<u64, ApiKeyRecord>,
+    seen_nonces: Mutex<Nonces>,
+    started_at: u64,
+}
+
+// (key_id, nonce) pairs, oldest first. A timestamp passes the window check for at most
+// two windows after the nonce was first seen, so that's how long it's kept.
+#[derive(Default)]
+struct Nonces {
+    seen: HashSet<(u64, String)>,
+    order: VecDeque<(u64, u64, String)>, // (seen at, key_id, nonce)
+}
+
+impl Nonces {
+    // false = already seen
+    fn insert(&mut self, key_id: u64, nonce: &str, now: u64) -> bool {
+        while let Some((seen_at, _, _)) = self.order.front() {
+            if seen_at + API_SIGNATURE_WINDOW_SECS * 2 >= now {
+                break;
+            }
+            let (_, key_id, nonce) = self.order.pop_front().unwrap();
+            self.seen.remove(&(key_id, nonce));
+        }
+
+        if !self.seen.insert((key_id, nonce.to_string())) {
+            return false;
+        }
+        self.order.push_back((now, key_id, nonce.to_string()));
+        true
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl ApiKeys {
+    /// By now format::upgrade_data_files has given it a header and sealed it.
+    /// A torn last record (crashed mid-append) is cut off.
+    pub fn open(keys: Arc<Keyring>, engine_id: &[u8; 16]) -> io::Result<Self> {
+        let filename = API_KEYS.file;
+        let mut file = OpenOptions::new().read(true).append(true).open(filename)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        format::check_header(&bytes, &API_KEYS, engine_id)?;
+
+        // Later records (revocations) replace earlier ones
+        let mut records = HashMap::new();
+        let size = sealed_size(size_of::<ApiKeyRecord>());
+        let count = keys.open_each(filename, &bytes[FILE_HEADER_SIZE..], size, FILE_HEADER_SIZE, |plain| {
+            let record: ApiKeyRecord = bytemuck::pod_read_unaligned(plain);
+            records.insert(record.key_id, record);
+            Ok(())
+        })?;
+
+        let valid_len = (FILE_HEADER_SIZE + count * size) as u64;
+        if valid_len < bytes.len() as u64 {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+            println!("[Recovery] {}: cut off a torn last record at byte offset {} ({} bytes)",
+                filename, valid_len, bytes.len() as u64 - valid_len);
+        }
+
+        Ok(Self {
+            file,
+            records: count as u64,
+            sealer: keys,
+            keys: records,
+            seen_nonces: Mutex::new(Nonces::default()),
+            started_at: now(),
+        })
+    }
+
+    /// Returns the new record; its secret is only ever shown in this response.
+    pub fn create(&mut self, user: &AuthUser, scopes: u32) -> io::Result<ApiKeyRecord> {
+        let mut random = [0u8; 40];
+        getrandom::getrandom(&mut random).expect("OS random number generator unavailable");
+
+        let record = ApiKeyRecord {
+            key_id: u64::from_le_bytes(random[0..8].try_into().unwrap()),
+            user_id: user.user_id,
+            username: make_string(&user.username),
+            secret: random[8..40].try_into().unwrap(),
+            created_at: now(),
+            revoked_at: 0,
+            scopes,
+            _padding: [0; 4],
+        };
+        self.append(&record)?;
+        Ok(record)
+    }
+
+    pub fn list(&self, user_id: u64) -> Vec<&ApiKeyRecord> {
+        let mut keys: Vec<_> = self.keys.values().filter(|k| k.user_id == user_id).collect();
+        keys.sort_by_key(|k| k.created_at);
+        keys
+    }
+
+    pub fn revoke(&mut self, user_id: u64, key_id: u64) -> io::Result<bool> {
+        let Some(record) = self.keys.get(&key_id).filter(|k| k.user_id == user_id && k.revoked_at == 0) else {
+            return Ok(false);
+        };
+        let record = ApiKeyRecord { revoked_at: now(), ..*record };
+        self.append(&record)?;
+        Ok(true)
+    }
+
+    fn append(&mut self, record: &ApiKeyRecord) -> io::Result<()> {
+        let sealed = self.sealer.seal_record(API_KEYS.file, self.records, bytemuck::bytes_of(record));
+        let written = self.file.write_all(&sealed).and_then(|_| self.file.sync_data());
+        if written.is_err() {
+            // Don't leave half a record for the next one to land behind
+            let _ = self.file.set_len((FILE_HEADER_SIZE + self.records as usize * sealed.len()) as u64);
+        }
+        written?;
+        self.records += 1;
+        self.keys.insert(record.key_id, *record);
+        Ok(())
+    }
+
+    // Checks one signed request. Err = why it was refused.
+    fn verify(&self, headers: &HeaderMap, method: &str, path: &str, body: &[u8]) -> Result<AuthUser, &'static str> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+        // 1. Known, live key
+        let key_id = header("x-api-key")
+            .and_then(|v| u64::from_str_radix(v, 16).ok())
+            .ok_or("Invalid API key")?;
+        let record = self.keys.get(&key_id)
+            .filter(|k| k.revoked_at == 0)
+            .ok_or("Invalid API key")?;
+
+        // 2. Fresh timestamp
+        let timestamp: u64 = header("x-api-timestamp").and_then(|v| v.parse().ok()).ok_or("Missing timestamp")?;
+        let now = now();
+        if timestamp.abs_diff(now) > API_SIGNATURE_WINDOW_SECS {
+            return Err("Timestamp outside the allowed window");
+        }
+        if timestamp < self.started_at {
+            return Err("Timestamp from before the server started");
+        }
+        let nonce = header("x-api-nonce").filter(|n| !n.is_empty() && n.len() <= MAX_NONCE).ok_or("Missing nonce")?;
+
+        // 3. Signature (constant-time compare)
+        let signature = header("x-api-signature").and_then(|v| hex::decode(v).ok()).ok_or("Invalid signature")?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(hex::encode(record.secret).as_bytes())
+            .expect("HMAC takes any key size");
+        mac.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes());
+        mac.update(body);
+        mac.verify_slice(&signature).map_err(|_| "Invalid signature")?;
+
+        // 4. Replay: each nonce once per window. Only checked after the signature,
+        // so nobody can burn someone else's nonces.
+        if !self.seen_nonces.lock().unwrap().insert(key_id, nonce, now) {
+            return Err("Nonce already used");
+        }
+
+        Ok(AuthUser {
+            user_id: record.user_id,
+            username: crate::accounts::user_name_bytes(&record.username).to_string(),
+            session_id: 0,
+            expires_at: 0,
+            scopes: record.scopes,
+            api_key: Some(key_id),
+            flags: 0,
+        })
+    }
+}
+
+// Middleware: requests carrying X-Api-Key are checked here (this needs the body,
+// which an extractor can't see) and handed on with the AuthUser attached.
+pub async fn verify_signature(State(state): State<SharedState>, request: Request, next: Next) -> Response {
+    if !request.headers().contains_key("x-api-key") {
+        return next.run(request).await;
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(serde_json::json!({"error": "Body too large"}))).into_response();
+    };
+
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let verified = state.read().unwrap().api_keys.verify(&parts.headers, parts.method.as_str(), path, &body);
+    match verified {
+        Ok(user) => {
+            parts.extensions.insert(user);
+            next.run(Request::from_parts(parts, Body::from(body))).await
+        }
+        Err(e) => (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": e}))).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{SCOPE_READ, SCOPE_TRADE};
+
+    fn owner() -> AuthUser {
+        AuthUser { user_id: 3, username: "alice".to_string(), session_id: 1, expires_at: u64::MAX, scopes: 0, api_key: None, flags: 0 }
+    }
+
+    // The headers a bot sends, signed with `record`'s secret
+    fn signed(record: &ApiKeyRecord, method: &str, path: &str, timestamp: u64, nonce: &str, body: &[u8]) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(hex::encode(record.secret).as_bytes()).unwrap();
+        mac.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes());
+        mac.update(body);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", format!("{:016x}", record.key_id).parse().unwrap());
+        headers.insert("x-api-timestamp", timestamp.to_string().parse().unwrap());
+        headers.insert("x-api-nonce", nonce.parse().unwrap());
+        headers.insert("x-api-signature", hex::encode(mac.finalize().into_bytes()).parse().unwrap());
+        headers
+    }
+
+    fn open_keys() -> ApiKeys {
+        let keys = Arc::new(Keyring::load("data.key").unwrap());
+        let engine_id = format::upgrade_data_files(&keys).unwrap();
+        let mut api_keys = ApiKeys::open(keys, &engine_id).unwrap();
+        api_keys.started_at -= 10; // So the tests can sign a little in the past
+        api_keys
+    }
+
+    #[test]
+    fn signed_requests_are_checked() {
+        format::in_data_dir("apikeys-verify", || {
+            let mut api_keys = open_keys();
+            let record = api_keys.create(&owner(), SCOPE_READ | SCOPE_TRADE).unwrap();
+            let body = br#"{"symbol_id":7,"quantity":1}"#;
+            let now = now();
+
+            // 1. A good one
+            let user = api_keys.verify(&signed(&record, "POST", "/trade", now, "n1", body), "POST", "/trade", body).unwrap();
+            assert_eq!((user.user_id, user.username.as_str(), user.api_key), (3, "alice", Some(record.key_id)));
+            assert_eq!(user.require(SCOPE_TRADE), Ok(()));
+
+            // 2. Signed for something else than what arrived
+            let headers = signed(&record, "POST", "/trade", now, "n2", body);
+            assert_eq!(api_keys.verify(&headers, "POST", "/trade", b"{}").unwrap_err(), "Invalid signature");
+            assert_eq!(api_keys.verify(&headers, "POST", "/transfer", body).unwrap_err(), "Invalid signature");
+            assert_eq!(api_keys.verify(&headers, "PUT", "/trade", body).unwrap_err(), "Invalid signature");
+
+            // 3. Outside the window, or from before the server started
+            let stale = now - API_SIGNATURE_WINDOW_SECS - 1;
+            let headers = signed(&record, "GET", "/balance", stale, "n3", b"");
+            assert_eq!(api_keys.verify(&headers, "GET", "/balance", b"").unwrap_err(), "Timestamp outside the allowed window");
+            let headers = signed(&record, "GET", "/balance", now + API_SIGNATURE_WINDOW_SECS + 1, "n3", b"");
+            assert_eq!(api_keys.verify(&headers, "GET", "/balance", b"").unwrap_err(), "Timestamp outside the allowed window");
+            let headers = signed(&record, "GET", "/balance", api_keys.started_at - 1, "n3", b"");
+            assert_eq!(api_keys.verify(&headers, "GET", "/balance", b"").unwrap_err(), "Timestamp from before the server started");
+        });
+    }
+
+    #[test]
+    fn nonces_revocation_and_scopes() {
+        format::in_data_dir("apikeys-replay", || {
+            let mut api_keys = open_keys();
+            let record = api_keys.create(&owner(), SCOPE_READ).unwrap();
+            let now = now();
+
+            // 1. A forged request with a nonce doesn't use it up; the real one goes through once
+            let mut forged = signed(&record, "GET", "/balance", now, "n1", b"");
+            forged.insert("x-api-signature", "00".repeat(32).parse().unwrap());
+            assert_eq!(api_keys.verify(&forged, "GET", "/balance", b"").unwrap_err(), "Invalid signature");
+            let headers = signed(&record, "GET", "/balance", now, "n1", b"");
+            let user = api_keys.verify(&headers, "GET", "/balance", b"").unwrap();
+            assert_eq!(api_keys.verify(&headers, "GET", "/balance", b"").unwrap_err(), "Nonce already used");
+
+            // 2. Only the scopes it was created with
+            assert_eq!(user.require(SCOPE_READ), Ok(()));
+            assert_eq!(user.require(SCOPE_TRADE), Err("Missing scope: trade"));
+
+            // 3. Revoked: refused, and still after reopening
+            assert!(api_keys.revoke(3, record.key_id).unwrap());
+            let headers = signed(&record, "GET", "/balance", now, "n2", b"");
+            assert_eq!(api_keys.verify(&headers, "GET", "/balance", b"").unwrap_err(), "Invalid API key");
+            let reopened = open_keys();
+            assert_eq!(reopened.verify(&headers, "GET", "/balance", b"").unwrap_err(), "Invalid API key");
+            assert!(reopened.list(3)[0].revoked_at > 0);
+        });
+    }
+}
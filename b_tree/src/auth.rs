@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use argon2::{Algorithm, Argon2, Params, Version};
+use axum::extract::FromRequestParts;
+use axum::http::{header::AUTHORIZATION, request::Parts, StatusCode};
+use axum::Json;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use crate::consts::{
+    env_u64, UserMeta, SCOPE_ALL, SCOPE_READ, SCOPE_TRADE, SCOPE_WITHDRAW, SESSION_TTL_SECS, USER_ACTIVE, USER_ADMIN,
+    USER_FROZEN, USER_KYC_VERIFIED, USER_WITHDRAW_DISABLED,
+};
+use crate::SharedState;
+
+// --- PASSWORDS ---
+// Argon2id with a random 16-byte salt per user. Hash and salt live in UserMeta
+// (pass_hash / salt), so users.bin is all we need to verify a login.
+
+// OWASP minimum for Argon2id: 19 MiB, 2 passes, 1 lane
+fn hasher() -> Argon2<'static> {
+    let params = Params::new(19 * 1024, 2, 1, Some(32)).expect("valid Argon2 params");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+pub fn new_salt() -> [u8; 16] {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).expect("OS random number generator unavailable");
+    salt
+}
+
+// Slow on purpose (tens of ms): call it outside the state lock
+pub fn hash_password(password: &str, salt: &[u8; 16]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hasher()
+        .hash_password_into(password.as_bytes(), salt, &mut hash)
+        .expect("Argon2 output size is fixed");
+    hash
+}
+
+/// Constant-time check. `None` (unknown user) still pays for a hash, so the
+/// response time doesn't reveal which usernames exist.
+pub fn verify_password(user: Option<&UserMeta>, password: &str) -> bool {
+    let Some(user) = user else {
+        hash_password(password, &[0; 16]);
+        return false;
+    };
+    // Accounts created before hashing existed have no password that could match
+    if user.pass_hash == [0; 32] {
+        return false;
+    }
+    hash_password(password, &user.salt).ct_eq(&user.pass_hash).into()
+}
+
+// --- SESSIONS ---
+// token = hex(user_id(8) | session_id(8) | expires_at(8) | username | HMAC-SHA256 of all that)
+// Stateless to check: only the HMAC key and the revocation list are needed.
+// The key comes from JDB_SESSION_KEY (64 hex chars) or session.key (created on first start),
+// so tokens survive a restart. Revoked session IDs are kept in sessions.revoked until they expire.
+
+const PAYLOAD_HEADER: usize = 24;
+const MAC_SIZE: usize = 32;
+
+/// The user a request was authenticated as.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: u64,
+    pub username: String,
+    pub session_id: u64,     // 0 for API keys
+    pub expires_at: u64,
+    pub scopes: u32,         // SCOPE_ALL for sessions
+    pub api_key: Option<u64>, // Set when the request was signed with an API key
+    pub flags: u32,          // UserMeta.flags as of this request (filled in by the extractor)
+}
+
+// Moving money out (withdrawals, transfers) needs USER_KYC_VERIFIED. Accounts from before the
+// flag existed don't have it, so upgrading locks them out until an admin verifies them:
+// JDB_WITHDRAW_REQUIRES_KYC=0 turns the check off in the meantime.
+fn withdraw_requires_kyc() -> bool {
+    static REQUIRED: OnceLock<bool> = OnceLock::new();
+    *REQUIRED.get_or_init(|| env_u64("JDB_WITHDRAW_REQUIRES_KYC", 1) != 0)
+}
+
+impl AuthUser {
+    /// What the account's flags allow first, then what the credential's scopes allow.
+    pub fn require(&self, scope: u32) -> Result<(), &'static str> {
+        if scope != SCOPE_READ && self.flags & USER_FROZEN != 0 {
+            return Err("Account frozen");
+        }
+        if scope & SCOPE_WITHDRAW != 0 {
+            if self.flags & USER_WITHDRAW_DISABLED != 0 {
+                return Err("Withdrawals disabled");
+            }
+            if withdraw_requires_kyc() && self.flags & USER_KYC_VERIFIED == 0 {
+                return Err("KYC verification required");
+            }
+        }
+
+        if self.scopes & scope == scope {
+            return Ok(());
+        }
+        Err(match scope {
+            SCOPE_READ => "Missing scope: read",
+            SCOPE_TRADE => "Missing scope: trade",
+            SCOPE_WITHDRAW => "Missing scope: withdraw",
+            _ => "Missing scope",
+        })
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.flags & USER_ADMIN != 0
+    }
+}
+
+pub struct Sessions {
+    key: [u8; 32],
+    revoked: HashMap<u64, u64>, // session_id -> expires_at
+    revoked_file: File,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl Sessions {
+    pub fn open(key_file: &str, revoked_file: &str) -> io::Result<Self> {
+        let key = load_key(key_file, "JDB_SESSION_KEY", "session")?;
+
+        // 1. Load the revocation list, forgetting sessions that expired anyway
+        let mut bytes = Vec::new();
+        if let Ok(mut file) = File::open(revoked_file) {
+            file.read_to_end(&mut bytes)?;
+        }
+        let now = now();
+        let revoked: HashMap<u64, u64> = bytes.chunks_exact(16)
+            .map(|r| (u64::from_le_bytes(r[0..8].try_into().unwrap()), u64::from_le_bytes(r[8..16].try_into().unwrap())))
+            .filter(|(_, expires_at)| *expires_at > now)
+            .collect();
+
+        // 2. Rewrite it without them
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(revoked_file)?;
+        for (session_id, expires_at) in &revoked {
+            file.write_all(&session_id.to_le_bytes())?;
+            file.write_all(&expires_at.to_le_bytes())?;
+        }
+        file.sync_all()?;
+
+        Ok(Self { key, revoked, revoked_file: file })
+    }
+
+    /// New session token for a user who just proved who they are. Returns (token, expires_at).
+    pub fn issue(&self, user_id: u64, username: &str) -> (String, u64) {
+        let mut session_id = [0u8; 8];
+        getrandom::getrandom(&mut session_id).expect("OS random number generator unavailable");
+        let expires_at = now() + SESSION_TTL_SECS;
+
+        let mut token = Vec::with_capacity(PAYLOAD_HEADER + username.len() + MAC_SIZE);
+        token.extend_from_slice(&user_id.to_le_bytes());
+        token.extend_from_slice(&session_id);
+        token.extend_from_slice(&expires_at.to_le_bytes());
+        token.extend_from_slice(username.as_bytes());
+        let mac = self.mac().chain_update(&token).finalize().into_bytes();
+        token.extend_from_slice(&mac);
+
+        (hex::encode(token), expires_at)
+    }
+
+    pub fn verify(&self, token: &str) -> Option<AuthUser> {
+        let bytes = hex::decode(token).ok()?;
+        if bytes.len() < PAYLOAD_HEADER + MAC_SIZE {
+            return None;
+        }
+        let (payload, mac) = bytes.split_at(bytes.len() - MAC_SIZE);
+        self.mac().chain_update(payload).verify_slice(mac).ok()?;
+
+        let field = |i: usize| u64::from_le_bytes(payload[i * 8..i * 8 + 8].try_into().unwrap());
+        let user = AuthUser {
+            user_id: field(0),
+            session_id: field(1),
+            expires_at: field(2),
+            username: String::from_utf8(payload[PAYLOAD_HEADER..].to_vec()).ok()?,
+            scopes: SCOPE_ALL,
+            api_key: None,
+            flags: 0,
+        };
+        if user.expires_at <= now() || self.revoked.contains_key(&user.session_id) {
+            return None;
+        }
+        Some(user)
+    }
+
+    /// Logout: the token stops working right away (and after a restart).
+    pub fn revoke(&mut self, user: &AuthUser) -> io::Result<()> {
+        self.revoked_file.write_all(&user.session_id.to_le_bytes())?;
+        self.revoked_file.write_all(&user.expires_at.to_le_bytes())?;
+        self.revoked_file.sync_data()?;
+
+        let now = now();
+        self.revoked.retain(|_, expires_at| *expires_at > now);
+        self.revoked.insert(user.session_id, user.expires_at);
+        Ok(())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key size")
+    }
+}
+
+// `env` (64 hex chars) wins over the file; `what` names the key in the log
+fn load_key(key_file: &str, env: &str, what: &str) -> io::Result<[u8; 32]> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{} must be 32 bytes", what));
+
+    if let Ok(hex_key) = std::env::var(env) {
+        let name = format!("{} (hex)", env);
+        let bytes = hex::decode(hex_key.trim()).map_err(|_| invalid(&name))?;
+        return bytes.try_into().map_err(|_| invalid(&name));
+    }
+
+    if let Ok(mut file) = File::open(key_file) {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        return bytes.try_into().map_err(|_| invalid(key_file));
+    }
+
+    // First start: make one, readable by us only
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key).expect("OS random number generator unavailable");
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(key_file)?;
+    file.write_all(&key)?;
+    file.sync_all()?;
+    println!("[Auth] Generated a new {} key in {}", what, key_file);
+    Ok(key)
+}
+
+// --- REQUEST KEYS ---
+// Clients send any string as their idempotency key. The journal keeps a 128-bit HMAC-SHA256 of it,
+// keyed with JDB_REQUEST_KEY (64 hex chars) or request.key (created on first start), so two
+// keys can't be made to collide on purpose. Without the old key, retries from before a
+// restart are new requests.
+pub struct RequestHasher {
+    key: [u8; 32],
+}
+
+impl RequestHasher {
+    pub fn open(key_file: &str) -> io::Result<Self> {
+        Ok(Self { key: load_key(key_file, "JDB_REQUEST_KEY", "request")? })
+    }
+
+    /// Never 0, which means "no key"
+    pub fn hash(&self, request_id: &str) -> u128 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key size");
+        mac.update(request_id.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        u128::from_le_bytes(digest[..16].try_into().unwrap()).max(1)
+    }
+}
+
+// "Authorization: Bearer <token>" on every route that acts for a user
+// (or a signed API request, already checked by apikeys::verify_signature)
+impl FromRequestParts<SharedState> for AuthUser {
+    type Rejection = (StatusCode, Json<serde_json::Value>);
+
+    async fn from_request_parts(parts: &mut Parts, state: &SharedState) -> Result<Self, Self::Rejection> {
+        let unauthorized = |e: &str| (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": e})));
+
+        let mut user = match parts.extensions.get::<AuthUser>() {
+            Some(user) => user.clone(),
+            None => session_user(parts, state).map_err(unauthorized)?,
+        };
+
+        // Flags are looked up every time, so freezing an account takes effect on its next request
+        let meta = state.read().unwrap().users.get_meta(&user.username).map_err(|e| {
+            eprintln!("[Auth] users.idx read failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Storage Error"})))
+        })?;
+        user.flags = meta.filter(|m| m.user_id == user.user_id).map_or(0, |m| m.flags);
+        if user.flags & USER_ACTIVE == 0 {
+            return Err((StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "Account disabled"}))));
+        }
+        Ok(user)
+    }
+}
+
+fn session_user(parts: &Parts, state: &SharedState) -> Result<AuthUser, &'static str> {
+    let token = parts.headers.get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or("Missing session token")?;
+
+    state.read().unwrap().sessions.verify(token.trim()).ok_or("Invalid or expired session")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::format;
+
+    fn user(password: &str) -> UserMeta {
+        let salt = new_salt();
+        UserMeta { user_id: 3, salt, pass_hash: hash_password(password, &salt), ..bytemuck::Zeroable::zeroed() }
+    }
+
+    // A token for any expiry, signed the way issue() signs them
+    fn token(sessions: &Sessions, session_id: u64, expires_at: u64) -> String {
+        let mut token = [3u64.to_le_bytes(), session_id.to_le_bytes(), expires_at.to_le_bytes()].concat();
+        token.extend_from_slice(b"alice");
+        let mac = sessions.mac().chain_update(&token).finalize().into_bytes();
+        token.extend_from_slice(&mac);
+        hex::encode(token)
+    }
+
+    fn auth_user(flags: u32, scopes: u32) -> AuthUser {
+        AuthUser { user_id: 3, username: "alice".to_string(), session_id: 1, expires_at: u64::MAX, scopes, api_key: None, flags }
+    }
+
+    #[test]
+    fn passwords_verify_against_their_hash() {
+        let alice = user("correct horse");
+        assert!(verify_password(Some(&alice), "correct horse"));
+        assert!(!verify_password(Some(&alice), "correct horsf"));
+        // From before hashing: nothing matches, not even an empty password
+        let legacy = UserMeta { pass_hash: [0; 32], ..alice };
+        assert!(!verify_password(Some(&legacy), ""));
+
+        // An unknown user costs a hash too, so the time taken doesn't tell who exists
+        let started = Instant::now();
+        assert!(!verify_password(Some(&alice), "wrong"));
+        let known = started.elapsed();
+        let started = Instant::now();
+        assert!(!verify_password(None, "correct horse"));
+        assert!(started.elapsed() * 4 > known, "{:?} vs {:?}", started.elapsed(), known);
+    }
+
+    #[test]
+    fn session_tokens_check_out() {
+        format::in_data_dir("auth-sessions", || {
+            let mut sessions = Sessions::open("session.key", "sessions.revoked").unwrap();
+            let (issued, expires_at) = sessions.issue(3, "alice");
+            let user = sessions.verify(&issued).unwrap();
+            assert_eq!((user.user_id, user.username.as_str(), user.expires_at, user.scopes), (3, "alice", expires_at, SCOPE_ALL));
+
+            // 1. Tampered: any byte of the payload or the MAC
+            for at in [0, 20, 48, issued.len() - 1] {
+                let mut tampered = issued.clone().into_bytes();
+                tampered[at] = if tampered[at] == b'0' { b'1' } else { b'0' };
+                assert!(sessions.verify(std::str::from_utf8(&tampered).unwrap()).is_none(), "byte {}", at);
+            }
+            assert!(sessions.verify(&issued[..40]).is_none());
+
+            // 2. Expired, though signed
+            assert!(sessions.verify(&token(&sessions, 5, now() + 60)).is_some());
+            assert!(sessions.verify(&token(&sessions, 5, now() - 1)).is_none());
+
+            // 3. Revoked, and still after a restart
+            sessions.revoke(&user).unwrap();
+            assert!(sessions.verify(&issued).is_none());
+            let reopened = Sessions::open("session.key", "sessions.revoked").unwrap();
+            assert!(reopened.verify(&issued).is_none());
+            assert!(reopened.verify(&token(&reopened, 5, now() + 60)).is_some());
+
+            // 4. Another key signed it
+            std::fs::remove_file("session.key").unwrap();
+            let other = Sessions::open("session.key", "sessions.revoked").unwrap();
+            assert!(other.verify(&token(&reopened, 5, now() + 60)).is_none());
+        });
+    }
+
+    #[test]
+    fn account_flags_come_before_scopes() {
+        let verified = USER_ACTIVE | USER_KYC_VERIFIED;
+        assert_eq!(auth_user(verified, SCOPE_ALL).require(SCOPE_WITHDRAW), Ok(()));
+
+        // Frozen: may still look
+        let frozen = auth_user(verified | USER_FROZEN, SCOPE_ALL);
+        assert_eq!(frozen.require(SCOPE_READ), Ok(()));
+        assert_eq!(frozen.require(SCOPE_TRADE), Err("Account frozen"));
+        assert_eq!(frozen.require(SCOPE_WITHDRAW), Err("Account frozen"));
+
+        // Withdrawals disabled: may still trade
+        let grounded = auth_user(verified | USER_WITHDRAW_DISABLED, SCOPE_ALL);
+        assert_eq!(grounded.require(SCOPE_TRADE), Ok(()));
+        assert_eq!(grounded.require(SCOPE_WITHDRAW), Err("Withdrawals disabled"));
+
+        // Not KYC verified (checked unless JDB_WITHDRAW_REQUIRES_KYC=0): may still trade
+        let unverified = auth_user(USER_ACTIVE, SCOPE_ALL);
+        assert_eq!(unverified.require(SCOPE_TRADE), Ok(()));
+        assert_eq!(unverified.require(SCOPE_WITHDRAW), Err("KYC verification required"));
+
+        // A credential only gets the scopes it was given
+        let read_only = auth_user(verified, SCOPE_READ);
+        assert_eq!(read_only.require(SCOPE_READ), Ok(()));
+        assert_eq!(read_only.require(SCOPE_TRADE), Err("Missing scope: trade"));
+        assert_eq!(auth_user(verified | USER_FROZEN, SCOPE_READ).require(SCOPE_TRADE), Err("Account frozen"));
+    }
+}
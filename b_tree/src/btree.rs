@@ -0,0 +1,1203 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::mem::size_of;
+use crate::consts::{TreeMeta, WalKind, WalRecordHeader, PAGE_LSN_OFFSET, PAGE_SIZE, TREE_MAGIC, TREE_VERSION};
+use crate::crash;
+use crate::nodes::{self, LeafValue, Node, MAX_INLINE, MAX_KEY, MIN_USED, NODE_INTERNAL, NODE_LEAF};
+use crate::pager::{page_lsn, set_page_lsn, Pager};
+use crate::wal::{Wal, WalRecord};
+
+// --- B+TREE (bytes -> bytes) ---
+// Keys compare as raw bytes (so big-endian integers sort numerically).
+// Values only live in leaves; leaves are chained left-to-right for range scans.
+// Page 0 holds TreeMeta, page 1 is the first root.
+//
+// Mutations copy the pages they touch into a local buffer, edit them through
+// a Node view, then store them back. store() logs the changed byte range
+// (before + after image) to the WAL and stamps the page with that record's LSN.
+//
+// TRANSACTIONS: every change belongs to the open transaction (insert/delete start
+// one if needed). commit() makes it durable. After a crash, open() replays the WAL
+// ARIES-style: redo everything, then undo whatever never committed.
+//
+// I/O ERRORS: a change that fails halfway (the pager couldn't read a page, or write one back
+// to make room) would leave the pages it already touched half-done. So the tree rolls back to
+// its last commit the way it would after a crash: the pool is dropped unwritten and the WAL
+// replayed. The error still goes to the caller; the whole open transaction is gone.
+pub struct BTree {
+    pager: Pager,
+    meta: TreeMeta,
+    txn: Option<Txn>,
+    file: String,
+    frames: usize,
+    broken: bool, // A change failed and so did rolling it back: only reopening helps
+}
+
+struct Txn {
+    id: u64,       // LSN of its Begin record
+    last_lsn: u64, // Its newest record (undo walks back from here)
+}
+
+// A node split in two: (first key of the new right half, new right page)
+type Split = Option<(Vec<u8>, u32)>;
+
+// OVERFLOW PAGE LAYOUT: [Type(1) | Reserved(1) | Len(2) | NextPage(4) | Data | PageLSN(8)]
+// NextPage sits where a node keeps RightPtr, so freed pages chain the same way.
+const NODE_OVERFLOW: u8 = 2;
+const OVERFLOW_HEADER: usize = 8;
+const OVERFLOW_DATA: usize = PAGE_LSN_OFFSET - OVERFLOW_HEADER;
+
+impl BTree {
+    /// `frames` = buffer pool size in pages (the tree itself may be any size).
+    /// The WAL lives next to the tree file as `<filename>.wal`.
+    pub fn open(filename: &str, frames: usize) -> io::Result<Self> {
+        let mut tree = Self::recover_file(filename, frames)?;
+        if !tree.valid() {
+            // Brand new, unrecognizable, or an older page format.
+            // Index files are derived data, so starting over is always safe.
+            tree.reset()?;
+        }
+        Ok(tree)
+    }
+
+    // The tree as the file and its WAL have it, after recovery (its meta as found)
+    fn recover_file(filename: &str, frames: usize) -> io::Result<Self> {
+        let (wal, records) = Wal::open(&format!("{}.wal", filename))?;
+        let pager = Pager::new(filename, frames, wal)?;
+
+        let mut tree = Self {
+            pager,
+            meta: bytemuck::Zeroable::zeroed(),
+            txn: None,
+            file: filename.to_string(),
+            frames,
+            broken: false,
+        };
+        tree.recover(records)?;
+
+        tree.meta = bytemuck::pod_read_unaligned(&tree.pager.get_page(0)?[..size_of::<TreeMeta>()]);
+        Ok(tree)
+    }
+
+    fn valid(&self) -> bool {
+        self.meta.magic == TREE_MAGIC && self.meta.version == TREE_VERSION
+    }
+
+    /// Drops every entry and starts over with an empty root leaf.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.txn = None;
+        self.pager.truncate()?;
+        self.meta = TreeMeta {
+            magic: TREE_MAGIC,
+            version: TREE_VERSION,
+            _pad1: [0; 2],
+            root_page: 1,
+            page_count: 2,
+            free_head: 0,
+            _pad2: [0; 4],
+            applied_index: 0,
+            entry_count: 0,
+        };
+
+        // Written straight to disk, not logged: there is nothing older to recover.
+        // Root first, so a crash in between leaves no meta pointing at a blank page.
+        Node::new(self.pager.get_page_mut(1)?).init(NODE_LEAF);
+        self.pager.flush()?;
+        let meta = self.meta;
+        self.pager.get_page_mut(0)?[..size_of::<TreeMeta>()].copy_from_slice(bytemuck::bytes_of(&meta));
+        self.pager.flush()?;
+
+        let next_lsn = self.pager.wal().next_lsn();
+        self.pager.wal().truncate(next_lsn)
+    }
+
+    pub fn applied_index(&self) -> u64 {
+        self.meta.applied_index
+    }
+
+    /// Records which log index (or record count) the tree is caught up to.
+    /// Part of the open transaction, so it commits atomically with the data.
+    pub fn set_applied_index(&mut self, applied_index: u64) -> io::Result<()> {
+        self.usable()?;
+        self.meta.applied_index = applied_index;
+        let result = self.write_meta();
+        self.undo_failed(result)
+    }
+
+    fn write_meta(&mut self) -> io::Result<()> {
+        let mut buf = self.load(0)?;
+        buf[..size_of::<TreeMeta>()].copy_from_slice(bytemuck::bytes_of(&self.meta));
+        self.store(0, &buf)
+    }
+
+    // --- I/O ERRORS ---
+
+    fn usable(&self) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::other(format!("{}: a failed change couldn't be rolled back, reopen the tree", self.file)));
+        }
+        Ok(())
+    }
+
+    // After a change: if it failed, back to the last commit (see the top of the file).
+    // Never by starting over, like open() may: the caller still counts on what was committed.
+    fn undo_failed<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if result.is_err() && self.txn.is_some() {
+            match Self::recover_file(&self.file, self.frames) {
+                Ok(tree) if tree.valid() => *self = tree,
+                Ok(_) => {
+                    eprintln!("[BTree] {}: can't roll back a failed change: the meta page is unreadable", self.file);
+                    self.broken = true;
+                }
+                Err(e) => {
+                    eprintln!("[BTree] {}: can't roll back a failed change: {}", self.file, e);
+                    self.broken = true;
+                }
+            }
+        }
+        result
+    }
+
+    // --- TRANSACTIONS ---
+
+    /// Starts a transaction, unless one is already open (then later changes just join it).
+    pub fn begin(&mut self) {
+        if self.txn.is_some() {
+            return;
+        }
+        let id = self.pager.wal().next_lsn();
+        self.txn = Some(Txn { id, last_lsn: 0 });
+        self.log(WalKind::Begin, 0, 0, 0, &[]);
+    }
+
+    /// Makes every change since begin() durable (WAL fsync). The pages themselves
+    /// stay in the pool until eviction or checkpoint().
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.usable()?;
+        if self.txn.is_none() {
+            return Ok(());
+        }
+        crash::point("tree-before-commit");
+        self.log(WalKind::Commit, 0, 0, 0, &[]);
+        self.pager.wal().flush()?;
+        self.txn = None;
+        crash::point("tree-after-commit");
+        Ok(())
+    }
+
+    /// Writes every dirty page to the tree file, then empties the WAL.
+    /// Only between transactions: anything uncommitted would lose its undo records.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.usable()?;
+        if self.txn.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "checkpoint with an open transaction"));
+        }
+
+        // 1. All pages (the pager flushes the WAL ahead of each one)
+        self.pager.flush()?;
+        crash::point("checkpoint-truncate");
+
+        // 2. Nothing in the log is needed anymore
+        let next_lsn = self.pager.wal().next_lsn();
+        self.pager.wal().truncate(next_lsn)
+    }
+
+    // Appends a record to the open transaction's chain
+    fn log(&mut self, kind: WalKind, page_id: u32, offset: usize, undo_next: u64, payload: &[u8]) -> u64 {
+        let txn = self.txn.as_mut().expect("WAL record outside a transaction");
+        let len = match kind {
+            WalKind::Update => payload.len() / 2,
+            _ => payload.len(),
+        };
+        let header = WalRecordHeader {
+            lsn: 0,
+            prev_lsn: txn.last_lsn,
+            txn_id: txn.id,
+            undo_next,
+            page_id,
+            offset: offset as u16,
+            len: len as u16,
+            kind: kind as u8,
+            _padding: [0; 3],
+            crc: 0,
+        };
+        txn.last_lsn = self.pager.wal().append(header, payload);
+        txn.last_lsn
+    }
+
+    // --- RECOVERY (ARIES: analysis, redo, undo) ---
+
+    fn recover(&mut self, records: Vec<WalRecord>) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        println!("[Recovery] Replaying {} WAL records...", records.len());
+
+        // 1. ANALYSIS: transactions that never committed (or finished aborting)
+        let mut losers: HashMap<u64, u64> = HashMap::new(); // txn id -> last lsn
+        for r in &records {
+            match r.kind() {
+                Some(WalKind::Commit | WalKind::Abort) => { losers.remove(&r.header.txn_id); }
+                _ => { losers.insert(r.header.txn_id, r.header.lsn); }
+            }
+        }
+
+        // 2. REDO: repeat history, losers included. The page LSN says what a page already has.
+        for r in &records {
+            if matches!(r.kind(), Some(WalKind::Update | WalKind::Clr)) {
+                let page = self.pager.get_page(r.header.page_id)?;
+                if page_lsn(page) < r.header.lsn {
+                    apply(self.pager.get_page_mut(r.header.page_id)?, r, r.after());
+                }
+            }
+        }
+
+        // 3. UNDO: roll the losers back, newest record first.
+        // Each undone update is logged as a CLR, so a crash during undo never undoes twice.
+        let by_lsn: HashMap<u64, &WalRecord> = records.iter().map(|r| (r.header.lsn, r)).collect();
+        let mut to_undo: BTreeSet<u64> = losers.values().copied().collect();
+        println!("[Recovery] Rolling back {} unfinished transaction(s)", losers.len());
+
+        while let Some(lsn) = to_undo.pop_last() {
+            let r = by_lsn[&lsn];
+            let txn_id = r.header.txn_id;
+            self.txn = Some(Txn { id: txn_id, last_lsn: losers[&txn_id] });
+
+            let next = match r.kind() {
+                Some(WalKind::Update) => {
+                    let clr = self.log(WalKind::Clr, r.header.page_id, r.header.offset as usize, r.header.prev_lsn, r.before());
+                    let page = self.pager.get_page_mut(r.header.page_id)?;
+                    apply(page, r, r.before());
+                    set_page_lsn(page, clr);
+                    crash::point("recovery-undo");
+                    r.header.prev_lsn
+                }
+                Some(WalKind::Clr) => r.header.undo_next,
+                _ => 0, // Begin: nothing before it
+            };
+
+            if next == 0 {
+                self.log(WalKind::Abort, 0, 0, 0, &[]);
+                losers.remove(&txn_id);
+            } else {
+                losers.insert(txn_id, self.txn.as_ref().unwrap().last_lsn);
+                to_undo.insert(next);
+            }
+            self.txn = None;
+        }
+
+        // 4. Everything is consistent again: persist it and start a fresh log
+        self.checkpoint()
+    }
+
+    // --- PAGE ALLOCATION (Free list threaded through RightPtr) ---
+
+    fn allocate_page(&mut self) -> io::Result<u32> {
+        if self.meta.free_head != 0 {
+            let page_id = self.meta.free_head;
+            self.meta.free_head = Node::new(self.pager.get_page(page_id)?).get_right_child();
+            return Ok(page_id);
+        }
+        let page_id = self.meta.page_count;
+        self.meta.page_count += 1;
+        Ok(page_id)
+    }
+
+    fn free_page(&mut self, page_id: u32) -> io::Result<()> {
+        let mut buf = self.load(page_id)?;
+        let mut node = Node::new(&mut buf[..]);
+        node.init(NODE_INTERNAL);
+        node.set_right_child(self.meta.free_head);
+        self.store(page_id, &buf)?;
+        self.meta.free_head = page_id;
+        Ok(())
+    }
+
+    fn load(&mut self, page_id: u32) -> io::Result<Vec<u8>> {
+        Ok(self.pager.get_page(page_id)?.to_vec())
+    }
+
+    // Logs the bytes that differ from the current page, then applies them
+    fn store(&mut self, page_id: u32, buf: &[u8]) -> io::Result<()> {
+        self.begin();
+        let page = self.pager.get_page(page_id)?;
+        let Some(first) = (0..PAGE_LSN_OFFSET).find(|&i| page[i] != buf[i]) else { return Ok(()) };
+        let last = (first..PAGE_LSN_OFFSET).rfind(|&i| page[i] != buf[i]).unwrap();
+        let changed = first..last + 1;
+
+        let mut payload = page[changed.clone()].to_vec();
+        payload.extend_from_slice(&buf[changed.clone()]);
+        let lsn = self.log(WalKind::Update, page_id, first, 0, &payload);
+
+        // Logged first: if the page can't come back into the pool, undo has the record anyway
+        let page = self.pager.get_page_mut(page_id)?;
+        page[changed.clone()].copy_from_slice(&buf[changed]);
+        set_page_lsn(page, lsn);
+        Ok(())
+    }
+
+    // --- OVERFLOW CHAINS (values longer than MAX_INLINE) ---
+
+    fn write_overflow(&mut self, value: &[u8]) -> io::Result<u32> {
+        // Written back to front so each page already knows its successor
+        let mut next: u32 = 0;
+        for chunk in value.chunks(OVERFLOW_DATA).rev() {
+            let page_id = self.allocate_page()?;
+            let mut page = vec![0u8; PAGE_SIZE];
+            page[0] = NODE_OVERFLOW;
+            page[2..4].copy_from_slice(&(chunk.len() as u16).to_le_bytes());
+            page[4..8].copy_from_slice(&next.to_le_bytes());
+            page[OVERFLOW_HEADER..OVERFLOW_HEADER + chunk.len()].copy_from_slice(chunk);
+            self.store(page_id, &page)?;
+            next = page_id;
+        }
+        Ok(next)
+    }
+
+    fn read_overflow(&mut self, mut page_id: u32, len: usize) -> io::Result<Vec<u8>> {
+        let mut value = Vec::with_capacity(len);
+        while page_id != 0 {
+            let page = self.pager.get_page(page_id)?;
+            let chunk_len = u16::from_le_bytes([page[2], page[3]]) as usize;
+            value.extend_from_slice(&page[OVERFLOW_HEADER..OVERFLOW_HEADER + chunk_len]);
+            page_id = u32::from_le_bytes(page[4..8].try_into().unwrap());
+        }
+        Ok(value)
+    }
+
+    fn free_overflow(&mut self, mut page_id: u32) -> io::Result<()> {
+        while page_id != 0 {
+            let next = u32::from_le_bytes(self.pager.get_page(page_id)?[4..8].try_into().unwrap());
+            self.free_page(page_id)?;
+            page_id = next;
+        }
+        Ok(())
+    }
+
+    // Frees the overflow chain of leaf cell `index`, if it has one
+    fn release_value(&mut self, node: &Node<&mut [u8]>, index: u16) -> io::Result<()> {
+        if let LeafValue::Overflow { page, .. } = node.get_value_at_index(index) {
+            self.free_overflow(page)?;
+        }
+        Ok(())
+    }
+
+    fn read_value(&mut self, value: LeafValue) -> io::Result<Vec<u8>> {
+        match value {
+            LeafValue::Inline(bytes) => Ok(bytes.to_vec()),
+            LeafValue::Overflow { page, len } => self.read_overflow(page, len),
+        }
+    }
+
+    // --- SEARCH ---
+
+    pub fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.usable()?;
+        let mut current_page_id = self.meta.root_page;
+
+        loop {
+            // 1. Load the Page (Directly from RAM cache or SSD)
+            let page = self.load(current_page_id)?;
+            let node = Node::new(&page[..]);
+
+            // 2. Search inside the Page
+            if node.is_leaf() {
+                let Ok(index) = node.binary_search(key) else { return Ok(None) };
+                return self.read_value(node.get_value_at_index(index)).map(Some);
+            }
+
+            // Internal: pick the correct child and dig deeper
+            current_page_id = node.child_at(node.child_index(key));
+        }
+    }
+
+    /// Calls `f` for every entry with lo <= key < hi (no upper bound if `hi` is None), in key order.
+    pub fn range(&mut self, lo: &[u8], hi: Option<&[u8]>, mut f: impl FnMut(&[u8], &[u8])) -> io::Result<()> {
+        self.usable()?;
+        // 1. Find the leaf where `lo` would live
+        let mut page_id = self.meta.root_page;
+        loop {
+            let node = Node::new(self.pager.get_page(page_id)?);
+            if node.is_leaf() {
+                break;
+            }
+            page_id = node.child_at(node.child_index(lo));
+        }
+
+        // 2. Walk the leaf chain. The leaf stays pinned while its overflow values
+        // stream through the pool, so they can't evict it.
+        let mut start = Node::new(self.pager.get_page(page_id)?).binary_search(lo).unwrap_or_else(|i| i);
+        while page_id != 0 {
+            let frame = self.pager.pin(page_id)?;
+            let num_cells = Node::new(self.pager.frame(frame)).get_num_cells();
+
+            for i in start..num_cells {
+                let node = Node::new(self.pager.frame(frame));
+                let key = node.get_key_at_index(i).to_vec();
+                if hi.is_some_and(|hi| key.as_slice() >= hi) {
+                    self.pager.unpin(frame, false);
+                    return Ok(());
+                }
+                let value = match node.get_value_at_index(i) {
+                    LeafValue::Inline(bytes) => Ok(bytes.to_vec()),
+                    LeafValue::Overflow { page, len } => self.read_overflow(page, len),
+                };
+                let value = match value {
+                    Ok(value) => value,
+                    Err(e) => {
+                        self.pager.unpin(frame, false);
+                        return Err(e);
+                    }
+                };
+                f(&key, &value);
+            }
+
+            page_id = Node::new(self.pager.frame(frame)).get_right_child();
+            self.pager.unpin(frame, false);
+            start = 0;
+        }
+        Ok(())
+    }
+
+    // --- INSERT (Upsert) ---
+
+    /// Returns true if the key is new, false if an existing value was overwritten.
+    /// Panics if the key is longer than MAX_KEY (callers bound their key sizes).
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> io::Result<bool> {
+        assert!(key.len() <= MAX_KEY, "B-Tree key too long ({} bytes)", key.len());
+        self.usable()?;
+        self.begin();
+        let result = self.try_insert(key, value);
+        self.undo_failed(result)
+    }
+
+    fn try_insert(&mut self, key: &[u8], value: &[u8]) -> io::Result<bool> {
+        let overflow = if value.len() > MAX_INLINE { self.write_overflow(value)? } else { 0 };
+        let cell = nodes::leaf_cell(key, value, overflow);
+
+        let root = self.meta.root_page;
+        let (split, inserted) = self.insert_into(root, key, &cell)?;
+        self.grow_root(split)?;
+
+        if inserted {
+            self.meta.entry_count += 1;
+        }
+        self.write_meta()?;
+        Ok(inserted)
+    }
+
+    // The root itself split: grow the tree by one level
+    fn grow_root(&mut self, split: Split) -> io::Result<()> {
+        let Some((sep, right)) = split else { return Ok(()) };
+        let old_root = self.meta.root_page;
+        let new_root = self.allocate_page()?;
+
+        let cell = nodes::internal_cell(&sep, old_root);
+        self.write_node(new_root, NODE_INTERNAL, &[cell], right)?;
+        self.meta.root_page = new_root;
+        Ok(())
+    }
+
+    fn insert_into(&mut self, page_id: u32, key: &[u8], cell: &[u8]) -> io::Result<(Split, bool)> {
+        let mut buf = self.load(page_id)?;
+        let mut node = Node::new(&mut buf[..]);
+
+        if node.is_leaf() {
+            // Overwrite = drop the old cell, then insert the new one in its place
+            let (index, inserted) = match node.binary_search(key) {
+                Ok(i) => {
+                    self.release_value(&node, i)?;
+                    node.remove_cell(i);
+                    (i, false)
+                }
+                Err(i) => (i, true),
+            };
+
+            if node.insert_cell(index, cell) {
+                self.store(page_id, &buf)?;
+                return Ok((None, inserted));
+            }
+
+            // THE SPLIT: upper half (by bytes) moves to a new right sibling
+            let mut cells = flatten(&node);
+            cells.insert(index as usize, cell.to_vec());
+            let mid = split_point(&cells, 1);
+
+            let right_id = self.allocate_page()?;
+            let next_leaf = node.get_right_child();
+            let sep = nodes::cell_key(&cells[mid], true).to_vec();
+            self.write_node(page_id, NODE_LEAF, &cells[..mid], right_id)?;
+            self.write_node(right_id, NODE_LEAF, &cells[mid..], next_leaf)?;
+            return Ok((Some((sep, right_id)), inserted));
+        }
+
+        // Internal: recurse, then absorb the child's split if it had one
+        let index = node.child_index(key);
+        let child = node.child_at(index);
+        let (split, inserted) = self.insert_into(child, key, cell)?;
+        let Some((sep, new_child)) = split else { return Ok((None, inserted)) };
+
+        Ok((self.insert_separator(page_id, index, &sep, child, new_child)?, inserted))
+    }
+
+    /// Puts `sep` between children `left` and `right` at position `index` of an internal
+    /// node (the slot at `index` currently points at `left`). Splits the node if it's full.
+    fn insert_separator(&mut self, page_id: u32, index: u16, sep: &[u8], left: u32, right: u32) -> io::Result<Split> {
+        let mut buf = self.load(page_id)?;
+        let mut node = Node::new(&mut buf[..]);
+        let cell = nodes::internal_cell(sep, left);
+
+        if node.insert_cell(index, &cell) {
+            node.set_child_at(index + 1, right);
+            self.store(page_id, &buf)?;
+            return Ok(None);
+        }
+
+        // Internal split: lay everything out flat, the middle key moves up to the parent
+        let mut cells = flatten(&node);
+        let mut right_ptr = node.get_right_child();
+        cells.insert(index as usize, cell);
+        if index as usize + 1 == cells.len() {
+            right_ptr = right;
+        } else {
+            set_cell_child(&mut cells[index as usize + 1], right);
+        }
+
+        let mid = split_point(&cells, 2);
+        let promoted = nodes::cell_key(&cells[mid], false).to_vec();
+        let promoted_child = nodes::cell_child(&cells[mid]);
+
+        let right_id = self.allocate_page()?;
+        self.write_node(page_id, NODE_INTERNAL, &cells[..mid], promoted_child)?;
+        self.write_node(right_id, NODE_INTERNAL, &cells[mid + 1..], right_ptr)?;
+        Ok(Some((promoted, right_id)))
+    }
+
+    // Rewrites a page from scratch (split, merge and refill all end up here)
+    fn write_node(&mut self, page_id: u32, node_type: u8, cells: &[Vec<u8>], right_ptr: u32) -> io::Result<()> {
+        let mut buf = vec![0u8; PAGE_SIZE];
+        let mut node = Node::new(&mut buf[..]);
+        node.init(node_type);
+        for (i, cell) in cells.iter().enumerate() {
+            assert!(node.insert_cell(i as u16, cell), "cells don't fit in one page");
+        }
+        node.set_right_child(right_ptr);
+        self.store(page_id, &buf)
+    }
+
+    // --- DELETE ---
+
+    /// Returns true if the key existed.
+    pub fn delete(&mut self, key: &[u8]) -> io::Result<bool> {
+        self.usable()?;
+        self.begin();
+        let result = self.try_delete(key);
+        self.undo_failed(result)
+    }
+
+    fn try_delete(&mut self, key: &[u8]) -> io::Result<bool> {
+        let root = self.meta.root_page;
+        let (found, split) = self.delete_from(root, key)?;
+        self.grow_root(split)?;
+
+        // Root lost its last separator: its only child becomes the new root
+        let root = self.meta.root_page;
+        let node = Node::new(self.pager.get_page(root)?);
+        if !node.is_leaf() && node.get_num_cells() == 0 {
+            self.meta.root_page = node.get_right_child();
+            self.free_page(root)?;
+        }
+
+        if found {
+            self.meta.entry_count -= 1;
+        }
+        self.write_meta()?;
+        Ok(found)
+    }
+
+    // A refill can lengthen a separator, so even a delete may split a parent
+    fn delete_from(&mut self, page_id: u32, key: &[u8]) -> io::Result<(bool, Split)> {
+        let mut buf = self.load(page_id)?;
+        let mut node = Node::new(&mut buf[..]);
+
+        if node.is_leaf() {
+            let Ok(index) = node.binary_search(key) else { return Ok((false, None)) };
+            self.release_value(&node, index)?;
+            node.remove_cell(index);
+            self.store(page_id, &buf)?;
+            return Ok((true, None));
+        }
+
+        // Separators may still equal a deleted key; they remain valid bounds
+        let index = node.child_index(key);
+        let child = node.child_at(index);
+        let (found, split) = self.delete_from(child, key)?;
+
+        if let Some((sep, new_child)) = split {
+            return Ok((found, self.insert_separator(page_id, index, &sep, child, new_child)?));
+        }
+        if found && Node::new(self.pager.get_page(child)?).used_space() < MIN_USED {
+            return Ok((found, self.rebalance(page_id, index)?));
+        }
+        Ok((found, None))
+    }
+
+    // Fixes an underfull child: merge it with a sibling when both fit in one page,
+    // otherwise split their combined cells evenly between the two.
+    fn rebalance(&mut self, parent_id: u32, index: u16) -> io::Result<Split> {
+        let mut parent_buf = self.load(parent_id)?;
+        let mut parent = Node::new(&mut parent_buf[..]);
+
+        // Work on the pair (left, right) around separator `sep_index`
+        let sep_index = if index > 0 { index - 1 } else { index };
+        let left_id = parent.child_at(sep_index);
+        let right_id = parent.child_at(sep_index + 1);
+        let sep = parent.get_key_at_index(sep_index).to_vec();
+
+        let left_buf = self.load(left_id)?;
+        let right_buf = self.load(right_id)?;
+        let left = Node::new(&left_buf[..]);
+        let right = Node::new(&right_buf[..]);
+        let leaf = left.is_leaf();
+        let node_type = if leaf { NODE_LEAF } else { NODE_INTERNAL };
+
+        // 1. Everything the pair holds, in key order.
+        // Internal nodes pull the separator down between the two halves.
+        let mut cells = flatten(&left);
+        if !leaf {
+            cells.push(nodes::internal_cell(&sep, left.get_right_child()));
+        }
+        cells.extend(flatten(&right));
+        let right_ptr = right.get_right_child();
+
+        // 2. MERGE: right folds into left, separator leaves the parent
+        if total_size(&cells) <= nodes::CAPACITY {
+            self.write_node(left_id, node_type, &cells, right_ptr)?;
+            self.free_page(right_id)?;
+
+            parent.remove_cell(sep_index);
+            parent.set_child_at(sep_index, left_id);
+            self.store(parent_id, &parent_buf)?;
+            return Ok(None);
+        }
+
+        // 3. REFILL: split evenly, the new boundary key replaces the separator
+        let new_sep = if leaf {
+            let mid = split_point(&cells, 1);
+            self.write_node(left_id, node_type, &cells[..mid], right_id)?;
+            self.write_node(right_id, node_type, &cells[mid..], right_ptr)?;
+            nodes::cell_key(&cells[mid], true).to_vec()
+        } else {
+            let mid = split_point(&cells, 2);
+            self.write_node(left_id, node_type, &cells[..mid], nodes::cell_child(&cells[mid]))?;
+            self.write_node(right_id, node_type, &cells[mid + 1..], right_ptr)?;
+            nodes::cell_key(&cells[mid], false).to_vec()
+        };
+
+        parent.remove_cell(sep_index);
+        self.store(parent_id, &parent_buf)?;
+        self.insert_separator(parent_id, sep_index, &new_sep, left_id, right_id)
+    }
+}
+
+fn flatten<B: AsRef<[u8]>>(node: &Node<B>) -> Vec<Vec<u8>> {
+    (0..node.get_num_cells()).map(|i| node.cell(i).to_vec()).collect()
+}
+
+// Bytes the cells take in a page, slots included
+fn total_size(cells: &[Vec<u8>]) -> usize {
+    cells.iter().map(|c| c.len() + 2).sum()
+}
+
+// Where to cut `cells` so both halves hold about the same number of bytes.
+// `keep` = cells the right side must get at least (internal splits also promote one).
+fn split_point(cells: &[Vec<u8>], keep: usize) -> usize {
+    let half = total_size(cells) / 2;
+    let mut left = 0;
+    let mut mid = 0;
+    while mid < cells.len() && left < half {
+        left += cells[mid].len() + 2;
+        mid += 1;
+    }
+    mid.clamp(1, cells.len() - keep)
+}
+
+fn set_cell_child(cell: &mut [u8], child: u32) {
+    cell[2..6].copy_from_slice(&child.to_le_bytes());
+}
+
+// Redo/undo: puts `bytes` back at the record's offset and stamps the page
+fn apply(page: &mut [u8], record: &WalRecord, bytes: &[u8]) {
+    let offset = record.header.offset as usize;
+    page[offset..offset + bytes.len()].copy_from_slice(bytes);
+    set_page_lsn(page, record.header.lsn);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+
+    // A tree file in a directory of its own (remove_dir_all it afterwards)
+    fn temp_tree(name: &str) -> (std::path::PathBuf, String) {
+        let dir = std::env::temp_dir().join(format!("jdb-btree-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("tree.idx").to_str().unwrap().to_string();
+        (dir, file)
+    }
+
+    // --- STRUCTURE ---
+    // Walks the whole tree and checks what every change must keep true: keys sorted and
+    // between their separators, all leaves at one depth, no non-root node under MIN_USED,
+    // the leaf chain in key order (and agreeing with range), and every page accounted for:
+    // in the tree, in an overflow chain, or on the free list.
+    // Returns the depth and the leaves, left to right.
+    fn check(tree: &mut BTree) -> (usize, Vec<u32>) {
+        let mut leaves = Vec::new();
+        let mut pages = 1; // The meta page
+        let root = tree.meta.root_page;
+        let depth = check_node(tree, root, None, None, &mut leaves, &mut pages);
+
+        let mut chain = Vec::new();
+        let mut keys = Vec::new();
+        let mut page_id = leaves[0];
+        while page_id != 0 {
+            chain.push(page_id);
+            let node = Node::new(tree.pager.get_page(page_id).unwrap());
+            keys.extend((0..node.get_num_cells()).map(|i| node.get_key_at_index(i).to_vec()));
+            page_id = node.get_right_child();
+        }
+        assert_eq!(chain, leaves, "the leaf chain skips or repeats a leaf");
+        assert_eq!(keys.len() as u64, tree.meta.entry_count);
+
+        let mut scanned = Vec::new();
+        tree.range(&[], None, |key, _| scanned.push(key.to_vec())).unwrap();
+        assert!(scanned == keys, "range disagrees with the leaves");
+
+        let mut page_id = tree.meta.free_head;
+        while page_id != 0 {
+            pages += 1;
+            page_id = Node::new(tree.pager.get_page(page_id).unwrap()).get_right_child();
+        }
+        assert_eq!(pages, tree.meta.page_count, "pages leaked or used twice");
+        (depth, leaves)
+    }
+
+    fn check_node(tree: &mut BTree, page_id: u32, lo: Option<&[u8]>, hi: Option<&[u8]>, leaves: &mut Vec<u32>, pages: &mut u32) -> usize {
+        *pages += 1;
+        let page = tree.load(page_id).unwrap();
+        let node = Node::new(&page[..]);
+        let n = node.get_num_cells();
+        if page_id != tree.meta.root_page {
+            assert!(node.used_space() >= MIN_USED, "page {} is underfull ({} bytes)", page_id, node.used_space());
+        }
+
+        let keys: Vec<Vec<u8>> = (0..n).map(|i| node.get_key_at_index(i).to_vec()).collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]), "page {} is out of order", page_id);
+        for key in &keys {
+            let key = key.as_slice();
+            assert!(lo.is_none_or(|lo| key >= lo) && hi.is_none_or(|hi| key < hi), "page {} is under the wrong separator", page_id);
+        }
+
+        if node.is_leaf() {
+            leaves.push(page_id);
+            for i in 0..n {
+                if let LeafValue::Overflow { len, .. } = node.get_value_at_index(i) {
+                    *pages += len.div_ceil(OVERFLOW_DATA) as u32;
+                }
+            }
+            return 1;
+        }
+
+        assert!(n > 0, "internal page {} has no separators", page_id);
+        let children: Vec<u32> = (0..=n).map(|i| node.child_at(i)).collect();
+        let mut depth = None;
+        for (i, &child) in children.iter().enumerate() {
+            let lo = if i == 0 { lo } else { Some(keys[i - 1].as_slice()) };
+            let hi = keys.get(i).map(|key| key.as_slice()).or(hi);
+            let d = check_node(tree, child, lo, hi, leaves, pages);
+            assert!(depth.is_none_or(|depth| depth == d), "leaves at different depths under page {}", page_id);
+            depth = Some(d);
+        }
+        depth.unwrap() + 1
+    }
+
+    // Long keys keep the fanout low, so a few thousand entries already need internal splits
+    fn long_key(i: u32) -> Vec<u8> {
+        let mut key = i.to_be_bytes().to_vec();
+        key.resize(200, b'k');
+        key
+    }
+
+    // Every key in 0..n once, in a scrambled but repeatable order (`step` coprime with n)
+    fn scrambled(n: u32, step: u32) -> impl Iterator<Item = u32> {
+        (0..n).map(move |i| (i as u64 * step as u64 % n as u64) as u32)
+    }
+
+    #[test]
+    fn splits_grow_the_tree_and_deletes_shrink_it_back() {
+        let (dir, file) = temp_tree("shape");
+        let mut tree = BTree::open(&file, 64).unwrap();
+        let n = 2_000;
+
+        // 1. Inserts split leaves, then internal nodes, then the root twice over
+        for (done, i) in scrambled(n, 7_919).enumerate() {
+            assert!(tree.insert(&long_key(i), &i.to_le_bytes().repeat(12)).unwrap());
+            if done % 100 == 99 {
+                check(&mut tree);
+            }
+        }
+        tree.commit().unwrap();
+        let (depth, _) = check(&mut tree);
+        assert!(depth >= 3, "{} levels only", depth);
+
+        // 2. Deletes merge and refill their way back down to one empty leaf
+        for (done, i) in scrambled(n, 1_237).enumerate() {
+            assert!(tree.delete(&long_key(i)).unwrap());
+            assert!(!tree.delete(&long_key(i)).unwrap());
+            if done % 100 == 99 {
+                check(&mut tree);
+                let probe = scrambled(n, 1_237).nth(done + 1).unwrap_or(0);
+                let expected = (done + 1 < n as usize).then(|| probe.to_le_bytes().repeat(12));
+                assert_eq!(tree.get(&long_key(probe)).unwrap(), expected);
+            }
+        }
+        tree.commit().unwrap();
+        assert_eq!(check(&mut tree), (1, vec![tree.meta.root_page]));
+        assert_eq!(tree.meta.entry_count, 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn underfull_leaf_refills_from_a_full_sibling_then_merges() {
+        let (dir, file) = temp_tree("rebalance");
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        let insert = |tree: &mut BTree, i: u32| tree.insert(&i.to_be_bytes(), &[i as u8; 100]).unwrap();
+
+        // 1. Two leaves, the right one filled up to the last cell that fits
+        let mut next = 0;
+        while check(&mut tree).1.len() < 2 {
+            insert(&mut tree, next);
+            next += 1;
+        }
+        loop {
+            let leaves = check(&mut tree).1;
+            let right = Node::new(tree.pager.get_page(leaves[1]).unwrap());
+            if right.free_space() < 2 * (nodes::leaf_cell(&[0; 4], &[0; 100], 0).len() + 2) {
+                break;
+            }
+            insert(&mut tree, next);
+            next += 1;
+        }
+        let (_, leaves) = check(&mut tree);
+        assert_eq!(leaves.len(), 2);
+        let sep = Node::new(tree.pager.get_page(tree.meta.root_page).unwrap()).get_key_at_index(0).to_vec();
+
+        // 2. Emptying the left leaf: once it's underfull the pair can't merge, so it refills.
+        // Same two pages, a new separator, and check() finds both at least MIN_USED.
+        let mut i: u32 = 0;
+        while Node::new(tree.pager.get_page(tree.meta.root_page).unwrap()).get_key_at_index(0) == sep {
+            assert!(tree.delete(&i.to_be_bytes()).unwrap());
+            i += 1;
+        }
+        assert_eq!(check(&mut tree).1, leaves);
+        assert_eq!(tree.meta.free_head, 0);
+
+        // 3. Keep going: the pair ends up small enough to merge into the left page, the root with it
+        while !Node::new(tree.pager.get_page(tree.meta.root_page).unwrap()).is_leaf() {
+            assert!(tree.delete(&i.to_be_bytes()).unwrap());
+            i += 1;
+        }
+        assert_eq!(check(&mut tree).1, vec![leaves[0]]);
+        assert_ne!(tree.meta.free_head, 0);
+        tree.commit().unwrap();
+        drop(tree);
+
+        // 4. And all of it survives a reopen
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        check(&mut tree);
+        assert_eq!(tree.meta.entry_count, (next - i) as u64);
+        assert_eq!(tree.get(&(next - 1).to_be_bytes()).unwrap(), Some(vec![(next - 1) as u8; 100]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn range_respects_its_bounds() {
+        let (dir, file) = temp_tree("range");
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        for k in (0..600u64).step_by(2) {
+            tree.insert(&k.to_be_bytes(), &value(0, k)).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let scan = |tree: &mut BTree, lo: u64, hi: Option<u64>| {
+            let mut found = Vec::new();
+            let hi = hi.map(u64::to_be_bytes);
+            tree.range(&lo.to_be_bytes(), hi.as_ref().map(|hi| &hi[..]), |key, found_value| {
+                let k = u64::from_be_bytes(key.try_into().unwrap());
+                assert_eq!(found_value, value(0, k), "key {}", k);
+                found.push(k);
+            })
+            .unwrap();
+            found
+        };
+        let evens = |from: u64, to: u64| (from..to).filter(|k| k % 2 == 0).collect::<Vec<u64>>();
+
+        // lo is inclusive, hi exclusive, and neither has to be a key. The overflow values
+        // (every 37th key) stream through a pool of 8 frames while the leaf stays pinned.
+        assert_eq!(scan(&mut tree, 100, Some(200)), evens(100, 200));
+        assert_eq!(scan(&mut tree, 101, Some(199)), evens(102, 199));
+        assert_eq!(scan(&mut tree, 0, None), evens(0, 600));
+        assert_eq!(scan(&mut tree, 451, None), evens(452, 600));
+        assert_eq!(scan(&mut tree, 300, Some(300)), Vec::<u64>::new());
+        assert_eq!(scan(&mut tree, 300, Some(100)), Vec::<u64>::new());
+        assert_eq!(scan(&mut tree, 600, None), Vec::<u64>::new());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn key_and_value_size_limits() {
+        let (dir, file) = temp_tree("limits");
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+
+        // 1. The biggest inline cells (MAX_KEY key, MAX_INLINE value) still split and merge cleanly
+        let key = |i: u8| [i; MAX_KEY];
+        for i in 0..100 {
+            assert!(tree.insert(&key(i), &[i; MAX_INLINE]).unwrap());
+        }
+        check(&mut tree);
+        for i in (0..100).step_by(3) {
+            assert!(tree.delete(&key(i)).unwrap());
+        }
+        check(&mut tree);
+        assert_eq!(tree.get(&key(1)).unwrap(), Some(vec![1; MAX_INLINE]));
+
+        // 2. One byte past MAX_INLINE goes to overflow pages; empty keys and values are fine
+        let pages = tree.meta.page_count;
+        assert!(tree.insert(b"", &[7; MAX_INLINE + 1]).unwrap());
+        assert_eq!(tree.meta.page_count, pages + 1);
+        assert_eq!(tree.get(b"").unwrap(), Some(vec![7; MAX_INLINE + 1]));
+        assert!(tree.insert(b"empty", b"").unwrap());
+        assert_eq!(tree.get(b"empty").unwrap(), Some(vec![]));
+
+        // 3. Overwrites aren't new entries, and an overwritten overflow value gives its pages back
+        let entries = tree.meta.entry_count;
+        let big = vec![9; 10 * OVERFLOW_DATA];
+        assert!(!tree.insert(b"", &big).unwrap());
+        assert_eq!(tree.get(b"").unwrap(), Some(big));
+        assert!(!tree.insert(b"", b"small").unwrap());
+        assert_eq!(tree.get(b"").unwrap().as_deref(), Some(&b"small"[..]));
+        assert_eq!(tree.meta.entry_count, entries);
+        check(&mut tree);
+        tree.commit().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "B-Tree key too long")]
+    fn key_over_max_key_panics() {
+        let (dir, file) = temp_tree("long-key");
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        let _ = tree.insert(&[0; MAX_KEY + 1], b"value");
+    }
+
+    // --- CRASH RECOVERY ---
+    // A child process (this test binary, running crash_workload) works on a tree with
+    // JDB_CRASH_AT set; once it's killed, the tree is reopened here. Batch b rewrites the
+    // whole key range: every key gets a value tagged b, and every fifth one is deleted.
+    // So the tree says which batch it holds, and one from two batches at once is a failure.
+    const KEYS: u64 = 300;
+    const FRAMES: usize = 8; // The pager's minimum: batches evict all the time
+
+    fn value(batch: u64, k: u64) -> Vec<u8> {
+        let len = if k.is_multiple_of(37) { 3 * MAX_INLINE } else { 20 + (k % 80) as usize };
+        let mut value = format!("{}:{}:", batch, k).into_bytes();
+        value.resize(len, b'x');
+        value
+    }
+
+    fn present(batch: u64, k: u64) -> bool {
+        !(k + batch).is_multiple_of(5)
+    }
+
+    fn run_batch(tree: &mut BTree, batch: u64) {
+        for k in 0..KEYS {
+            if present(batch, k) {
+                tree.insert(&k.to_be_bytes(), &value(batch, k)).unwrap();
+            } else {
+                tree.delete(&k.to_be_bytes()).unwrap();
+            }
+        }
+    }
+
+    // Runs in the child: JDB_TEST_BATCHES batches, a checkpoint after every odd one.
+    // After each, `<tree>.progress` says which batch is done.
+    #[test]
+    #[ignore]
+    fn crash_workload() {
+        let Ok(file) = std::env::var("JDB_TEST_TREE") else { return };
+        let batches: u64 = std::env::var("JDB_TEST_BATCHES").unwrap().parse().unwrap();
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        for batch in 0..batches {
+            run_batch(&mut tree, batch);
+            tree.commit().unwrap();
+            if batch % 2 == 1 {
+                tree.checkpoint().unwrap();
+            }
+            fs::write(format!("{}.progress", file), batch.to_string()).unwrap();
+        }
+    }
+
+    // Runs crash_workload until JDB_CRASH_AT kills it. Returns the last batch it finished.
+    fn crash(file: &str, at: &str, batches: u64) -> Option<u64> {
+        let out = Command::new(std::env::current_exe().unwrap())
+            .args(["btree::tests::crash_workload", "--exact", "--ignored", "--nocapture"])
+            .env("JDB_TEST_TREE", file)
+            .env("JDB_TEST_BATCHES", batches.to_string())
+            .env("JDB_CRASH_AT", at)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(!out.status.success() && stderr.contains("Injected crash"), "{} never crashed: {}", at, stderr);
+        fs::read_to_string(format!("{}.progress", file)).ok().map(|s| s.parse().unwrap())
+    }
+
+    // Reopens (recovers) the tree and returns the batch it holds, checking it holds exactly that one
+    fn recovered(file: &str) -> Option<u64> {
+        let mut tree = BTree::open(file, FRAMES).unwrap();
+        let mut found = Vec::new();
+        tree.range(&[], None, |key, value| found.push((u64::from_be_bytes(key.try_into().unwrap()), value.to_vec()))).unwrap();
+        assert_eq!(tree.meta.entry_count, found.len() as u64);
+
+        let (_, first) = found.first()?;
+        let batch: u64 = std::str::from_utf8(first).unwrap().split(':').next().unwrap().parse().unwrap();
+        let expected: Vec<(u64, Vec<u8>)> = (0..KEYS).filter(|&k| present(batch, k)).map(|k| (k, value(batch, k))).collect();
+        assert!(found == expected, "the tree doesn't hold batch {} and nothing else", batch);
+        Some(batch)
+    }
+
+    #[test]
+    fn crash_points_keep_committed_batches_only() {
+        let cases = [
+            ("tree-before-commit:1", Some(None)),
+            ("tree-before-commit:4", Some(Some(2))),
+            ("tree-after-commit:1", Some(Some(0))),
+            ("tree-after-commit:4", Some(Some(3))),
+            ("evict-write:1", None),
+            ("evict-write:40", None),
+            ("checkpoint-flush:3", None),
+            ("checkpoint-flush:4", None),
+            ("checkpoint-truncate:1", None),
+            ("checkpoint-truncate:2", None),
+        ];
+        for (at, expected) in cases {
+            let (dir, file) = temp_tree(&at.replace(':', "-"));
+            let done = crash(&file, at, 6);
+            let batch = recovered(&file);
+            match expected {
+                // The commit it died in is the one in doubt
+                Some(expected) => assert_eq!(batch, expected, "{}", at),
+                // Mid-batch or mid-checkpoint: the batch it was on may have committed
+                None => assert!(batch == done || batch == Some(done.map_or(0, |b| b + 1)), "{}: finished {:?}, recovered {:?}", at, done, batch),
+            }
+
+            // And it carries on from there
+            let mut tree = BTree::open(&file, FRAMES).unwrap();
+            run_batch(&mut tree, 9);
+            tree.commit().unwrap();
+            drop(tree);
+            assert_eq!(recovered(&file), Some(9), "{}", at);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn crash_during_undo_never_undoes_twice() {
+        let (dir, file) = temp_tree("undo");
+        // 1. Batch 1 dies before its commit, after eviction pushed much of it to the WAL
+        assert_eq!(crash(&file, "tree-before-commit:2", 2), Some(0));
+        let wal = format!("{}.wal", file);
+        let wal_len = fs::metadata(&wal).unwrap().len();
+
+        // 2. Recovery dies halfway through rolling it back, twice. Its CLRs only reach the WAL
+        // when eviction writes an undone page back, hence far enough in to have evicted some.
+        // The second time, and again below, they're replayed and undo goes on from where they point.
+        crash(&file, "recovery-undo:200", 0);
+        assert!(fs::metadata(&wal).unwrap().len() > wal_len, "the first undo left no CLRs behind");
+        crash(&file, "recovery-undo:50", 0);
+
+        // 3. Batch 0, untouched
+        assert_eq!(recovered(&file), Some(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_commit_record_rolls_the_batch_back() {
+        let (dir, file) = temp_tree("torn");
+        assert_eq!(crash(&file, "tree-after-commit:2", 2), Some(0));
+
+        // The last thing written was batch 1's Commit: cut it in half
+        let wal = fs::OpenOptions::new().write(true).open(format!("{}.wal", file)).unwrap();
+        let len = wal.metadata().unwrap().len();
+        wal.set_len(len - size_of::<WalRecordHeader>() as u64 / 2).unwrap();
+        drop(wal);
+
+        assert_eq!(recovered(&file), Some(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // --- I/O ERRORS ---
+
+    #[test]
+    fn failed_change_rolls_back_to_the_last_commit() {
+        let (dir, file) = temp_tree("io-error");
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        run_batch(&mut tree, 0);
+        tree.commit().unwrap();
+        tree.checkpoint().unwrap();
+        let entries = tree.meta.entry_count;
+        drop(tree);
+
+        // 1. Every page but the meta page and the first root unreadable (the pager still thinks they're there)
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        let bytes = fs::read(&file).unwrap();
+        fs::OpenOptions::new().write(true).open(&file).unwrap().set_len(2 * PAGE_SIZE as u64).unwrap();
+
+        // 2. The overflow pages get written, then the way down fails: an error, not a panic,
+        // and nothing of it is left, in RAM or in the WAL
+        let new_key = (KEYS + 1).to_be_bytes();
+        let err = tree.insert(&new_key, &value(1, 0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(tree.txn.is_none());
+        assert_eq!(tree.meta.entry_count, entries);
+        tree.commit().unwrap();
+        drop(tree);
+
+        // 3. The disk is back: the tree is batch 0, and takes the change this time
+        fs::write(&file, &bytes).unwrap();
+        assert_eq!(recovered(&file), Some(0));
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        assert!(tree.insert(&new_key, &value(1, 0)).unwrap());
+        tree.commit().unwrap();
+        assert_eq!(tree.get(&new_key).unwrap(), Some(value(1, 0)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_refuses_an_open_transaction() {
+        let (dir, file) = temp_tree("checkpoint");
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        tree.insert(b"key", b"value").unwrap();
+        assert_eq!(tree.checkpoint().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        tree.commit().unwrap();
+        tree.checkpoint().unwrap();
+        drop(tree);
+
+        let mut tree = BTree::open(&file, FRAMES).unwrap();
+        assert_eq!(tree.get(b"key").unwrap().as_deref(), Some(&b"value"[..]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
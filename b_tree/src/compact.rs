@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::consts::{env_u64, LogEntry, FILE_HEADER_SIZE, JOURNAL_ARCHIVE_DIR, LOG_MAGIC};
+use crate::crypto::{Keyring, SealedReader, SealedWriter};
+use crate::format::{self, JOURNAL_ARCHIVE};
+use crate::segments::{self, Segment, LOG_SEAL_NAME};
+use crate::snapshot;
+use crate::state::{Committed, Portfolio};
+
+// --- JOURNAL COMPACTION (b_tree compact) ---
+// Everything the journal holds before the newest snapshot goes into one compressed archive:
+//   archive/journal-<first log index>-<end log index>.zst
+//   [FileHeader (first_record) | sealed stream: first log index | entries | zstd(LogEntry * entries)]
+// Entries are compressed before they're sealed (sealed bytes don't compress). Each archive starts
+// where the one before it ends, so the chain still replays from log index 0 for audits.
+//
+// Runs offline, with the engine stopped: both hold the data directory lock (format::lock_data_dir),
+// and the files are taken as they are, never upgraded (start the engine once for that). Inputs: segments already retired to archive/ (and the
+// history.bin from before segments), then the live segments up to the snapshot. Before anything
+// is deleted the archive is read back: it must hold the same entries, and replayed from log index 0
+// it must reproduce the snapshot's Portfolio map. (If the chain doesn't reach back that far, say after
+// JDB_SEGMENT_RETIRE=delete, it must reproduce the Portfolio map of the entries it replaces.)
+// Afterwards the journal starts at the snapshot, so older snapshots can't be started from any more.
+// Archives aren't re-sealed when the key changes: keep the old key in data.key while they're needed.
+//   JDB_ARCHIVE_ZSTD_LEVEL  compression level (default 19)
+const SEAL_NAME: &str = "journal archive";
+const DEFAULT_ZSTD_LEVEL: u64 = 19;
+
+pub struct Archive {
+    pub path: PathBuf,
+    pub first: u64, // Log index of its first entry
+    pub end: u64,   // ...and of the one after its last
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn archive_path(first: u64, end: u64) -> PathBuf {
+    Path::new(JOURNAL_ARCHIVE_DIR).join(format!("journal-{:012}-{:012}.zst", first, end))
+}
+
+// What's in archive/, as (file name, path)
+fn archive_dir() -> io::Result<Vec<(String, PathBuf)>> {
+    let dir = match fs::read_dir(JOURNAL_ARCHIVE_DIR) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in dir {
+        let entry = entry?;
+        if let Ok(name) = entry.file_name().into_string() {
+            files.push((name, entry.path()));
+        }
+    }
+    Ok(files)
+}
+
+/// The compressed archives, oldest first. Each one must start where the one before it ends.
+pub fn list_archives() -> io::Result<Vec<Archive>> {
+    let mut archives: Vec<Archive> = archive_dir()?.into_iter().filter_map(|(name, path)| {
+        let (first, end) = name.strip_prefix("journal-")?.strip_suffix(".zst")?.split_once('-')?;
+        Some(Archive { path, first: first.parse().ok()?, end: end.parse().ok()? })
+    }).collect();
+    archives.sort_by_key(|a| a.first);
+
+    for pair in archives.windows(2) {
+        if pair[1].first != pair[0].end {
+            return Err(invalid(format!("{} ends at log index {}, but {} starts at {}",
+                pair[0].path.display(), pair[0].end, pair[1].path.display(), pair[1].first)));
+        }
+    }
+    Ok(archives)
+}
+
+// --- WRITING / READING ONE ---
+
+fn write_archive(path: &Path, first: u64, entries: &[LogEntry], keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut header = format::new_header(&JOURNAL_ARCHIVE, engine_id, now);
+    header.first_record = first;
+
+    // 1. Range (inside the seal, so it can't be swapped for another archive's), then the entries
+    let mut sealed = SealedWriter::new(bytemuck::bytes_of(&header).to_vec(), keys, SEAL_NAME);
+    sealed.write_all(&first.to_le_bytes())?;
+    sealed.write_all(&(entries.len() as u64).to_le_bytes())?;
+    let level = env_u64("JDB_ARCHIVE_ZSTD_LEVEL", DEFAULT_ZSTD_LEVEL) as i32;
+    let mut zstd = zstd::Encoder::new(sealed, level)?;
+    zstd.write_all(bytemuck::cast_slice(entries))?;
+    let bytes = zstd.finish()?.finish()?;
+
+    // 2. Temp file + rename, like everything else
+    fs::create_dir_all(JOURNAL_ARCHIVE_DIR)?;
+    let tmp = path.with_extension("zst.tmp");
+    let mut out = File::create(&tmp)?;
+    out.write_all(&bytes)?;
+    out.sync_all()?;
+    fs::rename(&tmp, path)?;
+    File::open(JOURNAL_ARCHIVE_DIR)?.sync_all()?;
+    Ok(bytes.len() as u64)
+}
+
+/// The entries of a compressed archive, and the log index of the first one
+pub fn read_archive(path: &Path, keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<(u64, Vec<LogEntry>)> {
+    let name = path.display();
+    let in_file = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", name, e));
+    let bytes = fs::read(path).map_err(in_file)?;
+    // Archives aren't upgraded at startup: older ones are migrated as they're read
+    let header = format::check_old_header(&bytes, &JOURNAL_ARCHIVE, engine_id).map_err(in_file)?;
+
+    let mut reader = SealedReader::new(&bytes[FILE_HEADER_SIZE..], keys, SEAL_NAME);
+    let mut range = [0u8; 16];
+    reader.read_exact(&mut range).map_err(in_file)?;
+    let first = u64::from_le_bytes(range[..8].try_into().unwrap());
+    let count = u64::from_le_bytes(range[8..].try_into().unwrap());
+    if first != header.first_record {
+        return Err(invalid(format!("{}: sealed as starting at log index {}, the header says {}", name, first, header.first_record)));
+    }
+
+    let mut plain = Vec::new();
+    zstd::Decoder::new(reader).and_then(|mut zstd| zstd.read_to_end(&mut plain)).map_err(in_file)?;
+    let entry_size = format::record_size(&JOURNAL_ARCHIVE, header.version);
+    if plain.len() as u64 != count * entry_size as u64 {
+        return Err(invalid(format!("{}: {} bytes of entries, expected {}", name, plain.len(), count * entry_size as u64)));
+    }
+
+    // Same checks as the live journal
+    let mut entries = Vec::with_capacity(count as usize);
+    for (i, chunk) in plain.chunks_exact(entry_size).enumerate() {
+        let chunk = format::migrate_record(JOURNAL_ARCHIVE.kind, header.version, chunk);
+        let entry: LogEntry = bytemuck::pod_read_unaligned(&chunk);
+        if entry.magic != LOG_MAGIC || !entry.checksum_ok() {
+            return Err(invalid(format!("{}: entry {} is damaged", name, first + i as u64)));
+        }
+        entries.push(entry);
+    }
+    Ok((first, entries))
+}
+
+// --- REPLAYING (cash and holdings only) ---
+
+/// Applies entries (log order, first one at `first`) the way replay does, committed groups only.
+/// Stops at the first one that takes a balance out of range.
+pub fn replay_portfolios(portfolios: &mut HashMap<u64, Portfolio>, groups: &mut Committed, first: u64, entries: &[LogEntry]) -> io::Result<()> {
+    let mut failed = None;
+    for (i, entry) in entries.iter().enumerate() {
+        groups.push(first + i as u64, *entry, |idx, leg| {
+            if let Err(e) = portfolios.entry(leg.user_id).or_default().apply(idx, leg) {
+                failed.get_or_insert(e);
+            }
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Reservations come from the book, and an empty portfolio is the same as none
+fn normalize(portfolios: &HashMap<u64, Portfolio>) -> BTreeMap<u64, (i64, BTreeMap<u32, i64>)> {
+    portfolios.iter().filter_map(|(user_id, p)| {
+        let stocks: BTreeMap<u32, i64> = p.stocks.iter().filter(|(_, q)| **q != 0).map(|(s, q)| (*s, *q)).collect();
+        (p.cash != 0 || !stocks.is_empty()).then_some((*user_id, (p.cash, stocks)))
+    }).collect()
+}
+
+fn same_portfolios(got: &HashMap<u64, Portfolio>, expected: &HashMap<u64, Portfolio>) -> Result<(), String> {
+    let (got, expected) = (normalize(got), normalize(expected));
+    for user_id in got.keys().chain(expected.keys()) {
+        if got.get(user_id) != expected.get(user_id) {
+            return Err(format!("user {}: replayed {:?}, expected {:?}", user_id, got.get(user_id), expected.get(user_id)));
+        }
+    }
+    Ok(())
+}
+
+/// Replays the whole archive chain: where it starts and ends, and the cash and holdings at its end
+pub fn replay_archives(keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<(u64, u64, HashMap<u64, Portfolio>)> {
+    let archives = list_archives()?;
+    let mut portfolios = HashMap::new();
+    let mut groups = Committed::default();
+    for archive in &archives {
+        let (first, entries) = read_archive(&archive.path, keys, engine_id)?;
+        replay_portfolios(&mut portfolios, &mut groups, first, &entries)?;
+    }
+    let first = archives.first().map_or(0, |a| a.first);
+    let end = archives.last().map_or(0, |a| a.end);
+    Ok((first, end, portfolios))
+}
+
+// --- COMPACTING ---
+
+#[derive(Debug, Default)]
+pub struct Compaction {
+    pub archive: Option<PathBuf>, // None = nothing to compact
+    pub first: u64,
+    pub end: u64,
+    pub entries: u64,
+    pub bytes: u64, // Of the archive
+    pub checked_against_snapshot: bool,
+    pub removed: Vec<String>,
+}
+
+// A journal file that goes into the archive
+struct Source {
+    path: PathBuf,
+    first: u64,
+    entries: Vec<LogEntry>,
+    live: Option<Segment>, // Listed in history.segments (None = already in archive/)
+}
+
+impl Source {
+    fn end(&self) -> u64 {
+        self.first + self.entries.len() as u64
+    }
+}
+
+fn sources(keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<Vec<Source>> {
+    let mut sources = Vec::new();
+    // Retired segments, and history.bin from before segments (which the first segment repeats)
+    for (name, path) in archive_dir()? {
+        if name == format::HISTORY.file || segments::segment_number(&name).is_some() {
+            let (first, entries) = segments::read_journal_file(keys, engine_id, &path)?;
+            sources.push(Source { path, first, entries, live: None });
+        }
+    }
+    for segment in segments::read_index()? {
+        let (first, entries) = segments::read_journal_file(keys, engine_id, Path::new(&segment.file))?;
+        sources.push(Source { path: PathBuf::from(&segment.file), first, entries, live: Some(segment) });
+    }
+    sources.sort_by_key(|s| (s.first, s.live.is_some()));
+    Ok(sources)
+}
+
+/// Compacts the journal up to the newest snapshot. With `dry_run` everything is written
+/// and checked, then the archive is thrown away and nothing else changes.
+pub fn compact_journal(keys: &Keyring, engine_id: &[u8; 16], dry_run: bool) -> io::Result<Compaction> {
+    // 1. Up to where? The newest snapshot save_snapshot recorded
+    let snapshot = snapshot::read_manifest()?.pop()
+        .ok_or_else(|| invalid("no snapshot yet, nothing could be compacted".to_string()))?;
+    let upto = snapshot.last_log_index;
+
+    // 2. Everything there is, in log order: the archives so far, then the journal files.
+    // Files may overlap (history.bin and the first segment): overlaps must agree.
+    let archives = list_archives()?;
+    let sources = sources(keys, engine_id)?;
+    let base = archives.first().map(|a| a.first).or(sources.first().map(|s| s.first)).unwrap_or(0);
+    let mut log = Vec::new();
+    for archive in &archives {
+        log.extend(read_archive(&archive.path, keys, engine_id)?.1);
+    }
+    let start = base + log.len() as u64; // What the archives don't have yet
+    for source in &sources {
+        let end = base + log.len() as u64;
+        if source.first > end {
+            return Err(invalid(format!("log indexes {} to {} are missing (next is {})", end, source.first, source.path.display())));
+        }
+        let overlap = (end - source.first).min(source.entries.len() as u64) as usize;
+        let known = &log[(source.first - base) as usize..][..overlap];
+        if bytemuck::cast_slice::<LogEntry, u8>(known) != bytemuck::cast_slice::<LogEntry, u8>(&source.entries[..overlap]) {
+            return Err(invalid(format!("{} disagrees with the journal before it", source.path.display())));
+        }
+        log.extend_from_slice(&source.entries[overlap..]);
+    }
+    let end = base + log.len() as u64;
+    if end < upto {
+        return Err(invalid(format!("the journal ends at log index {}, before the snapshot ({})", end, upto)));
+    }
+    if upto <= start {
+        return Ok(Compaction { first: start, end: start, ..Default::default() });
+    }
+
+    // 3. The archive
+    let entries = &log[(start - base) as usize..(upto - base) as usize];
+    let path = archive_path(start, upto);
+    let bytes = write_archive(&path, start, entries, keys, engine_id)?;
+
+    // 4. Read it back before anything goes
+    let checked = verify(&path, &log, base, start, entries, &snapshot.file, keys, engine_id);
+    if checked.is_err() || dry_run {
+        let _ = fs::remove_file(&path);
+    }
+    let checked_against_snapshot = checked?;
+    let mut compaction = Compaction {
+        archive: Some(path),
+        first: start,
+        end: upto,
+        entries: entries.len() as u64,
+        bytes,
+        checked_against_snapshot,
+        removed: Vec::new(),
+    };
+    if dry_run {
+        return Ok(compaction);
+    }
+
+    // 5. Flag changes stay with the journal, then it starts at the snapshot.
+    // The segment the snapshot falls in is copied from there on; the index switching over is what commits.
+    segments::carry_flags(entries)?;
+    let mut index = Vec::new();
+    let mut next_number = sources.iter().filter_map(|s| segments::segment_number(&s.live.as_ref()?.file)).max().unwrap_or(0) + 1;
+    let mut replaced = Vec::new();
+    for source in sources.iter().filter(|s| s.live.is_some()) {
+        let segment = source.live.clone().unwrap();
+        let active = !segment.full;
+        if source.first >= upto {
+            index.push(segment);
+            continue;
+        }
+        replaced.push(source.path.clone());
+        if source.end() <= upto && !active {
+            continue;
+        }
+
+        let kept = &source.entries[(upto - source.first) as usize..];
+        let mut sealed = Vec::with_capacity(kept.len() * segments::ENTRY_SIZE);
+        for (i, entry) in kept.iter().enumerate() {
+            sealed.extend_from_slice(&keys.seal_record(LOG_SEAL_NAME, upto + i as u64, bytemuck::bytes_of(entry)));
+        }
+        let slots = kept.len() as u64 + if active { segments::new_segment_slots() } else { 0 };
+        let file = segments::segment_file(next_number);
+        next_number += 1;
+        segments::create_segment(&file, upto, slots, engine_id, &sealed)?;
+        let entries = if active { 0 } else { kept.len() as u64 };
+        index.push(Segment { file, first: upto, entries, full: !active });
+    }
+    segments::write_index(&index)?;
+
+    // 6. Only now: the files it replaced
+    let retired = sources.iter().filter(|s| s.live.is_none() && s.end() <= upto).map(|s| s.path.clone());
+    for path in replaced.into_iter().chain(retired) {
+        match fs::remove_file(&path) {
+            Ok(()) => compaction.removed.push(path.display().to_string()),
+            Err(e) => eprintln!("[Compact] Can't delete {}: {}", path.display(), e),
+        }
+    }
+    format::sync_dir()?;
+    File::open(JOURNAL_ARCHIVE_DIR)?.sync_all()?;
+    Ok(compaction)
+}
+
+// Same entries, and the same Portfolio map. Ok(true) = checked against the snapshot itself.
+#[allow(clippy::too_many_arguments)]
+fn verify(path: &Path, log: &[LogEntry], base: u64, start: u64, entries: &[LogEntry], snapshot_file: &str,
+          keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<bool> {
+    let failed = |what: String| invalid(format!("{} failed verification, nothing was deleted: {}", path.display(), what));
+
+    let (first, archived) = read_archive(path, keys, engine_id)?;
+    if first != start || bytemuck::cast_slice::<LogEntry, u8>(&archived) != bytemuck::cast_slice::<LogEntry, u8>(entries) {
+        return Err(failed("it doesn't read back as the entries written".to_string()));
+    }
+
+    // What came before it (the older archives), then the archive as read back
+    let before = &log[..(start - base) as usize];
+    let mut groups = Committed::default();
+    let mut replayed = HashMap::new();
+    replay_portfolios(&mut replayed, &mut groups, base, before)?;
+    replay_portfolios(&mut replayed, &mut groups, start, &archived)?;
+
+    let (expected, against_snapshot) = if base == 0 {
+        (snapshot::read_snapshot(snapshot_file, u64::MAX, keys, engine_id)?.portfolios, true)
+    } else {
+        let mut groups = Committed::default();
+        let mut original = HashMap::new();
+        replay_portfolios(&mut original, &mut groups, base, before)?;
+        replay_portfolios(&mut original, &mut groups, start, entries)?;
+        (original, false)
+    };
+    same_portfolios(&replayed, &expected).map_err(failed)?;
+    Ok(against_snapshot)
+}
+
+// --- CLI ---
+
+/// `b_tree compact [--dry-run]` and `b_tree audit`. Returns the exit code.
+pub fn run_cli(command: &str, args: &[String], keys: &Keyring) -> i32 {
+    // 1. What to do
+    let (tag, dry_run) = match (command, args) {
+        ("compact", []) => ("[Compact]", false),
+        ("compact", [flag]) if flag == "--dry-run" => ("[Compact]", true),
+        ("compact", [other]) => return usage(other),
+        ("audit", []) => ("[Audit]", false),
+        _ => return usage(command),
+    };
+
+    // 2. Not while the engine runs (or another of these), then whose files they are
+    let _lock = match format::lock_data_dir() {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{} {}", tag, e);
+            return 1;
+        }
+    };
+    let engine_id = match format::read_engine_id() {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            eprintln!("{} No data files with a header here: start the engine here once first", tag);
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("{} {}", tag, e);
+            return 1;
+        }
+    };
+
+    // 3. The command itself
+    if command == "audit" {
+        return match replay_archives(keys, &engine_id) {
+            Ok((first, end, portfolios)) => {
+                let users: BTreeMap<String, serde_json::Value> = normalize(&portfolios).into_iter()
+                    .map(|(user_id, (cash, stocks))| (user_id.to_string(), serde_json::json!({"cash": cash, "stocks": stocks})))
+                    .collect();
+                println!("{}", serde_json::json!({"first": first, "end": end, "portfolios": users}));
+                0
+            }
+            Err(e) => {
+                eprintln!("{} {}", tag, e);
+                1
+            }
+        };
+    }
+    match compact_journal(keys, &engine_id, dry_run) {
+        Ok(Compaction { archive: None, first, .. }) => {
+            println!("[Compact] Nothing to compact: the archives already reach log index {}", first);
+            0
+        }
+        Ok(c) => {
+            let path = c.archive.as_ref().unwrap().display();
+            println!("[Compact] Log indexes {} to {}: {} entries in {} ({} bytes){}",
+                c.first, c.end, c.entries, path, c.bytes, if dry_run { ", dry run: removed again" } else { "" });
+            println!("[Compact] Verified: same entries, same Portfolio map as {}",
+                if c.checked_against_snapshot { "the snapshot" } else { "the entries it replaces (the archives don't start at log index 0)" });
+            for file in &c.removed {
+                println!("[Compact] Deleted {}", file);
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("{} {}", tag, e);
+            1
+        }
+    }
+}
+
+fn usage(what: &str) -> i32 {
+    eprintln!("Unknown argument {:?}. Usage (with the engine stopped, from its data directory):", what);
+    eprintln!("  b_tree compact [--dry-run]   archive the journal up to the newest snapshot (zstd)");
+    eprintln!("  b_tree audit                 replay the archives: cash and holdings at their end (JSON)");
+    2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::accounts::{Accounts, UserIndex};
+    use crate::consts::{ActionType, ACCOUNTS_POOL_FRAMES, LOG_FLAG_IN_TXN, USERS_POOL_FRAMES};
+    use crate::reader::DatabaseReader;
+    use crate::segments::{LogReader, SegmentWriter};
+
+    // Deposits, a withdrawal, a flag change, a committed trade and one cut short (it never counts)
+    fn round(n: i64) -> Vec<LogEntry> {
+        let leg = |user_id, quantity, money| LogEntry { flags: LOG_FLAG_IN_TXN, ..LogEntry::new(user_id, ActionType::Trade, 7, quantity, money) };
+        vec![
+            LogEntry::new(1, ActionType::Deposit, 0, 0, 1_000 * n),
+            LogEntry::new(2, ActionType::Deposit, 0, 0, 500),
+            LogEntry::new(3, ActionType::SetFlags, 0, n, n - 1),
+            LogEntry::txn_marker(ActionType::TxnBegin, n as u64, 0),
+            leg(1, 3, 30 * n),
+            leg(2, -3, -30 * n),
+            LogEntry::txn_marker(ActionType::TxnCommit, n as u64, 2),
+            LogEntry::new(2, ActionType::Withdraw, 0, 0, 100),
+            LogEntry::txn_marker(ActionType::TxnBegin, 100 + n as u64, 0),
+            leg(1, 1, 5),
+        ]
+    }
+
+    #[test]
+    fn compacted_archive_audits_to_the_snapshot() {
+        format::in_data_dir("compact", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = format::upgrade_data_files(&keys).unwrap();
+
+            // 1. Two rounds, a snapshot of them (the way the engine takes one), then a third round
+            let mut writer = SegmentWriter::open(keys.clone(), engine_id).unwrap();
+            let before = [round(1), round(2)].concat();
+            writer.append(&before).unwrap();
+            let upto = before.len() as u64;
+            let mut expected = HashMap::new();
+            replay_portfolios(&mut expected, &mut Committed::default(), 0, &before).unwrap();
+
+            let reader = DatabaseReader::new(&keys, &engine_id).unwrap();
+            let users = UserIndex::open("users.idx", USERS_POOL_FRAMES, &reader).unwrap();
+            let mut accounts = Accounts::open("accounts.idx", ACCOUNTS_POOL_FRAMES).unwrap();
+            for (user_id, portfolio) in &expected {
+                accounts.insert(*user_id, portfolio.clone());
+            }
+            let checkpoint = accounts.freeze(&users, upto);
+            snapshot::save_snapshot(&checkpoint, &[], &[], &BTreeMap::from([(3, 2)]), 1, &keys, &engine_id).unwrap();
+            writer.append(&round(3)).unwrap();
+            let end = upto + round(3).len() as u64;
+            drop(writer);
+
+            // 2. Not while the directory is locked (the engine running)
+            let lock = format::lock_data_dir().unwrap();
+            assert_eq!(run_cli("compact", &[], &keys), 1);
+            assert_eq!(run_cli("audit", &[], &keys), 1);
+            drop(lock);
+
+            // 3. Compacted: the journal starts at the snapshot, the archive holds everything before it
+            assert_eq!(run_cli("compact", &[], &keys), 0);
+            assert!(archive_path(0, upto).exists());
+            let log = LogReader::open(keys.clone(), engine_id).unwrap();
+            assert_eq!((log.first(), log.end()), (upto, end));
+            assert_eq!(log.entries(upto, end).filter(|e| e.is_ok()).count() as u64, end - upto);
+            assert_eq!(segments::read_carried_flags().unwrap(), BTreeMap::from([(3, 2)]));
+
+            // 4. What audit replays is what the snapshot holds
+            let (first, archived_end, audited) = replay_archives(&keys, &engine_id).unwrap();
+            assert_eq!((first, archived_end), (0, upto));
+            let snapshot = snapshot::read_snapshot(&snapshot::snapshot_file(upto), u64::MAX, &keys, &engine_id).unwrap();
+            same_portfolios(&audited, &snapshot.portfolios).unwrap();
+            same_portfolios(&audited, &expected).unwrap();
+            assert_eq!(normalize(&audited)[&1], (1_000 + 2_000 - 30 - 60, BTreeMap::from([(7, 6)])));
+            assert_eq!(run_cli("audit", &[], &keys), 0);
+
+            // 5. Once is enough
+            assert!(compact_journal(&keys, &engine_id, false).unwrap().archive.is_none());
+        });
+    }
+}
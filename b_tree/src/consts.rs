@@ -1,4 +1,5 @@
 use bytemuck::{Pod, Zeroable};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // --- FIX 1: Do NOT derive Pod for the Enum ---
 // Enums are not Pod safe by default. We store it as a u8 in the struct.
@@ -9,8 +10,76 @@ pub enum ActionType {
     Deposit = 1,
     Withdraw = 2,
     Trade = 3,
+    OrderRejected = 4, // FOK couldn't fully fill, or Post-Only would have crossed
+    OrderExpired = 5,  // IOC / Market remainder that was not filled
+    OrderPlaced = 6,   // Remainder now rests on the book
+    OrderCancelled = 7,
+    OrderAmended = 8,  // Reduced in place, keeps queue priority
+    OrderReplaced = 9, // Pulled from the book, re-enters as a fresh order with the same ID
+    TxnBegin = 10,     // Opens a journal group (see AppState::begin)
+    TxnCommit = 11,    // Closes it. A group without one is ignored on replay.
+    Transfer = 12,     // One leg of a user-to-user move: amount_money / quantity are signed
+    SetFlags = 13,     // Admin changed UserMeta.flags: quantity = new flags, amount_money = old ones
+    Issue = 14,        // Admin credited user_id with new stock: quantity > 0, the admin as counterparty
 }
 
+impl ActionType {
+    // Replay reads raw u8s from disk, so unknown values map to None
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => ActionType::Deposit,
+            2 => ActionType::Withdraw,
+            3 => ActionType::Trade,
+            4 => ActionType::OrderRejected,
+            5 => ActionType::OrderExpired,
+            6 => ActionType::OrderPlaced,
+            7 => ActionType::OrderCancelled,
+            8 => ActionType::OrderAmended,
+            9 => ActionType::OrderReplaced,
+            10 => ActionType::TxnBegin,
+            11 => ActionType::TxnCommit,
+            12 => ActionType::Transfer,
+            13 => ActionType::SetFlags,
+            14 => ActionType::Issue,
+            _ => ActionType::None,
+        }
+    }
+}
+
+pub const LOG_MAGIC: u16 = 0xAABB;
+pub const LOG_VERSION: u16 = 3; // 2 = entries carry a CRC32C, 3 = and a 128-bit request key
+pub const LOG_ENTRY_V2_SIZE: usize = 64; // What a LogEntry was before the request key (history v1/v2)
+
+// LogEntry.reason, so a replayed rejection reads the same as the live one
+pub const REJECT_REASONS: [&str; 3] = ["Rejected", "Would Cross", "Cannot Fill Entirely"];
+pub const REJECT_WOULD_CROSS: u8 = 1;
+pub const REJECT_CANNOT_FILL: u8 = 2;
+
+// LogEntry.flags
+pub const LOG_FLAG_IN_TXN: u8 = 1; // A leg of a journal group: only counts once the group commits
+
+// UserMeta.flags. Every account starts out as USER_ACTIVE only.
+pub const USER_ACTIVE: u32 = 1;            // Cleared = disabled: can't log in or use any route
+pub const USER_FROZEN: u32 = 2;            // Read-only: can look, can't trade, cancel or move money. Freezing cancels resting orders
+pub const USER_ADMIN: u32 = 4;             // May change other users' flags
+// Its only effect: MAX_OPEN_ORDERS doesn't apply (main.rs trade), so a desk quoting many
+// levels on many symbols isn't capped like a retail account. Nothing else about it is special.
+pub const USER_MARKET_MAKER: u32 = 8;
+pub const USER_WITHDRAW_DISABLED: u32 = 16;
+pub const USER_KYC_VERIFIED: u32 = 32;     // Required to move money out (withdrawals, transfers), unless JDB_WITHDRAW_REQUIRES_KYC=0
+
+pub const USER_FLAG_NAMES: [(u32, &str); 6] = [
+    (USER_ACTIVE, "active"),
+    (USER_FROZEN, "frozen"),
+    (USER_ADMIN, "admin"),
+    (USER_MARKET_MAKER, "market_maker"),
+    (USER_WITHDRAW_DISABLED, "withdraw_disabled"),
+    (USER_KYC_VERIFIED, "kyc_verified"),
+];
+
+// Resting orders per user, unless they are a market maker (USER_MARKET_MAKER)
+pub const MAX_OPEN_ORDERS: usize = 200;
+
 // --- FIX 2: Ensure #[repr(C)] is present ---
 #[repr(C)] 
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
@@ -21,7 +90,7 @@ pub struct UserMeta {
     pub pass_hash: [u8; 32],
     pub salt: [u8; 16],     
     pub created_at: u64,    
-    pub flags: u32,         
+    pub flags: u32,          // USER_*
     pub _padding: [u8; 4], // Pad to align to 8 bytes
 }
 
@@ -30,7 +99,7 @@ pub struct UserMeta {
 pub struct LogEntry {
     pub magic: u16,          
     pub version: u16,        
-    pub _pad1: [u8; 4],      // Explicit padding to reach byte 8
+    pub crc: u32,            // CRC32C of the entry with this field zeroed (set by the writer, or on migration)
 
     pub user_id: u64,        
     pub timestamp: u64,      
@@ -41,13 +110,97 @@ pub struct LogEntry {
     pub request_id: [u8; 16],    
     
     pub action_type: u8,     
-    pub _pad2: [u8; 3],      // Explicit padding to align next u32
+    pub flags: u8,           // LOG_FLAG_* (0 in entries written before groups existed)
+    pub reason: u8,          // OrderRejected: index into REJECT_REASONS
+    pub _pad2: [u8; 1],      // Explicit padding to align next u32
     
     pub symbol_id: u32,      
     pub quantity: i64,       
     pub amount_money: i64,   
+
+    // Keyed hash of the client's idempotency key (0 = none), see auth::RequestHasher.
+    // Only the requester's own entries carry it.
+    pub request_key: [u8; 16],
 }
 
+impl LogEntry {
+    pub fn new(user_id: u64, action: ActionType, symbol_id: u32, quantity: i64, amount_money: i64) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        LogEntry {
+            magic: LOG_MAGIC,
+            version: LOG_VERSION,
+            crc: 0,
+            user_id,
+            timestamp: now,
+            request_id: [0; 16],
+            action_type: action as u8,
+            flags: 0,
+            reason: 0,
+            _pad2: [0; 1],
+            symbol_id,
+            quantity,
+            amount_money,
+            request_key: [0; 16],
+        }
+    }
+
+    // request_id[0..8] carries the server-assigned order ID (0 = not an order)
+    pub fn with_order_id(mut self, order_id: u64) -> Self {
+        self.request_id[0..8].copy_from_slice(&order_id.to_le_bytes());
+        self
+    }
+
+    pub fn order_id(&self) -> u64 {
+        u64::from_le_bytes(self.request_id[0..8].try_into().unwrap())
+    }
+
+    // Transfer legs (and admin actions) reuse request_id[0..8] for the other side's user ID
+    pub fn with_counterparty(self, user_id: u64) -> Self {
+        self.with_order_id(user_id)
+    }
+
+    pub fn counterparty(&self) -> u64 {
+        self.order_id()
+    }
+
+    pub fn with_request_key(mut self, key: u128) -> Self {
+        self.request_key = key.to_le_bytes();
+        self
+    }
+
+    pub fn request_key(&self) -> u128 {
+        u128::from_le_bytes(self.request_key)
+    }
+
+    // Group markers: request_id[8..16] = txn id, quantity = number of legs (Commit only)
+    pub fn txn_marker(action: ActionType, txn_id: u64, legs: i64) -> Self {
+        let mut entry = LogEntry::new(0, action, 0, legs, 0);
+        entry.request_id[8..16].copy_from_slice(&txn_id.to_le_bytes());
+        entry
+    }
+
+    pub fn txn_id(&self) -> u64 {
+        u64::from_le_bytes(self.request_id[8..16].try_into().unwrap())
+    }
+
+    pub fn in_txn(&self) -> bool {
+        self.flags & LOG_FLAG_IN_TXN != 0
+    }
+
+    pub fn checksum(&self) -> u32 {
+        crc32c::crc32c(bytemuck::bytes_of(&LogEntry { crc: 0, ..*self }))
+    }
+
+    pub fn with_checksum(self) -> Self {
+        LogEntry { crc: self.checksum(), ..self }
+    }
+
+    // Entries from before the CRC existed get one when their file is migrated
+    // (format::migrate_record), so every entry that's read has to match
+    pub fn checksum_ok(&self) -> bool {
+        self.crc == self.checksum()
+    }
+}
 
 // ... (Keep existing UserMeta and LogEntry) ...
 
@@ -71,7 +224,272 @@ pub struct SnapshotStock {
     pub quantity: i64,
 }
 
-enum DbMessage {
-    WriteLog(LogEntry),
-    Shutdown,
-}
\ No newline at end of file
+// 3. Open Orders (after every SnapshotHeader, behind a marker header)
+// Very old snapshots end before the marker; format.rs adds an empty one when upgrading them.
+pub const SNAPSHOT_ORDERS_MARKER: u64 = u64::MAX;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SnapshotOrder {
+    pub order_id: u64,
+    pub user_id: u64,
+    pub symbol_id: u32,
+    pub side: u8, // 0 = Buy, 1 = Sell
+    pub _padding: [u8; 3],
+    pub price: i64,
+    pub quantity: i64,
+}
+
+// 4. Receipts of keyed requests (after the open orders, behind a marker header: num_stocks = count),
+// so retries are still recognized once the segments they were journaled in are retired.
+// Each one is followed by its fills. Snapshots from before (v2) get an empty section.
+pub const SNAPSHOT_RECEIPTS_MARKER: u64 = u64::MAX - 1;
+
+pub const RECEIPT_CASH: u8 = 0;
+pub const RECEIPT_ORDER: u8 = 1;
+pub const RECEIPT_TRANSFER: u8 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SnapshotReceipt {
+    pub user_id: u64,
+    pub key: [u8; 16],
+    pub made_at: u64,     // Unix seconds, for JDB_REQUEST_TTL_SECS
+    pub kind: u8,         // RECEIPT_*
+    pub status: u8,       // Order: OrderStatus (state::OrderStatus::code); Transfer: 1 = cash
+    pub _padding: [u8; 2],
+    pub symbol_id: u32,   // Transfer
+    pub id: u64,          // Order: order ID; Transfer: recipient
+    pub amount: i64,      // Order: filled; Transfer: amount
+    pub resting: i64,     // Order only
+    pub expired: i64,     // Order only
+    pub num_fills: u64,   // SnapshotFill records that follow
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SnapshotFill {
+    pub price: i64,
+    pub quantity: i64,
+}
+
+// 5. Flags set through the journal (after the receipts, behind a marker header: num_stocks = count),
+// so replay can start at the snapshot instead of the start of the log. v3 snapshots get theirs
+// worked out from the journal when they're upgraded.
+pub const SNAPSHOT_FLAGS_MARKER: u64 = u64::MAX - 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SnapshotFlags {
+    pub user_id: u64,
+    pub flags: u32,
+    pub _padding: [u8; 4],
+}
+
+// 6. Trailer (last thing in the stream): a snapshot without it, or whose count / CRC
+// doesn't add up, is not used
+pub const SNAPSHOT_TRAILER_MAGIC: u32 = 0x4C52_5453; // "STRL"
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SnapshotTrailer {
+    pub magic: u32,
+    pub checksum: u32, // CRC32C of every byte before the trailer
+    pub records: u64,  // Portfolios + open orders + receipts + flags
+}
+
+// Rotation: snapshot-<last log index>.bin files, listed oldest first in the manifest
+pub const SNAPSHOT_MANIFEST: &str = "snapshots.manifest";
+pub const SNAPSHOTS_KEPT: usize = 3;
+
+// Journal segments: history-<number>.bin, listed oldest first in the segment index (segments.rs)
+pub const SEGMENT_INDEX: &str = "history.segments";
+pub const JOURNAL_ARCHIVE_DIR: &str = "archive"; // Where retired segments go
+pub const CARRIED_FLAGS: &str = "history.flags";   // Flags last set in retired segments
+pub const DATA_LOCK: &str = "jdb.lock";            // flock'd by whichever process has the directory (format.rs)
+
+// --- SESSIONS ---
+pub const SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+
+// --- KEYED REQUESTS ---
+// How long a receipt is kept (JDB_REQUEST_TTL_SECS): a retry after that is a new request
+pub const REQUEST_TTL_SECS: u64 = 24 * 60 * 60;
+
+// --- API KEYS (apikeys.bin) ---
+// Scopes a key can carry. Sessions (interactive logins) have all of them.
+pub const SCOPE_READ: u32 = 1;
+pub const SCOPE_TRADE: u32 = 2;    // Orders, cancels, deposits
+pub const SCOPE_WITHDRAW: u32 = 4; // Withdrawals and transfers out
+pub const SCOPE_ALL: u32 = SCOPE_READ | SCOPE_TRADE | SCOPE_WITHDRAW;
+
+// A signed request's timestamp must be this close to ours; nonces are remembered this long
+pub const API_SIGNATURE_WINDOW_SECS: u64 = 30;
+
+// Append-only: a revocation appends the record again with revoked_at set. Last one wins.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct ApiKeyRecord {
+    pub key_id: u64,
+    pub user_id: u64,
+    pub username: [u8; 32],
+    pub secret: [u8; 32],
+    pub created_at: u64,
+    pub revoked_at: u64, // 0 = active
+    pub scopes: u32,     // SCOPE_*
+    pub _padding: [u8; 4],
+}
+
+// --- FILE HEADERS (users.bin, apikeys.bin, history-*.bin, snapshot-*.bin, archive/journal-*.zst) ---
+// Each file starts with a plaintext FileHeader, so a reader knows what it is looking at
+// before touching a single record. See format.rs.
+pub const FILE_MAGIC: u32 = 0x4642_444A; // "JDBF"
+pub const FILE_HEADER_SIZE: usize = 64;
+
+pub const FILE_KIND_USERS: u16 = 1;
+pub const FILE_KIND_HISTORY: u16 = 2;
+pub const FILE_KIND_SNAPSHOT: u16 = 3;
+pub const FILE_KIND_ARCHIVE: u16 = 4;
+pub const FILE_KIND_API_KEYS: u16 = 5;
+
+// Current layout of each file. Bump one when its records change, and add the step to format::migrate_record.
+// (0 = the headerless files from before headers existed)
+pub const USERS_FORMAT: u16 = 1;    // Sealed UserMeta records
+pub const API_KEYS_FORMAT: u16 = 1; // Sealed ApiKeyRecord records
+pub const HISTORY_FORMAT: u16 = 3;  // Sealed LogEntry records: v2 every one with a CRC, v3 with a request key
+pub const SNAPSHOT_FORMAT: u16 = 4; // Sealed stream: last log index | portfolios | open orders | receipts | flags | trailer
+pub const ARCHIVE_FORMAT: u16 = 3;  // Sealed stream: first log index | entries | zstd(LogEntry * entries), as HISTORY
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct FileHeader {
+    pub magic: u32,
+    pub kind: u16,         // FILE_KIND_*
+    pub version: u16,      // *_FORMAT it was written with
+    pub record_size: u32,  // Plaintext size of one record (0 = variable, i.e. snapshots)
+    pub header_size: u32,  // FILE_HEADER_SIZE, so a later header can grow
+    pub created_at: u64,
+    pub engine_id: [u8; 16], // Same in every file of one database: files from elsewhere are refused
+    pub first_record: u64,   // Journal segments / archives: log index of their first entry (0 elsewhere)
+    pub _reserved: [u8; 16],
+}
+
+// --- ENCRYPTION AT REST (users.bin, history.bin, snapshot-*.bin) ---
+pub const SEAL_MAGIC: u32 = 0x434E_454A; // "JENC"
+pub const SEAL_TAG: usize = 16;          // Poly1305 tag after the ciphertext
+pub const SEAL_CHUNK: usize = 64 * 1024; // Snapshots are sealed in chunks of up to this much plaintext
+pub const SEAL_LAST_CHUNK: u32 = 1;
+
+// Starts every sealed record: [SealHeader | ciphertext | tag]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SealHeader {
+    pub magic: u32,
+    pub key_id: u32, // Fingerprint of the key it was sealed with
+    pub nonce: [u8; 12],
+}
+
+// Starts every chunk of a sealed stream: [SealChunkHeader | ciphertext (len bytes) | tag]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SealChunkHeader {
+    pub magic: u32,
+    pub key_id: u32,
+    pub nonce: [u8; 12],
+    pub len: u32,
+    pub flags: u32, // SEAL_LAST_CHUNK
+}
+
+// On-disk size of a sealed record of `size` plaintext bytes
+pub const fn sealed_size(size: usize) -> usize {
+    size_of::<SealHeader>() + size + SEAL_TAG
+}
+
+// --- B-TREE FILES (users.idx, accounts.idx) ---
+pub const PAGE_SIZE: usize = 4096;
+pub const TREE_MAGIC: u32 = 0x4A44_4231; // "JDB1"
+pub const TREE_VERSION: u16 = 4; // 3 = page LSN in the last 8 bytes of every page, 4 = users.idx holds UserMeta
+// Every page (meta, node, overflow) ends with the LSN of the last WAL record applied to it
+pub const PAGE_LSN_OFFSET: usize = PAGE_SIZE - 8;
+
+// Buffer pool sizes, in 4 KiB frames
+pub const ACCOUNTS_POOL_FRAMES: usize = 2048; // 8 MiB
+pub const USERS_POOL_FRAMES: usize = 512;     // 2 MiB
+
+// Lives at the start of page 0. Page 0 is never a node, so page id 0 doubles as "null".
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct TreeMeta {
+    pub magic: u32,
+    pub version: u16,
+    pub _pad1: [u8; 2],
+    pub root_page: u32,
+    pub page_count: u32,   // Next never-used page id
+    pub free_head: u32,    // First page of the free list (0 = empty)
+    pub _pad2: [u8; 4],
+    pub applied_index: u64, // Log index the tree contents correspond to
+    pub entry_count: u64,
+}
+
+// --- TREE WAL (users.idx.wal, accounts.idx.wal) ---
+// File = [WalFileHeader | (WalRecordHeader | payload)*]
+pub const WAL_MAGIC: u32 = 0x4A57_414C; // "JWAL"
+pub const WAL_VERSION: u16 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct WalFileHeader {
+    pub magic: u32,
+    pub version: u16,
+    pub _padding: [u8; 2],
+    pub base_lsn: u64, // LSN of the first record (the log restarts here after a checkpoint)
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalKind {
+    Begin = 1,
+    Update = 2,  // payload = before image | after image of bytes [offset, offset + len)
+    Commit = 3,
+    Clr = 4,     // Compensation (undo already done): payload = restored bytes
+    Abort = 5,   // Undo of the transaction is complete
+}
+
+impl WalKind {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(WalKind::Begin),
+            2 => Some(WalKind::Update),
+            3 => Some(WalKind::Commit),
+            4 => Some(WalKind::Clr),
+            5 => Some(WalKind::Abort),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct WalRecordHeader {
+    pub lsn: u64,
+    pub prev_lsn: u64,  // Previous record of the same transaction (0 = none)
+    pub txn_id: u64,    // = LSN of the transaction's Begin record
+    pub undo_next: u64, // CLR only: next record of the transaction still to undo
+    pub page_id: u32,
+    pub offset: u16,
+    pub len: u16,
+    pub kind: u8,
+    pub _padding: [u8; 3],
+    pub crc: u32,       // CRC32C of this header (crc = 0) + payload; catches torn tails
+}
+
+// --- CONFIG ---
+// Tunables come from JDB_* environment variables, like the admin and key settings
+pub fn env_u64(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(v) => v.trim().parse().unwrap_or_else(|_| {
+            eprintln!("{}: not a number ({:?}), using {}", name, v, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// --- CRASH INJECTION (recovery testing) ---
+// JDB_CRASH_AT=<point>[:<n>] kills the process (no destructors, no flushes) the n-th
+// time execution reaches `point(<point>)`. n defaults to 1. Unset = every point is a no-op.
+//
+// Points:
+//   tree-before-commit   all page updates of a transaction are logged, Commit isn't
+//   tree-after-commit    Commit is durable, pages are still only in the pool
+//   evict-write          a dirty page is about to be written back by eviction
+//   checkpoint-flush     a checkpoint has written some (not all) dirty pages
+//   checkpoint-truncate  every page is on disk, the WAL isn't truncated yet
+//   recovery-undo        recovery is halfway through rolling back a transaction
+
+static TARGET: OnceLock<Option<(String, u64)>> = OnceLock::new();
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+pub fn point(name: &str) {
+    let target = TARGET.get_or_init(|| {
+        let spec = std::env::var("JDB_CRASH_AT").ok()?;
+        let (point, n) = match spec.split_once(':') {
+            Some((point, n)) => (point.to_string(), n.parse().unwrap_or(1)),
+            None => (spec, 1),
+        };
+        Some((point, n))
+    });
+
+    let Some((point, n)) = target else { return };
+    if point == name && HITS.fetch_add(1, Ordering::SeqCst) + 1 == *n {
+        eprintln!("[Crash] Injected crash at '{}'", name);
+        std::process::abort();
+    }
+}
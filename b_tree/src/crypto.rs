@@ -0,0 +1,367 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use sha2::{Digest, Sha256};
+use crate::consts::{SealChunkHeader, SealHeader, SEAL_CHUNK, SEAL_LAST_CHUNK, SEAL_MAGIC, SEAL_TAG};
+
+// --- ENCRYPTION AT REST ---
+// users.bin / history.bin: every record is sealed on its own with ChaCha20-Poly1305,
+// so the files stay fixed-size and append-only: [SealHeader | ciphertext | tag].
+// The file name and the record's index are authenticated along with it,
+// so records can't be reordered or moved between files unnoticed.
+// A snapshot is one stream, sealed in chunks (SealedWriter / SealedReader).
+// Its last chunk is flagged, so a cut-off snapshot is an error, not a shorter snapshot.
+//
+// Keys come from JDB_DATA_KEY (hex, comma separated) or data.key (one hex key per line,
+// created on first start). The first key seals, all of them can open. Records name their key
+// by fingerprint, so the wrong key is reported as such instead of as corruption.
+// Rotation: put the new key first and restart (format::upgrade_data_files re-seals everything
+// with it), after which the old key can be removed.
+
+pub struct Keyring {
+    keys: Vec<(u32, ChaCha20Poly1305)>, // (fingerprint, cipher), the current key first
+    source: String,                     // Where the keys came from, for error messages
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn fingerprint(key: &[u8]) -> u32 {
+    let digest = Sha256::new().chain_update(b"jdb data key").chain_update(key).finalize();
+    u32::from_le_bytes(digest[0..4].try_into().unwrap())
+}
+
+// Authenticated along with each record / chunk
+fn record_aad(file: &str, index: u64) -> Vec<u8> {
+    let mut aad = file.as_bytes().to_vec();
+    aad.extend_from_slice(&index.to_le_bytes());
+    aad
+}
+
+fn chunk_aad(file: &str, index: u64, len: u32, flags: u32) -> Vec<u8> {
+    let mut aad = record_aad(file, index);
+    aad.extend_from_slice(&len.to_le_bytes());
+    aad.extend_from_slice(&flags.to_le_bytes());
+    aad
+}
+
+// Sealed records and chunks both start with SEAL_MAGIC
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && u32::from_le_bytes(bytes[0..4].try_into().unwrap()) == SEAL_MAGIC
+}
+
+impl Keyring {
+    pub fn load(key_file: &str) -> io::Result<Self> {
+        let (text, source) = match std::env::var("JDB_DATA_KEY") {
+            Ok(keys) => (keys.replace(',', "\n"), "JDB_DATA_KEY".to_string()),
+            Err(_) => match fs::read_to_string(key_file) {
+                Ok(text) => (text, key_file.to_string()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (create_key_file(key_file)?, key_file.to_string()),
+                Err(e) => return Err(e),
+            },
+        };
+
+        let mut keys = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key: [u8; 32] = hex::decode(line).ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| invalid(format!("{} line {}: expected a key of 64 hex characters", source, n + 1)))?;
+            keys.push((fingerprint(&key), ChaCha20Poly1305::new(Key::from_slice(&key))));
+        }
+        if keys.is_empty() {
+            return Err(invalid(format!("{} has no keys", source)));
+        }
+
+        Ok(Self { keys, source })
+    }
+
+    /// Fingerprint of the key new data is sealed with.
+    pub fn current(&self) -> u32 {
+        self.keys[0].0
+    }
+
+    /// Errors (clearly) if `key_id` isn't one of ours.
+    pub fn check_key(&self, file: &str, key_id: u32) -> io::Result<()> {
+        self.cipher(file, key_id).map(|_| ())
+    }
+
+    fn cipher(&self, file: &str, key_id: u32) -> io::Result<&ChaCha20Poly1305> {
+        match self.keys.iter().find(|(id, _)| *id == key_id) {
+            Some((_, cipher)) => Ok(cipher),
+            None => {
+                let have: Vec<String> = self.keys.iter().map(|(id, _)| format!("{:08x}", id)).collect();
+                Err(invalid(format!(
+                    "{} is encrypted with key {:08x}, which is not in {} (have {}). Wrong key?",
+                    file, key_id, self.source, have.join(", ")
+                )))
+            }
+        }
+    }
+
+    // Encrypts `buf` in place with the current key and appends the tag
+    fn seal(&self, aad: &[u8], buf: &mut Vec<u8>) -> (u32, [u8; 12]) {
+        let mut nonce = [0u8; 12];
+        getrandom::getrandom(&mut nonce).expect("OS random number generator unavailable");
+
+        let (key_id, cipher) = &self.keys[0];
+        let tag = cipher.encrypt_in_place_detached(Nonce::from_slice(&nonce), aad, buf)
+            .expect("ChaCha20-Poly1305 seal failed");
+        buf.extend_from_slice(&tag);
+        (*key_id, nonce)
+    }
+
+    // Checks the tag and decrypts `buf` (ciphertext | tag) in place, leaving the plaintext
+    fn open(&self, file: &str, what: &str, key_id: u32, nonce: &[u8; 12], aad: &[u8], buf: &mut Vec<u8>) -> io::Result<()> {
+        let cipher = self.cipher(file, key_id)?;
+        let tag = Tag::clone_from_slice(&buf[buf.len() - SEAL_TAG..]);
+        buf.truncate(buf.len() - SEAL_TAG);
+        cipher.decrypt_in_place_detached(Nonce::from_slice(nonce), aad, buf, &tag)
+            .map_err(|_| invalid(format!("{} {}: authentication failed (corrupted or tampered with)", file, what)))
+    }
+
+    /// [SealHeader | ciphertext | tag] for the record at `index` of `file`.
+    pub fn seal_record(&self, file: &str, index: u64, plain: &[u8]) -> Vec<u8> {
+        let mut buf = plain.to_vec();
+        let (key_id, nonce) = self.seal(&record_aad(file, index), &mut buf);
+
+        let header = SealHeader { magic: SEAL_MAGIC, key_id, nonce };
+        let mut record = bytemuck::bytes_of(&header).to_vec();
+        record.extend_from_slice(&buf);
+        record
+    }
+
+    pub fn open_record(&self, file: &str, index: u64, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let what = format!("record {}", index);
+        let header: SealHeader = bytemuck::pod_read_unaligned(&sealed[..size_of::<SealHeader>()]);
+        if header.magic != SEAL_MAGIC {
+            return Err(invalid(format!("{} {}: not an encrypted record", file, what)));
+        }
+
+        let mut buf = sealed[size_of::<SealHeader>()..].to_vec();
+        self.open(file, &what, header.key_id, &header.nonce, &record_aad(file, index), &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Opens every whole record of a sealed file (`size` bytes each on disk) and hands it to `f`.
+    /// A bad last record is a torn write (crashed mid-append) and is left out, so the
+    /// caller can cut it off; a bad record anywhere else is corruption and an error.
+    /// `offset` is where `bytes` starts in the file (after its header), for error messages.
+    pub fn open_each(
+        &self,
+        file: &str,
+        bytes: &[u8],
+        size: usize,
+        offset: usize,
+        mut f: impl FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> io::Result<usize> {
+        let count = bytes.len() / size;
+
+        for (index, sealed) in bytes.chunks_exact(size).enumerate() {
+            // A key we don't have is never a torn write
+            let header: SealHeader = bytemuck::pod_read_unaligned(&sealed[..size_of::<SealHeader>()]);
+            if header.magic == SEAL_MAGIC {
+                self.cipher(file, header.key_id)?;
+            }
+
+            let result = self.open_record(file, index as u64, sealed).and_then(|plain| {
+                f(&plain).map_err(|e| invalid(format!("{} record {}: {}", file, index, e)))
+            });
+            match result {
+                Ok(()) => {}
+                Err(_) if index + 1 == count => return Ok(index),
+                Err(e) => return Err(invalid(format!("{}, at byte offset {}", e, offset + index * size))),
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn create_key_file(key_file: &str) -> io::Result<String> {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key).expect("OS random number generator unavailable");
+    let text = format!("{}\n", hex::encode(key));
+
+    // Readable by us only
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(key_file)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+
+    println!("[Crypto] Generated a new data key in {}. Back it up: the data files can't be read without it.", key_file);
+    Ok(text)
+}
+
+// --- SEALED STREAMS (snapshots) ---
+pub struct SealedWriter<'a, W: Write> {
+    inner: W,
+    keys: &'a Keyring,
+    file: &'static str,
+    buf: Vec<u8>,
+    chunk: u64,
+}
+
+impl<'a, W: Write> SealedWriter<'a, W> {
+    pub fn new(inner: W, keys: &'a Keyring, file: &'static str) -> Self {
+        Self { inner, keys, file, buf: Vec::new(), chunk: 0 }
+    }
+
+    fn write_chunk(&mut self, len: usize, flags: u32) -> io::Result<()> {
+        let mut buf: Vec<u8> = self.buf.drain(..len).collect();
+        let (key_id, nonce) = self.keys.seal(&chunk_aad(self.file, self.chunk, len as u32, flags), &mut buf);
+
+        let header = SealChunkHeader { magic: SEAL_MAGIC, key_id, nonce, len: len as u32, flags };
+        self.inner.write_all(bytemuck::bytes_of(&header))?;
+        self.inner.write_all(&buf)?;
+        self.chunk += 1;
+        Ok(())
+    }
+
+    /// Seals what's left as the last chunk. Without this the stream reads as truncated.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(self.buf.len(), SEAL_LAST_CHUNK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealedWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        // Only full chunks go out here: the last one has to wait for finish()
+        while self.buf.len() > SEAL_CHUNK {
+            self.write_chunk(SEAL_CHUNK, 0)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct SealedReader<'a, R: Read> {
+    inner: R,
+    keys: &'a Keyring,
+    file: &'static str,
+    plain: Vec<u8>,
+    pos: usize,
+    chunk: u64,
+    done: bool,
+}
+
+impl<'a, R: Read> SealedReader<'a, R> {
+    pub fn new(inner: R, keys: &'a Keyring, file: &'static str) -> Self {
+        Self { inner, keys, file, plain: Vec::new(), pos: 0, chunk: 0, done: false }
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let truncated = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid(format!("{} is truncated", self.file)),
+            _ => e,
+        };
+
+        let mut header_buf = [0u8; size_of::<SealChunkHeader>()];
+        self.inner.read_exact(&mut header_buf).map_err(truncated)?;
+        let header: SealChunkHeader = bytemuck::cast(header_buf);
+        if header.magic != SEAL_MAGIC || header.len as usize > SEAL_CHUNK {
+            return Err(invalid(format!("{} chunk {}: not an encrypted chunk", self.file, self.chunk)));
+        }
+
+        let mut buf = vec![0u8; header.len as usize + SEAL_TAG];
+        self.inner.read_exact(&mut buf).map_err(truncated)?;
+        let aad = chunk_aad(self.file, self.chunk, header.len, header.flags);
+        self.keys.open(self.file, &format!("chunk {}", self.chunk), header.key_id, &header.nonce, &aad, &mut buf)?;
+
+        self.plain = buf;
+        self.pos = 0;
+        self.chunk += 1;
+        self.done = header.flags & SEAL_LAST_CHUNK != 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SealedReader<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+
+        let n = out.len().min(self.plain.len() - self.pos);
+        out[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format;
+
+    const HEADER: usize = size_of::<SealChunkHeader>();
+
+    fn sealed(keys: &Keyring, file: &'static str, plain: &[u8]) -> Vec<u8> {
+        let mut writer = SealedWriter::new(Vec::new(), keys, file);
+        writer.write_all(plain).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn opened(keys: &Keyring, file: &'static str, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        SealedReader::new(sealed, keys, file).read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn sealed_streams_round_trip() {
+        format::in_data_dir("crypto-round-trip", || {
+            let keys = Keyring::load("data.key").unwrap();
+
+            // 1. Empty, and more than two chunks' worth
+            let plain: Vec<u8> = (0..2 * SEAL_CHUNK + 100).map(|i| (i % 251) as u8).collect();
+            assert_eq!(opened(&keys, "snapshot", &sealed(&keys, "snapshot", b"")).unwrap(), b"");
+            let stream = sealed(&keys, "snapshot", &plain);
+            assert_eq!(stream.len(), plain.len() + 3 * (HEADER + SEAL_TAG));
+            assert_eq!(opened(&keys, "snapshot", &stream).unwrap(), plain);
+
+            // 2. Without its last chunk it's truncated, not shorter
+            let err = opened(&keys, "snapshot", &stream[..2 * (HEADER + SEAL_CHUNK + SEAL_TAG)]).unwrap_err();
+            assert_eq!(err.to_string(), "snapshot is truncated");
+        });
+    }
+
+    #[test]
+    fn sealed_streams_only_open_as_written() {
+        format::in_data_dir("crypto-tamper", || {
+            let keys = Keyring::load("data.key").unwrap();
+            let other = Keyring::load("other.key").unwrap();
+            let stream = sealed(&keys, "snapshot", b"balances and order books");
+
+            // 1. Under another name, or with another key
+            let err = opened(&keys, "history", &stream).unwrap_err();
+            assert_eq!(err.to_string(), "history chunk 0: authentication failed (corrupted or tampered with)");
+            let err = opened(&other, "snapshot", &stream).unwrap_err();
+            assert!(err.to_string().ends_with("Wrong key?"), "{}", err);
+
+            // 2. One flipped bit of ciphertext
+            let mut flipped = stream.clone();
+            flipped[HEADER + 3] ^= 1;
+            let err = opened(&keys, "snapshot", &flipped).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), "snapshot chunk 0: authentication failed (corrupted or tampered with)");
+        });
+    }
+}
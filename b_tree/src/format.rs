@@ -0,0 +1,573 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::consts::{
+    sealed_size, ActionType, DATA_LOCK, ApiKeyRecord, FileHeader, LogEntry, SealChunkHeader, SealHeader, SnapshotTrailer, UserMeta, ARCHIVE_FORMAT, FILE_HEADER_SIZE,
+    LOG_ENTRY_V2_SIZE, LOG_VERSION,
+    FILE_KIND_API_KEYS, FILE_KIND_ARCHIVE, FILE_KIND_HISTORY, FILE_KIND_SNAPSHOT, FILE_KIND_USERS, FILE_MAGIC, HISTORY_FORMAT, SEAL_MAGIC,
+    SNAPSHOT_FORMAT, USERS_FORMAT, API_KEYS_FORMAT,
+};
+use crate::crypto::{is_sealed, Keyring, SealedReader, SealedWriter};
+use crate::segments;
+use crate::snapshot;
+
+// --- FILE FORMATS ---
+// users.bin / apikeys.bin / history.bin: [FileHeader | sealed record * N]
+// history-*.bin:           [FileHeader | sealed record * N | zeroed slots] (journal segments, see segments.rs)
+// snapshot-*.bin:          [FileHeader | sealed stream] (see snapshot.rs)
+// archive/journal-*.zst:   [FileHeader | sealed stream] (compacted journal, see compact.rs)
+// history.bin is from before segments: it's upgraded like users.bin, then becomes the first segment.
+//
+// upgrade_data_files() runs before anything else opens them and brings every file to the
+// current format: headerless files (plaintext, or sealed before headers existed) get a header,
+// older versions are migrated record by record, and records sealed with an old key are re-sealed.
+// After that, readers only accept the current version (check_header).
+
+pub struct FileSpec {
+    pub file: &'static str,
+    pub kind: u16,
+    pub version: u16,
+    pub record_size: usize, // 0 = variable (a stream)
+}
+
+pub const USERS: FileSpec = FileSpec {
+    file: "users.bin",
+    kind: FILE_KIND_USERS,
+    version: USERS_FORMAT,
+    record_size: size_of::<UserMeta>(),
+};
+
+pub const API_KEYS: FileSpec = FileSpec {
+    file: "apikeys.bin",
+    kind: FILE_KIND_API_KEYS,
+    version: API_KEYS_FORMAT,
+    record_size: size_of::<ApiKeyRecord>(),
+};
+
+pub const HISTORY: FileSpec = FileSpec {
+    file: "history.bin",
+    kind: FILE_KIND_HISTORY,
+    version: HISTORY_FORMAT,
+    record_size: size_of::<LogEntry>(),
+};
+
+// The single snapshot from before rotation, adopted into the manifest on upgrade
+const LEGACY_SNAPSHOT: &str = "snapshot.bin";
+
+pub const SNAPSHOT: FileSpec = FileSpec {
+    file: "snapshot.bin",
+    kind: FILE_KIND_SNAPSHOT,
+    version: SNAPSHOT_FORMAT,
+    record_size: 0,
+};
+
+// Not upgraded or re-keyed at startup: archives are only read by the compact / audit commands
+pub const JOURNAL_ARCHIVE: FileSpec = FileSpec {
+    file: "journal archive",
+    kind: FILE_KIND_ARCHIVE,
+    version: ARCHIVE_FORMAT,
+    record_size: size_of::<LogEntry>(),
+};
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub fn new_header(spec: &FileSpec, engine_id: &[u8; 16], created_at: u64) -> FileHeader {
+    FileHeader {
+        magic: FILE_MAGIC,
+        kind: spec.kind,
+        version: spec.version,
+        record_size: spec.record_size as u32,
+        header_size: FILE_HEADER_SIZE as u32,
+        created_at,
+        engine_id: *engine_id,
+        first_record: 0,
+        _reserved: [0; 16],
+    }
+}
+
+// None = no header (a file from before headers existed)
+fn parse_header(bytes: &[u8]) -> Option<FileHeader> {
+    if bytes.len() < FILE_HEADER_SIZE {
+        return None;
+    }
+    let header: FileHeader = bytemuck::pod_read_unaligned(&bytes[..size_of::<FileHeader>()]);
+    (header.magic == FILE_MAGIC).then_some(header)
+}
+
+// What any version must agree on
+fn check(header: &FileHeader, spec: &FileSpec, engine_id: &[u8; 16]) -> io::Result<()> {
+    if header.kind != spec.kind {
+        return Err(invalid(format!("{} has the header of a different kind of file ({})", spec.file, header.kind)));
+    }
+    if header.version > spec.version {
+        return Err(invalid(format!(
+            "{} was written by a newer engine (format v{}, this build reads up to v{})",
+            spec.file, header.version, spec.version
+        )));
+    }
+    if header.version == spec.version && header.record_size as usize != spec.record_size {
+        return Err(invalid(format!(
+            "{} has {}-byte records, this build expects {}",
+            spec.file, header.record_size, spec.record_size
+        )));
+    }
+    if header.engine_id != *engine_id {
+        return Err(invalid(format!(
+            "{} belongs to a different engine ({}, expected {})",
+            spec.file, hex::encode(header.engine_id), hex::encode(engine_id)
+        )));
+    }
+    Ok(())
+}
+
+/// For readers: the file must start with a current-version header of the right kind.
+pub fn check_header(bytes: &[u8], spec: &FileSpec, engine_id: &[u8; 16]) -> io::Result<FileHeader> {
+    let header = check_old_header(bytes, spec, engine_id)?;
+    if header.version != spec.version {
+        return Err(invalid(format!("{} is still format v{} (expected v{})", spec.file, header.version, spec.version)));
+    }
+    Ok(header)
+}
+
+/// Same, but an older version is fine too: for code that migrates the records itself
+pub fn check_old_header(bytes: &[u8], spec: &FileSpec, engine_id: &[u8; 16]) -> io::Result<FileHeader> {
+    let header = parse_header(bytes).ok_or_else(|| invalid(format!("{} has no file header", spec.file)))?;
+    check(&header, spec, engine_id)?;
+    Ok(header)
+}
+
+// --- STARTUP: UPGRADE / RE-KEY ---
+/// Returns the engine ID every file now carries.
+pub fn upgrade_data_files(keys: &Arc<Keyring>) -> io::Result<[u8; 16]> {
+    // Snapshots: whatever the manifest lists, plus a snapshot.bin from before rotation existed
+    let snapshots = snapshot_files();
+    let journal = segments::read_index()?;
+
+    // 1. Which database are these files from? A new one if none has a header yet.
+    let engine_id = read_engine_id()?.unwrap_or_else(|| {
+        let mut id = [0u8; 16];
+        getrandom::getrandom(&mut id).expect("OS random number generator unavailable");
+        println!("[Format] New engine ID {}", hex::encode(id));
+        id
+    });
+
+    // 2. Bring each file up to date
+    upgrade_records(keys, &USERS, &engine_id)?;
+    upgrade_records(keys, &API_KEYS, &engine_id)?;
+    if Path::new(HISTORY.file).exists() {
+        upgrade_records(keys, &HISTORY, &engine_id)?;
+        segments::adopt_legacy(&engine_id)?;
+    }
+    for segment in &journal {
+        segments::upgrade_segment(keys, segment, &engine_id)?;
+    }
+
+    // Not fatal: startup falls back to an older snapshot, or to replaying the whole log
+    let mut manifest = snapshot::read_manifest().unwrap_or_default();
+    let before = manifest.clone();
+    for file in &snapshots {
+        match upgrade_snapshot(keys, file, &engine_id) {
+            // A new format has a new CRC (and maybe more records): the manifest has to agree, or the snapshot is skipped
+            Ok(Some(trailer)) => manifest.iter_mut().filter(|e| e.file == *file).for_each(|e| {
+                e.checksum = trailer.checksum;
+                e.records = trailer.records;
+            }),
+            Ok(None) => {}
+            Err(e) => eprintln!("[Format] {} left as it is: {}", file, e),
+        }
+    }
+    if manifest != before {
+        snapshot::write_manifest(&manifest)?;
+    }
+    if let Err(e) = adopt_legacy_snapshot(keys, &engine_id) {
+        eprintln!("[Format] {} left as it is: {}", LEGACY_SNAPSHOT, e);
+    }
+    Ok(engine_id)
+}
+
+fn snapshot_files() -> Vec<String> {
+    let mut snapshots: Vec<String> = snapshot::read_manifest().unwrap_or_default().into_iter().map(|e| e.file).collect();
+    snapshots.push(LEGACY_SNAPSHOT.to_string());
+    snapshots
+}
+
+/// The engine ID in the file headers (None = no file has one yet). Every header there is must agree.
+/// Only reads: the offline tools use it without upgrading anything.
+pub fn read_engine_id() -> io::Result<Option<[u8; 16]>> {
+    let snapshots = snapshot_files();
+    let journal = segments::read_index()?;
+    let mut engine_id = None;
+    let files = [USERS.file, API_KEYS.file, HISTORY.file].into_iter()
+        .chain(journal.iter().map(|s| s.file.as_str()))
+        .chain(snapshots.iter().map(|f| f.as_str()));
+    for file in files {
+        let Some(header) = read_header(file)? else { continue };
+        match engine_id {
+            Some(id) if id != header.engine_id => {
+                return Err(invalid(format!(
+                    "{} belongs to a different engine ({}, the other files are {})",
+                    file, hex::encode(header.engine_id), hex::encode(id)
+                )));
+            }
+            _ => engine_id = Some(header.engine_id),
+        }
+    }
+    Ok(engine_id)
+}
+
+fn read_header(file: &str) -> io::Result<Option<FileHeader>> {
+    let mut bytes = Vec::new();
+    match File::open(file) {
+        Ok(f) => f.take(FILE_HEADER_SIZE as u64).read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(parse_header(&bytes))
+}
+
+fn read_if_exists(file: &str) -> io::Result<Vec<u8>> {
+    match fs::read(file) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Plaintext size of one record in a file of `version`
+pub fn record_size(spec: &FileSpec, version: u16) -> usize {
+    match spec.kind {
+        FILE_KIND_HISTORY | FILE_KIND_ARCHIVE if version < 3 => LOG_ENTRY_V2_SIZE,
+        _ => spec.record_size,
+    }
+}
+
+// One step per format version, oldest first. 0 -> 1 only added the file header;
+// 1 -> 2 added the entry CRC and the notional on trades from before the matching engine.
+// When a record layout changes (say LogEntry grows a field), bump its *_FORMAT and convert here.
+pub fn migrate_record(kind: u16, from: u16, record: &[u8]) -> Vec<u8> {
+    let mut record = record.to_vec();
+    if matches!(kind, FILE_KIND_HISTORY | FILE_KIND_ARCHIVE) {
+        if from < 2 {
+            record = log_v1_to_v2(&record);
+        }
+        if from < 3 {
+            record = log_v2_to_v3(&record);
+        }
+    }
+    record
+}
+
+// CRC32C of a v1/v2 entry (64 bytes, the CRC at 4..8), the way LogEntry::checksum did it then
+fn v2_checksum(record: &[u8]) -> u32 {
+    let mut bytes = record[..LOG_ENTRY_V2_SIZE].to_vec();
+    bytes[4..8].fill(0);
+    crc32c::crc32c(&bytes)
+}
+
+// v1 journals could hold entries from before the CRC existed. From v2 on every entry has one.
+// Entries that already carry one keep it, so a damaged one still fails the check.
+//
+// They could also hold trades from before the matching engine: one entry per trade, no order ID,
+// and the unit price in amount_money. Replay takes amount_money as the signed notional
+// (price * quantity, so negative for a sale), which is what those get. One whose notional
+// doesn't fit in an i64 is left failing its CRC: startup reports it instead of replaying it wrong.
+fn log_v1_to_v2(record: &[u8]) -> Vec<u8> {
+    let mut bytes = record.to_vec();
+    let version = u16::from_le_bytes([bytes[2], bytes[3]]);
+    if version < 2 {
+        let field = |at: usize| i64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let order_id = field(V2_ORDER_ID);
+        let mut intact = true;
+        if let ActionType::Trade = ActionType::from_u8(bytes[V2_ACTION]) && order_id == 0 {
+            match field(V2_AMOUNT).checked_mul(field(V2_QUANTITY)) {
+                Some(notional) => bytes[V2_AMOUNT..V2_AMOUNT + 8].copy_from_slice(&notional.to_le_bytes()),
+                None => intact = false,
+            }
+        }
+        let crc = v2_checksum(&bytes);
+        bytes[4..8].copy_from_slice(&if intact { crc } else { !crc }.to_le_bytes());
+    }
+    bytes
+}
+
+// Where v1/v2 entries keep what log_v1_to_v2 looks at
+const V2_ORDER_ID: usize = 24; // request_id[0..8]
+const V2_ACTION: usize = 40;
+const V2_QUANTITY: usize = 48;
+const V2_AMOUNT: usize = 56;
+
+// v3 gave the request key 128 bits of its own: it used to be request_id[8..16] (still the
+// txn id in group markers). The CRC covers the new layout, if the old one was right.
+fn log_v2_to_v3(record: &[u8]) -> Vec<u8> {
+    let intact = u32::from_le_bytes(record[4..8].try_into().unwrap()) == v2_checksum(record);
+    let mut bytes = record.to_vec();
+    bytes.resize(size_of::<LogEntry>(), 0);
+    let old: LogEntry = bytemuck::pod_read_unaligned(&bytes);
+
+    let mut entry = LogEntry { version: LOG_VERSION, ..old };
+    if !matches!(ActionType::from_u8(entry.action_type), ActionType::TxnBegin | ActionType::TxnCommit) {
+        let key = u64::from_le_bytes(entry.request_id[8..16].try_into().unwrap());
+        entry.request_id[8..16].fill(0);
+        entry = entry.with_request_key(key as u128);
+    }
+    let crc = entry.checksum();
+    bytemuck::bytes_of(&LogEntry { crc: if intact { crc } else { !crc }, ..entry }).to_vec()
+}
+
+fn upgrade_records(keys: &Keyring, spec: &FileSpec, engine_id: &[u8; 16]) -> io::Result<()> {
+    let bytes = read_if_exists(spec.file)?;
+
+    // Fresh database: just the header
+    if bytes.is_empty() {
+        replace_file(spec.file, bytemuck::bytes_of(&new_header(spec, engine_id, now())))?;
+        println!("[Format] Created {}", spec.file);
+        return Ok(());
+    }
+
+    // 1. Where are we starting from? Before headers, a file could still be plaintext.
+    let (version, created_at, offset) = match parse_header(&bytes) {
+        Some(header) => {
+            check(&header, spec, engine_id)?;
+            (header.version, header.created_at, FILE_HEADER_SIZE)
+        }
+        None => (0, now(), 0),
+    };
+    let body = &bytes[offset..];
+    let sealed = version > 0 || is_sealed(body);
+    let size = record_size(spec, version);
+    let disk_size = if sealed { sealed_size(size) } else { size };
+
+    let stale = sealed && body.chunks_exact(disk_size).any(|record| {
+        let header: SealHeader = bytemuck::pod_read_unaligned(&record[..size_of::<SealHeader>()]);
+        header.magic == SEAL_MAGIC && header.key_id != keys.current()
+    });
+    if version == spec.version && !stale {
+        return Ok(());
+    }
+
+    // 2. Every record, in plaintext (a torn last record is dropped, damage elsewhere is an error)
+    let mut records = Vec::new();
+    if sealed {
+        keys.open_each(spec.file, body, disk_size, offset, |plain| {
+            records.push(plain.to_vec());
+            Ok(())
+        })?;
+    } else {
+        records = body.chunks_exact(disk_size).map(|r| r.to_vec()).collect();
+    }
+    let dropped = body.len() - records.len() * disk_size;
+    if dropped > 0 {
+        println!("[Format] {}: dropped {} bytes of a half-written last record", spec.file, dropped);
+    }
+
+    // 3. Rewrite: current header, current layout, current key
+    let mut out = bytemuck::bytes_of(&new_header(spec, engine_id, created_at)).to_vec();
+    for (index, record) in records.iter().enumerate() {
+        let record = migrate_record(spec.kind, version, record);
+        out.extend_from_slice(&keys.seal_record(spec.file, index as u64, &record));
+    }
+    replace_file(spec.file, &out)?;
+
+    println!("[Format] {}: {} records, format v{} -> v{}{}, sealed with key {:08x}",
+        spec.file, records.len(), version, spec.version, if sealed { "" } else { " (was plaintext)" }, keys.current());
+    Ok(())
+}
+
+// Some(the new trailer) if it was rewritten, for the manifest
+fn upgrade_snapshot(keys: &Arc<Keyring>, file: &str, engine_id: &[u8; 16]) -> io::Result<Option<SnapshotTrailer>> {
+    let spec = &SNAPSHOT;
+    let bytes = read_if_exists(file)?;
+    if bytes.is_empty() {
+        return Ok(None); // No snapshot yet
+    }
+
+    let (version, created_at, body) = match parse_header(&bytes) {
+        Some(header) => {
+            check(&header, spec, engine_id)?;
+            (header.version, header.created_at, &bytes[FILE_HEADER_SIZE..])
+        }
+        None => (0, now(), &bytes[..]),
+    };
+
+    // A stream is always sealed with a single key, so its first chunk tells
+    let sealed = version > 0 || is_sealed(body);
+    let stale = sealed && body.len() >= size_of::<SealChunkHeader>() && {
+        let chunk: SealChunkHeader = bytemuck::pod_read_unaligned(&body[..size_of::<SealChunkHeader>()]);
+        keys.check_key(spec.file, chunk.key_id)?;
+        chunk.key_id != keys.current()
+    };
+    if version == spec.version && !stale {
+        return Ok(None);
+    }
+
+    let mut plain = if sealed {
+        let mut plain = Vec::new();
+        SealedReader::new(body, keys, spec.file).read_to_end(&mut plain)?;
+        plain
+    } else {
+        body.to_vec()
+    };
+    // v1 -> v2: count + CRC trailer
+    if version < 2 {
+        plain = snapshot::add_trailer(plain)?;
+    }
+    // v2 -> v3: receipts section
+    if version < 3 {
+        plain = snapshot::add_receipts(plain)?;
+    }
+    // v3 -> v4: flags section, worked out from the journal (already upgraded above)
+    if version < 4 {
+        let flags = segments::flags_at(keys.clone(), *engine_id, snapshot::stream_log_index(&plain)?)?;
+        plain = snapshot::add_flags(plain, &flags)?;
+    }
+
+    let trailer = snapshot::trailer(&plain);
+    let out = bytemuck::bytes_of(&new_header(spec, engine_id, created_at)).to_vec();
+    let mut writer = SealedWriter::new(out, keys, spec.file);
+    writer.write_all(&plain)?;
+    replace_file(file, &writer.finish()?)?;
+
+    println!("[Format] {}: format v{} -> v{}{}, sealed with key {:08x}",
+        file, version, spec.version, if sealed { "" } else { " (was plaintext)" }, keys.current());
+    Ok(trailer)
+}
+
+// snapshot.bin (a single snapshot) becomes the first entry of the manifest
+fn adopt_legacy_snapshot(keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<()> {
+    if !Path::new(LEGACY_SNAPSHOT).exists() {
+        return Ok(());
+    }
+    let data = snapshot::read_snapshot(LEGACY_SNAPSHOT, u64::MAX, keys, engine_id)?;
+    let file = snapshot::snapshot_file(data.last_log_index);
+    fs::rename(LEGACY_SNAPSHOT, &file)?;
+    sync_dir()?;
+    snapshot::add_to_manifest(snapshot::ManifestEntry {
+        file: file.clone(),
+        last_log_index: data.last_log_index,
+        records: data.records,
+        checksum: data.checksum,
+    })?;
+    println!("[Format] {} is now {}", LEGACY_SNAPSHOT, file);
+    Ok(())
+}
+
+/// Write a copy, then rename it over the original: a crash leaves one or the other, never half of each.
+pub fn replace_file(file: &str, bytes: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", file);
+    let mut out = File::create(&tmp)?;
+    out.write_all(bytes)?;
+    out.sync_all()?;
+    fs::rename(&tmp, file)?;
+    sync_dir()
+}
+
+// --- DATA DIRECTORY LOCK ---
+// One process at a time: the engine holds an exclusive flock on jdb.lock for as long as it runs,
+// and so do the offline tools (compact.rs). The file itself is never deleted, only locked.
+// The lock goes with the process, however it exits.
+/// Locks the data directory for as long as the returned file is open.
+/// Fails with WouldBlock when another process has it.
+pub fn lock_data_dir() -> io::Result<File> {
+    let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(DATA_LOCK)?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                    format!("{} is locked: the engine (or b_tree compact / audit) is already running in this directory", DATA_LOCK)));
+            }
+            return Err(e);
+        }
+    }
+    Ok(file)
+}
+
+/// Makes renames / creations in the data directory durable (the files are relative to it).
+pub fn sync_dir() -> io::Result<()> {
+    File::open(".")?.sync_all()
+}
+
+// --- TEST DATA DIRECTORIES ---
+// The data files are relative to the working directory, and there's one per process:
+// tests that use them take turns.
+#[cfg(test)]
+static TEST_CWD: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Runs `f` in a fresh data directory, removed afterwards (even if `f` panics).
+#[cfg(test)]
+pub fn in_data_dir(name: &str, f: impl FnOnce()) {
+    let _cwd = TEST_CWD.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = std::env::temp_dir().join(format!("jdb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let before = std::env::current_dir().unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    std::env::set_current_dir(before).unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AppState;
+    use tokio::sync::mpsc;
+
+    // A journal entry the way v1 wrote it: 64 bytes, no CRC, unit price in amount_money
+    // for trades from before the matching engine (which have no order ID).
+    fn v1_entry(user_id: u64, action: ActionType, order_id: u64, quantity: i64, amount: i64) -> Vec<u8> {
+        let mut bytes = vec![0u8; LOG_ENTRY_V2_SIZE];
+        bytes[0..2].copy_from_slice(&crate::consts::LOG_MAGIC.to_le_bytes());
+        bytes[2..4].copy_from_slice(&1u16.to_le_bytes());
+        bytes[8..16].copy_from_slice(&user_id.to_le_bytes());
+        bytes[16..24].copy_from_slice(&1_700_000_000u64.to_le_bytes());
+        bytes[V2_ORDER_ID..V2_ORDER_ID + 8].copy_from_slice(&order_id.to_le_bytes());
+        bytes[V2_ACTION] = action as u8;
+        bytes[44..48].copy_from_slice(&7u32.to_le_bytes());
+        bytes[V2_QUANTITY..V2_QUANTITY + 8].copy_from_slice(&quantity.to_le_bytes());
+        bytes[V2_AMOUNT..V2_AMOUNT + 8].copy_from_slice(&amount.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn v1_trades_replay_with_their_notional() {
+        in_data_dir("format-v1", || {
+            // A headerless v1 history: trades at a unit price of 100, then a matched pair
+            // (order IDs, notional already in amount_money) that must come through untouched.
+            let history: Vec<u8> = [
+                v1_entry(1, ActionType::Deposit, 0, 0, 10_000),
+                v1_entry(2, ActionType::Deposit, 0, 0, 1_000),
+                v1_entry(1, ActionType::Trade, 0, 5, 100),
+                v1_entry(1, ActionType::Trade, 0, -2, 100),
+                v1_entry(2, ActionType::Trade, 11, 1, 50),
+                v1_entry(1, ActionType::Trade, 10, -1, -50),
+            ].concat();
+            fs::write(HISTORY.file, history).unwrap();
+
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = upgrade_data_files(&keys).unwrap();
+            let (tx, _rx) = mpsc::channel(1_000);
+            let state = AppState::new(tx, keys, engine_id).unwrap();
+
+            let first = state.portfolios.get(&1).unwrap();
+            assert_eq!(first.cash, 10_000 - 500 + 200 + 50);
+            assert_eq!(first.stocks.get(&7), Some(&2));
+            let second = state.portfolios.get(&2).unwrap();
+            assert_eq!(second.cash, 1_000 - 50);
+            assert_eq!(second.stocks.get(&7), Some(&1));
+        });
+    }
+}
@@ -3,6 +3,20 @@ mod writer;
 mod reader;
 mod state;
 mod snapshot;
+mod orderbook;
+mod pager;
+mod nodes;
+mod btree;
+mod accounts;
+mod wal;
+mod crash;
+mod auth;
+mod apikeys;
+mod crypto;
+mod format;
+mod persister;
+mod segments;
+mod compact;
 
 
 use tower_http::cors::{CorsLayer, Any}; // Import this
@@ -10,19 +24,27 @@ use tower_http::cors::{CorsLayer, Any}; // Import this
 
 use axum::{
     extract::{Path, State},
-    routing::{get, post},
+    http::StatusCode,
+    middleware,
+    routing::{get, post, put, delete},
     Json, Router,
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use std::io;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::task;
-use tokio::sync::mpsc; // Import Channel
+use tokio::sync::{mpsc, oneshot};
 
-use state::{AppState, DbMessage}; // Import DbMessage
-use snapshot::save_snapshot;
+use auth::AuthUser;
+use state::{AppState, DbMessage, OrderOutcome, OrderStatus, Portfolio, Receipt, Transfer};
 use writer::{DatabaseWriter, make_string};
-use consts::{UserMeta, LogEntry, ActionType};
+use snapshot::SnapshotStats;
+use consts::{
+    UserMeta, LogEntry, ActionType, MAX_OPEN_ORDERS, SCOPE_ALL, SCOPE_READ, SCOPE_TRADE,
+    SCOPE_WITHDRAW, USER_ACTIVE, USER_FLAG_NAMES, USER_FROZEN, USER_MARKET_MAKER,
+};
+use orderbook::{NewOrder, Order, OrderType, Side, TimeInForce};
 
 type SharedState = Arc<RwLock<AppState>>;
 
@@ -30,80 +52,214 @@ type SharedState = Arc<RwLock<AppState>>;
 async fn main() {
     println!("Initializing Engine...");
 
+    // 0. DATA FILES: headers, format upgrades, sealing plaintext / re-sealing with a new key,
+    // all before anything else opens them
+    let keys = match crypto::Keyring::load("data.key") {
+        Ok(keys) => Arc::new(keys),
+        Err(e) => {
+            eprintln!("[Crypto] Can't load the data key: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Offline tools instead of the server: b_tree compact [--dry-run] | b_tree audit (compact.rs).
+    // They take the files as they are: nothing is upgraded for them.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        std::process::exit(compact::run_cli(command, &args[1..], &keys));
+    }
+
+    // Ours until the process exits (format.rs)
+    let _data_lock = match format::lock_data_dir() {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("[Startup] {}", e);
+            std::process::exit(1);
+        }
+    };
+    let engine_id = match format::upgrade_data_files(&keys) {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("[Format] {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // 1. SETUP CHANNEL (The Buffer)
     // Capacity 10,000 means we can hold 10k pending writes in RAM before slowing down.
-    let (tx, mut rx) = mpsc::channel::<DbMessage>(10_000);
-
-    // 2. SPAWN PERSISTER THREAD (Dedicated Disk Worker)
-    // We use std::thread because file I/O is blocking.
-    std::thread::spawn(move || {
-        println!("[Persister] Disk Thread Started");
-        let mut db = DatabaseWriter::new().unwrap();
-
-        // Loop forever, waiting for messages
-        while let Some(msg) = rx.blocking_recv() {
-            match msg {
-                DbMessage::WriteLog(entry) => {
-                    if let Err(e) = db.append_log(&entry) {
-                        eprintln!("[Persister] LOG WRITE FAILED: {}", e);
-                    }
-                }
-                DbMessage::WriteUser(user) => {
-                    if let Err(e) = db.append_user(&user) {
-                        eprintln!("[Persister] USER WRITE FAILED: {}", e);
-                    }
+    let (tx, rx) = mpsc::channel::<DbMessage>(10_000);
+
+    // 2. START ENGINE (Pass 'tx' to AppState)
+    // Anything it journals while starting up waits in the channel for the persister
+    let app_state = match AppState::new(tx, keys.clone(), engine_id) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("[Startup] {}", e);
+            std::process::exit(1);
+        }
+    };
+    let shared_state = Arc::new(RwLock::new(app_state));
+
+    // 3. SPAWN PERSISTER THREAD (Dedicated Disk Worker)
+    // Only now: startup may have cut a torn record off the journal, and the writer counts records on open.
+    // We use std::thread because file I/O is blocking. It commits in batches (persister.rs).
+    let db = DatabaseWriter::new(keys, engine_id).expect("Failed to open users.bin / the journal");
+    let journal = shared_state.read().unwrap().journal.clone();
+    let config = persister::PersisterConfig::from_env();
+    let persister_journal = journal.clone();
+    std::thread::spawn(move || persister::run(db, rx, persister_journal, config));
+
+    // 4. SPAWN RECOVERY: after a failed write the persister drops everything behind it,
+    // so RAM is rolled back to the disk and the engine goes read-only
+    let rec_state = shared_state.clone();
+    task::spawn(async move {
+        loop {
+            journal.failed.notified().await;
+            loop {
+                let state = rec_state.clone();
+                match task::spawn_blocking(move || recover_journal(&state)).await {
+                    Ok(Ok(())) => break,
+                    Ok(Err(e)) => eprintln!("[Journal] Rollback failed, retrying: {}", e),
+                    Err(e) => eprintln!("[Journal] Rollback task failed, retrying: {}", e),
                 }
+                tokio::time::sleep(Duration::from_secs(1)).await;
             }
         }
     });
-    
-    // 3. START ENGINE (Pass 'tx' to AppState)
-    let app_state = AppState::new(tx);
-    let shared_state = Arc::new(RwLock::new(app_state));
 
-    // 4. SPAWN BACKGROUND SNAPSHOTTER
+    // 5. SPAWN BACKGROUND SNAPSHOTTER
+    // Checks its triggers every second. A snapshot only holds the lock for a moment
+    // (see snapshot::take_snapshot), so trading doesn't stall.
     let bg_state = shared_state.clone();
     task::spawn(async move {
         loop {
-            tokio::time::sleep(Duration::from_secs(600)).await;
-            let app = bg_state.read().unwrap();
-            let log_len = app.reader.get_live_log_length(); 
-            println!("[Snapshot] Saving state...");
-            let _ = save_snapshot(&app.portfolios, log_len);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let (snapshotter, next_lsn) = {
+                let app = bg_state.read().unwrap();
+                (app.snapshotter.clone(), app.next_lsn)
+            };
+            let Some(trigger) = snapshotter.due(next_lsn) else { continue };
+
+            let state = bg_state.clone();
+            match task::spawn_blocking(move || snapshotter.run(&state, trigger)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("[Snapshot] Failed: {}", e),
+                Err(e) => eprintln!("[Snapshot] Task failed: {}", e),
+            }
         }
     });
 
-
-
-
-        
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
 
     let app = Router::new()
-        .route("/balance/{username}", get(get_balance))
+        .route("/balance", get(get_balance))
         .route("/trade", post(execute_trade))
+        .route("/transfer", post(transfer))
+        .route("/book/{symbol_id}", get(get_book))
+        .route("/orders", get(list_orders).delete(cancel_all_orders))
+        .route("/orders/{id}", delete(cancel_order).put(amend_order))
         .route("/register", post(register_user))
         .route("/login", post(login_user))
-        .layer(cors) // <--- ADD THIS LAYER
-        .with_state(shared_state);
+        .route("/logout", post(logout_user))
+        .route("/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api-keys/{key_id}", delete(revoke_api_key))
+        .route("/admin/users/{username}", get(get_user_flags))
+        .route("/admin/users/{username}/flags", put(set_user_flags))
+        .route("/admin/users/{username}/issue", post(issue_stock))
+        .route("/admin/snapshots", get(get_snapshots).post(take_snapshot_now))
+        .route("/admin/journal", get(get_journal))
+        .route("/admin/journal/resume", post(resume_journal))
+        .layer(middleware::from_fn_with_state(shared_state.clone(), apikeys::verify_signature))
+        .layer(cors)
+        .with_state(shared_state.clone());
 
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
     println!("🚀 High-Frequency Engine Ready at http://localhost:3000");
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app).with_graceful_shutdown(shutdown_signal()).await.unwrap();
+
+    // 6. SHUTDOWN: one last snapshot, so the next start has nothing to replay
+    println!("Shutting down...");
+    let snapshotter = shared_state.read().unwrap().snapshotter.clone();
+    let result = task::spawn_blocking(move || {
+        // A periodic one may still be running
+        while snapshotter.stats().running {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        snapshotter.run(&shared_state, "shutdown")
+    }).await;
+    match result {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => eprintln!("[Snapshot] Final snapshot failed: {}", e),
+        Err(e) => eprintln!("[Snapshot] Task failed: {}", e),
+    }
+}
+
+// See AppState::recover_journal. Blocking (reads the data files).
+fn recover_journal(state: &SharedState) -> io::Result<()> {
+    state.write().unwrap().recover_journal()
+}
+
+// Ctrl-C, or SIGTERM from a service manager
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
 // --- HANDLERS (Now Non-Blocking!) ---
+// Everything that acts for a user takes AuthUser: the session token (or API key) says who, not the body.
+// API keys only get what their scopes allow; sessions can do everything.
+// On top of that, UserMeta.flags can freeze an account or block its withdrawals (see AuthUser::require).
+// In durable-ack mode (JDB_DURABLE_ACK=1, or "durable": true on a trade / transfer), replies
+// wait until what the request journaled is fsynced; otherwise they go out once it's queued.
 
 #[derive(Deserialize)]
 struct TradeRequest {
-    username: String,
     symbol_id: u32,
-    amount: i64, 
+    amount: i64, // Cash: +deposit / -withdraw. Stock: +buy / -sell quantity
     is_cash: bool, 
+    price: Option<i64>, // Limit price per unit, required for limit orders
+    #[serde(default)]
+    order_type: OrderType,
+    #[serde(default)]
+    time_in_force: TimeInForce,
+    request_id: Option<String>, // Idempotency key: a retry gets the first answer back instead of trading again
+    durable: Option<bool>, // Wait for the fsync before answering (default: JDB_DURABLE_ACK)
+}
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    to: String,
+    #[serde(default)]
+    symbol_id: u32,
+    amount: i64, // Always positive: what leaves the sender
+    is_cash: bool,
+    request_id: Option<String>, // Idempotency key: a retry with the same one won't move anything twice
+    durable: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct AmendRequest {
+    price: Option<i64>,
+    quantity: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -115,82 +271,363 @@ struct AuthRequest {
 
 async fn execute_trade(
     State(state): State<SharedState>,
+    user: AuthUser,
     Json(payload): Json<TradeRequest>,
-) -> Json<serde_json::Value> {
-    // 1. Lock RAM (Fast)
-    let mut app = state.write().unwrap();
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Withdrawing needs more than trading
+    let scope = if payload.is_cash && payload.amount < 0 { SCOPE_WITHDRAW } else { SCOPE_TRADE };
+    if let Err(e) = user.require(scope) {
+        return Ok(Json(serde_json::json!({"error": e})));
+    }
 
-    let user_id = match app.user_index.get(&payload.username) {
-        Some(id) => *id,
-        None => return Json(serde_json::json!({"error": "User not found"})),
+    // 1. Lock RAM (Fast), released before waiting on the disk.
+    // The account is read first: past this point nothing can fail halfway on a read.
+    let (res, ack) = {
+        let mut app = state.write().unwrap();
+        app.load_accounts(&[user.user_id]).map_err(|e| unavailable("Trade", e))?;
+        let journaled = app.next_lsn;
+        let res = trade(&mut app, &user, &payload);
+        let ack = ack_if(&mut app, payload.durable, journaled, &res);
+        (res, ack)
     };
+    Ok(confirm(res, ack).await)
+}
 
-    // 2. Update RAM Logic (Instant)
-    let db_sender = app.db_sender.clone();
-    let portfolio = app.portfolios.entry(user_id).or_default();
-    let cost = payload.amount * 100;
+fn trade(app: &mut AppState, user: &AuthUser, payload: &TradeRequest) -> serde_json::Value {
+    let user_id = user.user_id;
 
-    // ... (Your existing validation logic) ...
+    // 2. Retried request? Answer it the way we did the first time
+    let key = app.request_key(payload.request_id.as_deref());
+    if let Some(receipt) = app.receipt(user_id, key) {
+        let cash = app.portfolios.cached(user_id).cash;
+        return receipt_json(receipt, cash);
+    }
+
+    // i64::MIN has no absolute value
+    let Some(size) = payload.amount.checked_abs() else {
+        return serde_json::json!({"status": "Invalid Amount"});
+    };
+
+    // 3. Stock orders go through the Matching Engine
     if !payload.is_cash {
-        if payload.amount > 0 && portfolio.cash < cost {
-             return Json(serde_json::json!({"status": "Insufficient Funds"}));
+        let order = NewOrder {
+            side: if payload.amount > 0 { Side::Buy } else { Side::Sell },
+            order_type: payload.order_type,
+            time_in_force: payload.time_in_force,
+            price: payload.price,
+            quantity: size,
+        };
+
+        // Only market makers may keep an unbounded number of orders on the books
+        let may_rest = order.order_type == OrderType::Limit
+            && matches!(order.time_in_force, TimeInForce::Gtc | TimeInForce::PostOnly);
+        if may_rest && user.flags & USER_MARKET_MAKER == 0 && app.user_orders(user_id).len() >= MAX_OPEN_ORDERS {
+            return serde_json::json!({"status": "Too many open orders"});
         }
-        if payload.amount < 0 {
-             let qty = portfolio.stocks.entry(payload.symbol_id).or_default();
-             if *qty < payload.amount.abs() {
-                 return Json(serde_json::json!({"status": "Insufficient Stock"}));
-             }
+
+        return match app.submit_order(user_id, key, payload.symbol_id, order) {
+            Ok(outcome) => {
+                let cash = app.portfolios.cached(user_id).cash;
+                outcome_json(&outcome, cash)
+            }
+            Err(e) => serde_json::json!({"status": e}),
+        };
+    }
+
+    // 4. Cash movements (Instant)
+    let portfolio = app.portfolios.cached(user_id);
+
+    // Cash locked by resting orders can't be withdrawn
+    if payload.amount < 0 && portfolio.available_cash() < size {
+        return serde_json::json!({"status": "Insufficient Funds"});
+    }
+    let Some(new_cash) = portfolio.cash.checked_add(payload.amount) else {
+        return serde_json::json!({"status": "Amount Out Of Range"});
+    };
+    // RAM only changes if the entry can be journaled too
+    if let Err(e) = app.journal_room(1) {
+        return serde_json::json!({"status": e});
+    }
+    app.portfolios.cached_mut(user_id).cash = new_cash;
+
+    // 5. Construct Log Entry
+    let action = if payload.amount > 0 { ActionType::Deposit } else { ActionType::Withdraw };
+    let entry = LogEntry::new(user_id, action, payload.symbol_id, 0, size);
+
+    // 6. Send to Persister
+    // This puts the message in the channel buffer. It returns instantly.
+    // The Persister thread will handle the disk write whenever it can (durable mode waits for it).
+    let mut txn = app.begin_request(user_id, key);
+    txn.stage(entry);
+    if let Err(e) = app.commit(txn) {
+        return serde_json::json!({"error": e});
+    }
+    app.remember(user_id, key, Receipt::Cash);
+
+    serde_json::json!({"status": "Trade Executed", "new_cash": new_cash})
+}
+
+// Durable-ack mode: an ack to wait for, if the request journaled anything
+// (or repeats one that did, which may not be on disk yet either)
+fn ack_if(
+    app: &mut AppState,
+    durable: Option<bool>,
+    journaled: u64,
+    res: &serde_json::Value,
+) -> Option<oneshot::Receiver<Result<u64, String>>> {
+    let wanted = durable.unwrap_or(app.durable_ack)
+        && res.get("error").is_none()
+        && (app.next_lsn != journaled || res["duplicate"] == true);
+    wanted.then(|| app.ack())
+}
+
+// The reply, once the persister has fsynced it (no ack = answer right away).
+// A failed write means RAM is rolled back to the disk, so the request never happened.
+async fn confirm(
+    mut res: serde_json::Value,
+    ack: Option<oneshot::Receiver<Result<u64, String>>>,
+) -> Json<serde_json::Value> {
+    let Some(ack) = ack else { return Json(res) };
+    match ack.await {
+        Ok(Ok(_)) => {
+            res["durable"] = true.into();
+            Json(res)
         }
+        Ok(Err(reason)) => Json(serde_json::json!({
+            "error": "Not durable: the journal write failed, the request is rolled back",
+            "reason": reason
+        })),
+        // The ack never got queued, or the persister is gone: nobody knows
+        Err(_) => Json(serde_json::json!({"error": "Durability unknown, retry with the same request_id"})),
+    }
+}
+
+async fn transfer(
+    State(state): State<SharedState>,
+    user: AuthUser,
+    Json(payload): Json<TransferRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if let Err(e) = user.require(SCOPE_WITHDRAW) {
+        return Ok(Json(serde_json::json!({"error": e})));
     }
+    let (res, ack) = {
+        let mut app = state.write().unwrap();
+        let journaled = app.next_lsn;
+        let key = app.request_key(payload.request_id.as_deref());
+
+        let from = user.user_id;
+        let to = match app.users.get(&payload.to) {
+            Ok(Some(id)) => id,
+            Ok(None) => return Ok(Json(serde_json::json!({"error": "Recipient not found"}))),
+            Err(e) => return Ok(Json(storage_error("Transfer", e))),
+        };
+        app.load_accounts(&[from, to]).map_err(|e| unavailable("Transfer", e))?;
+        let request = Transfer {
+            to,
+            symbol_id: payload.symbol_id,
+            is_cash: payload.is_cash,
+            amount: payload.amount,
+        };
+
+        let res = match app.transfer(from, key, request) {
+            Ok(done) => serde_json::json!({
+                "status": "Transferred",
+                "from": user.username,
+                "to": payload.to,
+                "symbol_id": done.symbol_id,
+                "is_cash": done.is_cash,
+                "amount": done.amount
+            }),
+            Err(e) => serde_json::json!({"error": e}),
+        };
+        let ack = ack_if(&mut app, payload.durable, journaled, &res);
+        (res, ack)
+    };
+    Ok(confirm(res, ack).await)
+}
 
-    // Apply RAM changes
-    if payload.is_cash {
-        portfolio.cash += payload.amount;
-    } else {
-        portfolio.cash -= cost;
-        *portfolio.stocks.entry(payload.symbol_id).or_default() += payload.amount;
+// The reply to a duplicate request. new_cash is the balance now, not back then.
+fn receipt_json(receipt: &Receipt, cash: i64) -> serde_json::Value {
+    let mut res = match receipt {
+        Receipt::Cash => serde_json::json!({"status": "Trade Executed", "new_cash": cash}),
+        Receipt::Order(outcome) => outcome_json(outcome, cash),
+        Receipt::Transfer(_) => return serde_json::json!({"error": "Request ID reused"}),
+    };
+    res["duplicate"] = true.into();
+    res
+}
+
+fn outcome_json(outcome: &OrderOutcome, cash: i64) -> serde_json::Value {
+    let fills: Vec<_> = outcome.fills.iter()
+        .map(|(price, quantity)| serde_json::json!({"price": price, "quantity": quantity}))
+        .collect();
+    let mut res = serde_json::json!({
+        "order_id": outcome.order_id,
+        "status": outcome.status.as_str(),
+        "filled": outcome.filled,
+        "resting": outcome.resting,
+        "expired": outcome.expired,
+        "fills": fills,
+        "new_cash": cash
+    });
+    if let OrderStatus::Rejected(reason) = outcome.status {
+        res["reason"] = reason.into();
     }
+    res
+}
 
-    // 3. Construct Log Entry
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let action = if payload.is_cash { 
-        if payload.amount > 0 { ActionType::Deposit } else { ActionType::Withdraw }
-    } else { ActionType::Trade };
-
-    let entry = LogEntry {
-        magic: 0xAABB,
-        version: 1,
-        _pad1: [0; 4],
-        user_id,
-        timestamp: now,
-        request_id: [0; 16],
-        action_type: action as u8,
-        _pad2: [0; 3],
-        symbol_id: payload.symbol_id,
-        quantity: if payload.is_cash { 0 } else { payload.amount },
-        amount_money: if payload.is_cash { payload.amount.abs() } else { 100 }, 
+fn order_json(symbol_id: u32, order: &Order) -> serde_json::Value {
+    serde_json::json!({
+        "order_id": order.id,
+        "symbol_id": symbol_id,
+        "side": if order.side == Side::Buy { "buy" } else { "sell" },
+        "price": order.price,
+        "quantity": order.quantity
+    })
+}
+
+async fn list_orders(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if let Err(e) = user.require(SCOPE_READ) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    let app = state.read().unwrap();
+    let user_id = user.user_id;
+
+    let orders: Vec<_> = app.user_orders(user_id).into_iter()
+        .map(|(symbol_id, o)| order_json(symbol_id, o))
+        .collect();
+    Json(serde_json::json!({"orders": orders}))
+}
+
+async fn cancel_order(
+    State(state): State<SharedState>,
+    Path(order_id): Path<u64>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if let Err(e) = user.require(SCOPE_TRADE) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    let (res, ack) = {
+        let mut app = state.write().unwrap();
+        let journaled = app.next_lsn;
+        let user_id = user.user_id;
+
+        let res = match app.cancel_order(user_id, order_id) {
+            Ok(order) => serde_json::json!({"status": "Cancelled", "order_id": order.id, "cancelled": order.quantity}),
+            Err(e) => serde_json::json!({"error": e}),
+        };
+        let ack = ack_if(&mut app, None, journaled, &res);
+        (res, ack)
     };
+    confirm(res, ack).await
+}
 
-    // 4. FIRE AND FORGET (Send to Persister)
-    // This puts the message in the channel buffer. It returns instantly.
-    // The Persister thread will handle the disk write whenever it can.
-    
-    let _ = db_sender.try_send(DbMessage::WriteLog(entry));
+async fn cancel_all_orders(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if let Err(e) = user.require(SCOPE_TRADE) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    let (res, ack) = {
+        let mut app = state.write().unwrap();
+        let journaled = app.next_lsn;
+        let user_id = user.user_id;
+
+        let res = match app.cancel_all(user_id) {
+            Ok(cancelled) => {
+                let ids: Vec<u64> = cancelled.iter().map(|o| o.id).collect();
+                serde_json::json!({"status": "Cancelled", "order_ids": ids})
+            }
+            Err(e) => serde_json::json!({"error": e}),
+        };
+        let ack = ack_if(&mut app, None, journaled, &res);
+        (res, ack)
+    };
+    confirm(res, ack).await
+}
+
+async fn amend_order(
+    State(state): State<SharedState>,
+    Path(order_id): Path<u64>,
+    user: AuthUser,
+    Json(payload): Json<AmendRequest>,
+) -> Json<serde_json::Value> {
+    if let Err(e) = user.require(SCOPE_TRADE) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    let (res, ack) = {
+        let mut app = state.write().unwrap();
+        let journaled = app.next_lsn;
+        let user_id = user.user_id;
+
+        let res = match app.amend_order(user_id, order_id, payload.price, payload.quantity) {
+            Ok(outcome) => {
+                // Its owner has a resting order, so the account is cached
+                let cash = app.portfolios.cached(user_id).cash;
+                outcome_json(&outcome, cash)
+            }
+            Err(e) => serde_json::json!({"error": e}),
+        };
+        let ack = ack_if(&mut app, None, journaled, &res);
+        (res, ack)
+    };
+    confirm(res, ack).await
+}
 
-    Json(serde_json::json!({"status": "Trade Executed", "new_cash": portfolio.cash}))
+// A blocking task that panicked or was cancelled: the request failed, the server didn't
+fn internal_error(e: task::JoinError) -> (StatusCode, Json<serde_json::Value>) {
+    eprintln!("[Server] Blocking task failed: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "Internal Error"})))
+}
+
+// users.idx couldn't be read: only this request fails
+fn storage_error(what: &str, e: io::Error) -> serde_json::Value {
+    eprintln!("[{}] users.idx read failed: {}", what, e);
+    serde_json::json!({"error": "Storage Error"})
+}
+
+// accounts.idx couldn't be read (or its pool is busy): nothing changed, a retry may well work
+fn unavailable(what: &str, e: io::Error) -> (StatusCode, Json<serde_json::Value>) {
+    eprintln!("[{}] accounts.idx read failed: {}", what, e);
+    (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "Storage Error"})))
 }
 
 async fn register_user(
     State(state): State<SharedState>,
     Json(payload): Json<AuthRequest>,
-) -> Json<serde_json::Value> {
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // Usernames are stored in a fixed 32-byte field (and are B-Tree keys)
+    if payload.username.is_empty() || payload.username.len() > 32 {
+        return Ok(Json(serde_json::json!({"error": "Invalid Username"})));
+    }
+    if payload.password.is_empty() || payload.password.len() > 128 {
+        return Ok(Json(serde_json::json!({"error": "Invalid Password"})));
+    }
+
+    // Argon2 is slow on purpose: hash before taking the lock, off the async workers
+    let salt = auth::new_salt();
+    let password = payload.password.clone();
+    let pass_hash = task::spawn_blocking(move || auth::hash_password(&password, &salt))
+        .await
+        .map_err(internal_error)?;
+
     let mut app = state.write().unwrap();
 
-    if app.user_index.contains_key(&payload.username) {
-        return Json(serde_json::json!({"error": "Username taken"}));
+    match app.users.contains(&payload.username) {
+        Ok(false) => {}
+        Ok(true) => return Ok(Json(serde_json::json!({"error": "Username taken"}))),
+        Err(e) => return Ok(Json(storage_error("Register", e))),
+    }
+    // A user users.bin never gets would have a user_id that points nowhere
+    if let Err(e) = app.journal_room(0) {
+        return Ok(Json(serde_json::json!({"error": e})));
     }
 
-    let new_id = app.user_index.len() as u64;
+    let new_id = app.users.next_id();
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
 
     // Create User Struct
@@ -198,69 +635,579 @@ async fn register_user(
         user_id: new_id,
         username: make_string(&payload.username),
         email: make_string(payload.email.as_deref().unwrap_or("")),
-        pass_hash: [0; 32], 
-        salt: [0; 16],
+        pass_hash,
+        salt,
         created_at: now,
-        flags: 1,
+        flags: USER_ACTIVE,
         _padding: [0; 4],
     };
 
     // 1. Update RAM Immediately
-    app.user_index.insert(payload.username.clone(), new_id);
-    app.portfolios.insert(new_id, Default::default());
+    if let Err(e) = app.users.insert(&new_user) {
+        eprintln!("[Register] users.idx write failed: {}", e);
+        return Ok(Json(serde_json::json!({"error": "Storage Error"})));
+    }
+    app.portfolios.insert(new_id, Portfolio::default());
 
-    // 2. Queue Disk Write
-    let _ = app.db_sender.try_send(DbMessage::WriteUser(new_user));
+    // 2. Queue Disk Write (journal_room kept a slot for it)
+    if let Err(e) = app.db_sender.try_send(DbMessage::WriteUser(new_user)) {
+        // Rolls users.idx back along with everything else
+        app.journal_failed(format!("user write not queued: {}", e));
+        return Ok(Json(serde_json::json!({"error": "Storage Error"})));
+    }
 
-    Json(serde_json::json!({"status": "User Registered", "user_id": new_id}))
+    // 3. Registering logs you in
+    let (token, expires_at) = app.sessions.issue(new_id, &payload.username);
+    Ok(Json(serde_json::json!({
+        "status": "User Registered",
+        "user_id": new_id,
+        "token": token,
+        "expires_at": expires_at
+    })))
 }
 
-// ... (get_balance and login_user remain the same as before) ...
 async fn get_balance(
     State(state): State<SharedState>,
-    Path(username): Path<String>,
+    user: AuthUser,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if let Err(e) = user.require(SCOPE_READ) {
+        return Ok(Json(serde_json::json!({"error": e})));
+    }
+    let app = state.read().unwrap();
+
+    let p = app.portfolios.get(&user.user_id).map_err(|e| unavailable("Balance", e))?;
+    Ok(Json(serde_json::json!({
+        "user": user.username,
+        "flags": flag_names(user.flags),
+        "cash": p.cash,
+        "stocks": p.stocks,
+        "reserved_cash": p.reserved_cash,
+        "reserved_stocks": p.reserved_stocks
+    })))
+}
+
+async fn get_book(
+    State(state): State<SharedState>,
+    Path(symbol_id): Path<u32>,
 ) -> Json<serde_json::Value> {
     let app = state.read().unwrap();
 
-    let user_id = match app.user_index.get(&username) {
-        Some(id) => *id,
-        None => return Json(serde_json::json!({"error": "User not found"})),
+    let (bids, asks) = match app.books.get(&symbol_id) {
+        Some(book) => book.depth(10),
+        None => (Vec::new(), Vec::new()),
     };
 
-    if let Some(p) = app.portfolios.get(&user_id) {
-        Json(serde_json::json!({
-            "user": username,
-            "cash": p.cash,
-            "stocks": p.stocks
-        }))
-    } else {
-        Json(serde_json::json!({"error": "Portfolio not found"}))
-    }
+    Json(serde_json::json!({
+        "symbol_id": symbol_id,
+        "bids": bids,
+        "asks": asks
+    }))
 }
 
 async fn login_user(
     State(state): State<SharedState>,
     Json(payload): Json<AuthRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // 1. Look the user up (users.idx has every record, including ones registered since startup)
+    let user = match state.read().unwrap().users.get_meta(&payload.username) {
+        Ok(user) => user,
+        Err(e) => return Ok(Json(storage_error("Login", e))),
+    };
+
+    // 2. Verify outside the lock. Unknown user and wrong password look the same.
+    let password = payload.password;
+    let valid = task::spawn_blocking(move || auth::verify_password(user.as_ref(), &password))
+        .await
+        .map_err(internal_error)?;
+
+    let user_id = match user {
+        Some(user) if valid && user.flags & USER_ACTIVE == 0 => {
+            return Ok(Json(serde_json::json!({"error": "Account disabled"})));
+        }
+        Some(user) if valid => user.user_id,
+        _ => return Ok(Json(serde_json::json!({"error": "Invalid Credentials"}))),
+    };
+
+    // 3. Hand out a session token: "Authorization: Bearer <token>" from now on
+    let (token, expires_at) = state.read().unwrap().sessions.issue(user_id, &payload.username);
+    Ok(Json(serde_json::json!({
+        "status": "Login Success",
+        "user_id": user_id,
+        "token": token,
+        "expires_at": expires_at
+    })))
+}
+
+async fn logout_user(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if user.api_key.is_some() {
+        return Json(serde_json::json!({"error": "API keys can't log out; revoke the key instead"}));
+    }
+    let mut app = state.write().unwrap();
+
+    match app.sessions.revoke(&user) {
+        Ok(()) => Json(serde_json::json!({"status": "Logged Out"})),
+        Err(e) => {
+            eprintln!("[Logout] sessions.revoked write failed: {}", e);
+            Json(serde_json::json!({"error": "Storage Error"}))
+        }
+    }
+}
+// --- API KEYS ---
+// Managed from a logged-in session only: a leaked key can't mint more keys or un-revoke itself.
+
+#[derive(Deserialize)]
+struct ApiKeyRequest {
+    scopes: Vec<String>, // "read", "trade", "withdraw"
+}
+
+fn scope_names(scopes: u32) -> Vec<&'static str> {
+    [(SCOPE_READ, "read"), (SCOPE_TRADE, "trade"), (SCOPE_WITHDRAW, "withdraw")]
+        .into_iter()
+        .filter(|(bit, _)| scopes & bit != 0)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+fn api_key_json(key: &consts::ApiKeyRecord) -> serde_json::Value {
+    serde_json::json!({
+        "key_id": format!("{:016x}", key.key_id),
+        "scopes": scope_names(key.scopes),
+        "created_at": key.created_at,
+        "revoked_at": if key.revoked_at == 0 { None } else { Some(key.revoked_at) }
+    })
+}
+
+async fn create_api_key(
+    State(state): State<SharedState>,
+    user: AuthUser,
+    Json(payload): Json<ApiKeyRequest>,
+) -> Json<serde_json::Value> {
+    if user.api_key.is_some() {
+        return Json(serde_json::json!({"error": "API keys are managed from a logged-in session"}));
+    }
+    if user.flags & USER_FROZEN != 0 {
+        return Json(serde_json::json!({"error": "Account frozen"}));
+    }
+
+    // 1. Parse scopes
+    let mut scopes = 0;
+    for name in &payload.scopes {
+        scopes |= match name.as_str() {
+            "read" => SCOPE_READ,
+            "trade" => SCOPE_TRADE,
+            "withdraw" => SCOPE_WITHDRAW,
+            _ => return Json(serde_json::json!({"error": format!("Unknown scope: {}", name)})),
+        };
+    }
+    if scopes & SCOPE_ALL == 0 {
+        return Json(serde_json::json!({"error": "At least one scope is required"}));
+    }
+
+    // 2. Store it. The secret is shown this once and never again.
+    let mut app = state.write().unwrap();
+    match app.api_keys.create(&user, scopes) {
+        Ok(key) => {
+            let mut res = api_key_json(&key);
+            res["secret"] = hex::encode(key.secret).into();
+            Json(res)
+        }
+        Err(e) => {
+            eprintln!("[ApiKeys] apikeys.bin write failed: {}", e);
+            Json(serde_json::json!({"error": "Storage Error"}))
+        }
+    }
+}
+
+async fn list_api_keys(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if user.api_key.is_some() {
+        return Json(serde_json::json!({"error": "API keys are managed from a logged-in session"}));
+    }
+    let app = state.read().unwrap();
+
+    let keys: Vec<_> = app.api_keys.list(user.user_id).into_iter().map(api_key_json).collect();
+    Json(serde_json::json!({"api_keys": keys}))
+}
+
+async fn revoke_api_key(
+    State(state): State<SharedState>,
+    Path(key_id): Path<String>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if user.api_key.is_some() {
+        return Json(serde_json::json!({"error": "API keys are managed from a logged-in session"}));
+    }
+    let Ok(key_id) = u64::from_str_radix(&key_id, 16) else {
+        return Json(serde_json::json!({"error": "API key not found"}));
+    };
+    let mut app = state.write().unwrap();
+
+    match app.api_keys.revoke(user.user_id, key_id) {
+        Ok(true) => Json(serde_json::json!({"status": "Revoked", "key_id": format!("{:016x}", key_id)})),
+        Ok(false) => Json(serde_json::json!({"error": "API key not found"})),
+        Err(e) => {
+            eprintln!("[ApiKeys] apikeys.bin write failed: {}", e);
+            Json(serde_json::json!({"error": "Storage Error"}))
+        }
+    }
+}
+
+// --- ADMIN ---
+// Flag changes (ActionType::SetFlags) and new stock (ActionType::Issue) are journaled with the admin's user_id.
+
+#[derive(Deserialize)]
+struct FlagsRequest {
+    #[serde(default)]
+    set: Vec<String>, // Names from USER_FLAG_NAMES
+    #[serde(default)]
+    clear: Vec<String>,
+}
+
+fn flag_names(flags: u32) -> Vec<&'static str> {
+    USER_FLAG_NAMES.into_iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+fn parse_flags(names: &[String]) -> Result<u32, String> {
+    names.iter().try_fold(0, |flags, name| {
+        match USER_FLAG_NAMES.iter().find(|(_, n)| n == name) {
+            Some((bit, _)) => Ok(flags | bit),
+            None => Err(format!("Unknown flag: {}", name)),
+        }
+    })
+}
+
+fn user_flags_json(user: &UserMeta) -> serde_json::Value {
+    serde_json::json!({
+        "user_id": user.user_id,
+        "username": accounts::user_name(user),
+        "flags": flag_names(user.flags)
+    })
+}
+
+// Admin actions need the admin flag and a logged-in session (not an API key)
+fn admin_only(user: &AuthUser) -> Result<(), &'static str> {
+    if !user.is_admin() {
+        return Err("Admin only");
+    }
+    if user.api_key.is_some() {
+        return Err("Admin actions need a logged-in session");
+    }
+    Ok(())
+}
+
+async fn get_user_flags(
+    State(state): State<SharedState>,
+    Path(username): Path<String>,
+    user: AuthUser,
 ) -> Json<serde_json::Value> {
+    if let Err(e) = admin_only(&user) {
+        return Json(serde_json::json!({"error": e}));
+    }
     let app = state.read().unwrap();
 
-    let user_idx = match app.user_index.get(&payload.username) {
-        Some(idx) => *idx as usize,
-        None => return Json(serde_json::json!({"error": "User not found"})),
+    match app.users.get_meta(&username) {
+        Ok(Some(target)) => Json(user_flags_json(&target)),
+        Ok(None) => Json(serde_json::json!({"error": "User not found"})),
+        Err(e) => Json(storage_error("Flags", e)),
+    }
+}
+
+async fn set_user_flags(
+    State(state): State<SharedState>,
+    Path(username): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<FlagsRequest>,
+) -> Json<serde_json::Value> {
+    if let Err(e) = admin_only(&user) {
+        return Json(serde_json::json!({"error": e}));
+    }
+
+    // 1. Parse the change
+    let (set, clear) = match (parse_flags(&payload.set), parse_flags(&payload.clear)) {
+        (Ok(set), Ok(clear)) => (set, clear),
+        (Err(e), _) | (_, Err(e)) => return Json(serde_json::json!({"error": e})),
+    };
+    if set & clear != 0 {
+        return Json(serde_json::json!({"error": "A flag can't be both set and cleared"}));
+    }
+
+    let mut app = state.write().unwrap();
+    let target = match app.users.get_meta(&username) {
+        Ok(Some(target)) => target,
+        Ok(None) => return Json(serde_json::json!({"error": "User not found"})),
+        Err(e) => return Json(storage_error("Flags", e)),
     };
+    // So the last admin can't lock everyone out
+    if target.user_id == user.user_id {
+        return Json(serde_json::json!({"error": "Admins can't change their own flags"}));
+    }
+
+    // 2. Journal + apply (nothing to do if it changes nothing). Freezing cancels resting orders.
+    let flags = (target.flags | set) & !clear;
+    if flags == target.flags {
+        return Json(user_flags_json(&target));
+    }
+    let cancels = app.cancelled_by_flags(&target, flags).len();
+    if let Err(e) = app.journal_room(1 + cancels) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    match app.set_user_flags(user.user_id, target, flags) {
+        Ok(updated) => {
+            println!("[Admin] {} changed flags of {}: {:?} -> {:?} ({} orders cancelled)",
+                user.username, username, flag_names(target.flags), flag_names(flags), cancels);
+            Json(user_flags_json(&updated))
+        }
+        Err(e) => {
+            eprintln!("[Admin] users.idx write failed: {}", e);
+            Json(serde_json::json!({"error": "Storage Error"}))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IssueRequest {
+    symbol_id: u32,
+    quantity: i64,
+}
+
+// Stock only enters the system here: credits new shares to a user's holding
+async fn issue_stock(
+    State(state): State<SharedState>,
+    Path(username): Path<String>,
+    user: AuthUser,
+    Json(payload): Json<IssueRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if let Err(e) = admin_only(&user) {
+        return Ok(Json(serde_json::json!({"error": e})));
+    }
+
+    let mut app = state.write().unwrap();
+    let target = match app.users.get(&username) {
+        Ok(Some(id)) => id,
+        Ok(None) => return Ok(Json(serde_json::json!({"error": "User not found"}))),
+        Err(e) => return Ok(Json(storage_error("Issue", e))),
+    };
+    app.load_accounts(&[target]).map_err(|e| unavailable("Issue", e))?;
+
+    match app.issue_stock(user.user_id, target, payload.symbol_id, payload.quantity) {
+        Ok(held) => {
+            println!("[Admin] {} issued {} of symbol {} to {}", user.username, payload.quantity, payload.symbol_id, username);
+            Ok(Json(serde_json::json!({
+                "status": "Issued",
+                "username": username,
+                "symbol_id": payload.symbol_id,
+                "quantity": payload.quantity,
+                "holding": held
+            })))
+        }
+        Err(e) => Ok(Json(serde_json::json!({"error": e}))),
+    }
+}
+
+fn snapshot_stats_json(stats: &SnapshotStats) -> serde_json::Value {
+    serde_json::json!({
+        "last_index": stats.last_index,
+        "last_at": stats.last_at,
+        "last_duration_ms": stats.last_duration_ms,
+        "last_trigger": stats.last_trigger,
+        "last_error": stats.last_error,
+        "taken": stats.taken,
+        "running": stats.running,
+    })
+}
+
+async fn get_snapshots(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if let Err(e) = admin_only(&user) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    let (snapshotter, next_lsn) = {
+        let app = state.read().unwrap();
+        (app.snapshotter.clone(), app.next_lsn)
+    };
+    let stats = snapshotter.stats();
+
+    let files: Vec<_> = snapshot::read_manifest().unwrap_or_default().into_iter().map(|e| serde_json::json!({
+        "file": e.file,
+        "last_index": e.last_log_index,
+        "records": e.records,
+    })).collect();
+
+    Json(serde_json::json!({
+        "stats": snapshot_stats_json(&stats),
+        "log_index": next_lsn,
+        "entries_since": next_lsn.saturating_sub(stats.last_index),
+        "interval_secs": snapshotter.interval.as_secs(),
+        "every_entries": snapshotter.every_entries,
+        "snapshots": files,
+    }))
+}
+
+async fn take_snapshot_now(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if let Err(e) = admin_only(&user) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    let snapshotter = state.read().unwrap().snapshotter.clone();
+
+    match task::spawn_blocking(move || snapshotter.run(&state, "admin")).await {
+        Ok(Ok(stats)) => Json(serde_json::json!({"status": "Snapshot saved", "stats": snapshot_stats_json(&stats)})),
+        Ok(Err(e)) => Json(serde_json::json!({"error": format!("Snapshot failed: {}", e)})),
+        Err(_) => Json(serde_json::json!({"error": "Snapshot failed"})),
+    }
+}
 
-    let users = app.reader.get_users();
-    
-    if user_idx >= users.len() {
-        return Json(serde_json::json!({"error": "Index mismatch"}));
+async fn get_journal(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if let Err(e) = admin_only(&user) {
+        return Json(serde_json::json!({"error": e}));
     }
-    
-    let user = &users[user_idx];
+    let app = state.read().unwrap();
+    let health = app.journal.health();
+    let max = app.db_sender.max_capacity();
 
-    // Simple password check (In real life use Argon2)
-    // Here we assume empty password for demo since we passed [0;32]
     Json(serde_json::json!({
-        "status": "Login Success", 
-        "user_id": user.user_id
+        "state": health.state.as_str(),
+        "reason": health.reason,
+        "since": health.since,
+        "failures": health.failures,
+        "log_index": app.next_lsn,
+        "durable": app.journal.durable(),
+        "batches": app.journal.batches(),
+        "channel": {
+            "depth": max - app.db_sender.capacity(),
+            "capacity": max,
+            "high_water": app.journal.high_water(),
+            "saturated": app.journal.saturated(),
+        },
+        // The active one (last) only counts its entries once it fills up
+        "segments": app.journal.segments().iter().map(|s| serde_json::json!({
+            "file": s.file,
+            "first": s.first,
+            "entries": if s.full { s.entries } else { app.journal.durable().saturating_sub(s.first) },
+            "full": s.full,
+        })).collect::<Vec<_>>(),
     }))
-}
\ No newline at end of file
+}
+
+// The operator's go-ahead after a failure: the persister writes again from where the files end
+async fn resume_journal(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> Json<serde_json::Value> {
+    if let Err(e) = admin_only(&user) {
+        return Json(serde_json::json!({"error": e}));
+    }
+    let app = state.write().unwrap();
+    if let Err(e) = app.journal.resume() {
+        return Json(serde_json::json!({"error": e}));
+    }
+    // Healthy before Resume goes out: if the persister fails again right away, that has to stick
+    if let Err(e) = app.db_sender.try_send(DbMessage::Resume) {
+        app.journal.fail(format!("can't resume the persister: {}", e));
+        return Json(serde_json::json!({"error": "The persister is gone, restart the engine"}));
+    }
+    println!("[Journal] Resumed by {} at log index {}", user.username, app.next_lsn);
+    Json(serde_json::json!({"status": "Resumed", "log_index": app.next_lsn}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::Keyring;
+
+    type Ack = Option<oneshot::Receiver<Result<u64, String>>>;
+
+    // An engine on the data directory, with one issue journaled (not written yet): its reply and ack
+    fn journaled_one(durable: Option<bool>) -> (AppState, mpsc::Receiver<DbMessage>, serde_json::Value, Ack) {
+        let keys = Arc::new(Keyring::load("data.key").unwrap());
+        let engine_id = format::upgrade_data_files(&keys).unwrap();
+        let (tx, rx) = mpsc::channel(100);
+        let mut app = AppState::new(tx, keys, engine_id).unwrap();
+
+        let journaled = app.next_lsn;
+        app.load_accounts(&[1]).unwrap();
+        let res = match app.issue_stock(9, 1, 7, 10) {
+            Ok(held) => serde_json::json!({"status": "Issued", "holding": held}),
+            Err(e) => serde_json::json!({"error": e}),
+        };
+        let ack = ack_if(&mut app, durable, journaled, &res);
+        (app, rx, res, ack)
+    }
+
+    fn start_persister(app: &AppState, rx: mpsc::Receiver<DbMessage>) -> std::thread::JoinHandle<()> {
+        let db = DatabaseWriter::new(app.keys.clone(), app.engine_id).unwrap();
+        let config = persister::PersisterConfig { max_batch: 16, linger: Duration::ZERO, retire: segments::Retire::Keep };
+        let journal = app.journal.clone();
+        std::thread::spawn(move || persister::run(db, rx, journal, config))
+    }
+
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn durable_reply_waits_for_the_fsync() {
+        format::in_data_dir("main-durable", || {
+            let (app, rx, res, ack) = journaled_one(Some(true));
+            assert!(ack.is_some());
+            assert_eq!(app.journal.durable(), 0);
+
+            let persister = start_persister(&app, rx);
+            let Json(reply) = block_on(confirm(res, ack));
+            assert_eq!(reply["durable"], true);
+            assert_eq!(reply["holding"], 10);
+            // By the time the reply is out, the journal is on disk up to the request
+            assert!(app.journal.durable() >= app.next_lsn);
+
+            drop(app);
+            persister.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn failed_journal_answers_with_an_error() {
+        format::in_data_dir("main-not-durable", || {
+            let (app, rx, res, ack) = journaled_one(Some(true));
+            app.journal.fail("disk gone".to_string());
+
+            let persister = start_persister(&app, rx);
+            let Json(reply) = block_on(confirm(res, ack));
+            assert_eq!(reply["error"], "Not durable: the journal write failed, the request is rolled back");
+            assert_eq!(reply["reason"], "disk gone");
+            assert!(reply.get("durable").is_none());
+            assert_eq!(app.journal.durable(), 0);
+
+            drop(app);
+            persister.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn only_durable_requests_that_journaled_wait() {
+        format::in_data_dir("main-no-ack", || {
+            let (mut app, _rx, res, ack) = journaled_one(Some(false));
+            assert!(ack.is_none());
+            // Nothing journaled since, or an error: nothing to wait for
+            let journaled = app.next_lsn;
+            assert!(ack_if(&mut app, Some(true), journaled, &res).is_none());
+            assert!(ack_if(&mut app, Some(true), 0, &serde_json::json!({"error": "Invalid Quantity"})).is_none());
+            // ...unless it's a duplicate, whose first run may not be on disk yet
+            assert!(ack_if(&mut app, Some(true), journaled, &serde_json::json!({"duplicate": true})).is_some());
+        });
+    }
+}
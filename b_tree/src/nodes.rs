@@ -0,0 +1,460 @@
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use crate::consts::PAGE_LSN_OFFSET;
+
+// --- SLOTTED PAGE ---
+// [Header | Slot 0 | Slot 1 | ... -> free gap <- ... | Cell 1 | Cell 0 | PageLSN(8)]
+// The slot array grows forward from the header and stays sorted by key.
+// Cell bodies grow backward from the end of the page in whatever order they arrive.
+// Removing a cell leaves a hole ("fragmented" bytes) until the page is compacted.
+
+// HEADER LAYOUT: [Type(1) | Reserved(1) | NumCells(2) | RightPtr(4) | ContentStart(2) | FragBytes(2)] = 12 Bytes
+// RightPtr: Internal = child holding keys >= the last key. Leaf = next leaf (0 = none).
+const HEADER_SIZE: usize = 12;
+const NODE_TYPE_OFFSET: usize = 0;
+const NUM_CELLS_OFFSET: usize = 2;
+const RIGHT_CHILD_OFFSET: usize = 4;
+const CONTENT_START_OFFSET: usize = 8;
+const FRAG_BYTES_OFFSET: usize = 10;
+const SLOT_SIZE: usize = 2;
+
+// LEAF CELL:     [KeyLen(2) | ValueLen(4) | OverflowPage(4) | Key | Value (only if OverflowPage == 0)]
+// INTERNAL CELL: [KeyLen(2) | ChildPage(4) | Key]   (the child holds every key < Key)
+const LEAF_CELL_HEADER: usize = 10;
+const INTERNAL_CELL_HEADER: usize = 6;
+
+pub const NODE_INTERNAL: u8 = 0;
+pub const NODE_LEAF: u8 = 1;
+
+// Bytes available for slots + cells
+pub const CAPACITY: usize = PAGE_LSN_OFFSET - HEADER_SIZE;
+pub const MAX_KEY: usize = 255;
+// Values longer than this go to overflow pages, so every page fits at least 4 leaf cells
+pub const MAX_INLINE: usize = 512;
+// A non-root node using fewer bytes than this gets merged or refilled
+pub const MIN_USED: usize = CAPACITY / 4;
+
+pub enum LeafValue<'a> {
+    Inline(&'a [u8]),
+    Overflow { page: u32, len: usize },
+}
+
+/// Builds a leaf cell. `overflow` = first page of the value if it doesn't fit inline.
+pub fn leaf_cell(key: &[u8], value: &[u8], overflow: u32) -> Vec<u8> {
+    let mut cell = Vec::with_capacity(LEAF_CELL_HEADER + key.len() + value.len());
+    cell.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    cell.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    cell.extend_from_slice(&overflow.to_le_bytes());
+    cell.extend_from_slice(key);
+    if overflow == 0 {
+        cell.extend_from_slice(value);
+    }
+    cell
+}
+
+pub fn internal_cell(key: &[u8], child: u32) -> Vec<u8> {
+    let mut cell = Vec::with_capacity(INTERNAL_CELL_HEADER + key.len());
+    cell.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    cell.extend_from_slice(&child.to_le_bytes());
+    cell.extend_from_slice(key);
+    cell
+}
+
+/// Key of a raw cell (as returned by `Node::cell`).
+pub fn cell_key(cell: &[u8], leaf: bool) -> &[u8] {
+    let key_len = u16::from_le_bytes(cell[0..2].try_into().unwrap()) as usize;
+    let start = if leaf { LEAF_CELL_HEADER } else { INTERNAL_CELL_HEADER };
+    &cell[start..start + key_len]
+}
+
+/// Child pointer of a raw internal cell.
+pub fn cell_child(cell: &[u8]) -> u32 {
+    u32::from_le_bytes(cell[2..6].try_into().unwrap())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+// Zero-copy view over a page: B = &[u8] for reads, &mut [u8] for writes
+pub struct Node<B> {
+    bytes: B,
+}
+
+impl<B: AsRef<[u8]>> Node<B> {
+    pub fn new(bytes: B) -> Self {
+        Self { bytes }
+    }
+
+    fn b(&self) -> &[u8] {
+        self.bytes.as_ref()
+    }
+
+    pub fn get_num_cells(&self) -> u16 {
+        read_u16(self.b(), NUM_CELLS_OFFSET)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.b()[NODE_TYPE_OFFSET] == NODE_LEAF
+    }
+
+    pub fn get_right_child(&self) -> u32 {
+        read_u32(self.b(), RIGHT_CHILD_OFFSET)
+    }
+
+    fn content_start(&self) -> usize {
+        read_u16(self.b(), CONTENT_START_OFFSET) as usize
+    }
+
+    fn frag_bytes(&self) -> usize {
+        read_u16(self.b(), FRAG_BYTES_OFFSET) as usize
+    }
+
+    fn slot(&self, index: u16) -> usize {
+        read_u16(self.b(), HEADER_SIZE + index as usize * SLOT_SIZE) as usize
+    }
+
+    fn cell_len_at(&self, offset: usize) -> usize {
+        let bytes = self.b();
+        let key_len = read_u16(bytes, offset) as usize;
+        if !self.is_leaf() {
+            return INTERNAL_CELL_HEADER + key_len;
+        }
+        let value_len = read_u32(bytes, offset + 2) as usize;
+        let inline = if read_u32(bytes, offset + 6) == 0 { value_len } else { 0 };
+        LEAF_CELL_HEADER + key_len + inline
+    }
+
+    /// The raw bytes of cell `index` (header included).
+    pub fn cell(&self, index: u16) -> &[u8] {
+        let offset = self.slot(index);
+        &self.b()[offset..offset + self.cell_len_at(offset)]
+    }
+
+    pub fn get_key_at_index(&self, index: u16) -> &[u8] {
+        cell_key(self.cell(index), self.is_leaf())
+    }
+
+    pub fn get_value_at_index(&self, index: u16) -> LeafValue<'_> {
+        let cell = self.cell(index);
+        let value_len = read_u32(cell, 2) as usize;
+        match read_u32(cell, 6) {
+            0 => LeafValue::Inline(&cell[cell.len() - value_len..]),
+            page => LeafValue::Overflow { page, len: value_len },
+        }
+    }
+
+    /// Bytes taken by slots + cells (holes not counted).
+    pub fn used_space(&self) -> usize {
+        CAPACITY - self.free_space()
+    }
+
+    /// Bytes a new cell could use, counting holes that compaction would reclaim.
+    pub fn free_space(&self) -> usize {
+        self.gap() + self.frag_bytes()
+    }
+
+    // The contiguous space between the slot array and the first cell
+    fn gap(&self) -> usize {
+        self.content_start() - (HEADER_SIZE + self.get_num_cells() as usize * SLOT_SIZE)
+    }
+
+    // --- INTERNAL NODE HELPERS ---
+    // An internal node with N cells has N + 1 children: cell children 0..N, then RightPtr.
+
+    pub fn child_at(&self, index: u16) -> u32 {
+        if index == self.get_num_cells() {
+            self.get_right_child()
+        } else {
+            cell_child(self.cell(index))
+        }
+    }
+
+    // Which child a key lives under. Keys equal to a separator live on its right.
+    pub fn child_index(&self, key: &[u8]) -> u16 {
+        match self.binary_search(key) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    }
+
+    pub fn binary_search(&self, target: &[u8]) -> Result<u16, u16> {
+        let mut low: u16 = 0;
+        let mut high: u16 = self.get_num_cells();
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let key_at_mid = self.get_key_at_index(mid);
+
+            match key_at_mid.cmp(target) {
+                Ordering::Equal => return Ok(mid),
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+            }
+        }
+        Err(low)
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Node<B> {
+    fn b_mut(&mut self) -> &mut [u8] {
+        self.bytes.as_mut()
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        self.b_mut()[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // Wipes the page into an empty node
+    pub fn init(&mut self, node_type: u8) {
+        let bytes = self.b_mut();
+        bytes[..HEADER_SIZE].fill(0);
+        bytes[NODE_TYPE_OFFSET] = node_type;
+        self.write_u16(CONTENT_START_OFFSET, PAGE_LSN_OFFSET as u16);
+    }
+
+    fn set_num_cells(&mut self, n: u16) {
+        self.write_u16(NUM_CELLS_OFFSET, n);
+    }
+
+    pub fn set_right_child(&mut self, page_id: u32) {
+        self.b_mut()[RIGHT_CHILD_OFFSET..RIGHT_CHILD_OFFSET + 4].copy_from_slice(&page_id.to_le_bytes());
+    }
+
+    pub fn set_child_at(&mut self, index: u16, page_id: u32) {
+        if index == self.get_num_cells() {
+            self.set_right_child(page_id);
+        } else {
+            // The child pointer has a fixed size, so it can be patched in place
+            let offset = self.slot(index) + 2;
+            self.b_mut()[offset..offset + 4].copy_from_slice(&page_id.to_le_bytes());
+        }
+    }
+
+    /// Writes `cell` at slot `index`, compacting first if only the holes have room.
+    /// Returns false (page untouched) if it doesn't fit at all: the caller must split.
+    pub fn insert_cell(&mut self, index: u16, cell: &[u8]) -> bool {
+        let needed = cell.len() + SLOT_SIZE;
+        if needed > self.free_space() {
+            return false;
+        }
+        if needed > self.gap() {
+            self.compact();
+        }
+
+        // 1. Cell body goes just below the current content
+        let offset = self.content_start() - cell.len();
+        self.b_mut()[offset..offset + cell.len()].copy_from_slice(cell);
+        self.write_u16(CONTENT_START_OFFSET, offset as u16);
+
+        // 2. Open a slot at `index`
+        let n = self.get_num_cells();
+        let start = HEADER_SIZE + index as usize * SLOT_SIZE;
+        let end = HEADER_SIZE + n as usize * SLOT_SIZE;
+        self.b_mut().copy_within(start..end, start + SLOT_SIZE);
+        self.write_u16(start, offset as u16);
+        self.set_num_cells(n + 1);
+        true
+    }
+
+    pub fn remove_cell(&mut self, index: u16) {
+        let offset = self.slot(index);
+        let len = self.cell_len_at(offset);
+
+        // The lowest cell just moves the content boundary; anything else becomes a hole
+        if offset == self.content_start() {
+            self.write_u16(CONTENT_START_OFFSET, (offset + len) as u16);
+        } else {
+            let frag = self.frag_bytes() + len;
+            self.write_u16(FRAG_BYTES_OFFSET, frag as u16);
+        }
+
+        let n = self.get_num_cells();
+        let start = HEADER_SIZE + index as usize * SLOT_SIZE;
+        let end = HEADER_SIZE + n as usize * SLOT_SIZE;
+        self.b_mut().copy_within(start + SLOT_SIZE..end, start);
+        self.set_num_cells(n - 1);
+    }
+
+    /// Rewrites every cell back-to-back at the end of the page, turning all holes
+    /// into one contiguous gap. Slot order (and so key order) is unchanged.
+    pub fn compact(&mut self) {
+        let n = self.get_num_cells();
+        let cells: Vec<Vec<u8>> = (0..n).map(|i| self.cell(i).to_vec()).collect();
+
+        let mut offset = PAGE_LSN_OFFSET;
+        for (i, cell) in cells.iter().enumerate() {
+            offset -= cell.len();
+            self.b_mut()[offset..offset + cell.len()].copy_from_slice(cell);
+            self.write_u16(HEADER_SIZE + i * SLOT_SIZE, offset as u16);
+        }
+        self.write_u16(CONTENT_START_OFFSET, offset as u16);
+        self.write_u16(FRAG_BYTES_OFFSET, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::PAGE_SIZE;
+
+    fn leaf() -> Vec<u8> {
+        let mut page = vec![0u8; PAGE_SIZE];
+        Node::new(&mut page[..]).init(NODE_LEAF);
+        page
+    }
+
+    fn keys<B: AsRef<[u8]>>(node: &Node<B>) -> Vec<Vec<u8>> {
+        (0..node.get_num_cells()).map(|i| node.get_key_at_index(i).to_vec()).collect()
+    }
+
+    // Cell i: key [i], a 100-byte inline value of i
+    fn cell(i: u8) -> Vec<u8> {
+        leaf_cell(&[i], &[i; 100], 0)
+    }
+
+    #[test]
+    fn cells_stay_in_key_order() {
+        let mut page = leaf();
+        let mut node = Node::new(&mut page[..]);
+        assert_eq!((node.get_num_cells(), node.used_space(), node.free_space()), (0, 0, CAPACITY));
+
+        // Inserted out of order, at the slot binary_search says
+        for i in [5, 1, 9, 3, 7] {
+            let index = node.binary_search(&[i]).unwrap_err();
+            assert!(node.insert_cell(index, &cell(i)));
+        }
+        assert_eq!(keys(&node), [[1], [3], [5], [7], [9]]);
+        assert_eq!(node.used_space(), 5 * (cell(0).len() + SLOT_SIZE));
+        assert_eq!(node.binary_search(&[7]), Ok(3));
+        assert_eq!(node.binary_search(&[0]), Err(0));
+        assert_eq!(node.binary_search(&[6]), Err(3));
+        assert_eq!(node.binary_search(&[10]), Err(5));
+        let LeafValue::Inline(value) = node.get_value_at_index(3) else { panic!("overflow") };
+        assert_eq!(value, [7; 100]);
+
+        node.remove_cell(0);
+        node.remove_cell(3);
+        assert_eq!(keys(&node), [[3], [5], [7]]);
+        assert_eq!(node.used_space(), 3 * (cell(0).len() + SLOT_SIZE));
+    }
+
+    #[test]
+    fn removed_cells_leave_holes_until_compacted() {
+        let mut page = leaf();
+        let mut node = Node::new(&mut page[..]);
+        for i in 0..10 {
+            assert!(node.insert_cell(i as u16, &cell(i)));
+        }
+        let size = cell(0).len();
+
+        // 1. The lowest cell in the page just moves the content boundary; any other one becomes a hole
+        node.remove_cell(9);
+        assert_eq!((node.frag_bytes(), node.gap()), (0, CAPACITY - 9 * (size + SLOT_SIZE)));
+        node.remove_cell(2);
+        node.remove_cell(4); // Key 5 (key 2 is gone)
+        assert_eq!(node.frag_bytes(), 2 * size);
+        assert_eq!(node.free_space(), node.gap() + 2 * size);
+        assert_eq!(node.used_space(), 7 * (size + SLOT_SIZE));
+
+        // 2. compact() turns the holes into gap; slots, keys and values are unchanged
+        let before: Vec<Vec<u8>> = (0..7).map(|i| node.cell(i).to_vec()).collect();
+        let free = node.free_space();
+        node.compact();
+        assert_eq!((node.frag_bytes(), node.gap(), node.free_space()), (0, free, free));
+        assert_eq!(keys(&node), [[0], [1], [3], [4], [6], [7], [8]]);
+        assert!((0..7).all(|i| node.cell(i) == before[i as usize]));
+        assert_eq!(node.content_start(), PAGE_LSN_OFFSET - 7 * size);
+    }
+
+    #[test]
+    fn insert_compacts_when_only_the_holes_have_room() {
+        let mut page = leaf();
+        let mut node = Node::new(&mut page[..]);
+        let mut n = 0;
+        while node.insert_cell(n, &cell(n as u8)) {
+            n += 1;
+        }
+        let full = page.clone();
+        let mut node = Node::new(&mut page[..]);
+
+        // 1. Full: the cell doesn't go in, and the page isn't touched
+        assert!(node.free_space() < cell(0).len() + SLOT_SIZE);
+        assert!(!node.insert_cell(0, &cell(200)));
+        assert!(page == full);
+
+        // 2. A hole in the middle: the gap alone is still too small, so the insert compacts first
+        let mut node = Node::new(&mut page[..]);
+        node.remove_cell(n / 2);
+        assert!(node.gap() < cell(0).len() + SLOT_SIZE);
+        assert!(node.insert_cell(n / 2, &leaf_cell(&[n as u8 / 2], &[0xAA; 100], 0)));
+        assert_eq!(node.frag_bytes(), 0);
+        assert_eq!(keys(&node), (0..n as u8).map(|i| vec![i]).collect::<Vec<_>>());
+        let LeafValue::Inline(value) = node.get_value_at_index(n / 2) else { panic!("overflow") };
+        assert_eq!(value, [0xAA; 100]);
+        let LeafValue::Inline(value) = node.get_value_at_index(n - 1) else { panic!("overflow") };
+        assert_eq!(value, [n as u8 - 1; 100]);
+    }
+
+    #[test]
+    fn internal_nodes_route_keys_to_children() {
+        let mut page = vec![0u8; PAGE_SIZE];
+        let mut node = Node::new(&mut page[..]);
+        node.init(NODE_INTERNAL);
+
+        // Children 10 | "b" | 20 | "d" | 30 (RightPtr)
+        assert!(node.insert_cell(0, &internal_cell(b"b", 10)));
+        assert!(node.insert_cell(1, &internal_cell(b"d", 20)));
+        node.set_right_child(30);
+        assert!(!node.is_leaf());
+
+        // Keys equal to a separator belong on its right
+        let route = |node: &Node<&mut [u8]>, key: &[u8]| node.child_at(node.child_index(key));
+        assert_eq!([route(&node, b"a"), route(&node, b"b"), route(&node, b"c")], [10, 20, 20]);
+        assert_eq!([route(&node, b"d"), route(&node, b"z"), route(&node, b"")], [30, 30, 10]);
+
+        // Child pointers are patched in place, RightPtr included
+        node.set_child_at(1, 21);
+        node.set_child_at(2, 31);
+        assert_eq!([node.child_at(0), node.child_at(1), node.child_at(2)], [10, 21, 31]);
+        assert_eq!(node.get_key_at_index(1), b"d");
+        assert_eq!(cell_child(node.cell(1)), 21);
+        assert_eq!(cell_key(node.cell(1), false), b"d");
+    }
+
+    #[test]
+    fn biggest_cells_still_fit_four_to_a_page() {
+        // 1. MAX_KEY key + MAX_INLINE value, inline
+        let mut page = leaf();
+        let mut node = Node::new(&mut page[..]);
+        for i in 0..4 {
+            assert!(node.insert_cell(i, &leaf_cell(&[i as u8; MAX_KEY], &[i as u8; MAX_INLINE], 0)));
+        }
+        assert_eq!(node.get_key_at_index(3), [3; MAX_KEY]);
+        assert_eq!(cell_key(node.cell(3), true), [3; MAX_KEY]);
+        let LeafValue::Inline(value) = node.get_value_at_index(3) else { panic!("overflow") };
+        assert_eq!(value, [3; MAX_INLINE]);
+
+        // 2. An overflowing value keeps only its length and first page in the cell
+        let cell = leaf_cell(b"key", &[0; 3 * MAX_INLINE], 77);
+        assert_eq!(cell.len(), LEAF_CELL_HEADER + 3);
+        assert!(node.insert_cell(4, &cell));
+        assert_eq!(node.cell(4), cell);
+        let LeafValue::Overflow { page, len } = node.get_value_at_index(4) else { panic!("inline") };
+        assert_eq!((page, len), (77, 3 * MAX_INLINE));
+
+        // 3. Internal cells with MAX_KEY separators: plenty per page
+        let mut page = vec![0u8; PAGE_SIZE];
+        let mut node = Node::new(&mut page[..]);
+        node.init(NODE_INTERNAL);
+        for i in 0..8 {
+            assert!(node.insert_cell(i, &internal_cell(&[i as u8; MAX_KEY], i as u32 + 1)));
+        }
+        assert_eq!(node.cell(7).len(), INTERNAL_CELL_HEADER + MAX_KEY);
+        assert_eq!(node.child_at(7), 8);
+    }
+}
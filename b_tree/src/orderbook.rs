@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use serde::Deserialize;
+
+// --- ORDER BOOK (One per symbol_id) ---
+// Bids and Asks are price levels. Each level is a FIFO queue,
+// so the oldest order at the best price always trades first (Price-Time Priority).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    Market, // No price, takes whatever liquidity exists. Never rests.
+    #[default]
+    Limit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    #[default]
+    Gtc,      // Good-Til-Cancelled: remainder rests on the book
+    Ioc,      // Immediate-Or-Cancel: remainder is expired
+    Fok,      // Fill-Or-Kill: fully fills right now, or nothing happens
+    PostOnly, // Must add liquidity: rejected if it would trade on arrival
+}
+
+// Everything the client asked for, before we know what happens to it
+#[derive(Debug, Clone, Copy)]
+pub struct NewOrder {
+    pub side: Side,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub price: Option<i64>,
+    pub quantity: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Order {
+    pub id: u64,       // Server-assigned, also gives time priority (lower = older)
+    pub user_id: u64,
+    pub side: Side,
+    pub price: i64,    // Cash per 1 unit of stock
+    pub quantity: i64, // Remaining (unfilled) quantity, always > 0
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fill {
+    pub maker_order: u64,
+    pub taker_order: u64,
+    pub maker_user: u64,
+    pub taker_user: u64,
+    pub taker_side: Side,
+    pub price: i64, // Always the maker's (resting) price
+    pub quantity: i64,
+}
+
+impl Fill {
+    pub fn buyer(&self) -> u64 {
+        if self.taker_side == Side::Buy { self.taker_user } else { self.maker_user }
+    }
+
+    pub fn seller(&self) -> u64 {
+        if self.taker_side == Side::Buy { self.maker_user } else { self.taker_user }
+    }
+}
+
+// (price, total quantity at that price)
+pub type Level = (i64, i64);
+
+#[derive(Debug, Default)]
+pub struct OrderBook {
+    bids: BTreeMap<i64, VecDeque<Order>>, // Best bid = highest key
+    asks: BTreeMap<i64, VecDeque<Order>>, // Best ask = lowest key
+    locations: HashMap<u64, (Side, i64)>, // order id -> where it rests
+}
+
+impl OrderBook {
+    pub fn best_bid(&self) -> Option<i64> {
+        self.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<i64> {
+        self.asks.keys().next().copied()
+    }
+
+    /// True if `taker` would trade against the book on arrival.
+    pub fn crosses(&self, taker: &Order) -> bool {
+        match taker.side {
+            Side::Buy => self.best_ask().is_some_and(|p| p <= taker.price),
+            Side::Sell => self.best_bid().is_some_and(|p| p >= taker.price),
+        }
+    }
+
+    /// Dry run of `match_order`: the fills `taker` would get right now.
+    /// Own orders are skipped since they would be cancelled, not traded.
+    pub fn preview(&self, taker: &Order, mut budget: i64) -> Vec<Fill> {
+        let levels: Box<dyn Iterator<Item = (&i64, &VecDeque<Order>)>> = match taker.side {
+            Side::Buy => Box::new(self.asks.range(..=taker.price)),
+            Side::Sell => Box::new(self.bids.range(taker.price..).rev()),
+        };
+
+        let mut fills = Vec::new();
+        let mut left = taker.quantity;
+        for (price, level) in levels {
+            for maker in level.iter().filter(|o| o.user_id != taker.user_id) {
+                let qty = left.min(maker.quantity).min(budget / price);
+                if qty == 0 {
+                    return fills;
+                }
+                left -= qty;
+                budget -= qty * price;
+                fills.push(Fill {
+                    maker_order: maker.id,
+                    taker_order: taker.id,
+                    maker_user: maker.user_id,
+                    taker_user: taker.user_id,
+                    taker_side: taker.side,
+                    price: *price,
+                    quantity: qty,
+                });
+            }
+        }
+        fills
+    }
+
+    /// How much of `taker` could fill right now (see `preview`).
+    pub fn fillable(&self, taker: &Order, budget: i64) -> i64 {
+        self.preview(taker, budget).iter().map(|f| f.quantity).sum()
+    }
+
+    /// Most journal entries `match_order` could produce for `taker`: one per own order
+    /// it would cancel, two per fill. Ignores the budget, which can only stop it sooner.
+    pub fn legs_bound(&self, taker: &Order) -> usize {
+        let levels: Box<dyn Iterator<Item = (&i64, &VecDeque<Order>)>> = match taker.side {
+            Side::Buy => Box::new(self.asks.range(..=taker.price)),
+            Side::Sell => Box::new(self.bids.range(taker.price..).rev()),
+        };
+
+        let mut left = taker.quantity;
+        let mut legs = 0;
+        for maker in levels.flat_map(|(_, level)| level) {
+            if left == 0 {
+                break;
+            }
+            if maker.user_id == taker.user_id {
+                legs += 1;
+            } else {
+                legs += 2;
+                left -= left.min(maker.quantity);
+            }
+        }
+        legs
+    }
+
+    /// Walks the opposite side of the book and fills `taker` against it.
+    /// `budget` caps the cash a buyer can spend (market buys have no limit price).
+    /// Returns the fills plus any resting orders of the same user that were
+    /// removed instead of trading against themselves (self-trade prevention).
+    /// Whatever is left in `taker.quantity` did NOT trade.
+    pub fn match_order(&mut self, taker: &mut Order, mut budget: i64) -> (Vec<Fill>, Vec<Order>) {
+        let mut fills = Vec::new();
+        let mut self_cancels = Vec::new();
+
+        while taker.quantity > 0 {
+            // 1. Find the best opposite level that still crosses our limit
+            let best = match taker.side {
+                Side::Buy => self.best_ask().filter(|p| *p <= taker.price),
+                Side::Sell => self.best_bid().filter(|p| *p >= taker.price),
+            };
+            let Some(level_price) = best else { break };
+            if budget < level_price {
+                break; // Can't afford a single unit here, and it only gets pricier
+            }
+
+            let book_side = match taker.side {
+                Side::Buy => &mut self.asks,
+                Side::Sell => &mut self.bids,
+            };
+            let level = book_side.get_mut(&level_price).unwrap();
+
+            // 2. Eat through the level oldest-first
+            while taker.quantity > 0 {
+                let Some(maker) = level.front_mut() else { break };
+
+                if maker.user_id == taker.user_id {
+                    let cancelled = level.pop_front().unwrap();
+                    self.locations.remove(&cancelled.id);
+                    self_cancels.push(cancelled);
+                    continue;
+                }
+
+                let qty = taker.quantity.min(maker.quantity).min(budget / maker.price);
+                if qty == 0 {
+                    break;
+                }
+                budget -= qty * maker.price;
+                fills.push(Fill {
+                    maker_order: maker.id,
+                    taker_order: taker.id,
+                    maker_user: maker.user_id,
+                    taker_user: taker.user_id,
+                    taker_side: taker.side,
+                    price: maker.price,
+                    quantity: qty,
+                });
+
+                taker.quantity -= qty;
+                maker.quantity -= qty;
+                if maker.quantity == 0 {
+                    let done = level.pop_front().unwrap();
+                    self.locations.remove(&done.id);
+                }
+            }
+
+            // 3. Drop empty levels so best_bid/best_ask stay correct
+            if level.is_empty() {
+                book_side.remove(&level_price);
+            }
+        }
+
+        (fills, self_cancels)
+    }
+
+    /// Puts a (partially) unfilled order at the back of its price level.
+    pub fn rest(&mut self, order: Order) {
+        let book_side = match order.side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        self.locations.insert(order.id, (order.side, order.price));
+        book_side.entry(order.price).or_default().push_back(order);
+    }
+
+    pub fn get(&self, order_id: u64) -> Option<&Order> {
+        let (side, price) = self.locations.get(&order_id)?;
+        let book_side = match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        };
+        book_side.get(price)?.iter().find(|o| o.id == order_id)
+    }
+
+    /// Pulls an order out of the book entirely (cancel, or cancel/replace).
+    pub fn remove(&mut self, order_id: u64) -> Option<Order> {
+        let (side, price) = self.locations.remove(&order_id)?;
+        let book_side = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        let level = book_side.get_mut(&price)?;
+        let pos = level.iter().position(|o| o.id == order_id)?;
+        let order = level.remove(pos);
+        if level.is_empty() {
+            book_side.remove(&price);
+        }
+        order
+    }
+
+    /// Shrinks a resting order in place, so it keeps its queue position.
+    /// Removes it once nothing is left. Returns the order as it was before.
+    pub fn reduce(&mut self, order_id: u64, quantity: i64) -> Option<Order> {
+        let (side, price) = *self.locations.get(&order_id)?;
+        let book_side = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        let order = book_side.get_mut(&price)?.iter_mut().find(|o| o.id == order_id)?;
+        let before = order.clone();
+        order.quantity -= quantity;
+        if order.quantity <= 0 {
+            self.remove(order_id);
+        }
+        Some(before)
+    }
+
+    /// Every resting order, each price level in queue (time) order.
+    pub fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.bids.values().chain(self.asks.values()).flatten()
+    }
+
+    /// Aggregated levels, best first.
+    pub fn depth(&self, levels: usize) -> (Vec<Level>, Vec<Level>) {
+        let sum = |(price, q): (&i64, &VecDeque<Order>)| (*price, q.iter().map(|o| o.quantity).sum());
+        let bids = self.bids.iter().rev().take(levels).map(sum).collect();
+        let asks = self.asks.iter().take(levels).map(sum).collect();
+        (bids, asks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: u64, user_id: u64, side: Side, price: i64, quantity: i64) -> Order {
+        Order { id, user_id, side, price, quantity }
+    }
+
+    fn book(resting: &[Order]) -> OrderBook {
+        let mut book = OrderBook::default();
+        for o in resting {
+            book.rest(o.clone());
+        }
+        book
+    }
+
+    // (maker order, price, quantity) of each fill
+    fn trades(fills: &[Fill]) -> Vec<(u64, i64, i64)> {
+        fills.iter().map(|f| (f.maker_order, f.price, f.quantity)).collect()
+    }
+
+    #[test]
+    fn oldest_order_at_a_level_trades_first() {
+        let mut book = book(&[
+            order(1, 10, Side::Sell, 100, 5),
+            order(2, 11, Side::Sell, 100, 5),
+            order(3, 12, Side::Sell, 99, 5),
+        ]);
+        let mut taker = order(4, 20, Side::Buy, 100, 8);
+        let (fills, cancelled) = book.match_order(&mut taker, i64::MAX);
+
+        // Best price first, then the older of the two at 100
+        assert_eq!(trades(&fills), vec![(3, 99, 5), (1, 100, 3)]);
+        assert!(cancelled.is_empty());
+        assert_eq!(book.orders().map(|o| (o.id, o.quantity)).collect::<Vec<_>>(), vec![(1, 2), (2, 5)]);
+    }
+
+    #[test]
+    fn fills_happen_at_the_makers_price() {
+        let mut book = book(&[order(1, 10, Side::Buy, 105, 5)]);
+        let mut taker = order(2, 20, Side::Sell, 100, 5);
+        let (fills, _) = book.match_order(&mut taker, i64::MAX);
+
+        assert_eq!(trades(&fills), vec![(1, 105, 5)]);
+        assert_eq!((fills[0].buyer(), fills[0].seller()), (10, 20));
+        assert_eq!(book.best_bid(), None);
+    }
+
+    #[test]
+    fn partial_fill_leaves_the_rest_to_rest() {
+        let mut book = book(&[order(1, 10, Side::Sell, 100, 3)]);
+        let mut taker = order(2, 20, Side::Buy, 101, 10);
+        let (fills, _) = book.match_order(&mut taker, i64::MAX);
+        assert_eq!(trades(&fills), vec![(1, 100, 3)]);
+        assert_eq!(taker.quantity, 7);
+
+        // The caller rests what's left, at its own limit
+        book.rest(taker);
+        assert_eq!(book.depth(5), (vec![(101, 7)], vec![]));
+        assert_eq!(book.get(2).map(|o| o.quantity), Some(7));
+    }
+
+    #[test]
+    fn market_buy_stops_at_its_budget() {
+        let mut book = book(&[
+            order(1, 10, Side::Sell, 10, 4),
+            order(2, 10, Side::Sell, 20, 4),
+        ]);
+        // A market buy has no limit price: only the cash caps it. 40 + 2 * 20 = 80, 15 left over.
+        let taker = order(3, 20, Side::Buy, i64::MAX, 100);
+        assert_eq!(book.fillable(&taker, 95), 6);
+        let mut taker = taker;
+        let (fills, _) = book.match_order(&mut taker, 95);
+
+        assert_eq!(trades(&fills), vec![(1, 10, 4), (2, 20, 2)]);
+        assert_eq!(taker.quantity, 94);
+        assert_eq!(book.depth(5), (vec![], vec![(20, 2)]));
+    }
+
+    #[test]
+    fn own_resting_orders_are_cancelled_not_traded() {
+        let mut book = book(&[
+            order(1, 20, Side::Sell, 100, 5),
+            order(2, 10, Side::Sell, 100, 5),
+        ]);
+        let mut taker = order(3, 20, Side::Buy, 100, 5);
+        // The dry run skips it too
+        assert_eq!(trades(&book.preview(&taker, i64::MAX)), vec![(2, 100, 5)]);
+        let (fills, cancelled) = book.match_order(&mut taker, i64::MAX);
+
+        assert_eq!(trades(&fills), vec![(2, 100, 5)]);
+        assert_eq!(cancelled.iter().map(|o| o.id).collect::<Vec<_>>(), vec![1]);
+        assert!(book.get(1).is_none());
+        assert_eq!(book.orders().count(), 0);
+    }
+}
@@ -0,0 +1,375 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt; // Required for O_DIRECT
+use std::slice;
+use crate::consts::{PAGE_LSN_OFFSET, PAGE_SIZE};
+use crate::crash;
+use crate::wal::Wal;
+
+// O_DIRECT needs the RAM buffer itself to start on a 4096 boundary,
+// so pages can't be a plain Vec<u8> / Box<[u8]>.
+pub struct AlignedPage {
+    ptr: *mut u8,
+}
+
+// The buffer is uniquely owned, like a Box
+unsafe impl Send for AlignedPage {}
+unsafe impl Sync for AlignedPage {}
+
+impl AlignedPage {
+    const LAYOUT: Layout = match Layout::from_size_align(PAGE_SIZE, PAGE_SIZE) {
+        Ok(layout) => layout,
+        Err(_) => panic!("bad page layout"),
+    };
+
+    pub fn zeroed() -> Self {
+        let ptr = unsafe { alloc_zeroed(Self::LAYOUT) };
+        if ptr.is_null() {
+            panic!("Failed to allocate aligned memory");
+        }
+        Self { ptr }
+    }
+}
+
+impl Deref for AlignedPage {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, PAGE_SIZE) }
+    }
+}
+
+impl DerefMut for AlignedPage {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, PAGE_SIZE) }
+    }
+}
+
+impl Drop for AlignedPage {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, Self::LAYOUT) }
+    }
+}
+
+// --- BUFFER POOL ---
+// A fixed number of frames (each one AlignedPage). Pages are read into a free frame
+// on demand; when the pool is full the CLOCK hand picks a victim: pinned frames are
+// skipped, recently used ones get a second chance. A dirty victim is written back
+// before its frame is reused, so the tree can be far bigger than the pool.
+//
+// The pager also owns the tree's WAL, because it is the one place that can enforce
+// the WAL rule: before any page goes to disk, the log must be durable up to that
+// page's LSN (stamped in its last 8 bytes).
+struct Frame {
+    page: AlignedPage,
+    page_id: Option<u32>,
+    dirty: bool,
+    pins: u32,
+    referenced: bool, // CLOCK "second chance" bit
+}
+
+pub struct Pager {
+    file: File,
+    frames: Vec<Frame>, // Grows up to `capacity`, then frames get recycled
+    capacity: usize,
+    page_table: HashMap<u32, usize>, // page id -> frame
+    hand: usize,
+    file_pages: u32, // Pages that physically exist in the file
+    wal: Wal,
+}
+
+pub fn page_lsn(page: &[u8]) -> u64 {
+    u64::from_le_bytes(page[PAGE_LSN_OFFSET..].try_into().unwrap())
+}
+
+pub fn set_page_lsn(page: &mut [u8], lsn: u64) {
+    page[PAGE_LSN_OFFSET..].copy_from_slice(&lsn.to_le_bytes());
+}
+
+impl Pager {
+    pub fn new(filename: &str, capacity: usize, wal: Wal) -> io::Result<Self> {
+        let file = open_direct(filename)?;
+        let file_pages = (file.metadata()?.len() / PAGE_SIZE as u64) as u32;
+
+        Ok(Pager {
+            file,
+            frames: Vec::new(),
+            // A B-Tree operation keeps a handful of pages pinned at once
+            capacity: capacity.max(8),
+            page_table: HashMap::new(),
+            hand: 0,
+            file_pages,
+            wal,
+        })
+    }
+
+    pub fn wal(&mut self) -> &mut Wal {
+        &mut self.wal
+    }
+
+    pub fn get_page(&mut self, page_id: u32) -> io::Result<&[u8]> {
+        let frame = self.pin(page_id)?;
+        self.unpin(frame, false);
+        Ok(&self.frames[frame].page)
+    }
+
+    // Any mutable access marks the page dirty; it is written back on flush() or eviction
+    pub fn get_page_mut(&mut self, page_id: u32) -> io::Result<&mut [u8]> {
+        let frame = self.pin(page_id)?;
+        self.unpin(frame, true);
+        Ok(&mut self.frames[frame].page)
+    }
+
+    /// Brings a page into the pool and keeps it there until `unpin`.
+    /// Returns the frame to pass to `frame()` / `unpin()`.
+    /// On an error no page is lost: a dirty victim that can't be written back stays put.
+    pub fn pin(&mut self, page_id: u32) -> io::Result<usize> {
+        if let Some(&frame) = self.page_table.get(&page_id) {
+            let f = &mut self.frames[frame];
+            f.pins += 1;
+            f.referenced = true;
+            return Ok(frame);
+        }
+
+        let frame = self.victim()?;
+        if self.frames[frame].dirty {
+            crash::point("evict-write");
+            self.write_frame(frame)?;
+        }
+        if let Some(old) = self.frames[frame].page_id.take() {
+            self.page_table.remove(&old);
+        }
+
+        // READ FROM DISK (Pages past the end of the file are brand new: all zeros)
+        let f = &mut self.frames[frame];
+        if page_id < self.file_pages {
+            let offset = page_id as u64 * PAGE_SIZE as u64;
+            // Half a page must never pass for the page: the frame stays free
+            self.file.seek(SeekFrom::Start(offset))
+                .and_then(|_| self.file.read_exact(&mut f.page))
+                .map_err(|e| io::Error::new(e.kind(), format!("page {}: {}", page_id, e)))?;
+        } else {
+            f.page.fill(0);
+        }
+        f.page_id = Some(page_id);
+        f.dirty = false;
+        f.pins = 1;
+        f.referenced = true;
+        self.page_table.insert(page_id, frame);
+        Ok(frame)
+    }
+
+    pub fn unpin(&mut self, frame: usize, dirty: bool) {
+        let f = &mut self.frames[frame];
+        f.pins -= 1;
+        f.dirty |= dirty;
+    }
+
+    pub fn frame(&self, frame: usize) -> &[u8] {
+        &self.frames[frame].page
+    }
+
+    // A free frame if the pool isn't full yet, otherwise CLOCK
+    fn victim(&mut self) -> io::Result<usize> {
+        if self.frames.len() < self.capacity {
+            self.frames.push(Frame {
+                // MEMORY ALLOCATION (Aligned to 4096)
+                page: AlignedPage::zeroed(),
+                page_id: None,
+                dirty: false,
+                pins: 0,
+                referenced: false,
+            });
+            return Ok(self.frames.len() - 1);
+        }
+
+        // Two full sweeps clear every reference bit, so a third finds a victim if one exists
+        for _ in 0..self.frames.len() * 3 {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+
+            let f = &mut self.frames[frame];
+            if f.pins > 0 {
+                continue;
+            }
+            if f.referenced {
+                f.referenced = false;
+                continue;
+            }
+            return Ok(frame);
+        }
+        Err(io::Error::other(format!("all {} frames of the buffer pool are pinned", self.frames.len())))
+    }
+
+    fn write_frame(&mut self, frame: usize) -> io::Result<()> {
+        let f = &mut self.frames[frame];
+        self.wal.flush_to(page_lsn(&f.page))?;
+
+        let page_id = f.page_id.unwrap();
+        write_at(&mut self.file, page_id, &f.page)?;
+        self.file_pages = self.file_pages.max(page_id + 1);
+        f.dirty = false;
+        Ok(())
+    }
+
+    /// Writes every dirty page in file order, then fsyncs.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<(u32, usize)> = self.frames.iter().enumerate()
+            .filter(|(_, f)| f.dirty)
+            .map(|(frame, f)| (f.page_id.unwrap(), frame))
+            .collect();
+        dirty.sort_unstable();
+        for (i, (_, frame)) in dirty.into_iter().enumerate() {
+            self.write_frame(frame)?;
+            if i == 0 {
+                crash::point("checkpoint-flush");
+            }
+        }
+        self.sync()
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Throws away every page (in RAM and on disk). Used to rebuild a tree from scratch.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.frames.clear();
+        self.page_table.clear();
+        self.hand = 0;
+        self.file_pages = 0;
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
+}
+
+fn write_at(file: &mut File, page_id: u32, bytes: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(page_id as u64 * PAGE_SIZE as u64))?;
+    file.write_all(bytes)
+}
+
+// OPEN WITH O_DIRECT (Bypass OS Cache)
+// Some filesystems (tmpfs, overlayfs) refuse O_DIRECT; fall back to buffered I/O there.
+fn open_direct(filename: &str) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+
+    #[cfg(target_os = "linux")]
+    {
+        let mut direct = options.clone();
+        direct.custom_flags(libc::O_DIRECT);
+        match direct.open(filename) {
+            Ok(file) => return Ok(file),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    options.open(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::consts::{WalKind, WalRecordHeader};
+
+    // A pager of `capacity` frames on a new file (remove_dir_all the directory afterwards)
+    fn temp_pager(name: &str, capacity: usize) -> (std::path::PathBuf, String, Pager) {
+        let dir = std::env::temp_dir().join(format!("jdb-pager-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("tree.idx").to_str().unwrap().to_string();
+        let (wal, _) = Wal::open(&format!("{}.wal", file)).unwrap();
+        let pager = Pager::new(&file, capacity, wal).unwrap();
+        (dir, file, pager)
+    }
+
+    fn fill(page: &mut [u8], page_id: u32) {
+        page[..PAGE_LSN_OFFSET].fill(page_id as u8 + 1);
+    }
+
+    #[test]
+    fn evicted_pages_are_written_back() {
+        let (dir, file, mut pager) = temp_pager("evict", 8);
+        for page_id in 0..40 {
+            fill(pager.get_page_mut(page_id).unwrap(), page_id);
+        }
+        // Only 8 fit: the others went to the file on their way out
+        assert_eq!(pager.frames.len(), 8);
+        assert!(fs::metadata(&file).unwrap().len() >= 32 * PAGE_SIZE as u64);
+
+        for page_id in 0..40 {
+            let page = pager.get_page(page_id).unwrap();
+            assert!(page[..PAGE_LSN_OFFSET].iter().all(|b| *b == page_id as u8 + 1), "page {}", page_id);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn eviction_flushes_the_wal_first() {
+        let (dir, file, mut pager) = temp_pager("wal-rule", 8);
+        let header = WalRecordHeader {
+            lsn: 0,
+            prev_lsn: 0,
+            txn_id: 1,
+            undo_next: 0,
+            page_id: 0,
+            offset: 0,
+            len: 0,
+            kind: WalKind::Begin as u8,
+            _padding: [0; 3],
+            crc: 0,
+        };
+        let lsn = pager.wal().append(header, &[]);
+        set_page_lsn(pager.get_page_mut(0).unwrap(), lsn);
+        let wal_file = format!("{}.wal", file);
+        let before = fs::metadata(&wal_file).unwrap().len();
+
+        // Page 0 is the only dirty one, and the oldest: the first to go
+        for page_id in 1..=8 {
+            pager.get_page(page_id).unwrap();
+        }
+        assert!(!pager.page_table.contains_key(&0));
+        assert_eq!(fs::metadata(&wal_file).unwrap().len(), before + size_of::<WalRecordHeader>() as u64);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn all_frames_pinned_is_an_error() {
+        let (dir, _, mut pager) = temp_pager("pinned", 8);
+        let frames: Vec<usize> = (0..8).map(|page_id| pager.pin(page_id).unwrap()).collect();
+        assert!(pager.pin(8).is_err());
+
+        // Pinned pages stay, and a frame is free again once one is unpinned
+        assert_eq!(pager.pin(3).unwrap(), frames[3]);
+        pager.unpin(frames[3], false);
+        pager.unpin(frames[5], false);
+        assert_eq!(pager.pin(8).unwrap(), frames[5]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_read_leaves_the_pool_intact() {
+        let (dir, file, mut pager) = temp_pager("read-error", 8);
+        for page_id in 0..4 {
+            fill(pager.get_page_mut(page_id).unwrap(), page_id);
+        }
+        pager.flush().unwrap();
+        drop(pager);
+
+        // The file loses its last two pages behind the pager's back
+        let (wal, _) = Wal::open(&format!("{}.wal", file)).unwrap();
+        let mut pager = Pager::new(&file, 8, wal).unwrap();
+        fs::OpenOptions::new().write(true).open(&file).unwrap().set_len(2 * PAGE_SIZE as u64).unwrap();
+
+        let err = pager.get_page(3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!pager.page_table.contains_key(&3));
+        assert!(pager.get_page(1).unwrap()[..PAGE_LSN_OFFSET].iter().all(|b| *b == 2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,445 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::Notify;
+use crate::consts::env_u64;
+use crate::segments::{Retire, Segment};
+use crate::state::DbMessage;
+use crate::writer::DatabaseWriter;
+
+// --- PERSISTER (the disk thread) ---
+// Group commit: whatever piled up in the channel while the last fsync ran goes out
+// as one batch, with one write and one fsync per file, instead of one fsync per entry.
+//   JDB_BATCH_MAX        messages per batch (default 1024)
+//   JDB_BATCH_LINGER_US  how long to wait for more once a batch has started (default 0 = don't wait)
+// A batch never holds more log entries than a journal segment (segments.rs), so it lands in one.
+const DEFAULT_MAX_BATCH: u64 = 1024;
+const DEFAULT_LINGER_US: u64 = 0;
+
+pub struct PersisterConfig {
+    pub max_batch: usize,
+    pub linger: Duration,
+    pub retire: Retire, // What happens to segments a snapshot covers (JDB_SEGMENT_RETIRE)
+}
+
+impl PersisterConfig {
+    pub fn from_env() -> Self {
+        Self {
+            max_batch: env_u64("JDB_BATCH_MAX", DEFAULT_MAX_BATCH).max(1) as usize,
+            linger: Duration::from_micros(env_u64("JDB_BATCH_LINGER_US", DEFAULT_LINGER_US)),
+            retire: Retire::from_env(),
+        }
+    }
+}
+
+// --- JOURNAL HEALTH ---
+// Shared by the persister and the engine.
+//   Healthy  -> Failed    a write failed (or the persister is gone). The persister stops writing and
+//                         drops everything queued behind the failure, so the journal never gets a hole.
+//   Failed   -> ReadOnly  RAM was rolled back to what's on disk (AppState::reload). Reads work,
+//                         anything that would journal is refused.
+//   ReadOnly -> Healthy   only when an operator says so (POST /admin/journal/resume), once the disk
+//                         is fixed. The engine sends DbMessage::Resume and the persister carries on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Health {
+    #[default]
+    Healthy,
+    Failed,
+    ReadOnly,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::Failed => "failed",
+            Health::ReadOnly => "read_only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthStatus {
+    pub state: Health,
+    pub reason: Option<String>, // Of the latest failure (kept after resuming, for the record)
+    pub since: u64,             // Unix seconds of the latest change
+    pub failures: u64,          // Since startup
+}
+
+// The channel is reported as filling up past 80%, and again once it went back under 50%
+const DEPTH_WARN_PCT: usize = 80;
+const DEPTH_CLEAR_PCT: usize = 50;
+
+#[derive(Default)]
+pub struct Journal {
+    durable: AtomicU64, // Log entries on disk
+    batches: AtomicU64, // Written since startup: one write and one fsync per file each
+    health: Mutex<HealthStatus>,
+    halted: AtomicBool, // The persister has stopped writing, and is dropping messages until Resume
+    high_water: AtomicUsize, // Deepest the channel got
+    saturated: AtomicU64, // Requests refused because the channel was too full for them
+    depth_warned: AtomicBool,
+    segments: Mutex<Vec<Segment>>, // The persister's copy of the segment index
+    pub failed: Notify, // Wakes whoever rolls RAM back
+}
+
+impl Journal {
+    pub fn durable(&self) -> u64 {
+        self.durable.load(Ordering::SeqCst)
+    }
+
+    pub fn set_durable(&self, entries: u64) {
+        self.durable.store(entries, Ordering::SeqCst);
+    }
+
+    pub fn batches(&self) -> u64 {
+        self.batches.load(Ordering::Relaxed)
+    }
+
+    pub fn health(&self) -> HealthStatus {
+        self.health.lock().unwrap().clone()
+    }
+
+    pub fn state(&self) -> Health {
+        self.health.lock().unwrap().state
+    }
+
+    pub fn fail(&self, reason: String) {
+        let mut health = self.health.lock().unwrap();
+        if health.state == Health::Healthy {
+            health.failures += 1;
+        }
+        eprintln!("[Journal] FAILED: {}. Trading is suspended until an operator resumes it", reason);
+        health.state = Health::Failed;
+        health.reason = Some(reason);
+        health.since = now();
+        self.failed.notify_one();
+    }
+
+    /// Failed -> ReadOnly, once RAM matches the disk again
+    pub fn rolled_back(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.state == Health::Failed {
+            health.state = Health::ReadOnly;
+            health.since = now();
+        }
+    }
+
+    /// ReadOnly -> Healthy. The caller then sends DbMessage::Resume.
+    pub fn resume(&self) -> Result<(), &'static str> {
+        let mut health = self.health.lock().unwrap();
+        match health.state {
+            Health::ReadOnly => {
+                health.state = Health::Healthy;
+                health.since = now();
+                Ok(())
+            }
+            Health::Failed => Err("RAM is still being rolled back, try again in a moment"),
+            Health::Healthy => Err("The journal is healthy, nothing to resume"),
+        }
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+
+    // --- CHANNEL DEPTH ---
+    // How much is queued for the persister, seen whenever a request checks for room.
+
+    pub fn note_depth(&self, depth: usize, max: usize) {
+        self.high_water.fetch_max(depth, Ordering::Relaxed);
+        if depth * 100 >= max * DEPTH_WARN_PCT {
+            if !self.depth_warned.swap(true, Ordering::Relaxed) {
+                eprintln!("[Journal] Channel {}% full ({} of {}), the persister is falling behind",
+                    depth * 100 / max, depth, max);
+            }
+        } else if depth * 100 < max * DEPTH_CLEAR_PCT && self.depth_warned.swap(false, Ordering::Relaxed) {
+            println!("[Journal] Channel back under {}% ({} of {})", DEPTH_CLEAR_PCT, depth, max);
+        }
+    }
+
+    pub fn note_saturated(&self) {
+        self.saturated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    pub fn saturated(&self) -> u64 {
+        self.saturated.load(Ordering::Relaxed)
+    }
+
+    // --- SEGMENTS ---
+
+    pub fn segments(&self) -> Vec<Segment> {
+        self.segments.lock().unwrap().clone()
+    }
+
+    pub fn note_segments(&self, segments: &[Segment]) {
+        let mut ours = self.segments.lock().unwrap();
+        if ours.as_slice() != segments {
+            *ours = segments.to_vec();
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Runs until every Sender is gone. Blocking: give it its own thread.
+pub fn run(mut db: DatabaseWriter, mut rx: Receiver<DbMessage>, journal: Arc<Journal>, config: PersisterConfig) {
+    let max_batch = config.max_batch.min(db.max_batch()).max(1);
+    println!("[Persister] Disk Thread Started (batches of up to {}, linger {} us)",
+        max_batch, config.linger.as_micros());
+    journal.note_segments(db.segments());
+
+    // Lingering parks on the channel until the next message or the deadline, whichever
+    // comes first. Without a timer it doesn't linger at all: it takes what's queued.
+    let timer = tokio::runtime::Builder::new_current_thread().enable_time().build()
+        .inspect_err(|e| eprintln!("[Persister] No timer, batches won't linger: {}", e))
+        .ok();
+
+    let mut batch = Vec::with_capacity(max_batch);
+    // Set by a failure: what was journaled behind it is dropped, up to the engine's Resume
+    let mut discarding = false;
+
+    // Loop forever, waiting for messages
+    while let Some(first) = rx.blocking_recv() {
+        // 1. Collect a batch: what's already queued, plus what arrives within the linger time
+        batch.push(first);
+        let deadline = tokio::time::Instant::now() + config.linger;
+        while batch.len() < max_batch {
+            let next = match rx.try_recv() {
+                Ok(msg) => Some(msg),
+                Err(TryRecvError::Empty) => timer.as_ref()
+                    .and_then(|rt| rt.block_on(async { tokio::time::timeout_at(deadline, rx.recv()).await }).ok().flatten()),
+                Err(_) => None,
+            };
+            let Some(msg) = next else { break };
+            batch.push(msg);
+        }
+
+        // 2. After a failure, drop whatever was built on top of the lost entries.
+        // The engine may have failed too (a send it couldn't make): stop writing from here on.
+        if !discarding && journal.state() == Health::Failed {
+            discarding = true;
+            journal.halted.store(true, Ordering::SeqCst);
+        }
+        let mut msgs = batch.drain(..);
+        if discarding {
+            for msg in msgs.by_ref() {
+                match msg {
+                    DbMessage::Resume => {
+                        // RAM was rolled back to the files: carry on from wherever they ended
+                        match db.recount() {
+                            Ok(()) => {
+                                discarding = false;
+                                journal.halted.store(false, Ordering::SeqCst);
+                            }
+                            Err(e) => journal.fail(format!("can't read the data files back: {}", e)),
+                        }
+                        break;
+                    }
+                    DbMessage::Ack(reply) => { let _ = reply.send(Err(journal.health().reason.unwrap_or_default())); }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut users = Vec::new();
+        let mut logs = Vec::new();
+        let mut acks = Vec::new();
+        let mut retire = None;
+        for msg in msgs {
+            match msg {
+                DbMessage::WriteLog(entry) => logs.push(entry),
+                DbMessage::WriteUser(user) => users.push(user),
+                DbMessage::Ack(reply) => acks.push(reply),
+                DbMessage::Retire(covered) => retire = retire.max(Some(covered)),
+                DbMessage::Resume => {}
+            }
+        }
+        if discarding {
+            for reply in acks {
+                let _ = reply.send(Err(journal.health().reason.unwrap_or_default()));
+            }
+            continue;
+        }
+
+        // 3. Users first: log entries may refer to someone registered in the same batch
+        let mut written = Ok(());
+        if !users.is_empty() {
+            written = db.append_users(&users).map_err(|e| format!("users.bin write failed: {}", e));
+        }
+        if written.is_ok() && !logs.is_empty() {
+            written = db.append_logs(&logs).map_err(|e| format!("journal write failed: {}", e));
+        }
+
+        // 4. Only now is anything in this batch durable: answer whoever is waiting for it
+        match written {
+            Ok(()) => {
+                if !users.is_empty() || !logs.is_empty() {
+                    journal.batches.fetch_add(1, Ordering::Relaxed);
+                }
+                // Snapshots wait for this to reach their log index
+                let durable = journal.durable.fetch_add(logs.len() as u64, Ordering::SeqCst) + logs.len() as u64;
+                for reply in acks {
+                    let _ = reply.send(Ok(durable));
+                }
+
+                // 5. Segments a snapshot covers are only in the way now (not worth failing over)
+                if let Some(covered) = retire {
+                    match db.retire(covered, config.retire) {
+                        Ok(files) if !files.is_empty() => println!("[Journal] Retired {} (covered by the snapshot at log index {})",
+                            files.join(", "), covered),
+                        Ok(_) => {}
+                        Err(e) => eprintln!("[Journal] Can't retire segments up to log index {}: {}", covered, e),
+                    }
+                }
+                journal.note_segments(db.segments());
+            }
+            Err(reason) => {
+                eprintln!("[Persister] WRITE FAILED ({} users, {} entries): {}", users.len(), logs.len(), reason);
+                for reply in acks {
+                    let _ = reply.send(Err(reason.clone()));
+                }
+                discarding = true;
+                journal.halted.store(true, Ordering::SeqCst);
+                journal.fail(reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::JoinHandle;
+    use tokio::sync::mpsc::{self, Sender};
+    use tokio::sync::oneshot;
+    use crate::consts::{ActionType, LogEntry};
+    use crate::crypto::Keyring;
+    use crate::format;
+    use crate::orderbook::{NewOrder, OrderType, Side, TimeInForce};
+    use crate::segments::{LogReader, SegmentWriter};
+    use crate::state::{AppState, Transfer};
+
+    fn deposit(user_id: u64) -> DbMessage {
+        DbMessage::WriteLog(LogEntry::new(user_id, ActionType::Deposit, 0, 0, 100))
+    }
+
+    fn ack(tx: &Sender<DbMessage>) -> oneshot::Receiver<Result<u64, String>> {
+        let (reply, rx) = oneshot::channel();
+        tx.try_send(DbMessage::Ack(reply)).unwrap();
+        rx
+    }
+
+    // The persister on the data directory, started once `queue` has filled the channel
+    fn persister(keys: &Arc<Keyring>, engine_id: [u8; 16], queue: impl FnOnce(&Sender<DbMessage>)) -> (Sender<DbMessage>, Arc<Journal>, JoinHandle<()>) {
+        let db = DatabaseWriter::new(keys.clone(), engine_id).unwrap();
+        let (tx, rx) = mpsc::channel(100);
+        queue(&tx);
+        let journal = Arc::new(Journal::default());
+        let config = PersisterConfig { max_batch: 1024, linger: Duration::ZERO, retire: Retire::Keep };
+        let running = journal.clone();
+        (tx, journal, std::thread::spawn(move || run(db, rx, running, config)))
+    }
+
+    // User IDs of the journal on disk, in log order
+    fn on_disk(keys: &Arc<Keyring>, engine_id: [u8; 16]) -> Vec<u64> {
+        let log = LogReader::open(keys.clone(), engine_id).unwrap();
+        log.entries(0, u64::MAX).map(|e| e.unwrap().1.user_id).collect()
+    }
+
+    #[test]
+    fn queued_entries_go_out_as_one_batch() {
+        format::in_data_dir("persister-batch", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = format::upgrade_data_files(&keys).unwrap();
+            let mut acked = None;
+            let (tx, journal, thread) = persister(&keys, engine_id, |tx| {
+                for user_id in 1..=10 {
+                    tx.try_send(deposit(user_id)).unwrap();
+                }
+                acked = Some(ack(tx));
+            });
+
+            // The ack comes once all ten are synced: they're on disk, in order, by then
+            assert_eq!(acked.unwrap().blocking_recv().unwrap(), Ok(10));
+            assert_eq!(on_disk(&keys, engine_id), (1..=10).collect::<Vec<_>>());
+            assert_eq!((journal.durable(), journal.batches()), (10, 1));
+
+            drop(tx);
+            thread.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn after_a_failure_nothing_is_written_until_resume() {
+        format::in_data_dir("persister-resume", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = format::upgrade_data_files(&keys).unwrap();
+            let mut acked = None;
+            let (tx, journal, thread) = persister(&keys, engine_id, |tx| {
+                (1..=3).for_each(|user_id| tx.try_send(deposit(user_id)).unwrap());
+                acked = Some(ack(tx));
+            });
+            assert_eq!(acked.unwrap().blocking_recv().unwrap(), Ok(3));
+
+            // 1. Failed: what follows is dropped, and its ack says why
+            journal.fail("disk full".to_string());
+            tx.try_send(deposit(4)).unwrap();
+            assert_eq!(ack(&tx).blocking_recv().unwrap(), Err("disk full".to_string()));
+            assert!(journal.halted());
+            assert_eq!(on_disk(&keys, engine_id), vec![1, 2, 3]);
+
+            // 2. Meanwhile the files moved on (a batch that landed after all, say):
+            // on Resume the persister counts them again and appends behind them
+            let mut writer = SegmentWriter::open(keys.clone(), engine_id).unwrap();
+            writer.append(&[LogEntry::new(9, ActionType::Deposit, 0, 0, 100)]).unwrap();
+            drop(writer);
+            journal.rolled_back();
+            journal.resume().unwrap();
+            tx.try_send(DbMessage::Resume).unwrap();
+            tx.try_send(deposit(5)).unwrap();
+            assert!(ack(&tx).blocking_recv().unwrap().is_ok());
+            assert!(!journal.halted());
+            assert_eq!(on_disk(&keys, engine_id), vec![1, 2, 3, 9, 5]);
+
+            drop(tx);
+            thread.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn read_only_refuses_writes() {
+        format::in_data_dir("persister-read-only", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = format::upgrade_data_files(&keys).unwrap();
+            let (tx, mut rx) = mpsc::channel(100);
+            let mut app = AppState::new(tx, keys, engine_id).unwrap();
+            app.load_accounts(&[1, 2]).unwrap();
+            app.issue_stock(9, 1, 7, 10).unwrap();
+
+            app.journal.fail("disk full".to_string());
+            app.journal.rolled_back();
+            assert_eq!(app.journal.state(), Health::ReadOnly);
+            let lsn = app.next_lsn;
+            let read_only = "Read-only: the journal can't be written, trading is suspended";
+            assert_eq!(app.issue_stock(9, 1, 7, 10), Err(read_only));
+            let sell = NewOrder { side: Side::Sell, order_type: OrderType::Limit, time_in_force: TimeInForce::Gtc, price: Some(5), quantity: 1 };
+            assert_eq!(app.submit_order(1, 0, 7, sell).unwrap_err(), read_only);
+            let gift = Transfer { to: 2, symbol_id: 7, is_cash: false, amount: 1 };
+            assert_eq!(app.transfer(1, 0, gift), Err(read_only));
+
+            // Reads still work, and nothing more was journaled
+            assert_eq!(app.portfolios.get(&1).unwrap().stocks[&7], 10);
+            assert_eq!(app.next_lsn, lsn);
+            assert_eq!(std::iter::from_fn(|| rx.try_recv().ok()).count() as u64, lsn);
+        });
+    }
+}
@@ -1,45 +1,191 @@
-use std::fs::File;
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::mem::size_of;
-use memmap2::MmapOptions;
-use crate::consts::{UserMeta, LogEntry};
+use std::sync::Arc;
+use crate::consts::{sealed_size, SealHeader, UserMeta, FILE_HEADER_SIZE, SEAL_MAGIC};
+use crate::crypto::Keyring;
+use crate::format;
+use crate::segments::{LogEntries, LogReader};
 
+// Users and the journal are sealed record by record (crypto.rs), and are opened a record
+// at a time when they're asked for: neither has to fit in RAM.
+// This has to run before the persister opens them: a torn last record is cut off here.
+// By now format::upgrade_data_files has brought every file to the current format.
 pub struct DatabaseReader {
-    user_mmap: memmap2::Mmap,
-    log_mmap: memmap2::Mmap,
+    keys: Arc<Keyring>,
+    users: File,     // users.bin, as it was on open (the persister appends behind it)
+    num_users: u64,
+    log: LogReader,  // What's left of the journal (segments.rs)
 }
 
 impl DatabaseReader {
-    pub fn new() -> io::Result<Self> {
-        let u_file = File::open("users.bin")?;
-        let l_file = File::open("history.bin")?;
+    pub fn new(keys: &Arc<Keyring>, engine_id: &[u8; 16]) -> io::Result<Self> {
+        let (users, num_users) = open_users(keys, engine_id)?;
+        let log = LogReader::open(keys.clone(), *engine_id)?;
 
-        // Map the files into Virtual Memory
-        // This does NOT read the disk yet. It just reserves the addresses.
-        let user_mmap = unsafe { MmapOptions::new().map(&u_file)? };
-        let log_mmap = unsafe { MmapOptions::new().map(&l_file)? };
+        Ok(Self { keys: keys.clone(), users, num_users, log })
+    }
+
+    /// Records in users.bin: user IDs 0..num_users()
+    pub fn num_users(&self) -> u64 {
+        self.num_users
+    }
+
+    /// The users.bin record at `user_id` (None past the end)
+    pub fn user(&self, user_id: u64) -> io::Result<Option<UserMeta>> {
+        if user_id >= self.num_users {
+            return Ok(None);
+        }
+        read_user(&self.keys, &self.users, user_id).map(Some)
+    }
+
+    /// Journal entries `from..to` with their log index, read as they're iterated
+    pub fn log_entries(&self, from: u64, to: u64) -> LogEntries {
+        self.log.entries(from, to)
+    }
+
+    /// Log index of the first entry: the ones before it went with retired segments
+    pub fn first_log(&self) -> u64 {
+        self.log.first()
+    }
+
+    /// Log index the next entry gets
+    pub fn end_log(&self) -> u64 {
+        self.log.end()
+    }
+}
+
+const USER_SIZE: u64 = sealed_size(size_of::<UserMeta>()) as u64;
+
+// Only the last record is opened here: a damaged or half-written one is what a crash mid-append
+// leaves, so it is cut off. Damage anywhere else is an error (with its byte offset) when it's read.
+fn open_users(keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<(File, u64)> {
+    let filename = format::USERS.file;
+    let file = OpenOptions::new().read(true).write(true).open(filename)?;
+
+    let mut header = Vec::with_capacity(FILE_HEADER_SIZE);
+    (&file).take(FILE_HEADER_SIZE as u64).read_to_end(&mut header)?;
+    format::check_header(&header, &format::USERS, engine_id)?;
+
+    let file_len = file.metadata()?.len();
+    let mut count = (file_len - FILE_HEADER_SIZE as u64) / USER_SIZE;
+    if count > 0 {
+        // A key we don't have is never a torn write
+        let mut seal = [0u8; size_of::<SealHeader>()];
+        read_at(&file, record_offset(count - 1), &mut seal)?;
+        let seal: SealHeader = bytemuck::pod_read_unaligned(&seal);
+        if seal.magic == SEAL_MAGIC {
+            keys.check_key(filename, seal.key_id)?;
+        }
+        if read_user(keys, &file, count - 1).is_err() {
+            count -= 1;
+        }
+    }
+
+    let valid_len = record_offset(count);
+    if valid_len < file_len {
+        file.set_len(valid_len)?;
+        file.sync_all()?;
+        println!("[Recovery] {}: cut off a torn last record at byte offset {} ({} bytes)",
+            filename, valid_len, file_len - valid_len);
+    }
+    Ok((file, count))
+}
+
+fn record_offset(user_id: u64) -> u64 {
+    FILE_HEADER_SIZE as u64 + user_id * USER_SIZE
+}
+
+fn read_at(mut file: &File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
 
-        Ok(Self { user_mmap, log_mmap })
+fn read_user(keys: &Keyring, file: &File, user_id: u64) -> io::Result<UserMeta> {
+    let offset = record_offset(user_id);
+    let mut sealed = [0u8; USER_SIZE as usize];
+    read_at(file, offset, &mut sealed)?;
+    let plain = keys.open_record(format::USERS.file, user_id, &sealed)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}, at byte offset {}", e, offset)))?;
+    let user: UserMeta = bytemuck::pod_read_unaligned(&plain);
+    // Old records don't always carry their own position in user_id
+    Ok(UserMeta { user_id, ..user })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use crate::consts::{ActionType, LogEntry};
+    use crate::segments::{self, ENTRY_SIZE};
+    use crate::writer::DatabaseWriter;
+
+    // Flips one byte of the sealed record at `offset`
+    fn damage(file: &str, offset: u64) {
+        let file = OpenOptions::new().read(true).write(true).open(file).unwrap();
+        let mut byte = [0u8];
+        file.read_exact_at(&mut byte, offset + 40).unwrap();
+        file.write_all_at(&[byte[0] ^ 1], offset + 40).unwrap();
     }
 
-    // CAST RAW BYTES TO STRUCT SLICE
-    pub fn get_users(&self) -> &[UserMeta] {
-        let count = self.user_mmap.len() / size_of::<UserMeta>();
-        // Zero-Copy Cast: The struct lies directly on the OS file buffer
-        bytemuck::cast_slice(&self.user_mmap[0..count * size_of::<UserMeta>()])
+    // 3 users and 4 journal entries, the way the persister writes them
+    fn written(keys: &Arc<Keyring>) -> [u8; 16] {
+        let engine_id = format::upgrade_data_files(keys).unwrap();
+        let mut writer = DatabaseWriter::new(keys.clone(), engine_id).unwrap();
+        let users: Vec<UserMeta> = (0..3).map(|user_id| UserMeta { user_id, ..bytemuck::Zeroable::zeroed() }).collect();
+        writer.append_users(&users).unwrap();
+        let entries: Vec<LogEntry> = (1..=4).map(|n| LogEntry::new(n, ActionType::Deposit, 0, 0, 100)).collect();
+        writer.append_logs(&entries).unwrap();
+        engine_id
     }
 
-    pub fn get_logs(&self) -> &[LogEntry] {//maps memory in RAM for access
-        let count = self.log_mmap.len() / size_of::<LogEntry>();
-        bytemuck::cast_slice(&self.log_mmap[0..count * size_of::<LogEntry>()])
+    #[test]
+    fn torn_tails_are_cut_off() {
+        format::in_data_dir("reader-torn", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = written(&keys);
+            let segment = segments::read_index().unwrap()[0].file.clone();
+            // The last journal entry garbled mid-write
+            damage(&segment, FILE_HEADER_SIZE as u64 + 3 * ENTRY_SIZE as u64);
+            let users_len = fs::metadata(format::USERS.file).unwrap().len();
+            // Half a user record after the last whole one
+            fs::OpenOptions::new().append(true).open(format::USERS.file).unwrap().write_all(&[7; 50]).unwrap();
+
+            let reader = DatabaseReader::new(&keys, &engine_id).unwrap();
+            assert_eq!(reader.end_log(), 3);
+            let users: Vec<u64> = reader.log_entries(0, 10).map(|e| e.unwrap().1.user_id).collect();
+            assert_eq!(users, vec![1, 2, 3]);
+            assert_eq!(reader.num_users(), 3);
+            assert_eq!(fs::metadata(format::USERS.file).unwrap().len(), users_len);
+        });
     }
 
-    pub fn get_live_log_length(&self) -> u64 {
-        // We open the file fresh just to check metadata
-        let file = File::open("history.bin").unwrap();
-        let len = file.metadata().unwrap().len();
-        
-        // Calculate number of entries
-        len / size_of::<LogEntry>() as u64
+    #[test]
+    fn damage_in_the_middle_is_reported_where_it_is() {
+        format::in_data_dir("reader-damaged", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = written(&keys);
+            let segment = segments::read_index().unwrap()[0].file.clone();
+            let entry_offset = FILE_HEADER_SIZE as u64 + ENTRY_SIZE as u64;
+            damage(&segment, entry_offset);
+            damage(format::USERS.file, record_offset(1));
+
+            // Only the last record is checked on open, so both still open
+            let reader = DatabaseReader::new(&keys, &engine_id).unwrap();
+            assert_eq!((reader.end_log(), reader.num_users()), (4, 3));
+
+            // The journal stops at the damage, and says where it is
+            let mut entries = reader.log_entries(0, 4);
+            assert_eq!(entries.next().unwrap().unwrap().0, 0);
+            let err = entries.next().unwrap().unwrap_err();
+            assert!(err.to_string().contains(&format!("byte offset {}", entry_offset)), "{}", err);
+            assert!(entries.next().is_none());
+
+            let err = reader.user(1).unwrap_err();
+            assert!(err.to_string().contains(&format!("byte offset {}", record_offset(1))), "{}", err);
+            assert_eq!(reader.user(2).unwrap().map(|u| u.user_id), Some(2));
+        });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,714 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::consts::{
+    env_u64, sealed_size, ActionType, LogEntry, SealHeader, FILE_HEADER_SIZE, JOURNAL_ARCHIVE_DIR, LOG_MAGIC, SEAL_MAGIC,
+    CARRIED_FLAGS, SEGMENT_INDEX,
+};
+use crate::crypto::Keyring;
+use crate::format::{self, HISTORY};
+
+// --- JOURNAL SEGMENTS (history-000001.bin, ...) ---
+// The log is a chain of fixed-size segments instead of one ever-growing file. Each one is
+// pre-allocated when it's created, so an append never grows a file (and fdatasync has no
+// size change to flush), and a full disk shows up at rollover instead of mid-append:
+//   [FileHeader (first_record = log index of its first entry) | sealed LogEntry slots...]
+// Slots fill in order; the first all-zero one is where the log ends.
+// Entries are sealed as "history.bin" + their log index whatever file they're in,
+// so a segment can be moved (archived) without re-sealing.
+//
+// history.segments lists them oldest first: "<file> <first log index> <entries> <full|active>".
+// A full segment that the oldest kept snapshot covers isn't needed to start up any more,
+// and is archived (moved to archive/) or deleted: replay starts at the snapshot, which keeps
+// the receipts and flags it needs. The last flag change per user is carried over into
+// history.flags as well, which is what snapshots from before they had flags are upgraded with.
+//   JDB_SEGMENT_MB      size of a new segment (default 64)
+//   JDB_SEGMENT_RETIRE  archive (default) | delete | keep
+pub const LOG_SEAL_NAME: &str = "history.bin";
+const DEFAULT_SEGMENT_MB: u64 = 64;
+pub const ENTRY_SIZE: usize = sealed_size(size_of::<LogEntry>());
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub file: String,
+    pub first: u64,   // Log index of its first entry
+    pub entries: u64, // Set when it fills up (the active one is counted on open)
+    pub full: bool,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.first + self.entries
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retire {
+    Archive,
+    Delete,
+    Keep,
+}
+
+impl Retire {
+    pub fn from_env() -> Self {
+        match std::env::var("JDB_SEGMENT_RETIRE").as_deref().map(str::trim) {
+            Ok("delete") => Retire::Delete,
+            Ok("keep") => Retire::Keep,
+            Ok("archive") | Err(_) => Retire::Archive,
+            Ok(other) => {
+                eprintln!("JDB_SEGMENT_RETIRE: expected archive, delete or keep (not {:?}), archiving", other);
+                Retire::Archive
+            }
+        }
+    }
+}
+
+pub fn segment_file(number: u64) -> String {
+    format!("history-{:06}.bin", number)
+}
+
+pub fn segment_number(file: &str) -> Option<u64> {
+    file.strip_prefix("history-")?.strip_suffix(".bin")?.parse().ok()
+}
+
+// Slots in a new segment
+pub fn new_segment_slots() -> u64 {
+    let bytes = env_u64("JDB_SEGMENT_MB", DEFAULT_SEGMENT_MB).max(1) * 1024 * 1024;
+    (bytes - FILE_HEADER_SIZE as u64) / ENTRY_SIZE as u64
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// --- SEGMENT INDEX ---
+/// Oldest first. No index = no segments yet.
+pub fn read_index() -> io::Result<Vec<Segment>> {
+    let text = match fs::read_to_string(SEGMENT_INDEX) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut segments = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let segment = match fields[..] {
+            [file, first, entries, state @ ("full" | "active")] => (|| Some(Segment {
+                file: file.to_string(),
+                first: first.parse().ok()?,
+                entries: entries.parse().ok()?,
+                full: state == "full",
+            }))(),
+            _ => None,
+        };
+        segments.push(segment.ok_or_else(|| invalid(format!("{} line {}: can't parse {:?}", SEGMENT_INDEX, n + 1, line)))?);
+    }
+    Ok(segments)
+}
+
+pub fn write_index(segments: &[Segment]) -> io::Result<()> {
+    let mut text = String::from("# file, first log index, entries, full|active (oldest first)\n");
+    for s in segments {
+        text.push_str(&format!("{} {} {} {}\n", s.file, s.first, s.entries, if s.full { "full" } else { "active" }));
+    }
+    format::replace_file(SEGMENT_INDEX, text.as_bytes())
+}
+
+// --- CARRIED FLAGS ---
+/// "<user_id> <flags>" for every user whose flags last changed in a retired segment
+pub fn read_carried_flags() -> io::Result<BTreeMap<u64, u32>> {
+    let text = match fs::read_to_string(CARRIED_FLAGS) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut flags = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line.split_once(' ').and_then(|(user, value)| Some((user.parse().ok()?, value.trim().parse().ok()?)));
+        let (user_id, value) = parsed.ok_or_else(|| invalid(format!("{} line {}: can't parse {:?}", CARRIED_FLAGS, n + 1, line)))?;
+        flags.insert(user_id, value);
+    }
+    Ok(flags)
+}
+
+/// Notes the flag changes among entries that are about to leave the journal (in log order)
+pub fn carry_flags<'a>(entries: impl IntoIterator<Item = &'a LogEntry>) -> io::Result<()> {
+    let mut flags = read_carried_flags()?;
+    let before = flags.clone();
+    for entry in entries {
+        if let ActionType::SetFlags = ActionType::from_u8(entry.action_type) {
+            flags.insert(entry.user_id, entry.quantity as u32);
+        }
+    }
+    if flags != before {
+        write_carried_flags(&flags)?;
+    }
+    Ok(())
+}
+
+fn write_carried_flags(flags: &BTreeMap<u64, u32>) -> io::Result<()> {
+    let mut text = String::from("# user_id, flags (as of the last retired segment)\n");
+    for (user_id, value) in flags {
+        text.push_str(&format!("{} {}\n", user_id, value));
+    }
+    format::replace_file(CARRIED_FLAGS, text.as_bytes())
+}
+
+// --- CREATING ONE ---
+// Header, then `slots` zeroed slots. Made under a temp name and renamed, so a crash
+// never leaves half a segment; it only counts once the index lists it.
+pub fn create_segment(file: &str, first: u64, slots: u64, engine_id: &[u8; 16], sealed: &[u8]) -> io::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut header = format::new_header(&HISTORY, engine_id, now);
+    header.first_record = first;
+
+    let tmp = format!("{}.tmp", file);
+    let mut out = File::create(&tmp)?;
+    out.write_all(bytemuck::bytes_of(&header))?;
+    out.write_all(sealed)?; // Entries it starts out with (adopting history.bin)
+    preallocate(&out, FILE_HEADER_SIZE as u64 + slots * ENTRY_SIZE as u64)?;
+    out.sync_all()?;
+    fs::rename(&tmp, file)?;
+    format::sync_dir()
+}
+
+// Real blocks on Linux; elsewhere the file is only extended, which reads back as zeros all the same
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+            0 => return Ok(()),
+            libc::EOPNOTSUPP | libc::EINVAL => {} // Filesystem can't: fall back
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+    file.set_len(len)
+}
+
+// --- READING (startup) ---
+/// The journal as startup finds it: which segments are left and where it ends.
+/// Entries are only read when asked for, a segment at a time (see entries()).
+pub struct LogReader {
+    keys: Arc<Keyring>,
+    engine_id: [u8; 16],
+    segments: Vec<Segment>, // The active one's entries counted on open
+}
+
+impl LogReader {
+    /// Checks the index holds together and finds where the active segment ends.
+    /// A damaged or half-written last entry (what a crash mid-append leaves) is zeroed out.
+    pub fn open(keys: Arc<Keyring>, engine_id: [u8; 16]) -> io::Result<Self> {
+        let mut segments = read_index()?;
+        let mut end = segments.first().map_or(0, |s| s.first);
+        for segment in &segments {
+            if segment.first != end {
+                return Err(invalid(format!("{} starts at log index {}, the segment before it ends at {}", segment.file, segment.first, end)));
+            }
+            end = segment.end();
+        }
+        if let Some(active) = segments.last_mut().filter(|s| !s.full) {
+            active.entries = count_entries(&keys, &engine_id, active)?;
+        }
+        Ok(Self { keys, engine_id, segments })
+    }
+
+    /// Log index of the first entry still in the journal: older ones went with retired segments
+    pub fn first(&self) -> u64 {
+        self.segments.first().map_or(0, |s| s.first)
+    }
+
+    /// Log index the next entry gets
+    pub fn end(&self) -> u64 {
+        self.segments.last().map_or(0, |s| s.end())
+    }
+
+    /// Entries `from..to` (clamped to what the journal holds) with their log index, in order.
+    /// Only the segments they're in are opened. Damage is an error, with its byte offset.
+    pub fn entries(&self, from: u64, to: u64) -> LogEntries {
+        let next = from.max(self.first());
+        LogEntries {
+            keys: self.keys.clone(),
+            engine_id: self.engine_id,
+            segments: self.segments.iter().filter(|s| s.end() > next && s.first < to).cloned().collect::<Vec<_>>().into_iter(),
+            current: None,
+            next,
+            to: to.min(self.end()),
+        }
+    }
+}
+
+pub struct LogEntries {
+    keys: Arc<Keyring>,
+    engine_id: [u8; 16],
+    segments: std::vec::IntoIter<Segment>, // Still to open
+    current: Option<(Segment, BufReader<File>)>,
+    next: u64,
+    to: u64,
+}
+
+impl Iterator for LogEntries {
+    type Item = io::Result<(u64, LogEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.to {
+            return None;
+        }
+        let entry = self.read_next();
+        if entry.is_err() {
+            self.next = self.to; // Nothing after damage can be trusted
+        }
+        Some(entry)
+    }
+}
+
+impl LogEntries {
+    fn read_next(&mut self) -> io::Result<(u64, LogEntry)> {
+        // 1. The segment `next` is in, positioned at it
+        if self.current.as_ref().is_none_or(|(segment, _)| self.next >= segment.end()) {
+            let segment = self.segments.next()
+                .ok_or_else(|| invalid(format!("log index {} isn't in any segment", self.next)))?;
+            let file = open_segment(&self.engine_id, &segment)?;
+            let mut file = BufReader::new(file);
+            file.seek(SeekFrom::Start(FILE_HEADER_SIZE as u64 + (self.next - segment.first) * ENTRY_SIZE as u64))?;
+            self.current = Some((segment, file));
+        }
+        let (segment, file) = self.current.as_mut().unwrap();
+
+        // 2. The entry
+        let index = self.next;
+        let offset = FILE_HEADER_SIZE as u64 + (index - segment.first) * ENTRY_SIZE as u64;
+        let mut sealed = [0u8; ENTRY_SIZE];
+        file.read_exact(&mut sealed).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", segment.file, e)))?;
+        if sealed.iter().all(|b| *b == 0) {
+            return Err(invalid(format!("{} ends at log index {}, {} says {}", segment.file, index, SEGMENT_INDEX, segment.end())));
+        }
+        let entry = open_entry(&self.keys, index, &sealed, HISTORY.version)
+            .map_err(|e| invalid(format!("{} byte offset {}: {}", segment.file, offset, e)))?;
+        self.next += 1;
+        Ok((index, entry))
+    }
+}
+
+// Checks its header against the index
+fn open_segment(engine_id: &[u8; 16], segment: &Segment) -> io::Result<File> {
+    let in_file = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", segment.file, e));
+    let file = OpenOptions::new().read(true).write(true).open(&segment.file).map_err(in_file)?;
+    let mut header = Vec::with_capacity(FILE_HEADER_SIZE);
+    (&file).take(FILE_HEADER_SIZE as u64).read_to_end(&mut header).map_err(in_file)?;
+    let header = format::check_header(&header, &HISTORY, engine_id).map_err(in_file)?;
+    if header.first_record != segment.first {
+        return Err(invalid(format!("{} starts at log index {}, {} says {}", segment.file, header.first_record, SEGMENT_INDEX, segment.first)));
+    }
+    Ok(file)
+}
+
+// Entries in the active segment: up to the first zeroed slot, found the way SegmentWriter does.
+// Only the last one is opened, and zeroed if it's torn.
+fn count_entries(keys: &Keyring, engine_id: &[u8; 16], segment: &Segment) -> io::Result<u64> {
+    let file = open_segment(engine_id, segment)?;
+    let slots = file.metadata()?.len().saturating_sub(FILE_HEADER_SIZE as u64) / ENTRY_SIZE as u64;
+    let used = used_slots(&file, slots)?;
+    if used == 0 {
+        return Ok(0);
+    }
+
+    let offset = FILE_HEADER_SIZE as u64 + (used - 1) * ENTRY_SIZE as u64;
+    let mut sealed = [0u8; ENTRY_SIZE];
+    let mut reader = &file;
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut sealed)?;
+    if open_entry(keys, segment.first + used - 1, &sealed, HISTORY.version).is_ok() {
+        return Ok(used);
+    }
+    zero(&file, offset, ENTRY_SIZE)?;
+    println!("[Recovery] {}: zeroed a torn last entry at byte offset {}", segment.file, offset);
+    Ok(used - 1)
+}
+
+// Slots fill in order, so where they end is a binary search for the first zeroed one
+fn used_slots(file: &File, slots: u64) -> io::Result<u64> {
+    let (mut used, mut free) = (0, slots);
+    while used < free {
+        let mid = (used + free) / 2;
+        if slot_used(file, mid)? {
+            used = mid + 1;
+        } else {
+            free = mid;
+        }
+    }
+    Ok(used)
+}
+
+fn slot_used(mut file: &File, slot: u64) -> io::Result<bool> {
+    let mut buf = [0u8; ENTRY_SIZE];
+    file.seek(SeekFrom::Start(FILE_HEADER_SIZE as u64 + slot * ENTRY_SIZE as u64))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf.iter().any(|b| *b != 0))
+}
+
+/// Every user's flags as of log index `at`: what retired segments carried, then the journal up to there.
+/// For snapshots from before they had their own (format.rs).
+pub fn flags_at(keys: Arc<Keyring>, engine_id: [u8; 16], at: u64) -> io::Result<BTreeMap<u64, u32>> {
+    let mut flags = read_carried_flags()?;
+    let log = LogReader::open(keys, engine_id)?;
+    for item in log.entries(log.first(), at) {
+        let (_, entry) = item?;
+        if let ActionType::SetFlags = ActionType::from_u8(entry.action_type) {
+            flags.insert(entry.user_id, entry.quantity as u32);
+        }
+    }
+    Ok(flags)
+}
+
+/// Every entry in a journal file wherever it is (a segment, one in archive/, or the old history.bin),
+/// and the log index of the first one. Nothing is repaired here: any damage is an error.
+/// Retired files aren't upgraded at startup, so older formats are migrated as they're read.
+pub fn read_journal_file(keys: &Keyring, engine_id: &[u8; 16], path: &Path) -> io::Result<(u64, Vec<LogEntry>)> {
+    let name = path.display();
+    let bytes = fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+    let header = format::check_old_header(&bytes, &HISTORY, engine_id)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+
+    let slot_size = sealed_size(format::record_size(&HISTORY, header.version));
+    let mut entries = Vec::new();
+    for (slot, sealed) in bytes[FILE_HEADER_SIZE..].chunks_exact(slot_size).enumerate() {
+        if sealed.iter().all(|b| *b == 0) {
+            break;
+        }
+        let entry = open_entry(keys, header.first_record + slot as u64, sealed, header.version)
+            .map_err(|e| invalid(format!("{} byte offset {}: {}", name, FILE_HEADER_SIZE + slot * slot_size, e)))?;
+        entries.push(entry);
+    }
+    Ok((header.first_record, entries))
+}
+
+// Every entry must look like one and match its CRC (`version`: the file's format)
+fn open_entry(keys: &Keyring, index: u64, sealed: &[u8], version: u16) -> io::Result<LogEntry> {
+    let plain = keys.open_record(LOG_SEAL_NAME, index, sealed)?;
+    let plain = format::migrate_record(HISTORY.kind, version, &plain);
+    let entry: LogEntry = bytemuck::pod_read_unaligned(&plain);
+    if entry.magic != LOG_MAGIC {
+        return Err(invalid(format!("entry {}: bad magic", index)));
+    }
+    if !entry.checksum_ok() {
+        return Err(invalid(format!("entry {}: CRC32C mismatch", index)));
+    }
+    Ok(entry)
+}
+
+fn zero(mut file: &File, offset: u64, len: usize) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&vec![0u8; len])?;
+    file.sync_data()
+}
+
+// --- WRITING (the persister) ---
+pub struct SegmentWriter {
+    keys: Arc<Keyring>,
+    engine_id: [u8; 16],
+    segments: Vec<Segment>,
+    file: File, // The active (last) segment
+    slots: u64, // How many entries it can hold
+    next: u64,  // Log index of the next entry
+}
+
+impl SegmentWriter {
+    /// Opens the active segment (creating the first one for a new database)
+    pub fn open(keys: Arc<Keyring>, engine_id: [u8; 16]) -> io::Result<Self> {
+        let mut segments = read_index()?;
+        if segments.is_empty() {
+            let file = segment_file(1);
+            create_segment(&file, 0, new_segment_slots(), &engine_id, &[])?;
+            segments.push(Segment { file, first: 0, entries: 0, full: false });
+            write_index(&segments)?;
+        }
+
+        let file = OpenOptions::new().read(true).write(true).open(&segments.last().unwrap().file)?;
+        let mut writer = Self { keys, engine_id, segments, file, slots: 0, next: 0 };
+        writer.recount()?;
+        Ok(writer)
+    }
+
+    /// Finds where the active segment ends again. Slots fill in order, so that's
+    /// a binary search for the first zeroed one (after a failed batch, the file knows best).
+    pub fn recount(&mut self) -> io::Result<()> {
+        self.slots = self.file.metadata()?.len().saturating_sub(FILE_HEADER_SIZE as u64) / ENTRY_SIZE as u64;
+        self.next = self.active().first + used_slots(&self.file, self.slots)?;
+        Ok(())
+    }
+
+    fn active(&self) -> &Segment {
+        self.segments.last().unwrap()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The biggest batch append() takes: one always fits in a fresh segment
+    pub fn max_batch(&self) -> usize {
+        self.slots.min(new_segment_slots()) as usize
+    }
+
+    /// One write, one fdatasync. A batch that doesn't fit in what's left of the
+    /// active segment goes to a new one, so a failed batch is undone in one place.
+    pub fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let count = entries.len() as u64;
+        if self.next - self.active().first + count > self.slots {
+            self.roll_over()?;
+        }
+        if count > self.slots {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("a batch of {} doesn't fit in a segment", count)));
+        }
+
+        let mut bytes = Vec::with_capacity(entries.len() * ENTRY_SIZE);
+        for (i, entry) in entries.iter().enumerate() {
+            let entry = entry.with_checksum();
+            bytes.extend_from_slice(&self.keys.seal_record(LOG_SEAL_NAME, self.next + i as u64, bytemuck::bytes_of(&entry)));
+        }
+
+        // Critical for "Durability" - ensure it hits the disk
+        let offset = FILE_HEADER_SIZE as u64 + (self.next - self.active().first) * ENTRY_SIZE as u64;
+        let mut file = &self.file;
+        let written = file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(&bytes))
+            .and_then(|_| file.sync_data());
+        if written.is_err() {
+            // Whatever made it stays invisible: the log ends at the first zeroed slot
+            let _ = zero(&self.file, offset, bytes.len());
+        }
+        written?;
+
+        self.next += count;
+        Ok(())
+    }
+
+    fn roll_over(&mut self) -> io::Result<()> {
+        let number = segment_number(&self.active().file).unwrap_or(self.segments.len() as u64) + 1;
+        let file = segment_file(number);
+        let slots = new_segment_slots();
+        create_segment(&file, self.next, slots, &self.engine_id, &[])?;
+
+        // The old one is full at whatever it holds now; the new one counts once the index says so
+        let mut segments = self.segments.clone();
+        let old = segments.last_mut().unwrap();
+        old.entries = self.next - old.first;
+        old.full = true;
+        segments.push(Segment { file: file.clone(), first: self.next, entries: 0, full: false });
+        write_index(&segments)?;
+
+        self.file = OpenOptions::new().read(true).write(true).open(&file)?;
+        self.segments = segments;
+        self.slots = slots;
+        println!("[Journal] Rolled over to {} at log index {}", file, self.next);
+        Ok(())
+    }
+
+    /// Archives (or deletes) the full segments that end at or before `covered`. Returns their files.
+    pub fn retire(&mut self, covered: u64, mode: Retire) -> io::Result<Vec<String>> {
+        let count = self.segments.iter().take_while(|s| s.full && s.end() <= covered).count();
+        if count == 0 || mode == Retire::Keep {
+            return Ok(Vec::new());
+        }
+
+        // 1. Their flag changes stay with the journal (for upgrading snapshots from before they had them)
+        let (first, end) = (self.segments[0].first, self.segments[count - 1].end());
+        let log = LogReader { keys: self.keys.clone(), engine_id: self.engine_id, segments: self.segments[..count].to_vec() };
+        let mut changes = Vec::new();
+        for item in log.entries(first, end) {
+            let (_, entry) = item?;
+            if let ActionType::SetFlags = ActionType::from_u8(entry.action_type) {
+                changes.push(entry);
+            }
+        }
+        carry_flags(&changes)?;
+
+        // 2. Index next: from then on a failure only leaves a stray file behind
+        write_index(&self.segments[count..])?;
+        let retired: Vec<Segment> = self.segments.drain(..count).collect();
+
+        // 3. Then the files
+        if mode == Retire::Archive {
+            fs::create_dir_all(JOURNAL_ARCHIVE_DIR)?;
+        }
+        for segment in &retired {
+            let done = match mode {
+                Retire::Archive => fs::rename(&segment.file, Path::new(JOURNAL_ARCHIVE_DIR).join(&segment.file)),
+                _ => fs::remove_file(&segment.file),
+            };
+            if let Err(e) = done {
+                eprintln!("[Journal] Can't retire {}: {}", segment.file, e);
+            }
+        }
+        format::sync_dir()?;
+        if mode == Retire::Archive {
+            File::open(JOURNAL_ARCHIVE_DIR)?.sync_all()?;
+        }
+        Ok(retired.into_iter().map(|s| s.file).collect())
+    }
+}
+
+// --- UPGRADE ---
+/// history.bin from before segments (already brought up to date by format.rs) becomes the
+/// first segment, with room to spare, then goes to archive/. Called by format::upgrade_data_files.
+pub fn adopt_legacy(engine_id: &[u8; 16]) -> io::Result<()> {
+    let legacy = HISTORY.file;
+    if !Path::new(legacy).exists() {
+        return Ok(());
+    }
+
+    // Crashed between writing the index and moving history.bin away: it's already adopted
+    if read_index()?.is_empty() {
+        let bytes = fs::read(legacy)?;
+        let body = &bytes[FILE_HEADER_SIZE.min(bytes.len())..];
+        let entries = (body.len() / ENTRY_SIZE) as u64;
+
+        // Same seal name and log indexes, so the sealed bytes are copied as they are
+        let file = segment_file(1);
+        create_segment(&file, 0, entries + new_segment_slots(), engine_id, &body[..entries as usize * ENTRY_SIZE])?;
+        write_index(&[Segment { file: file.clone(), first: 0, entries: 0, full: false }])?;
+        println!("[Format] {}: {} entries moved to {}", legacy, entries, file);
+    }
+
+    fs::create_dir_all(JOURNAL_ARCHIVE_DIR)?;
+    fs::rename(legacy, Path::new(JOURNAL_ARCHIVE_DIR).join(legacy))?;
+    format::sync_dir()?;
+    println!("[Format] {} moved to {}/", legacy, JOURNAL_ARCHIVE_DIR);
+    Ok(())
+}
+
+/// Re-seals a segment's entries if any of them were sealed with an old key, and brings
+/// an older format up to date (the slots grow with LogEntry; the segment keeps its slot count)
+pub fn upgrade_segment(keys: &Keyring, segment: &Segment, engine_id: &[u8; 16]) -> io::Result<()> {
+    let bytes = fs::read(&segment.file)?;
+    let mut header = format::check_old_header(&bytes, &HISTORY, engine_id)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", segment.file, e)))?;
+    let version = header.version;
+    let slot_size = sealed_size(format::record_size(&HISTORY, version));
+    let slots: Vec<&[u8]> = bytes[FILE_HEADER_SIZE..].chunks_exact(slot_size).collect();
+
+    let used = |slot: &[u8]| slot.iter().any(|b| *b != 0);
+    let stale = slots.iter()
+        .take_while(|slot| used(slot))
+        .any(|slot| {
+            let header: SealHeader = bytemuck::pod_read_unaligned(&slot[..size_of::<SealHeader>()]);
+            header.magic == SEAL_MAGIC && header.key_id != keys.current()
+        });
+    if version == HISTORY.version && !stale {
+        return Ok(());
+    }
+
+    header.version = HISTORY.version;
+    header.record_size = HISTORY.record_size as u32;
+    let mut out = Vec::with_capacity(FILE_HEADER_SIZE + slots.len() * ENTRY_SIZE);
+    out.extend_from_slice(bytemuck::bytes_of(&header));
+    out.resize(FILE_HEADER_SIZE, 0);
+    let mut count = 0;
+    for (n, slot) in slots.iter().enumerate() {
+        if !used(slot) {
+            break;
+        }
+        let index = segment.first + n as u64;
+        match keys.open_record(LOG_SEAL_NAME, index, slot) {
+            Ok(plain) => {
+                let plain = format::migrate_record(HISTORY.kind, version, &plain);
+                out.extend_from_slice(&keys.seal_record(LOG_SEAL_NAME, index, &plain));
+                count += 1;
+            }
+            // A torn last entry goes, as LogReader::open() would have it
+            Err(_) if !slots[n + 1..].iter().any(|s| used(s)) => {
+                println!("[Format] {}: dropped a half-written last entry", segment.file);
+                break;
+            }
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{} entry {}: {}", segment.file, index, e))),
+        }
+    }
+    out.resize(FILE_HEADER_SIZE + slots.len() * ENTRY_SIZE, 0);
+    format::replace_file(&segment.file, &out)?;
+    println!("[Format] {}: {} entries, format v{} -> v{}, sealed with key {:08x}",
+        segment.file, count, version, HISTORY.version, keys.current());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENGINE: [u8; 16] = [7; 16];
+
+    fn deposits(users: std::ops::Range<u64>) -> Vec<LogEntry> {
+        users.map(|user_id| LogEntry::new(user_id, ActionType::Deposit, 0, 0, 100)).collect()
+    }
+
+    // A journal whose first segment holds 4 entries: 3 land there, the next batch of 3 rolls over
+    fn two_segments(keys: &Arc<Keyring>) -> SegmentWriter {
+        create_segment(&segment_file(1), 0, 4, &ENGINE, &[]).unwrap();
+        write_index(&[Segment { file: segment_file(1), first: 0, entries: 0, full: false }]).unwrap();
+        let mut writer = SegmentWriter::open(keys.clone(), ENGINE).unwrap();
+        writer.append(&deposits(0..3)).unwrap();
+        writer.append(&deposits(3..6)).unwrap();
+        writer
+    }
+
+    #[test]
+    fn appends_roll_over_and_replay_in_order() {
+        format::in_data_dir("segments-roll", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let writer = two_segments(&keys);
+            assert_eq!(writer.segments(), [
+                Segment { file: segment_file(1), first: 0, entries: 3, full: true },
+                Segment { file: segment_file(2), first: 3, entries: 0, full: false },
+            ]);
+            drop(writer);
+
+            // Reopened, it carries on in the second segment
+            let mut writer = SegmentWriter::open(keys.clone(), ENGINE).unwrap();
+            writer.append(&deposits(6..8)).unwrap();
+
+            let log = LogReader::open(keys.clone(), ENGINE).unwrap();
+            assert_eq!((log.first(), log.end()), (0, 8));
+            let read: Vec<(u64, u64)> = log.entries(0, 8).map(|e| e.map(|(idx, entry)| (idx, entry.user_id)).unwrap()).collect();
+            assert_eq!(read, (0..8).map(|n| (n, n)).collect::<Vec<_>>());
+            // Starting inside the second segment only opens that one
+            assert_eq!(log.entries(4, 6).map(|e| e.unwrap().0).collect::<Vec<_>>(), vec![4, 5]);
+        });
+    }
+
+    #[test]
+    fn retire_takes_only_segments_below_the_snapshot() {
+        format::in_data_dir("segments-retire", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let mut writer = two_segments(&keys);
+
+            // The first segment ends at log index 3: a snapshot at 2 still needs it
+            assert!(writer.retire(2, Retire::Delete).unwrap().is_empty());
+            assert!(writer.retire(3, Retire::Keep).unwrap().is_empty());
+            assert!(fs::exists(segment_file(1)).unwrap());
+
+            // The active segment stays, however far the snapshot reaches
+            assert_eq!(writer.retire(u64::MAX, Retire::Delete).unwrap(), vec![segment_file(1)]);
+            assert!(!fs::exists(segment_file(1)).unwrap());
+            assert!(fs::exists(segment_file(2)).unwrap());
+            assert_eq!(read_index().unwrap(), writer.segments());
+
+            let log = LogReader::open(keys.clone(), ENGINE).unwrap();
+            assert_eq!((log.first(), log.end()), (3, 6));
+            assert_eq!(log.entries(0, 6).map(|e| e.unwrap().0).collect::<Vec<_>>(), vec![3, 4, 5]);
+        });
+    }
+}
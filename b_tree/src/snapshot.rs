@@ -1,86 +1,529 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read, Write, BufWriter, BufReader};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::mem::size_of;
-use crate::consts::{SnapshotHeader, SnapshotStock};
-use crate::state::Portfolio; // Assuming Portfolio is in state.rs
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::consts::{
+    SnapshotHeader, SnapshotStock, SnapshotOrder, SnapshotTrailer, SnapshotReceipt, SnapshotFill, SnapshotFlags, FILE_HEADER_SIZE,
+    SNAPSHOT_MANIFEST, SNAPSHOT_ORDERS_MARKER, SNAPSHOT_RECEIPTS_MARKER, SNAPSHOT_FLAGS_MARKER, SNAPSHOT_TRAILER_MAGIC, SNAPSHOTS_KEPT,
+    RECEIPT_CASH, RECEIPT_ORDER, RECEIPT_TRANSFER, env_u64,
+};
+use crate::orderbook::{Order, Side};
+use crate::persister::Health;
+use crate::accounts::{encode_portfolio, Checkpoint};
+use crate::state::{DbMessage, OrderOutcome, OrderStatus, Portfolio, Receipt, Transfer};
+use crate::crypto::{Keyring, SealedReader, SealedWriter};
+use crate::format;
+use crate::SharedState;
+
+// Snapshots rotate: each one is snapshot-<last log index>.bin, and snapshots.manifest lists
+// the last SNAPSHOTS_KEPT of them (oldest first) with their record count and CRC.
+// Loading starts at the newest and falls back to older ones (= a longer replay) if it's bad.
+// Every snapshot is sealed under the name "snapshot.bin", whatever its file is called.
+const SEAL_NAME: &str = "snapshot.bin";
+
+// Everything needed to rebuild RAM without replaying the whole log
+#[derive(Default)]
+pub struct SnapshotData {
+    pub portfolios: HashMap<u64, Portfolio>,
+    pub portfolios_skipped: bool, // accounts.idx already holds them
+    pub orders: Vec<(u32, Order)>, // (symbol_id, order), in queue order
+    pub next_order_id: u64,
+    pub receipts: Vec<(u64, u128, u64, Receipt)>, // (user_id, request key, made at, receipt), oldest first
+    pub flags: BTreeMap<u64, u32>, // user_id -> flags, for every user the journal set them for
+    pub last_log_index: u64,
+    pub records: u64, // From the trailer
+    pub checksum: u32,
+}
+
+// One line of snapshots.manifest: "<file> <last log index> <records> <crc32c hex>"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub file: String,
+    pub last_log_index: u64,
+    pub records: u64,
+    pub checksum: u32,
+}
+
+pub fn snapshot_file(last_log_index: u64) -> String {
+    format!("snapshot-{:012}.bin", last_log_index)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// CRC32C of every byte that goes through (for the trailer)
+struct Checksummed<T> {
+    inner: T,
+    crc: u32,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, crc: 0 }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(data)?;
+        self.crc = crc32c::crc32c_append(self.crc, &data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.crc = crc32c::crc32c_append(self.crc, &out[..n]);
+        Ok(n)
+    }
+}
+
+// --- MANIFEST ---
+/// Oldest first. No manifest = no snapshots yet.
+pub fn read_manifest() -> io::Result<Vec<ManifestEntry>> {
+    let text = match fs::read_to_string(SNAPSHOT_MANIFEST) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let entry = match fields[..] {
+            [file, index, records, checksum] => (|| Some(ManifestEntry {
+                file: file.to_string(),
+                last_log_index: index.parse().ok()?,
+                records: records.parse().ok()?,
+                checksum: u32::from_str_radix(checksum, 16).ok()?,
+            }))(),
+            _ => None,
+        };
+        entries.push(entry.ok_or_else(|| invalid(format!("{} line {}: can't parse {:?}", SNAPSHOT_MANIFEST, n + 1, line)))?);
+    }
+    Ok(entries)
+}
+
+pub fn write_manifest(entries: &[ManifestEntry]) -> io::Result<()> {
+    let mut text = String::from("# file, last log index, records, crc32c (oldest first)\n");
+    for e in entries {
+        text.push_str(&format!("{} {} {} {:08x}\n", e.file, e.last_log_index, e.records, e.checksum));
+    }
+    format::replace_file(SNAPSHOT_MANIFEST, text.as_bytes())
+}
+
+// Lists `entry` as the newest snapshot, then forgets (and deletes) all but the last SNAPSHOTS_KEPT
+pub fn add_to_manifest(entry: ManifestEntry) -> io::Result<()> {
+    let mut entries = read_manifest().unwrap_or_else(|e| {
+        eprintln!("[Snapshot] {} unreadable ({}), starting a new one", SNAPSHOT_MANIFEST, e);
+        Vec::new()
+    });
+    entries.retain(|e| e.file != entry.file);
+    entries.push(entry);
+
+    let dropped: Vec<_> = entries.drain(..entries.len().saturating_sub(SNAPSHOTS_KEPT)).collect();
+    write_manifest(&entries)?;
+
+    // Only once the manifest no longer points at them
+    for old in dropped {
+        if let Err(e) = fs::remove_file(&old.file) {
+            eprintln!("[Snapshot] Can't delete {}: {}", old.file, e);
+        }
+    }
+    Ok(())
+}
+
+// --- TAKING ONE (without stalling trading) ---
+// RAM is frozen at an exact log index under a short write lock (changed accounts, the book),
+// then written out with no lock held. The trees only see the frozen copies, so the snapshot
+// and the checkpoint both describe history[..log_index] exactly, however trading moved on.
+const PERSIST_WAIT: Duration = Duration::from_secs(30);
+
+/// Snapshot + checkpoint, returns the log index they cover. Blocking (disk I/O).
+pub fn take_snapshot(state: &SharedState) -> io::Result<u64> {
+    // 1. Freeze (brief write lock)
+    let (checkpoint, orders, receipts, flags, next_order_id, keys, engine_id, journal) = {
+        let mut app = state.write().unwrap();
+        let app = &mut *app;
+        let checkpoint = app.portfolios.freeze(&app.users, app.next_lsn);
+        let orders: Vec<(u32, Order)> = app.books.iter()
+            .flat_map(|(symbol_id, book)| book.orders().map(|order| (*symbol_id, order.clone())))
+            .collect();
+        let receipts: Vec<_> = app.requests.iter()
+            .map(|(user_id, key, made_at, receipt)| (user_id, key, made_at, receipt.clone()))
+            .collect();
+        (checkpoint, orders, receipts, app.flags.clone(), app.next_order_id, app.keys.clone(), app.engine_id, app.journal.clone())
+    };
+
+    // 2. Don't claim more than the journal holds: wait for the persister to get there
+    let started = Instant::now();
+    let mut result = Ok(());
+    while journal.durable() < checkpoint.log_index {
+        // Those entries are never coming: RAM is about to be rolled back past them
+        if journal.state() == Health::Failed {
+            let reason = journal.health().reason.unwrap_or_default();
+            result = Err(io::Error::other(format!("the journal failed ({})", reason)));
+            break;
+        }
+        if started.elapsed() > PERSIST_WAIT {
+            result = Err(io::Error::new(io::ErrorKind::TimedOut, format!(
+                "the persister is stuck at log index {} (snapshot is at {})",
+                journal.durable(), checkpoint.log_index
+            )));
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // 3. Snapshot, then flush the trees at the same log index, so startup can trust them (no lock held)
+    let result = result
+        .and_then(|_| save_snapshot(&checkpoint, &orders, &receipts, &flags, next_order_id, &keys, &engine_id))
+        .and_then(|_| checkpoint.write());
+
+    // 4. Brief write lock again: drop what RAM no longer needs, or keep it dirty for next time
+    let mut app = state.write().unwrap();
+    match &result {
+        Ok(()) => {
+            app.portfolios.release(&checkpoint);
+            // Journal segments the oldest kept snapshot covers can go (best effort: the next one retries)
+            if let Some(oldest) = read_manifest().ok().and_then(|entries| entries.first().map(|e| e.last_log_index)) {
+                let _ = app.db_sender.try_send(DbMessage::Retire(oldest));
+            }
+        }
+        Err(_) => app.portfolios.restore(&checkpoint),
+    }
+    result.map(|_| checkpoint.log_index)
+}
+
+// --- TRIGGERS ---
+// A snapshot is taken when the interval is up, when enough log entries piled up since the
+// last one (that's what bounds replay after a crash), on shutdown, and when an admin asks.
+//   JDB_SNAPSHOT_SECS     interval (default 600)
+//   JDB_SNAPSHOT_ENTRIES  log entries since the last snapshot (default 100000, 0 = off)
+const DEFAULT_INTERVAL_SECS: u64 = 600;
+const DEFAULT_EVERY_ENTRIES: u64 = 100_000;
+const RETRY_SECS: u64 = 30; // After a failed one
+
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotStats {
+    pub last_index: u64,       // Log index of the newest snapshot (the loaded one, after startup)
+    pub last_at: u64,          // Unix seconds it was taken at (or startup)
+    pub last_duration_ms: u64, // 0 = not taken since startup
+    pub last_trigger: &'static str,
+    pub last_error: Option<String>, // From the latest attempt, if it failed
+    pub taken: u64,                 // Since startup
+    pub running: bool,
+}
+
+pub struct Snapshotter {
+    pub interval: Duration,
+    pub every_entries: u64, // 0 = off
+    stats: Mutex<SnapshotStats>,
+}
+
+impl Snapshotter {
+    pub fn from_env(loaded_index: u64) -> Self {
+        let interval = Duration::from_secs(env_u64("JDB_SNAPSHOT_SECS", DEFAULT_INTERVAL_SECS).max(1));
+        let every_entries = env_u64("JDB_SNAPSHOT_ENTRIES", DEFAULT_EVERY_ENTRIES);
+        let stats = SnapshotStats {
+            last_index: loaded_index,
+            last_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            last_trigger: "startup",
+            ..Default::default()
+        };
+        Self { interval, every_entries, stats: Mutex::new(stats) }
+    }
+
+    pub fn stats(&self) -> SnapshotStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Which trigger fired, if any (`next_lsn` = where the log is now).
+    pub fn due(&self, next_lsn: u64) -> Option<&'static str> {
+        let stats = self.stats.lock().unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if stats.running || (stats.last_error.is_some() && now.saturating_sub(stats.last_at) < RETRY_SECS) {
+            None
+        } else if self.every_entries > 0 && next_lsn.saturating_sub(stats.last_index) >= self.every_entries {
+            Some("entries")
+        } else if now.saturating_sub(stats.last_at) >= self.interval.as_secs() && next_lsn > stats.last_index {
+            Some("interval") // Nothing new = nothing to save
+        } else {
+            None
+        }
+    }
+
+    /// Takes one now (one at a time: errors if another is running). Blocking (disk I/O).
+    pub fn run(&self, state: &SharedState, trigger: &'static str) -> io::Result<SnapshotStats> {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            if stats.running {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "a snapshot is already being taken"));
+            }
+            stats.running = true;
+        }
+
+        println!("[Snapshot] Saving state ({})...", trigger);
+        let started = Instant::now();
+        let result = take_snapshot(state);
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.running = false;
+        stats.last_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        match result {
+            Ok(index) => {
+                stats.last_index = index;
+                stats.last_duration_ms = started.elapsed().as_millis() as u64;
+                println!("[Snapshot] Done in {} ms", stats.last_duration_ms);
+                stats.last_trigger = trigger;
+                stats.last_error = None;
+                stats.taken += 1;
+                Ok(stats.clone())
+            }
+            Err(e) => {
+                // last_at moves on anyway, so a failing snapshot isn't retried in a tight loop
+                stats.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
 
 // --- SAVING (Dump RAM to Disk) ---
 pub fn save_snapshot(
-    portfolios: &HashMap<u64, Portfolio>, 
-    last_log_index: u64
+    portfolios: &Checkpoint,
+    orders: &[(u32, Order)],
+    receipts: &[(u64, u128, u64, Receipt)],
+    flags: &BTreeMap<u64, u32>,
+    next_order_id: u64,
+    keys: &Keyring,
+    engine_id: &[u8; 16],
 ) -> io::Result<()> {
-    
+    let last_log_index = portfolios.log_index;
+    let file_name = snapshot_file(last_log_index);
+
     // We use a temporary file to avoid corruption if we crash mid-write
-    let file = File::create("snapshot.bin.tmp")?;
-    let mut writer = BufWriter::new(file);
+    let tmp_name = format!("{}.tmp", file_name);
+    let file = File::create(&tmp_name)?;
+    let mut out = BufWriter::new(file);
+
+    // 0. File header (plaintext), then everything else sealed
+    let created_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    out.write_all(bytemuck::bytes_of(&format::new_header(&format::SNAPSHOT, engine_id, created_at)))?;
+    let mut writer = Checksummed::new(SealedWriter::new(out, keys, SEAL_NAME));
 
     // 1. Write the "Last Log Index" first
     // This tells us: "This snapshot includes all history up to Log #X"
     writer.write_all(&last_log_index.to_le_bytes())?;
 
-    // 2. Iterate through every user in RAM
-    for (user_id, portfolio) in portfolios {
-        // A. Create and Write Header
-        let header = SnapshotHeader {
-            user_id: *user_id,
-            cash: portfolio.cash,
-            num_stocks: portfolio.stocks.len() as u32,
-            _padding: [0; 4],            
+    // 2. Iterate through every account (B-Tree + the frozen changes)
+    let mut result = Ok(());
+    let mut records = 0u64;
+    portfolios.for_each(|user_id, portfolio| {
+        if result.is_ok() {
+            // Same record layout accounts.idx stores: [SnapshotHeader | SnapshotStock * N]
+            result = writer.write_all(&encode_portfolio(user_id, portfolio));
+            records += 1;
+        }
+    })?;
+    result?;
+
+    // 3. Resting orders, so the book survives a restart.
+    // The marker reuses SnapshotHeader: cash = next order ID, num_stocks = order count
+    let num_orders = orders.len();
+    let marker = SnapshotHeader {
+        user_id: SNAPSHOT_ORDERS_MARKER,
+        cash: next_order_id as i64,
+        num_stocks: num_orders as u32,
+        _padding: [0; 4],
+    };
+    writer.write_all(bytemuck::bytes_of(&marker))?;
+
+    for (symbol_id, order) in orders {
+        let record = SnapshotOrder {
+            order_id: order.id,
+            user_id: order.user_id,
+            symbol_id: *symbol_id,
+            side: if order.side == Side::Buy { 0 } else { 1 },
+            _padding: [0; 3],
+            price: order.price,
+            quantity: order.quantity,
         };
-        
-        let header_bytes = bytemuck::bytes_of(&header);
-        writer.write_all(header_bytes)?;
-
-        // B. Write Stock List
-        for (symbol_id, qty) in &portfolio.stocks {
-            let stock = SnapshotStock {
-                symbol_id: *symbol_id,
-                _padding: [0; 4],
-                quantity: *qty,
-            };
-            let stock_bytes = bytemuck::bytes_of(&stock);
-            writer.write_all(stock_bytes)?;
-        }
-    }
-
-    writer.flush()?;
-    
-    // 3. atomic swap (Rename tmp -> actual)
-    // This ensures snapshot.bin is never half-written.
-    std::fs::rename("snapshot.bin.tmp", "snapshot.bin")?;
-    
-    println!("Snapshot saved. Last Log Index: {}", last_log_index);
+        writer.write_all(bytemuck::bytes_of(&record))?;
+    }
+    records += num_orders as u64;
+
+    // 4. Receipts of keyed requests: the segments they were journaled in may be retired
+    let marker = SnapshotHeader {
+        user_id: SNAPSHOT_RECEIPTS_MARKER,
+        cash: 0,
+        num_stocks: receipts.len() as u32,
+        _padding: [0; 4],
+    };
+    writer.write_all(bytemuck::bytes_of(&marker))?;
+    for (user_id, key, made_at, receipt) in receipts {
+        writer.write_all(&encode_receipt(*user_id, *key, *made_at, receipt))?;
+    }
+    records += receipts.len() as u64;
+
+    // 5. Flags: replay starts here, not where they were set
+    let marker = SnapshotHeader {
+        user_id: SNAPSHOT_FLAGS_MARKER,
+        cash: 0,
+        num_stocks: flags.len() as u32,
+        _padding: [0; 4],
+    };
+    writer.write_all(bytemuck::bytes_of(&marker))?;
+    for (&user_id, &flags) in flags {
+        writer.write_all(bytemuck::bytes_of(&SnapshotFlags { user_id, flags, _padding: [0; 4] }))?;
+    }
+    records += flags.len() as u64;
+
+    // 6. Trailer: how many records, and the CRC of everything above
+    let trailer = SnapshotTrailer { magic: SNAPSHOT_TRAILER_MAGIC, checksum: writer.crc, records };
+    let mut sealed = writer.inner;
+    sealed.write_all(bytemuck::bytes_of(&trailer))?;
+
+    // 7. On disk for real before it replaces anything
+    let file = sealed.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    // 8. atomic swap (Rename tmp -> actual), then make the rename itself durable
+    // This ensures a snapshot file is never half-written.
+    fs::rename(&tmp_name, &file_name)?;
+    format::sync_dir()?;
+
+    // 9. Rotate
+    add_to_manifest(ManifestEntry { file: file_name, last_log_index, records, checksum: trailer.checksum })?;
+
+    println!("Snapshot saved. Last Log Index: {} ({} records)", last_log_index, records);
     Ok(())
 }
 
 // --- LOADING (Restore RAM from Disk) ---
-pub fn load_snapshot() -> io::Result<(HashMap<u64, Portfolio>, u64)> {
-    let file = match File::open("snapshot.bin") {
-        Ok(f) => f,
-        Err(_) => return Ok((HashMap::new(), 0)), // File doesn't exist yet
-    };
+// If accounts.idx was checkpointed at `synced_index` and it matches the snapshot used,
+// the balances are skipped (the tree already has them) and only the book is loaded.
+// Errors only if the manifest itself is unreadable: bad snapshots are skipped.
+pub fn load_snapshot(synced_index: u64, keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<SnapshotData> {
+    let manifest = read_manifest()?;
+
+    // Newest first. An older snapshot just means replaying more of the log.
+    for entry in manifest.iter().rev() {
+        let data = read_snapshot(&entry.file, synced_index, keys, engine_id).and_then(|data| {
+            if (data.last_log_index, data.records, data.checksum) != (entry.last_log_index, entry.records, entry.checksum) {
+                return Err(invalid(format!(
+                    "it holds log index {}, {} records, CRC {:08x}; the manifest says {}, {}, {:08x}",
+                    data.last_log_index, data.records, data.checksum, entry.last_log_index, entry.records, entry.checksum
+                )));
+            }
+            Ok(data)
+        });
+        match data {
+            Ok(data) => {
+                println!("Snapshot loaded from {}. Resuming from Log Index: {}", entry.file, data.last_log_index);
+                return Ok(data);
+            }
+            Err(e) => eprintln!("[Snapshot] {} unusable ({}), falling back to the one before", entry.file, e),
+        }
+    }
+
+    if !manifest.is_empty() {
+        eprintln!("[Snapshot] No usable snapshot left, replaying the whole log");
+    }
+    Ok(SnapshotData { portfolios_skipped: synced_index == 0, ..Default::default() })
+}
+
+/// Without a manifest: the newest snapshot-*.bin on disk that reads back whole (header, seal,
+/// trailer). None at all means replaying the whole log, which restore() refuses if it's been retired.
+pub fn find_snapshot(synced_index: u64, keys: &Keyring, engine_id: &[u8; 16]) -> SnapshotData {
+    let mut files: Vec<(u64, String)> = fs::read_dir(".").into_iter().flatten().flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let index = name.strip_prefix("snapshot-")?.strip_suffix(".bin")?.parse().ok()?;
+            Some((index, name))
+        })
+        .collect();
+    files.sort_unstable_by(|a, b| b.cmp(a));
+
+    for (index, file) in files {
+        match read_snapshot(&file, synced_index, keys, engine_id) {
+            Ok(data) if data.last_log_index == index => {
+                println!("Snapshot loaded from {} (not in the manifest). Resuming from Log Index: {}", file, index);
+                return data;
+            }
+            Ok(data) => eprintln!("[Snapshot] {} unusable (it holds log index {})", file, data.last_log_index),
+            Err(e) => eprintln!("[Snapshot] {} unusable ({})", file, e),
+        }
+    }
+    eprintln!("[Snapshot] No usable snapshot file, replaying the whole log");
+    SnapshotData { portfolios_skipped: synced_index == 0, ..Default::default() }
+}
 
-    let mut reader = BufReader::new(file);
-    let mut portfolios = HashMap::new();
+/// Reads and checks one snapshot file (header, seal, trailer).
+pub fn read_snapshot(file_name: &str, synced_index: u64, keys: &Keyring, engine_id: &[u8; 16]) -> io::Result<SnapshotData> {
+    let file = File::open(file_name)?;
+
+    // 0. File header: ours, current format
+    let mut file = BufReader::new(file);
+    let mut header_buf = [0u8; FILE_HEADER_SIZE];
+    file.read_exact(&mut header_buf)?;
+    format::check_header(&header_buf, &format::SNAPSHOT, engine_id)?;
+
+    let mut reader = Checksummed::new(SealedReader::new(file, keys, SEAL_NAME));
+    let mut data = SnapshotData::default();
+    let mut records = 0u64;
 
     // 1. Read the "Last Log Index" (first 8 bytes)
     let mut idx_buf = [0u8; 8];
     reader.read_exact(&mut idx_buf)?;
-    let last_log_index = u64::from_le_bytes(idx_buf);
+    data.last_log_index = u64::from_le_bytes(idx_buf);
+    data.portfolios_skipped = synced_index == data.last_log_index;
 
-    // 2. Loop until EOF
+    // 2. Loop until the orders marker
     loop {
         // Try to read a Header
         let mut header_buf = [0u8; size_of::<SnapshotHeader>()];
         match reader.read_exact(&mut header_buf) {
             Ok(_) => {},
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break, // Done
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(invalid("ends before its open orders section".to_string()));
+            }
             Err(e) => return Err(e),
         }
 
         let header: SnapshotHeader = bytemuck::cast(header_buf);
-        
+
+        // 3. Open orders section (always last)
+        if header.user_id == SNAPSHOT_ORDERS_MARKER {
+            data.next_order_id = header.cash as u64;
+            for _ in 0..header.num_stocks {
+                let mut order_buf = [0u8; size_of::<SnapshotOrder>()];
+                reader.read_exact(&mut order_buf)?;
+                let record: SnapshotOrder = bytemuck::pod_read_unaligned(&order_buf);
+                data.orders.push((record.symbol_id, Order {
+                    id: record.order_id,
+                    user_id: record.user_id,
+                    side: if record.side == 0 { Side::Buy } else { Side::Sell },
+                    price: record.price,
+                    quantity: record.quantity,
+                }));
+            }
+            records += header.num_stocks as u64;
+            break;
+        }
+
         // Reconstruct Portfolio
         let mut stocks = HashMap::new();
 
@@ -91,13 +534,393 @@ pub fn load_snapshot() -> io::Result<(HashMap<u64, Portfolio>, u64)> {
             let stock: SnapshotStock = bytemuck::cast(stock_buf);
             stocks.insert(stock.symbol_id, stock.quantity);
         }
+        records += 1;
 
-        portfolios.insert(header.user_id, Portfolio {
+        if data.portfolios_skipped {
+            continue;
+        }
+        data.portfolios.insert(header.user_id, Portfolio {
             cash: header.cash,
             stocks,
+            ..Default::default()
+        });
+    }
+
+    // 4. Receipts section (always right after the orders)
+    let mut marker_buf = [0u8; size_of::<SnapshotHeader>()];
+    reader.read_exact(&mut marker_buf)?;
+    let marker: SnapshotHeader = bytemuck::cast(marker_buf);
+    if marker.user_id != SNAPSHOT_RECEIPTS_MARKER {
+        return Err(invalid("no receipts section after the open orders".to_string()));
+    }
+    for _ in 0..marker.num_stocks {
+        data.receipts.push(read_receipt(&mut reader)?);
+    }
+    records += marker.num_stocks as u64;
+
+    // 5. Flags section (right after the receipts)
+    reader.read_exact(&mut marker_buf)?;
+    let marker: SnapshotHeader = bytemuck::cast(marker_buf);
+    if marker.user_id != SNAPSHOT_FLAGS_MARKER {
+        return Err(invalid("no flags section after the receipts".to_string()));
+    }
+    for _ in 0..marker.num_stocks {
+        let mut flags_buf = [0u8; size_of::<SnapshotFlags>()];
+        reader.read_exact(&mut flags_buf)?;
+        let record: SnapshotFlags = bytemuck::pod_read_unaligned(&flags_buf);
+        data.flags.insert(record.user_id, record.flags);
+    }
+    records += marker.num_stocks as u64;
+
+    // 6. Trailer: must add up, and be the very end
+    let checksum = reader.crc;
+    let mut trailer_buf = [0u8; size_of::<SnapshotTrailer>()];
+    reader.read_exact(&mut trailer_buf)?;
+    let trailer: SnapshotTrailer = bytemuck::pod_read_unaligned(&trailer_buf);
+    if trailer.magic != SNAPSHOT_TRAILER_MAGIC {
+        return Err(invalid("no trailer".to_string()));
+    }
+    if trailer.records != records || trailer.checksum != checksum {
+        return Err(invalid(format!(
+            "trailer says {} records / CRC {:08x}, found {} / {:08x}",
+            trailer.records, trailer.checksum, records, checksum
+        )));
+    }
+    if reader.read(&mut [0u8; 1])? != 0 {
+        return Err(invalid("data after the trailer".to_string()));
+    }
+
+    data.records = records;
+    data.checksum = checksum;
+    Ok(data)
+}
+
+// --- RECEIPTS ---
+// [SnapshotReceipt | SnapshotFill * num_fills] each
+
+fn encode_receipt(user_id: u64, key: u128, made_at: u64, receipt: &Receipt) -> Vec<u8> {
+    let mut record = SnapshotReceipt {
+        user_id,
+        key: key.to_le_bytes(),
+        made_at,
+        kind: RECEIPT_CASH,
+        status: 0,
+        _padding: [0; 2],
+        symbol_id: 0,
+        id: 0,
+        amount: 0,
+        resting: 0,
+        expired: 0,
+        num_fills: 0,
+    };
+    let mut fills = Vec::new();
+    match receipt {
+        Receipt::Cash => {}
+        Receipt::Order(outcome) => {
+            record.kind = RECEIPT_ORDER;
+            record.status = outcome.status.code();
+            record.id = outcome.order_id;
+            record.amount = outcome.filled;
+            record.resting = outcome.resting;
+            record.expired = outcome.expired;
+            record.num_fills = outcome.fills.len() as u64;
+            fills = outcome.fills.iter().map(|&(price, quantity)| SnapshotFill { price, quantity }).collect();
+        }
+        Receipt::Transfer(transfer) => {
+            record.kind = RECEIPT_TRANSFER;
+            record.status = transfer.is_cash as u8;
+            record.symbol_id = transfer.symbol_id;
+            record.id = transfer.to;
+            record.amount = transfer.amount;
+        }
+    }
+
+    let mut bytes = bytemuck::bytes_of(&record).to_vec();
+    bytes.extend_from_slice(bytemuck::cast_slice(&fills));
+    bytes
+}
+
+fn read_receipt(reader: &mut impl Read) -> io::Result<(u64, u128, u64, Receipt)> {
+    let mut buf = [0u8; size_of::<SnapshotReceipt>()];
+    reader.read_exact(&mut buf)?;
+    let record: SnapshotReceipt = bytemuck::pod_read_unaligned(&buf);
+
+    let receipt = match record.kind {
+        RECEIPT_CASH => Receipt::Cash,
+        RECEIPT_ORDER => {
+            let mut fills = Vec::new();
+            for _ in 0..record.num_fills {
+                let mut fill_buf = [0u8; size_of::<SnapshotFill>()];
+                reader.read_exact(&mut fill_buf)?;
+                let fill: SnapshotFill = bytemuck::pod_read_unaligned(&fill_buf);
+                fills.push((fill.price, fill.quantity));
+            }
+            Receipt::Order(OrderOutcome {
+                order_id: record.id,
+                status: OrderStatus::from_code(record.status),
+                fills,
+                filled: record.amount,
+                resting: record.resting,
+                expired: record.expired,
+            })
+        }
+        RECEIPT_TRANSFER => Receipt::Transfer(Transfer {
+            to: record.id,
+            symbol_id: record.symbol_id,
+            is_cash: record.status != 0,
+            amount: record.amount,
+        }),
+        kind => return Err(invalid(format!("receipt of unknown kind {}", kind))),
+    };
+    Ok((record.user_id, u128::from_le_bytes(record.key), record.made_at, receipt))
+}
+
+// --- FORMAT UPGRADES (see format.rs) ---
+/// v1 -> v2: the trailer (and the orders marker, if the snapshot is older than that too).
+pub fn add_trailer(mut plain: Vec<u8>) -> io::Result<Vec<u8>> {
+    let too_short = || invalid(format!("{} is cut off", SEAL_NAME));
+    let mut pos = 8; // Last log index
+    let mut records = 0u64;
+    if plain.len() < pos {
+        return Err(too_short());
+    }
+
+    loop {
+        if pos == plain.len() {
+            // From before open orders were saved: an empty section
+            let marker = SnapshotHeader { user_id: SNAPSHOT_ORDERS_MARKER, cash: 0, num_stocks: 0, _padding: [0; 4] };
+            plain.extend_from_slice(bytemuck::bytes_of(&marker));
+            pos = plain.len();
+            break;
+        }
+        let bytes = plain.get(pos..pos + size_of::<SnapshotHeader>()).ok_or_else(too_short)?;
+        let header: SnapshotHeader = bytemuck::pod_read_unaligned(bytes);
+        let marker = header.user_id == SNAPSHOT_ORDERS_MARKER;
+        let item = if marker { size_of::<SnapshotOrder>() } else { size_of::<SnapshotStock>() };
+
+        pos += size_of::<SnapshotHeader>() + header.num_stocks as usize * item;
+        if pos > plain.len() {
+            return Err(too_short());
+        }
+        if marker {
+            records += header.num_stocks as u64;
+            break;
+        }
+        records += 1;
+    }
+    if pos != plain.len() {
+        return Err(invalid(format!("{} has {} unexpected bytes after its open orders", SEAL_NAME, plain.len() - pos)));
+    }
+
+    let trailer = SnapshotTrailer { magic: SNAPSHOT_TRAILER_MAGIC, checksum: crc32c::crc32c(&plain), records };
+    plain.extend_from_slice(bytemuck::bytes_of(&trailer));
+    Ok(plain)
+}
+
+/// v2 -> v3: an empty receipts section before the trailer (which is redone: the CRC changes).
+/// Requests keyed before the upgrade had 64-bit keys, which no retry matches any more anyway.
+pub fn add_receipts(mut plain: Vec<u8>) -> io::Result<Vec<u8>> {
+    let Some(end) = plain.len().checked_sub(size_of::<SnapshotTrailer>()) else {
+        return Err(invalid(format!("{} is cut off", SEAL_NAME)));
+    };
+    let trailer: SnapshotTrailer = bytemuck::pod_read_unaligned(&plain[end..]);
+    if trailer.magic != SNAPSHOT_TRAILER_MAGIC || trailer.checksum != crc32c::crc32c(&plain[..end]) {
+        return Err(invalid(format!("{} has no valid trailer", SEAL_NAME)));
+    }
+    plain.truncate(end);
+
+    let marker = SnapshotHeader { user_id: SNAPSHOT_RECEIPTS_MARKER, cash: 0, num_stocks: 0, _padding: [0; 4] };
+    plain.extend_from_slice(bytemuck::bytes_of(&marker));
+    let trailer = SnapshotTrailer { checksum: crc32c::crc32c(&plain), ..trailer };
+    plain.extend_from_slice(bytemuck::bytes_of(&trailer));
+    Ok(plain)
+}
+
+/// v3 -> v4: the flags section before the trailer, with the flags as of the snapshot's log index.
+pub fn add_flags(mut plain: Vec<u8>, flags: &BTreeMap<u64, u32>) -> io::Result<Vec<u8>> {
+    let Some(end) = plain.len().checked_sub(size_of::<SnapshotTrailer>()) else {
+        return Err(invalid(format!("{} is cut off", SEAL_NAME)));
+    };
+    let trailer: SnapshotTrailer = bytemuck::pod_read_unaligned(&plain[end..]);
+    if trailer.magic != SNAPSHOT_TRAILER_MAGIC || trailer.checksum != crc32c::crc32c(&plain[..end]) {
+        return Err(invalid(format!("{} has no valid trailer", SEAL_NAME)));
+    }
+    plain.truncate(end);
+
+    let marker = SnapshotHeader { user_id: SNAPSHOT_FLAGS_MARKER, cash: 0, num_stocks: flags.len() as u32, _padding: [0; 4] };
+    plain.extend_from_slice(bytemuck::bytes_of(&marker));
+    for (&user_id, &flags) in flags {
+        plain.extend_from_slice(bytemuck::bytes_of(&SnapshotFlags { user_id, flags, _padding: [0; 4] }));
+    }
+    let trailer = SnapshotTrailer { checksum: crc32c::crc32c(&plain), records: trailer.records + flags.len() as u64, ..trailer };
+    plain.extend_from_slice(bytemuck::bytes_of(&trailer));
+    Ok(plain)
+}
+
+/// Log index a (plaintext) snapshot stream is at: its first 8 bytes
+pub fn stream_log_index(plain: &[u8]) -> io::Result<u64> {
+    let bytes = plain.get(..8).ok_or_else(|| invalid(format!("{} is cut off", SEAL_NAME)))?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// The trailer of a (current format) snapshot: its manifest entry has to carry the same count and CRC
+pub fn trailer(plain: &[u8]) -> Option<SnapshotTrailer> {
+    let start = plain.len().checked_sub(size_of::<SnapshotTrailer>())?;
+    Some(bytemuck::pod_read_unaligned(&plain[start..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENGINE: [u8; 16] = [7; 16];
+
+    // A v1 snapshot stream: the log index, then one account per user (cash = 100 * user_id)
+    fn v1_stream(log_index: u64, users: u64) -> Vec<u8> {
+        let mut plain = log_index.to_le_bytes().to_vec();
+        for user_id in 1..=users {
+            let portfolio = Portfolio { cash: 100 * user_id as i64, stocks: HashMap::from([(7, 5)]), ..Default::default() };
+            plain.extend(encode_portfolio(user_id, &portfolio));
+        }
+        plain
+    }
+
+    // Upgraded to the current format, the way format.rs does it
+    fn current_stream(log_index: u64, users: u64, flags: &BTreeMap<u64, u32>) -> Vec<u8> {
+        let plain = add_trailer(v1_stream(log_index, users)).unwrap();
+        add_flags(add_receipts(plain).unwrap(), flags).unwrap()
+    }
+
+    // Seals it into snapshot-<log index>.bin and lists it in the manifest
+    fn write_snapshot(keys: &Keyring, plain: &[u8]) -> String {
+        let file = snapshot_file(stream_log_index(plain).unwrap());
+        let out = bytemuck::bytes_of(&format::new_header(&format::SNAPSHOT, &ENGINE, 0)).to_vec();
+        let mut writer = SealedWriter::new(out, keys, SEAL_NAME);
+        writer.write_all(plain).unwrap();
+        fs::write(&file, writer.finish().unwrap()).unwrap();
+        let trailer = trailer(plain).unwrap();
+        let last_log_index = stream_log_index(plain).unwrap();
+        add_to_manifest(ManifestEntry { file: file.clone(), last_log_index, records: trailer.records, checksum: trailer.checksum }).unwrap();
+        file
+    }
+
+    #[test]
+    fn upgraded_stream_reads_back() {
+        format::in_data_dir("snapshot-upgrade", || {
+            let keys = Keyring::load("data.key").unwrap();
+            let flags = BTreeMap::from([(2, 5), (3, 1)]);
+            let file = write_snapshot(&keys, &current_stream(40, 3, &flags));
+
+            let data = read_snapshot(&file, 0, &keys, &ENGINE).unwrap();
+            assert_eq!(data.last_log_index, 40);
+            assert_eq!(data.portfolios.len(), 3);
+            assert_eq!(data.portfolios[&2].cash, 200);
+            assert_eq!(data.portfolios[&2].stocks, HashMap::from([(7, 5)]));
+            assert!(data.orders.is_empty() && data.receipts.is_empty());
+            assert_eq!(data.flags, flags);
+            assert_eq!(data.records, 3 + 2);
+
+            // accounts.idx already at this log index: the balances are skipped
+            let data = read_snapshot(&file, 40, &keys, &ENGINE).unwrap();
+            assert!(data.portfolios_skipped && data.portfolios.is_empty());
+        });
+    }
+
+    #[test]
+    fn trailer_has_to_add_up() {
+        format::in_data_dir("snapshot-trailer", || {
+            let keys = Keyring::load("data.key").unwrap();
+            let plain = current_stream(40, 3, &BTreeMap::new());
+            let end = plain.len() - size_of::<SnapshotTrailer>();
+            let good = trailer(&plain).unwrap();
+
+            for bad in [
+                SnapshotTrailer { records: good.records + 1, ..good },
+                SnapshotTrailer { checksum: good.checksum ^ 1, ..good },
+            ] {
+                let mut plain = plain.clone();
+                plain[end..].copy_from_slice(bytemuck::bytes_of(&bad));
+                let file = write_snapshot(&keys, &plain);
+                let Err(err) = read_snapshot(&file, 0, &keys, &ENGINE) else { panic!("read past a bad trailer") };
+                assert!(err.to_string().contains("trailer says"), "{}", err);
+            }
+        });
+    }
+
+    #[test]
+    fn damaged_newest_snapshot_falls_back() {
+        format::in_data_dir("snapshot-fallback", || {
+            let keys = Keyring::load("data.key").unwrap();
+            write_snapshot(&keys, &current_stream(10, 1, &BTreeMap::new()));
+            write_snapshot(&keys, &current_stream(20, 2, &BTreeMap::new()));
+            let newest = write_snapshot(&keys, &current_stream(30, 3, &BTreeMap::new()));
+            assert_eq!(load_snapshot(0, &keys, &ENGINE).unwrap().last_log_index, 30);
+
+            // 1. A flipped byte in the newest file
+            let mut bytes = fs::read(&newest).unwrap();
+            let last = bytes.len() - 1;
+            bytes[last] ^= 1;
+            fs::write(&newest, bytes).unwrap();
+            let data = load_snapshot(0, &keys, &ENGINE).unwrap();
+            assert_eq!((data.last_log_index, data.portfolios.len()), (20, 2));
+
+            // 2. A file that reads fine but isn't what the manifest says
+            let mut manifest = read_manifest().unwrap();
+            manifest[1].records += 1;
+            write_manifest(&manifest).unwrap();
+            assert_eq!(load_snapshot(0, &keys, &ENGINE).unwrap().last_log_index, 10);
+        });
+    }
+
+    #[test]
+    fn manifest_keeps_the_newest_few() {
+        format::in_data_dir("snapshot-manifest", || {
+            let files: Vec<String> = (1..=SNAPSHOTS_KEPT as u64 + 2).map(|n| snapshot_file(n * 10)).collect();
+            for (n, file) in files.iter().enumerate() {
+                fs::write(file, b"").unwrap();
+                add_to_manifest(ManifestEntry { file: file.clone(), last_log_index: (n as u64 + 1) * 10, records: 0, checksum: 0 }).unwrap();
+            }
+
+            let kept: Vec<String> = read_manifest().unwrap().into_iter().map(|e| e.file).collect();
+            assert_eq!(kept, files[2..]);
+            for (n, file) in files.iter().enumerate() {
+                assert_eq!(fs::exists(file).unwrap(), n >= 2, "{}", file);
+            }
         });
     }
 
-    println!("Snapshot loaded. Resuming from Log Index: {}", last_log_index);
-    Ok((portfolios, last_log_index))
-}
\ No newline at end of file
+    #[test]
+    fn receipts_round_trip() {
+        let receipts = [
+            Receipt::Cash,
+            Receipt::Order(OrderOutcome {
+                order_id: 42,
+                status: OrderStatus::PartiallyFilled,
+                fills: vec![(10, 3), (11, 2)],
+                filled: 5,
+                resting: 4,
+                expired: 0,
+            }),
+            Receipt::Order(OrderOutcome {
+                order_id: 43,
+                status: OrderStatus::Rejected("Would Cross"),
+                fills: Vec::new(),
+                filled: 0,
+                resting: 0,
+                expired: 0,
+            }),
+            Receipt::Transfer(Transfer { to: 9, symbol_id: 7, is_cash: false, amount: 25 }),
+        ];
+
+        let mut bytes = Vec::new();
+        for (user_id, receipt) in receipts.iter().enumerate() {
+            bytes.extend(encode_receipt(user_id as u64, u128::MAX - user_id as u128, 1_700_000_000, receipt));
+        }
+        let mut reader = &bytes[..];
+        for (user_id, receipt) in receipts.iter().enumerate() {
+            let read = read_receipt(&mut reader).unwrap();
+            assert_eq!(read, (user_id as u64, u128::MAX - user_id as u128, 1_700_000_000, receipt.clone()));
+        }
+        assert!(reader.is_empty());
+    }
+}
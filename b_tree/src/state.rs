@@ -1,14 +1,34 @@
 use tokio::sync::mpsc::Sender; // Import Sender
-use std::collections::HashMap;
-use crate::consts::{UserMeta, LogEntry};
+use tokio::sync::oneshot;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::consts::{
+    env_u64, UserMeta, LogEntry, ActionType, ACCOUNTS_POOL_FRAMES, LOG_FLAG_IN_TXN, REJECT_CANNOT_FILL,
+    REJECT_REASONS, REJECT_WOULD_CROSS, REQUEST_TTL_SECS, USERS_POOL_FRAMES, USER_ADMIN,
+    USER_FROZEN,
+};
+use crate::orderbook::{Fill, NewOrder, Order, OrderBook, OrderType, Side, TimeInForce};
+use crate::accounts::{user_name, Accounts, UserIndex};
+use crate::apikeys::ApiKeys;
+use crate::crypto::Keyring;
+use crate::auth::{RequestHasher, Sessions};
 use crate::reader::DatabaseReader;
-use crate::snapshot::load_snapshot;
+use crate::persister::{Health, Journal};
+use crate::snapshot::{find_snapshot, load_snapshot, SnapshotData, Snapshotter};
 
 // 1. Define the Message Type (What can we send to the disk?)
 #[derive(Debug)]
 pub enum DbMessage {
     WriteLog(LogEntry),
     WriteUser(UserMeta),
+    // Answered once everything sent before it is fsynced: Ok(log entries on disk), or why not
+    Ack(oneshot::Sender<Result<u64, String>>),
+    // RAM was rolled back after a failed write, the persister may write again (persister.rs)
+    Resume,
+    // The oldest kept snapshot reaches this log index: segments before it can go (segments.rs)
+    Retire(u64),
 }
 
 // 2. Add Sender to AppState
@@ -16,58 +36,1580 @@ pub enum DbMessage {
 pub struct Portfolio {
     pub cash: i64,
     pub stocks: HashMap<u32, i64>,
+    // Locked up by resting orders. Not persisted: rebuilt from the book.
+    pub reserved_cash: i64,
+    pub reserved_stocks: HashMap<u32, i64>,
 }
 
+impl Portfolio {
+    pub fn available_cash(&self) -> i64 {
+        self.cash - self.reserved_cash
+    }
+
+    pub fn available_stock(&self, symbol_id: u32) -> i64 {
+        self.stocks.get(&symbol_id).copied().unwrap_or(0)
+            - self.reserved_stocks.get(&symbol_id).copied().unwrap_or(0)
+    }
+
+    /// What the journal entry at log index `idx` does to cash and holdings (the book is AppState's business).
+    /// Fails, changing nothing, if that takes a balance out of the i64 range.
+    pub fn apply(&mut self, idx: u64, entry: &LogEntry) -> io::Result<()> {
+        let action = ActionType::from_u8(entry.action_type);
+        let (cash, stock) = match action {
+            ActionType::Deposit => (Some(entry.amount_money), 0),
+            ActionType::Withdraw => (entry.amount_money.checked_neg(), 0),
+            ActionType::Transfer => (Some(entry.amount_money), entry.quantity),
+            ActionType::Issue => (Some(0), entry.quantity),
+            // amount_money is signed: buyer pays (+), seller receives (-)
+            ActionType::Trade => (entry.amount_money.checked_neg(), entry.quantity),
+            _ => return Ok(()),
+        };
+
+        let held = self.stocks.get(&entry.symbol_id).copied().unwrap_or(0);
+        let (Some(cash), Some(held)) = (cash.and_then(|c| self.cash.checked_add(c)), held.checked_add(stock)) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "log index {}: {:?} takes a balance of user {} out of range", idx, action, entry.user_id
+            )));
+        };
+        self.cash = cash;
+        if stock != 0 {
+            self.stocks.insert(entry.symbol_id, held);
+        }
+        Ok(())
+    }
+
+    pub fn has_reservations(&self) -> bool {
+        self.reserved_cash != 0 || self.reserved_stocks.values().any(|q| *q != 0)
+    }
+
+    // Locks (or with a negative amount, releases) what a resting order needs.
+    // validate_order refuses any order whose value doesn't fit in an i64.
+    fn reserve(&mut self, side: Side, price: i64, symbol_id: u32, quantity: i64) {
+        match side {
+            Side::Buy => {
+                let cost = price.checked_mul(quantity).expect("order value checked by validate_order");
+                self.reserved_cash = self.reserved_cash.checked_add(cost).expect("reserved cash is at most cash");
+            }
+            Side::Sell => *self.reserved_stocks.entry(symbol_id).or_default() += quantity,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    Filled,
+    PartiallyFilled, // Some filled, the rest is resting on the book
+    Resting,
+    Expired,         // IOC / Market: whatever didn't fill right away was dropped
+    Rejected(&'static str),
+}
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Filled => "Filled",
+            OrderStatus::PartiallyFilled => "Partially Filled",
+            OrderStatus::Resting => "Resting",
+            OrderStatus::Expired => "Expired",
+            OrderStatus::Rejected(_) => "Rejected",
+        }
+    }
+
+    // As snapshots store it: 0-3, or 4 + the index of a rejection reason in REJECT_REASONS
+    pub fn code(&self) -> u8 {
+        match self {
+            OrderStatus::Filled => 0,
+            OrderStatus::PartiallyFilled => 1,
+            OrderStatus::Resting => 2,
+            OrderStatus::Expired => 3,
+            OrderStatus::Rejected(reason) => 4 + REJECT_REASONS.iter().position(|r| r == reason).unwrap_or(0) as u8,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => OrderStatus::Filled,
+            1 => OrderStatus::PartiallyFilled,
+            2 => OrderStatus::Resting,
+            3 => OrderStatus::Expired,
+            n => OrderStatus::Rejected(REJECT_REASONS.get(n as usize - 4).unwrap_or(&REJECT_REASONS[0])),
+        }
+    }
+}
+
+// A journal group being built (see AppState::begin / commit)
+pub struct Txn {
+    id: u64,
+    legs: Vec<LogEntry>,
+    owner: u64, // Who made the request
+    key: u128,  // Their idempotency key (0 = none), stamped on their own legs
+}
+
+impl Txn {
+    pub fn stage(&mut self, entry: LogEntry) {
+        self.legs.push(entry);
+    }
+}
+
+// Reading groups back: legs are held until their TxnCommit, a group cut short
+// (crash, dropped write) is skipped as a whole. Used by replay and journal audits.
+#[derive(Default)]
+pub struct Committed {
+    group: Option<(u64, Vec<(u64, LogEntry)>)>,
+    skipped: usize,
+}
+
+impl Committed {
+    /// Hands `apply` every entry that counts, with its log index, in the order replay applies them
+    pub fn push(&mut self, idx: u64, entry: LogEntry, mut apply: impl FnMut(u64, &LogEntry)) {
+        match ActionType::from_u8(entry.action_type) {
+            ActionType::TxnBegin => {
+                self.skipped += self.group.take().map_or(0, |(_, legs)| legs.len());
+                self.group = Some((entry.txn_id(), Vec::new()));
+            }
+            ActionType::TxnCommit => match self.group.take() {
+                Some((id, legs)) if id == entry.txn_id() && legs.len() as i64 == entry.quantity => {
+                    for (leg_idx, leg) in &legs {
+                        apply(*leg_idx, leg);
+                    }
+                }
+                Some((_, legs)) => self.skipped += legs.len(),
+                None => {}
+            },
+            _ if entry.in_txn() => match &mut self.group {
+                Some((_, legs)) => legs.push((idx, entry)),
+                None => self.skipped += 1, // Its Begin never made it
+            },
+            _ => {
+                // Groups are written back to back, so anything else means the open one was cut short
+                self.skipped += self.group.take().map_or(0, |(_, legs)| legs.len());
+                apply(idx, &entry);
+            }
+        }
+    }
+
+    /// Entries skipped, counting a group still open at the end
+    pub fn finish(self) -> usize {
+        self.skipped + self.group.map_or(0, |(_, legs)| legs.len())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    pub to: u64,
+    pub symbol_id: u32,
+    pub is_cash: bool,
+    pub amount: i64,
+}
+
+// What happened to a submitted order
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderOutcome {
+    pub order_id: u64,
+    pub status: OrderStatus,
+    pub fills: Vec<(i64, i64)>, // (price, quantity)
+    pub filled: i64,
+    pub resting: i64,
+    pub expired: i64,
+}
+
+// The result of a request that came with an idempotency key.
+// Snapshots keep them, and the journal after a snapshot rebuilds the rest, so the index survives restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Receipt {
+    Cash, // Deposit / Withdraw
+    Order(OrderOutcome),
+    Transfer(Transfer),
+}
+
+/// Receipts by (user_id, request key), each forgotten `ttl` seconds after its request was made.
+/// `made` lists them oldest first, so expiring never looks at the ones still wanted.
+#[derive(Default)]
+pub struct Receipts {
+    receipts: HashMap<(u64, u128), (u64, Receipt)>, // -> (made at, receipt)
+    made: VecDeque<(u64, u64, u128)>,                 // (made at, user_id, key)
+    ttl: u64,
+}
+
+impl Receipts {
+    pub fn new(ttl: u64) -> Self {
+        Self { ttl, ..Default::default() }
+    }
+
+    pub fn get(&self, user_id: u64, key: u128, now: u64) -> Option<&Receipt> {
+        self.receipts.get(&(user_id, key))
+            .filter(|(made_at, _)| made_at + self.ttl > now)
+            .map(|(_, receipt)| receipt)
+    }
+
+    /// The receipt a request made at `made_at` is building up (replay adds an order's legs one by one)
+    pub fn entry(&mut self, user_id: u64, key: u128, made_at: u64, new: impl FnOnce() -> Receipt) -> &mut Receipt {
+        let id = (user_id, key);
+        if !self.receipts.contains_key(&id) {
+            self.made.push_back((made_at, user_id, key));
+        }
+        &mut self.receipts.entry(id).or_insert_with(|| (made_at, new())).1
+    }
+
+    pub fn insert(&mut self, user_id: u64, key: u128, made_at: u64, receipt: Receipt) {
+        let replaced = self.receipts.insert((user_id, key), (made_at, receipt));
+        if replaced.is_none_or(|(before, _)| before != made_at) {
+            self.made.push_back((made_at, user_id, key));
+        }
+    }
+
+    /// Forgets what's older than the TTL
+    pub fn expire(&mut self, now: u64) {
+        while let Some(&(made_at, user_id, key)) = self.made.front() {
+            if made_at + self.ttl > now {
+                break;
+            }
+            self.made.pop_front();
+            // Unless it was made again since (an expired key used once more)
+            if self.receipts.get(&(user_id, key)).is_some_and(|(made, _)| *made == made_at) {
+                self.receipts.remove(&(user_id, key));
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.receipts.clear();
+        self.made.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.receipts.len()
+    }
+
+    /// (user_id, key, made at, receipt), oldest first
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u128, u64, &Receipt)> {
+        self.made.iter().filter_map(|&(made_at, user_id, key)| {
+            let (made, receipt) = self.receipts.get(&(user_id, key)).filter(|(made, _)| *made == made_at)?;
+            Some((user_id, key, *made, receipt))
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The answer to a request whose journal entries couldn't all be queued
+pub const JOURNAL_FAILED: &str = "Journal write failed: the request was rolled back";
+
 pub struct AppState {
-    pub user_index: HashMap<String, u64>,
-    pub portfolios: HashMap<u64, Portfolio>,
+    pub users: UserIndex,
+    pub portfolios: Accounts,
+    pub books: HashMap<u32, OrderBook>,
+    pub open_orders: HashMap<u64, u32>, // order id -> symbol_id
+    pub next_order_id: u64,
+    pub next_txn_id: u64,
+    pub requests: Receipts, // (user_id, request key) -> what it did
+    pub flags: BTreeMap<u64, u32>, // Flags the journal set (user_id -> flags): snapshots keep them for replay
+    pub request_hasher: RequestHasher, // Client request_id -> request key
+    pub sessions: Sessions,
+    pub api_keys: ApiKeys,
+    pub keys: Arc<Keyring>, // Data file encryption keys (snapshots)
+    pub engine_id: [u8; 16], // Stamped in every data file header (format.rs)
     pub reader: DatabaseReader,
     pub db_sender: Sender<DbMessage>, // <--- NEW FIELD
+    pub next_lsn: u64, // Log entries journaled so far, i.e. RAM reflects exactly history[..next_lsn]
+    pub journal: Arc<Journal>, // How many of them the persister has on disk, and whether it's failing
+    pub durable_ack: bool, // JDB_DURABLE_ACK=1: replies wait for the fsync unless a request says otherwise
+    pub snapshotter: Arc<Snapshotter>,
 }
 
 impl AppState {
     // 3. Update Constructor to accept the Sender
-    pub fn new(db_sender: Sender<DbMessage>) -> Self {
+    // Errors say which file is the problem; the caller can't do much more than report it.
+    pub fn new(db_sender: Sender<DbMessage>, keys: Arc<Keyring>, engine_id: [u8; 16]) -> io::Result<Self> {
         println!("--- STARTUP SEQUENCE ---");
+        let context = |what: &'static str| move |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", what, e));
+
+        let reader = DatabaseReader::new(&keys, &engine_id).map_err(context("can't open the database"))?;
+        let users = UserIndex::open("users.idx", USERS_POOL_FRAMES, &reader)
+            .map_err(context("can't open users.idx"))?;
+        let sessions = Sessions::open("session.key", "sessions.revoked")
+            .map_err(context("can't load the session key"))?;
+        let api_keys = ApiKeys::open(keys.clone(), &engine_id).map_err(context("can't open apikeys.bin"))?;
+        let request_hasher = RequestHasher::open("request.key").map_err(context("can't load the request key"))?;
+        let portfolios = Accounts::open("accounts.idx", ACCOUNTS_POOL_FRAMES)
+            .map_err(context("can't open accounts.idx"))?;
+
+        let snapshot = newest_snapshot(&portfolios, &keys, &engine_id);
+        let last_snapshot_index = snapshot.last_log_index;
+
+        let mut state = Self {
+            users,
+            portfolios,
+            books: HashMap::new(),
+            open_orders: HashMap::new(),
+            next_order_id: 1,
+            next_txn_id: 1,
+            requests: Receipts::new(env_u64("JDB_REQUEST_TTL_SECS", REQUEST_TTL_SECS)),
+            flags: BTreeMap::new(),
+            request_hasher,
+            sessions,
+            api_keys,
+            keys,
+            engine_id,
+            reader,
+            db_sender,
+            next_lsn: 0,
+            journal: Arc::new(Journal::default()),
+            durable_ack: env_u64("JDB_DURABLE_ACK", 0) != 0,
+            snapshotter: Arc::new(Snapshotter::from_env(last_snapshot_index)),
+        };
+        state.restore(snapshot).map_err(context("can't rebuild the state"))?;
+
+        // JDB_ADMIN=<username> makes that user an admin (the first admin has to come from somewhere)
+        if let Ok(name) = std::env::var("JDB_ADMIN") {
+            match state.users.get_meta(name.trim()).map_err(context("can't look up JDB_ADMIN"))? {
+                Some(user) if user.flags & USER_ADMIN == 0 => {
+                    state.set_user_flags(0, user, user.flags | USER_ADMIN).map_err(context("can't grant admin"))?;
+                    println!("Granted admin to {}", name.trim());
+                }
+                Some(_) => {}
+                None => eprintln!("JDB_ADMIN: no user named {}", name.trim()),
+            }
+        }
+
+        println!("Startup Complete.");
+
+        Ok(state)
+    }
 
-        let (mut portfolios, last_snapshot_index) = load_snapshot()
-            .unwrap_or((HashMap::new(), 0));
-
-        let reader = DatabaseReader::new().expect("Failed to open DB");
-        
-        let logs = reader.get_logs();
-        let total_logs = logs.len() as u64;
-
-        if total_logs > last_snapshot_index {
-            println!("Replaying logs from {} to {}...", last_snapshot_index, total_logs);
-            for entry in logs.iter().skip(last_snapshot_index as usize) {
-                let portfolio = portfolios.entry(entry.user_id).or_insert(Portfolio::default());
-                match entry.action_type {
-                    1 => portfolio.cash += entry.amount_money,
-                    2 => portfolio.cash -= entry.amount_money,
-                    3 => {
-                        portfolio.cash -= entry.amount_money; 
-                        *portfolio.stocks.entry(entry.symbol_id).or_insert(0) += entry.quantity;
+    // RAM from the snapshot plus the log after it (startup, and reload)
+    fn restore(&mut self, snapshot: SnapshotData) -> io::Result<()> {
+        let last_snapshot_index = snapshot.last_log_index;
+
+        if snapshot.portfolios_skipped {
+            println!("Balances loaded from accounts.idx (Log Index {})", last_snapshot_index);
+            self.portfolios.discard();
+        } else {
+            println!("Rebuilding accounts.idx from snapshot...");
+            self.portfolios.reset()?;
+            for (user_id, portfolio) in snapshot.portfolios {
+                self.portfolios.insert(user_id, portfolio);
+            }
+        }
+
+        self.books.clear();
+        self.open_orders.clear();
+        self.requests.clear();
+        for (user_id, key, made_at, receipt) in snapshot.receipts {
+            self.requests.insert(user_id, key, made_at, receipt);
+        }
+        self.next_order_id = snapshot.next_order_id.max(1);
+        self.next_txn_id = 1;
+
+        // Put the snapshot's resting orders back in queue order (re-locks their funds)
+        for (symbol_id, order) in snapshot.orders {
+            self.portfolios.entry(order.user_id)?;
+            self.rest_order(symbol_id, order);
+        }
+
+        // Only the log after the snapshot is read. Retired segments are gone from it,
+        // so the snapshot has to reach what's left.
+        let (first_log, end_log) = (self.reader.first_log(), self.reader.end_log());
+        if last_snapshot_index < first_log {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "the journal starts at log index {} (older segments were retired), the snapshot ends at {}",
+                first_log, last_snapshot_index
+            )));
+        }
+        // users.idx may have been rebuilt from users.bin, which only has the flags users started with
+        self.flags.clear();
+        for (user_id, flags) in snapshot.flags {
+            self.set_replayed_flags(user_id, flags)?;
+        }
+        if end_log > last_snapshot_index {
+            println!("Replaying logs from {} to {}...", last_snapshot_index, end_log);
+        }
+        self.replay_log(last_snapshot_index, end_log)?;
+        self.requests.expire(now());
+        println!("Request index: {} keyed requests", self.requests.len());
+        self.next_lsn = end_log;
+        self.journal.set_durable(end_log);
+        Ok(())
+    }
+
+    /// Rolls RAM back to what's on disk after the persister lost a write: the newest
+    /// snapshot plus whatever history.bin still has, same as a restart would see.
+    /// Everything journaled after the failed batch is gone with it.
+    pub fn reload(&mut self) -> io::Result<()> {
+        // A running snapshot holds a frozen copy of accounts it will hand back to this RAM
+        if self.snapshotter.stats().running {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "a snapshot is being taken"));
+        }
+
+        let lost_from = self.journal.durable();
+        let lost = self.next_lsn.saturating_sub(lost_from);
+        self.reader = DatabaseReader::new(&self.keys, &self.engine_id)?;
+        self.users.rollback(&self.reader)?;
+        let snapshot = newest_snapshot(&self.portfolios, &self.keys, &self.engine_id);
+        self.restore(snapshot)?;
+        println!("[Journal] Rolled back to log index {} ({} entries from {} on were never written)",
+            self.next_lsn, lost, lost_from);
+        Ok(())
+    }
+
+    // Re-applies the journal from `from` to `to` (log indexes) to RAM, and feeds the request
+    // index (the snapshot has the receipts and flags from before). Entries are read as it goes.
+    // Legs of a group are held back until its TxnCommit; a group cut short
+    // (crash, dropped write) is skipped as a whole. A snapshot never falls inside a group.
+    fn replay_log(&mut self, from: u64, to: u64) -> io::Result<()> {
+        let mut groups = Committed::default();
+        let mut failed = None;
+        for item in self.reader.log_entries(from, to) {
+            let (idx, entry) = item?;
+            if let ActionType::TxnBegin = ActionType::from_u8(entry.action_type) {
+                self.next_txn_id = self.next_txn_id.max(entry.txn_id() + 1);
+            }
+            groups.push(idx, entry, |leg_idx, leg| {
+                if let Err(e) = self.apply_logged(leg_idx, leg) {
+                    failed.get_or_insert(e);
+                }
+            });
+            if let Some(e) = failed {
+                return Err(e);
+            }
+        }
+
+        let skipped = groups.finish();
+        if skipped > 0 {
+            println!("Ignored {} log entries from uncommitted groups", skipped);
+        }
+        Ok(())
+    }
+
+    fn apply_logged(&mut self, idx: u64, entry: &LogEntry) -> io::Result<()> {
+        if let ActionType::SetFlags = ActionType::from_u8(entry.action_type) {
+            return self.replay_flags(entry);
+        }
+        self.replay_entry(idx, entry)?;
+        if entry.request_key() != 0 {
+            self.note_request(entry);
+        }
+        Ok(())
+    }
+
+    // Rebuilds the receipt of a keyed request, one of its entries at a time
+    fn note_request(&mut self, entry: &LogEntry) {
+        let (user_id, key, made_at) = (entry.user_id, entry.request_key(), entry.timestamp);
+        let quantity = entry.quantity.abs();
+        match ActionType::from_u8(entry.action_type) {
+            ActionType::Deposit | ActionType::Withdraw => {
+                self.requests.insert(user_id, key, made_at, Receipt::Cash);
+            }
+            ActionType::Transfer => {
+                // The key sits on the sender's leg, which is the negative one
+                let transfer = Transfer {
+                    to: entry.counterparty(),
+                    symbol_id: entry.symbol_id,
+                    is_cash: entry.quantity == 0,
+                    amount: -(entry.amount_money + entry.quantity),
+                };
+                self.requests.insert(user_id, key, made_at, Receipt::Transfer(transfer));
+            }
+            action @ (ActionType::Trade | ActionType::OrderPlaced | ActionType::OrderExpired | ActionType::OrderRejected) => {
+                let receipt = self.requests.entry(user_id, key, made_at, || Receipt::Order(OrderOutcome {
+                    order_id: entry.order_id(),
+                    status: OrderStatus::Filled,
+                    fills: Vec::new(),
+                    filled: 0,
+                    resting: 0,
+                    expired: 0,
+                }));
+                let Receipt::Order(outcome) = receipt else { return };
+                match action {
+                    ActionType::Trade => {
+                        outcome.fills.push((entry.amount_money.abs() / quantity, quantity));
+                        outcome.filled += quantity;
                     }
-                    _ => {}
+                    ActionType::OrderPlaced => outcome.resting = quantity,
+                    ActionType::OrderExpired => outcome.expired = quantity,
+                    _ => {
+                        let reason = REJECT_REASONS.get(entry.reason as usize).unwrap_or(&REJECT_REASONS[0]);
+                        outcome.status = OrderStatus::Rejected(reason);
+                    }
+                }
+                // Same rules execute_order uses
+                if !matches!(outcome.status, OrderStatus::Rejected(_)) {
+                    outcome.status = match (outcome.filled, outcome.resting, outcome.expired) {
+                        (_, 0, 0) => OrderStatus::Filled,
+                        (0, _, 0) => OrderStatus::Resting,
+                        (_, 0, _) => OrderStatus::Expired,
+                        _ => OrderStatus::PartiallyFilled,
+                    };
                 }
             }
+            // Self-trade cancels and the like don't shape the reply
+            _ => {}
+        }
+    }
+
+    // Re-applies one journaled event. Must mirror the live code paths below exactly,
+    // but never journals anything itself.
+    fn replay_entry(&mut self, idx: u64, entry: &LogEntry) -> io::Result<()> {
+        let action = ActionType::from_u8(entry.action_type);
+        let order_id = entry.order_id();
+        if !matches!(action, ActionType::Transfer | ActionType::Issue) {
+            self.next_order_id = self.next_order_id.max(order_id + 1);
         }
 
-        let mut user_index = HashMap::new();
-        let users = reader.get_users();
-        for (idx, user) in users.iter().enumerate() {
-            let name = std::str::from_utf8(&user.username)
-                .unwrap_or("")
-                .trim_matches('\0')
-                .to_string();
-            if !name.is_empty() {
-                user_index.insert(name, idx as u64);
+        self.portfolios.entry(entry.user_id)?.apply(idx, entry)?;
+        match action {
+            ActionType::Deposit | ActionType::Withdraw | ActionType::Transfer | ActionType::Issue => {}
+            ActionType::Trade => {
+                // If this leg was the maker, its resting order shrinks (the taker never rests yet)
+                if self.is_resting(order_id, entry.user_id) {
+                    self.reduce_order(entry.symbol_id, order_id, entry.quantity.abs());
+                }
+            }
+            ActionType::OrderPlaced => {
+                let order = Order {
+                    id: order_id,
+                    user_id: entry.user_id,
+                    side: if entry.quantity > 0 { Side::Buy } else { Side::Sell },
+                    price: entry.amount_money,
+                    quantity: entry.quantity.abs(),
+                };
+                self.rest_order(entry.symbol_id, order);
+            }
+            ActionType::OrderCancelled | ActionType::OrderReplaced => {
+                self.remove_order(order_id);
             }
+            ActionType::OrderAmended => {
+                if let Some(order) = self.books.get(&entry.symbol_id).and_then(|b| b.get(order_id)) {
+                    let shrink = order.quantity - entry.quantity.abs();
+                    self.reduce_order(entry.symbol_id, order_id, shrink);
+                }
+            }
+            // Never touched the book or money (markers are handled by replay_log)
+            ActionType::OrderRejected | ActionType::OrderExpired
+            | ActionType::TxnBegin | ActionType::TxnCommit | ActionType::SetFlags | ActionType::None => {}
         }
+        Ok(())
+    }
 
-        println!("Startup Complete.");
+    // --- JOURNAL GROUPS ---
+    // Everything one request journals is staged into a Txn and sent with commit(),
+    // so replay applies all of its legs (both sides of a fill, a transfer...) or none.
+
+    pub fn begin(&mut self) -> Txn {
+        self.begin_request(0, 0)
+    }
+
+    // A group for a request that came with an idempotency key
+    pub fn begin_request(&mut self, user_id: u64, key: u128) -> Txn {
+        let id = self.next_txn_id;
+        self.next_txn_id += 1;
+        Txn { id, legs: Vec::new(), owner: user_id, key }
+    }
+
+    // Queues the group for the persister: TxnBegin | legs | TxnCommit.
+    // A single leg is atomic on its own and goes out bare.
+    // Err: it couldn't all be queued, and RAM is (being) rolled back to the disk, so
+    // the request never happened. Don't answer it with anything RAM says.
+    pub fn commit(&mut self, txn: Txn) -> Result<(), &'static str> {
+        let mut legs = txn.legs;
+        if txn.key != 0 {
+            for entry in legs.iter_mut().filter(|e| e.user_id == txn.owner) {
+                *entry = entry.with_request_key(txn.key);
+            }
+        }
+
+        if legs.len() <= 1 {
+            for entry in legs {
+                self.journal(entry)?;
+            }
+            return Ok(());
+        }
+
+        self.journal(LogEntry::txn_marker(ActionType::TxnBegin, txn.id, 0))?;
+        let count = legs.len() as i64;
+        for mut entry in legs {
+            entry.flags |= LOG_FLAG_IN_TXN;
+            self.journal(entry)?;
+        }
+        self.journal(LogEntry::txn_marker(ActionType::TxnCommit, txn.id, count))
+    }
+
+    // Every log entry goes out through here, so next_lsn always matches what was sent
+    fn journal(&mut self, entry: LogEntry) -> Result<(), &'static str> {
+        match self.db_sender.try_send(DbMessage::WriteLog(entry)) {
+            Ok(()) => {
+                self.next_lsn += 1;
+                Ok(())
+            }
+            // journal_room said there was space, so the persister is gone. RAM is ahead
+            // of the log now: treat it like a failed write, which rolls RAM back.
+            Err(e) => {
+                self.journal_failed(format!("journal send failed: {}", e));
+                Err(JOURNAL_FAILED)
+            }
+        }
+    }
+
+    /// Something RAM already did couldn't be queued. Rolls RAM back right away if the
+    /// persister has stopped; if not, the recovery task does once it has.
+    pub fn journal_failed(&mut self, reason: String) {
+        self.journal.fail(reason);
+        if let Err(e) = self.recover_journal() {
+            eprintln!("[Journal] Rollback left to the recovery task: {}", e);
+        }
+    }
+
+    /// Rolls RAM back to what's on disk, then stays read-only until an operator resumes
+    /// (see persister.rs for the states). Blocking (reads the data files).
+    pub fn recover_journal(&mut self) -> io::Result<()> {
+        if self.journal.state() != Health::Failed {
+            return Ok(());
+        }
+        // The files have to hold still while they're read back
+        if !self.journal.halted() && !self.db_sender.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "waiting for the persister to stop"));
+        }
+
+        self.reload()?;
+        self.journal.rolled_back();
+        eprintln!("[Journal] READ-ONLY at log index {}. Fix the disk, then POST /admin/journal/resume", self.next_lsn);
+        Ok(())
+    }
+
+    // --- JOURNAL ROOM ---
+    // A request that can't journal everything it did must not change RAM at all.
+    // So before touching anything, it checks the channel has room for its worst case.
+    // Everything that sends holds the write lock, so that room can only grow until it's done.
+
+    /// Room for `legs` entries, plus a group's markers and a durability ack
+    pub fn journal_room(&self, legs: usize) -> Result<(), &'static str> {
+        if self.journal.state() != Health::Healthy {
+            return Err("Read-only: the journal can't be written, trading is suspended");
+        }
+        let max = self.db_sender.max_capacity();
+        let room = self.db_sender.capacity();
+        self.journal.note_depth(max - room, max);
+
+        let needed = legs + 3;
+        if needed > max {
+            return Err("Too many fills for one request");
+        }
+        if room < needed {
+            self.journal.note_saturated();
+            return Err("Journal saturated, try again later");
+        }
+        Ok(())
+    }
+
+    /// Durable-ack mode: completes once the persister has fsynced everything
+    /// journaled so far, or failed to. Await it after letting go of the lock.
+    pub fn ack(&mut self) -> oneshot::Receiver<Result<u64, String>> {
+        let (reply, rx) = oneshot::channel();
+        if let Err(e) = self.db_sender.try_send(DbMessage::Ack(reply))
+            && let DbMessage::Ack(reply) = e.into_inner()
+        {
+            let _ = reply.send(Err("journal saturated".to_string()));
+        }
+        rx
+    }
+
+    /// The request key for a client's request_id (0 = none)
+    pub fn request_key(&self, request_id: Option<&str>) -> u128 {
+        request_id.map_or(0, |id| self.request_hasher.hash(id))
+    }
+
+    /// What an earlier request with this key did, if there was one (and it's not expired)
+    pub fn receipt(&self, user_id: u64, key: u128) -> Option<&Receipt> {
+        self.requests.get(user_id, key, now())
+    }
+
+    pub fn remember(&mut self, user_id: u64, key: u128, receipt: Receipt) {
+        if key != 0 {
+            let now = now();
+            self.requests.expire(now);
+            self.requests.insert(user_id, key, now, receipt);
+        }
+    }
+
+    // --- USER FLAGS ---
+    // users.idx holds the current flags; history.bin holds every change (who, before, after).
+
+    /// Journals the change, then updates users.idx. `admin_id` 0 = the server itself.
+    /// Freezing an account cancels its resting orders in the same journal group.
+    pub fn set_user_flags(&mut self, admin_id: u64, mut user: UserMeta, flags: u32) -> io::Result<UserMeta> {
+        let entry = LogEntry::new(user.user_id, ActionType::SetFlags, 0, flags as i64, user.flags as i64)
+            .with_counterparty(admin_id);
+        let mut txn = self.begin();
+        txn.stage(entry);
+        for order_id in self.cancelled_by_flags(&user, flags) {
+            let _ = self.cancel_in(&mut txn, user.user_id, order_id);
+        }
+        self.commit(txn).map_err(io::Error::other)?;
+
+        self.flags.insert(user.user_id, flags);
+        user.flags = flags;
+        self.users.insert(&user)?;
+        Ok(user)
+    }
+
+    /// The resting orders that changing `user`'s flags to `flags` cancels
+    pub fn cancelled_by_flags(&self, user: &UserMeta, flags: u32) -> Vec<u64> {
+        if flags & USER_FROZEN == 0 || user.flags & USER_FROZEN != 0 {
+            return Vec::new();
+        }
+        self.user_orders(user.user_id).iter().map(|(_, o)| o.id).collect()
+    }
+
+    fn replay_flags(&mut self, entry: &LogEntry) -> io::Result<()> {
+        self.set_replayed_flags(entry.user_id, entry.quantity as u32)
+    }
+
+    fn set_replayed_flags(&mut self, user_id: u64, flags: u32) -> io::Result<()> {
+        self.flags.insert(user_id, flags);
+        // users.bin position == user_id, and has the name users.idx is keyed by
+        let Some(name) = self.reader.user(user_id)?.map(|u| user_name(&u).to_string()) else {
+            return Ok(());
+        };
+        if let Some(mut user) = self.users.get_meta(&name)?.filter(|u| u.user_id == user_id && u.flags != flags) {
+            user.flags = flags;
+            self.users.insert(&user)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the accounts a request may change into the cache before it changes anything,
+    /// so a read error fails the request as a whole. Orders only need the taker's: every
+    /// account with a resting order is cached already. Transfers need both.
+    pub fn load_accounts(&mut self, user_ids: &[u64]) -> io::Result<()> {
+        for user_id in user_ids {
+            self.portfolios.entry(*user_id)?;
+        }
+        Ok(())
+    }
+
+    // --- TRANSFERS ---
+
+    /// Moves cash (is_cash) or a stock holding from one user to another as one journal group.
+    /// With a request key, repeating the same request returns the first result and moves nothing.
+    /// Both accounts have to be loaded (load_accounts).
+    pub fn transfer(&mut self, from: u64, key: u128, transfer: Transfer) -> Result<Transfer, &'static str> {
+        // 1. Seen this request before? (key 0 is never stored)
+        match self.receipt(from, key) {
+            Some(Receipt::Transfer(done)) if *done == transfer => return Ok(*done),
+            Some(_) => return Err("Request ID reused"),
+            None => {}
+        }
+
+        let Transfer { to, symbol_id, is_cash, amount } = transfer;
+        if amount <= 0 || from == to {
+            return Err("Invalid Transfer");
+        }
+
+        // 2. Only what isn't locked by resting orders can leave
+        let sender = self.portfolios.cached(from);
+        let available = if is_cash { sender.available_cash() } else { sender.available_stock(symbol_id) };
+        if available < amount {
+            return Err(if is_cash { "Insufficient Funds" } else { "Insufficient Stock" });
+        }
+
+        // 3. Work out both balances before either leg moves
+        let held = |p: &Portfolio| if is_cash { p.cash } else { p.stocks.get(&symbol_id).copied().unwrap_or(0) };
+        let sent = held(self.portfolios.cached(from)).checked_sub(amount);
+        let received = held(self.portfolios.cached(to)).checked_add(amount);
+        let (Some(sent), Some(received)) = (sent, received) else {
+            return Err("Amount Out Of Range");
+        };
+
+        // 4. Both legs, RAM first (once we know they can be journaled)
+        self.journal_room(2)?;
+        let (cash, stock) = if is_cash { (amount, 0) } else { (0, amount) };
+        for (user_id, balance) in [(from, sent), (to, received)] {
+            let portfolio = self.portfolios.cached_mut(user_id);
+            if is_cash {
+                portfolio.cash = balance;
+            } else {
+                portfolio.stocks.insert(symbol_id, balance);
+            }
+        }
+
+        // 5. Journal them together
+        let mut txn = self.begin_request(from, key);
+        txn.stage(LogEntry::new(from, ActionType::Transfer, symbol_id, -stock, -cash).with_counterparty(to));
+        txn.stage(LogEntry::new(to, ActionType::Transfer, symbol_id, stock, cash).with_counterparty(from));
+        self.commit(txn)?;
+
+        self.remember(from, key, Receipt::Transfer(transfer));
+        Ok(transfer)
+    }
+
+    // --- ISSUANCE ---
+    // Deposits bring cash in; this is how stock comes in. Trades and transfers only move it.
+
+    /// Credits `quantity` new shares of `symbol_id` to `user_id` (loaded, see load_accounts),
+    /// journaled as ActionType::Issue with the admin as counterparty. Returns the new holding.
+    pub fn issue_stock(&mut self, admin_id: u64, user_id: u64, symbol_id: u32, quantity: i64) -> Result<i64, &'static str> {
+        if quantity <= 0 {
+            return Err("Invalid Quantity");
+        }
+        let held = self.portfolios.cached(user_id).stocks.get(&symbol_id).copied().unwrap_or(0);
+        let held = held.checked_add(quantity).ok_or("Amount Out Of Range")?;
+
+        self.journal_room(1)?;
+        self.portfolios.cached_mut(user_id).stocks.insert(symbol_id, held);
+        let mut txn = self.begin();
+        txn.stage(LogEntry::new(user_id, ActionType::Issue, symbol_id, quantity, 0).with_counterparty(admin_id));
+        self.commit(txn)?;
+        Ok(held)
+    }
+
+    // --- BOOK BOOKKEEPING ---
+    // Every change to a resting order goes through these, so the book,
+    // open_orders and the owner's reserved funds never drift apart.
+
+    fn is_resting(&self, order_id: u64, user_id: u64) -> bool {
+        self.open_orders.get(&order_id)
+            .and_then(|sym| self.books.get(sym))
+            .and_then(|b| b.get(order_id))
+            .is_some_and(|o| o.user_id == user_id)
+    }
+
+    fn rest_order(&mut self, symbol_id: u32, order: Order) {
+        let portfolio = self.portfolios.cached_mut(order.user_id);
+        portfolio.reserve(order.side, order.price, symbol_id, order.quantity);
+        self.open_orders.insert(order.id, symbol_id);
+        self.books.entry(symbol_id).or_default().rest(order);
+    }
+
+    fn remove_order(&mut self, order_id: u64) -> Option<Order> {
+        let symbol_id = self.open_orders.remove(&order_id)?;
+        let order = self.books.get_mut(&symbol_id)?.remove(order_id)?;
+        let portfolio = self.portfolios.cached_mut(order.user_id);
+        portfolio.reserve(order.side, order.price, symbol_id, -order.quantity);
+        Some(order)
+    }
+
+    fn reduce_order(&mut self, symbol_id: u32, order_id: u64, quantity: i64) {
+        let Some(before) = self.books.get_mut(&symbol_id).and_then(|b| b.reduce(order_id, quantity)) else {
+            return;
+        };
+        let portfolio = self.portfolios.cached_mut(before.user_id);
+        portfolio.reserve(before.side, before.price, symbol_id, -quantity);
+        if before.quantity <= quantity {
+            self.open_orders.remove(&order_id);
+        }
+    }
+
+    // The resting order, but only if `user_id` owns it
+    pub fn get_order(&self, user_id: u64, order_id: u64) -> Option<(u32, &Order)> {
+        let symbol_id = *self.open_orders.get(&order_id)?;
+        let order = self.books.get(&symbol_id)?.get(order_id)?;
+        (order.user_id == user_id).then_some((symbol_id, order))
+    }
+
+    pub fn user_orders(&self, user_id: u64) -> Vec<(u32, &Order)> {
+        let mut orders: Vec<_> = self.open_orders.keys()
+            .filter_map(|id| self.get_order(user_id, *id))
+            .collect();
+        orders.sort_by_key(|(_, o)| o.id);
+        orders
+    }
+
+    // --- MATCHING ENGINE ---
+    // Validates funds, matches against the book, settles both sides of every fill
+    // and rests (GTC) or expires (IOC / Market) the remainder.
+    // Each fill is journaled as 2 Trade entries (buyer + seller).
+    // `key` = the client's idempotency key (0 = none); the caller checks receipt() first,
+    // and loads the taker's account (load_accounts).
+    pub fn submit_order(
+        &mut self,
+        user_id: u64,
+        key: u128,
+        symbol_id: u32,
+        req: NewOrder,
+    ) -> Result<OrderOutcome, &'static str> {
+        let (price, budget) = self.validate_order(user_id, symbol_id, &req)?;
+        // Its fills and cancels, plus how it ends (placed, expired or rejected)
+        self.journal_room(self.legs_bound(user_id, symbol_id, &req, price) + 1)?;
+
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+
+        let mut txn = self.begin_request(user_id, key);
+        let outcome = self.execute_order(&mut txn, order_id, user_id, symbol_id, req, price, budget);
+        self.commit(txn)?;
+        self.remember(user_id, key, Receipt::Order(outcome.clone()));
+        Ok(outcome)
+    }
+
+    // Most entries matching `req` could journal (see OrderBook::legs_bound)
+    fn legs_bound(&self, user_id: u64, symbol_id: u32, req: &NewOrder, price: i64) -> usize {
+        let taker = Order { id: 0, user_id, side: req.side, price, quantity: req.quantity };
+        self.books.get(&symbol_id).map_or(0, |book| book.legs_bound(&taker))
+    }
+
+    // Returns the effective limit price and the most cash the taker may spend
+    fn validate_order(&mut self, user_id: u64, symbol_id: u32, req: &NewOrder) -> Result<(i64, i64), &'static str> {
+        let NewOrder { side, order_type, time_in_force, quantity, .. } = *req;
+        if quantity <= 0 {
+            return Err("Invalid Order");
+        }
+
+        // 1. Work out the effective limit price. A resting order's value must fit in an i64.
+        let price = match (order_type, req.price) {
+            (OrderType::Limit, Some(p)) if p > 0 && p.checked_mul(quantity).is_some() => p,
+            (OrderType::Limit, Some(p)) if p > 0 => return Err("Order value out of range"),
+            (OrderType::Limit, _) => return Err("Limit price required"),
+            (OrderType::Market, _) if time_in_force == TimeInForce::PostOnly => {
+                return Err("Market orders can't be Post-Only");
+            }
+            // Market orders cross everything; the buyer's cash is the only limit
+            (OrderType::Market, _) => if side == Side::Buy { i64::MAX } else { 1 },
+        };
+
+        // 2. Check the taker can cover the whole order
+        let portfolio = self.portfolios.cached(user_id);
+        let budget = match side {
+            Side::Buy if order_type == OrderType::Market => portfolio.available_cash(),
+            Side::Buy => {
+                let cost = price.checked_mul(quantity).ok_or("Invalid Order")?;
+                if portfolio.available_cash() < cost {
+                    return Err("Insufficient Funds");
+                }
+                cost
+            }
+            Side::Sell => {
+                if portfolio.available_stock(symbol_id) < quantity {
+                    return Err("Insufficient Stock");
+                }
+                i64::MAX
+            }
+        };
+
+        // 3. And that whatever it fills leaves every balance in range
+        let taker = Order { id: 0, user_id, side, price, quantity };
+        self.check_settlement(symbol_id, &taker, budget)?;
+
+        Ok((price, budget))
+    }
+
+    // settle() would move these sums (and replay would again), so none may overflow.
+    // Totals per user: the taker is on every fill, a maker may be on several.
+    fn check_settlement(&mut self, symbol_id: u32, taker: &Order, budget: i64) -> Result<(), &'static str> {
+        const OUT_OF_RANGE: &str = "Amount Out Of Range";
+        let Some(book) = self.books.get(&symbol_id) else { return Ok(()) };
+        let mut changes: HashMap<u64, (i64, i64)> = HashMap::new(); // user_id -> (cash, stock)
+        for fill in book.preview(taker, budget) {
+            let notional = fill.price.checked_mul(fill.quantity).ok_or(OUT_OF_RANGE)?;
+            for (user_id, cash, stock) in [(fill.buyer(), -notional, fill.quantity), (fill.seller(), notional, -fill.quantity)] {
+                let (total_cash, total_stock) = changes.entry(user_id).or_default();
+                *total_cash = total_cash.checked_add(cash).ok_or(OUT_OF_RANGE)?;
+                *total_stock = total_stock.checked_add(stock).ok_or(OUT_OF_RANGE)?;
+            }
+        }
+
+        for (user_id, (cash, stock)) in changes {
+            let portfolio = self.portfolios.cached(user_id);
+            let held = portfolio.stocks.get(&symbol_id).copied().unwrap_or(0);
+            if portfolio.cash.checked_add(cash).is_none() || held.checked_add(stock).is_none() {
+                return Err(OUT_OF_RANGE);
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn execute_order(
+        &mut self,
+        txn: &mut Txn,
+        order_id: u64,
+        user_id: u64,
+        symbol_id: u32,
+        req: NewOrder,
+        price: i64,
+        budget: i64,
+    ) -> OrderOutcome {
+        let NewOrder { side, order_type, time_in_force, quantity, .. } = req;
+        let mut taker = Order { id: order_id, user_id, side, price, quantity };
+        let book = self.books.entry(symbol_id).or_default();
+
+        // 4. Order types that may be refused before touching the book
+        let rejection = match time_in_force {
+            TimeInForce::PostOnly if book.crosses(&taker) => Some(REJECT_WOULD_CROSS),
+            TimeInForce::Fok if book.fillable(&taker, budget) < quantity => Some(REJECT_CANNOT_FILL),
+            _ => None,
+        };
+        if let Some(reason) = rejection {
+            log_order_event(txn, ActionType::OrderRejected, &taker, symbol_id, req.price.unwrap_or(0));
+            if let Some(entry) = txn.legs.last_mut() {
+                entry.reason = reason;
+            }
+            return OrderOutcome {
+                order_id,
+                status: OrderStatus::Rejected(REJECT_REASONS[reason as usize]),
+                fills: Vec::new(),
+                filled: 0,
+                resting: 0,
+                expired: 0,
+            };
+        }
+
+        // 5. Match
+        let (fills, self_cancels) = book.match_order(&mut taker, budget);
+
+        // 6. Our own orders we would have traded with are cancelled instead
+        for cancelled in &self_cancels {
+            self.open_orders.remove(&cancelled.id);
+            let owner = self.portfolios.cached_mut(cancelled.user_id);
+            owner.reserve(cancelled.side, cancelled.price, symbol_id, -cancelled.quantity);
+            log_order_event(txn, ActionType::OrderCancelled, cancelled, symbol_id, cancelled.price);
+        }
+
+        // 7. Settle both counterparties
+        for fill in &fills {
+            self.settle(txn, symbol_id, fill);
+        }
+
+        // 8. GTC / Post-Only remainder rests and locks its funds, anything else expires
+        let remaining = taker.quantity;
+        let filled = quantity - remaining;
+        let rests = order_type == OrderType::Limit
+            && matches!(time_in_force, TimeInForce::Gtc | TimeInForce::PostOnly);
+
+        let (status, resting, expired) = if remaining == 0 {
+            (OrderStatus::Filled, 0, 0)
+        } else if rests {
+            log_order_event(txn, ActionType::OrderPlaced, &taker, symbol_id, price);
+            self.rest_order(symbol_id, taker);
+            let status = if filled > 0 { OrderStatus::PartiallyFilled } else { OrderStatus::Resting };
+            (status, remaining, 0)
+        } else {
+            log_order_event(txn, ActionType::OrderExpired, &taker, symbol_id, req.price.unwrap_or(0));
+            (OrderStatus::Expired, 0, remaining)
+        };
+
+        let fills = fills.iter().map(|f| (f.price, f.quantity)).collect();
+        OrderOutcome { order_id, status, fills, filled, resting, expired }
+    }
+
+    // --- CANCEL / AMEND ---
+
+    pub fn cancel_order(&mut self, user_id: u64, order_id: u64) -> Result<Order, &'static str> {
+        self.journal_room(1)?;
+        let mut txn = self.begin();
+        let cancelled = self.cancel_in(&mut txn, user_id, order_id);
+        self.commit(txn)?;
+        cancelled
+    }
 
-        Self { user_index, portfolios, reader, db_sender }
+    // Cancels every resting order of the user as one journal group
+    pub fn cancel_all(&mut self, user_id: u64) -> Result<Vec<Order>, &'static str> {
+        let ids: Vec<u64> = self.user_orders(user_id).iter().map(|(_, o)| o.id).collect();
+        self.journal_room(ids.len())?;
+        let mut txn = self.begin();
+        let cancelled = ids.into_iter()
+            .filter_map(|id| self.cancel_in(&mut txn, user_id, id).ok())
+            .collect();
+        self.commit(txn)?;
+        Ok(cancelled)
     }
-}
\ No newline at end of file
+
+    fn cancel_in(&mut self, txn: &mut Txn, user_id: u64, order_id: u64) -> Result<Order, &'static str> {
+        if self.get_order(user_id, order_id).is_none() {
+            return Err("Order not found");
+        }
+        let symbol_id = self.open_orders[&order_id];
+        let order = self.remove_order(order_id).ok_or("Order not found")?;
+        log_order_event(txn, ActionType::OrderCancelled, &order, symbol_id, order.price);
+        Ok(order)
+    }
+
+    /// Atomic cancel/replace. Shrinking the quantity at the same price keeps
+    /// queue priority; any other change re-enters the order (same ID) at the back.
+    pub fn amend_order(
+        &mut self,
+        user_id: u64,
+        order_id: u64,
+        new_price: Option<i64>,
+        new_quantity: Option<i64>,
+    ) -> Result<OrderOutcome, &'static str> {
+        let (symbol_id, old) = self.get_order(user_id, order_id).ok_or("Order not found")?;
+        let old = old.clone();
+        let price = new_price.unwrap_or(old.price);
+        let quantity = new_quantity.unwrap_or(old.quantity);
+        if price <= 0 || quantity <= 0 {
+            return Err("Invalid Order");
+        }
+
+        // 1. Same price, smaller (or equal) size: shrink in place
+        if price == old.price && quantity <= old.quantity {
+            self.journal_room(1)?;
+            self.reduce_order(symbol_id, order_id, old.quantity - quantity);
+            let amended = Order { quantity, ..old };
+            let mut txn = self.begin();
+            log_order_event(&mut txn, ActionType::OrderAmended, &amended, symbol_id, price);
+            self.commit(txn)?;
+            return Ok(OrderOutcome {
+                order_id,
+                status: OrderStatus::Resting,
+                fills: Vec::new(),
+                filled: 0,
+                resting: quantity,
+                expired: 0,
+            });
+        }
+
+        // 2. Otherwise validate the replacement as if the old order were already gone.
+        // On failure the old order is left exactly where it was.
+        let req = NewOrder {
+            side: old.side,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::Gtc,
+            price: Some(price),
+            quantity,
+        };
+        let portfolio = self.portfolios.cached_mut(user_id);
+        portfolio.reserve(old.side, old.price, symbol_id, -old.quantity);
+        let checked = self.validate_order(user_id, symbol_id, &req);
+        let portfolio = self.portfolios.cached_mut(user_id);
+        portfolio.reserve(old.side, old.price, symbol_id, old.quantity);
+        let (price, budget) = checked?;
+        // The replace, then the same as a new order
+        self.journal_room(self.legs_bound(user_id, symbol_id, &req, price) + 2)?;
+
+        // 3. Pull it and send it back through matching with the same ID.
+        // One group, so replay never sees the old order gone without its replacement.
+        let mut txn = self.begin();
+        self.remove_order(order_id);
+        log_order_event(&mut txn, ActionType::OrderReplaced, &old, symbol_id, old.price);
+        let outcome = self.execute_order(&mut txn, order_id, user_id, symbol_id, req, price, budget);
+        self.commit(txn)?;
+        Ok(outcome)
+    }
+
+    fn settle(&mut self, txn: &mut Txn, symbol_id: u32, fill: &Fill) {
+        const CHECKED: &str = "fills checked by check_settlement";
+        let notional = fill.price.checked_mul(fill.quantity).expect(CHECKED);
+        let maker_side = match fill.taker_side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+
+        // Maker's reservation was taken at its own price, which is the fill price
+        let maker = self.portfolios.cached_mut(fill.maker_user);
+        maker.reserve(maker_side, fill.price, symbol_id, -fill.quantity);
+        if self.books.get(&symbol_id).and_then(|b| b.get(fill.maker_order)).is_none() {
+            self.open_orders.remove(&fill.maker_order);
+        }
+
+        let buyer = self.portfolios.cached_mut(fill.buyer());
+        buyer.cash = buyer.cash.checked_sub(notional).expect(CHECKED);
+        let held = buyer.stocks.entry(symbol_id).or_default();
+        *held = held.checked_add(fill.quantity).expect(CHECKED);
+
+        let seller = self.portfolios.cached_mut(fill.seller());
+        seller.cash = seller.cash.checked_add(notional).expect(CHECKED);
+        let held = seller.stocks.entry(symbol_id).or_default();
+        *held = held.checked_sub(fill.quantity).expect(CHECKED);
+
+        // Each leg carries its own order ID so replay can find the maker in the book
+        let (buy_order, sell_order) = if fill.taker_side == Side::Buy {
+            (fill.taker_order, fill.maker_order)
+        } else {
+            (fill.maker_order, fill.taker_order)
+        };
+        let buy_log = LogEntry::new(fill.buyer(), ActionType::Trade, symbol_id, fill.quantity, notional)
+            .with_order_id(buy_order);
+        let sell_log = LogEntry::new(fill.seller(), ActionType::Trade, symbol_id, -fill.quantity, -notional)
+            .with_order_id(sell_order);
+        txn.stage(buy_log);
+        txn.stage(sell_log);
+    }
+}
+
+// Newest usable snapshot. Balances come from the tree if it was checkpointed together
+// with the snapshot, otherwise restore() rebuilds the tree from the snapshot's copy.
+fn newest_snapshot(portfolios: &Accounts, keys: &Keyring, engine_id: &[u8; 16]) -> SnapshotData {
+    // A bad snapshot isn't fatal: load_snapshot falls back to an older one (and a longer replay).
+    // Without a readable manifest, the snapshot files on disk are tried newest first instead.
+    let synced_index = portfolios.synced_index();
+    load_snapshot(synced_index, keys, engine_id).unwrap_or_else(|e| {
+        eprintln!("[Snapshot] Manifest unusable ({}), looking for snapshot files", e);
+        find_snapshot(synced_index, keys, engine_id)
+    })
+}
+
+// Stages an order lifecycle event. quantity is signed (+ buy / - sell),
+// amount_money carries the price (0 for market orders).
+fn log_order_event(txn: &mut Txn, action: ActionType, order: &Order, symbol_id: u32, price: i64) {
+    let signed_qty = if order.side == Side::Buy { order.quantity } else { -order.quantity };
+    let entry = LogEntry::new(order.user_id, action, symbol_id, signed_qty, price)
+        .with_order_id(order.id);
+    txn.stage(entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, Receiver};
+    use crate::consts::{USER_ACTIVE, USER_WITHDRAW_DISABLED};
+    use crate::format;
+
+    const SYMBOL: u32 = 7;
+
+    // An engine on the (fresh) data directory. What it journals waits in the receiver.
+    fn test_state() -> (AppState, Receiver<DbMessage>) {
+        let keys = Arc::new(Keyring::load("data.key").unwrap());
+        let engine_id = format::upgrade_data_files(&keys).unwrap();
+        let (tx, rx) = mpsc::channel(1_000);
+        (AppState::new(tx, keys, engine_id).unwrap(), rx)
+    }
+
+    fn journaled(rx: &mut Receiver<DbMessage>) -> Vec<LogEntry> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                DbMessage::WriteLog(entry) => Some(entry),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Balances straight into RAM (the tests about replay journal theirs)
+    fn fund(state: &mut AppState, user_id: u64, cash: i64, stock: i64) {
+        let portfolio = state.portfolios.entry(user_id).unwrap();
+        portfolio.cash = cash;
+        portfolio.stocks.insert(SYMBOL, stock);
+    }
+
+    fn limit(side: Side, price: i64, quantity: i64, time_in_force: TimeInForce) -> NewOrder {
+        NewOrder { side, order_type: OrderType::Limit, time_in_force, price: Some(price), quantity }
+    }
+
+    fn balances(state: &mut AppState, user_id: u64) -> (i64, i64, i64, i64) {
+        let p = state.portfolios.entry(user_id).unwrap();
+        let stock = p.stocks.get(&SYMBOL).copied().unwrap_or(0);
+        let reserved = p.reserved_stocks.get(&SYMBOL).copied().unwrap_or(0);
+        (p.cash, stock, p.reserved_cash, reserved)
+    }
+
+    #[test]
+    fn fills_that_would_overflow_a_balance_are_refused() {
+        format::in_data_dir("state-overflow", || {
+            let (mut state, mut rx) = test_state();
+            // 1 sells 10 @ 5 with almost i64::MAX cash; 2 can pay, 3 holds almost i64::MAX shares
+            fund(&mut state, 1, i64::MAX - 40, 10);
+            fund(&mut state, 2, 1_000, 0);
+            fund(&mut state, 3, 1_000, i64::MAX - 5);
+            state.submit_order(1, 0, SYMBOL, limit(Side::Sell, 5, 10, TimeInForce::Gtc)).unwrap();
+            journaled(&mut rx);
+            let before = [balances(&mut state, 1), balances(&mut state, 2), balances(&mut state, 3)];
+
+            // 1. The seller's cash (maker side) and the buyer's holding (taker side) would overflow
+            let buy = limit(Side::Buy, 5, 10, TimeInForce::Gtc);
+            assert_eq!(state.submit_order(2, 0, SYMBOL, buy).unwrap_err(), "Amount Out Of Range");
+            assert_eq!(state.submit_order(3, 0, SYMBOL, buy).unwrap_err(), "Amount Out Of Range");
+            let market = NewOrder { order_type: OrderType::Market, price: None, ..buy };
+            assert_eq!(state.submit_order(2, 0, SYMBOL, market).unwrap_err(), "Amount Out Of Range");
+
+            // 2. Nothing moved: the book, the balances, the journal
+            assert_eq!(state.books[&SYMBOL].depth(10), (vec![], vec![(5, 10)]));
+            assert_eq!([balances(&mut state, 1), balances(&mut state, 2), balances(&mut state, 3)], before);
+            assert!(journaled(&mut rx).is_empty());
+
+            // 3. A fill that fits still goes through
+            let outcome = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 5, 8, TimeInForce::Gtc)).unwrap();
+            assert_eq!(outcome.status, OrderStatus::Filled);
+            assert_eq!(balances(&mut state, 1), (i64::MAX, 2, 0, 2));
+        });
+    }
+
+    #[test]
+    fn replay_stops_at_an_entry_that_overflows() {
+        // 1. On its own: an error naming the entry, and the portfolio as it was
+        let mut portfolio = Portfolio { cash: i64::MAX - 1, ..Default::default() };
+        let deposit = LogEntry::new(1, ActionType::Deposit, 0, 0, 2);
+        let err = portfolio.apply(41, &deposit).unwrap_err();
+        assert!(err.to_string().contains("log index 41"), "{}", err);
+        assert_eq!(portfolio.cash, i64::MAX - 1);
+        let withdraw = LogEntry::new(1, ActionType::Withdraw, 0, 0, i64::MIN);
+        assert!(portfolio.apply(42, &withdraw).is_err());
+        let sell = LogEntry::new(1, ActionType::Trade, SYMBOL, i64::MIN, -1);
+        portfolio.stocks.insert(SYMBOL, -1);
+        assert!(portfolio.apply(43, &sell).is_err());
+        assert_eq!(portfolio.stocks[&SYMBOL], -1);
+
+        // 2. In a journal: startup refuses it, and says where
+        format::in_data_dir("state-replay-overflow", || {
+            let keys = Arc::new(Keyring::load("data.key").unwrap());
+            let engine_id = format::upgrade_data_files(&keys).unwrap();
+            let mut writer = crate::segments::SegmentWriter::open(keys.clone(), engine_id).unwrap();
+            writer.append(&[LogEntry::new(1, ActionType::Deposit, 0, 0, i64::MAX), deposit]).unwrap();
+            drop(writer);
+
+            let (tx, _rx) = mpsc::channel(10);
+            let Err(err) = AppState::new(tx, keys, engine_id) else { panic!("replayed past an overflow") };
+            assert!(err.to_string().contains("log index 1"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn issued_stock_can_be_sold_and_replays() {
+        format::in_data_dir("state-issue", || {
+            let (mut state, mut rx) = test_state();
+            state.load_accounts(&[1, 2]).unwrap();
+            let buy = limit(Side::Buy, 5, 4, TimeInForce::Gtc);
+
+            // 1. Nobody holds anything until an admin (user 9) issues it
+            assert_eq!(state.issue_stock(9, 1, SYMBOL, 0).unwrap_err(), "Invalid Quantity");
+            assert_eq!(state.submit_order(1, 0, SYMBOL, limit(Side::Sell, 5, 4, TimeInForce::Gtc)).unwrap_err(), "Insufficient Stock");
+            assert_eq!(state.issue_stock(9, 1, SYMBOL, 10).unwrap(), 10);
+            fund(&mut state, 2, 100, 0);
+            let outcome = state.submit_order(1, 0, SYMBOL, limit(Side::Sell, 5, 4, TimeInForce::Gtc)).unwrap();
+            assert_eq!(outcome.status, OrderStatus::Resting);
+            state.submit_order(2, 0, SYMBOL, buy).unwrap();
+            assert_eq!(balances(&mut state, 1), (20, 6, 0, 0));
+            let next_order_id = state.next_order_id;
+
+            // 2. One Issue entry, naming the admin
+            let log = journaled(&mut rx);
+            let issue = log.iter().find(|e| matches!(ActionType::from_u8(e.action_type), ActionType::Issue)).unwrap();
+            assert_eq!((issue.user_id, issue.quantity, issue.amount_money, issue.counterparty()), (1, 10, 0, 9));
+
+            // 3. Replay rebuilds the holding, and the admin's ID isn't taken for an order ID
+            let keys = state.keys.clone();
+            let mut writer = crate::segments::SegmentWriter::open(keys.clone(), state.engine_id).unwrap();
+            writer.append(&[LogEntry::new(2, ActionType::Deposit, 0, 0, 100)]).unwrap();
+            writer.append(&log).unwrap();
+            drop(writer);
+            let (tx, _rx) = mpsc::channel(10);
+            let mut replayed = AppState::new(tx, keys, state.engine_id).unwrap();
+            assert_eq!(balances(&mut replayed, 1), (20, 6, 0, 0));
+            assert_eq!(balances(&mut replayed, 2), (80, 4, 0, 0));
+            assert_eq!(replayed.next_order_id, next_order_id);
+        });
+    }
+
+    // User 1 offers 5 @ 10, user 2 has the cash to take it all
+    fn one_ask(name: &str, f: impl FnOnce(AppState, Receiver<DbMessage>)) {
+        format::in_data_dir(name, || {
+            let (mut state, mut rx) = test_state();
+            fund(&mut state, 1, 0, 5);
+            fund(&mut state, 2, 1_000, 0);
+            state.submit_order(1, 0, SYMBOL, limit(Side::Sell, 10, 5, TimeInForce::Gtc)).unwrap();
+            journaled(&mut rx);
+            f(state, rx);
+        });
+    }
+
+    #[test]
+    fn fok_without_the_depth_changes_nothing() {
+        one_ask("state-fok", |mut state, mut rx| {
+            let before = [balances(&mut state, 1), balances(&mut state, 2)];
+            let outcome = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 10, 8, TimeInForce::Fok)).unwrap();
+
+            assert_eq!(outcome.status, OrderStatus::Rejected("Cannot Fill Entirely"));
+            assert_eq!((outcome.filled, outcome.resting, outcome.expired), (0, 0, 0));
+            assert_eq!(state.books[&SYMBOL].depth(5), (vec![], vec![(10, 5)]));
+            assert_eq!([balances(&mut state, 1), balances(&mut state, 2)], before);
+            let log = journaled(&mut rx);
+            assert_eq!(log.len(), 1);
+            assert!(matches!(ActionType::from_u8(log[0].action_type), ActionType::OrderRejected));
+            assert_eq!(log[0].reason, REJECT_CANNOT_FILL);
+
+            // With the depth there, it fills
+            let outcome = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 10, 5, TimeInForce::Fok)).unwrap();
+            assert_eq!(outcome.status, OrderStatus::Filled);
+            assert_eq!(balances(&mut state, 2), (950, 5, 0, 0));
+        });
+    }
+
+    #[test]
+    fn ioc_remainder_expires() {
+        one_ask("state-ioc", |mut state, mut rx| {
+            let outcome = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 10, 8, TimeInForce::Ioc)).unwrap();
+
+            assert_eq!(outcome.status, OrderStatus::Expired);
+            assert_eq!((outcome.filled, outcome.resting, outcome.expired), (5, 0, 3));
+            assert_eq!(state.books[&SYMBOL].depth(5), (vec![], vec![]));
+            // The maker's reserved stock went with the fill; the taker never reserved any cash
+            assert_eq!(balances(&mut state, 1), (50, 0, 0, 0));
+            assert_eq!(balances(&mut state, 2), (950, 5, 0, 0));
+            let expired = journaled(&mut rx).into_iter()
+                .find(|e| matches!(ActionType::from_u8(e.action_type), ActionType::OrderExpired))
+                .unwrap();
+            assert_eq!((expired.user_id, expired.quantity), (2, 3));
+        });
+    }
+
+    #[test]
+    fn post_only_that_would_cross_is_rejected() {
+        one_ask("state-post-only", |mut state, mut rx| {
+            let before = [balances(&mut state, 1), balances(&mut state, 2)];
+            let outcome = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 10, 5, TimeInForce::PostOnly)).unwrap();
+
+            assert_eq!(outcome.status, OrderStatus::Rejected("Would Cross"));
+            assert_eq!(state.books[&SYMBOL].depth(5), (vec![], vec![(10, 5)]));
+            assert_eq!([balances(&mut state, 1), balances(&mut state, 2)], before);
+            assert_eq!(journaled(&mut rx)[0].reason, REJECT_WOULD_CROSS);
+
+            // One tick below, it adds liquidity and reserves its cash
+            let outcome = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 9, 5, TimeInForce::PostOnly)).unwrap();
+            assert_eq!(outcome.status, OrderStatus::Resting);
+            assert_eq!(balances(&mut state, 2), (1_000, 0, 45, 0));
+        });
+    }
+
+    // (id, user, price, quantity) of every resting order, in queue order
+    fn resting(state: &AppState) -> Vec<(u64, u64, i64, i64)> {
+        state.books[&SYMBOL].orders().map(|o| (o.id, o.user_id, o.price, o.quantity)).collect()
+    }
+
+    #[test]
+    fn amends_cancels_and_their_replay() {
+        format::in_data_dir("state-amend", || {
+            let (mut state, mut rx) = test_state();
+            fund(&mut state, 1, 1_000, 0);
+            fund(&mut state, 2, 1_000, 0);
+            let a = state.submit_order(1, 0, SYMBOL, limit(Side::Buy, 10, 5, TimeInForce::Gtc)).unwrap().order_id;
+            let b = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 10, 5, TimeInForce::Gtc)).unwrap().order_id;
+            let c = state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 9, 5, TimeInForce::Gtc)).unwrap().order_id;
+
+            // 1. Less quantity, same price: stays ahead of b
+            state.amend_order(1, a, None, Some(3)).unwrap();
+            assert_eq!(resting(&state), vec![(c, 2, 9, 5), (a, 1, 10, 3), (b, 2, 10, 5)]);
+            assert_eq!(balances(&mut state, 1).2, 30);
+
+            // 2. A new price re-enters it at the back of that level, behind c
+            state.amend_order(1, a, Some(9), None).unwrap();
+            assert_eq!(resting(&state), vec![(c, 2, 9, 5), (a, 1, 9, 3), (b, 2, 10, 5)]);
+            assert_eq!(balances(&mut state, 1).2, 27);
+
+            // 3. A cancel gives back what the order had reserved
+            state.cancel_order(2, b).unwrap();
+            assert_eq!(balances(&mut state, 2).2, 45);
+            assert_eq!(state.cancel_order(1, b).unwrap_err(), "Order not found");
+
+            // 4. Replaying the journal rebuilds the same book
+            let keys = state.keys.clone();
+            let mut writer = crate::segments::SegmentWriter::open(keys.clone(), state.engine_id).unwrap();
+            writer.append(&journaled(&mut rx)).unwrap();
+            drop(writer);
+            let (tx, _rx) = mpsc::channel(10);
+            let mut replayed = AppState::new(tx, keys, state.engine_id).unwrap();
+            assert_eq!(resting(&replayed), resting(&state));
+            assert_eq!(replayed.next_order_id, state.next_order_id);
+            assert_eq!(balances(&mut replayed, 1).2, 27);
+            assert_eq!(balances(&mut replayed, 2).2, 45);
+        });
+    }
+
+    #[test]
+    fn freezing_cancels_resting_orders() {
+        format::in_data_dir("state-freeze", || {
+            let (mut state, mut rx) = test_state();
+            fund(&mut state, 2, 1_000, 0);
+            fund(&mut state, 3, 1_000, 0);
+            state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 10, 5, TimeInForce::Gtc)).unwrap();
+            state.submit_order(2, 0, SYMBOL, limit(Side::Buy, 9, 5, TimeInForce::Gtc)).unwrap();
+            let other = state.submit_order(3, 0, SYMBOL, limit(Side::Buy, 9, 5, TimeInForce::Gtc)).unwrap().order_id;
+            journaled(&mut rx);
+
+            // 1. Freezing takes the user's orders off the book, with the flag change as one group
+            let mut bob = UserMeta { user_id: 2, flags: USER_ACTIVE, ..bytemuck::Zeroable::zeroed() };
+            bob.username[..3].copy_from_slice(b"bob");
+            let bob = state.set_user_flags(1, bob, USER_ACTIVE | USER_FROZEN).unwrap();
+            assert_eq!(resting(&state), vec![(other, 3, 9, 5)]);
+            assert_eq!(balances(&mut state, 2), (1_000, 0, 0, 0));
+            let entries = journaled(&mut rx);
+            let actions: Vec<_> = entries.iter().map(|e| ActionType::from_u8(e.action_type)).collect();
+            assert!(matches!(actions[..], [
+                ActionType::TxnBegin, ActionType::SetFlags, ActionType::OrderCancelled,
+                ActionType::OrderCancelled, ActionType::TxnCommit,
+            ]), "{:?}", actions);
+
+            // 2. Other flag changes leave orders alone
+            state.submit_order(3, 0, SYMBOL, limit(Side::Buy, 8, 5, TimeInForce::Gtc)).unwrap();
+            let carol = UserMeta { user_id: 3, flags: USER_ACTIVE, ..bytemuck::Zeroable::zeroed() };
+            assert!(state.cancelled_by_flags(&carol, USER_ACTIVE | USER_WITHDRAW_DISABLED).is_empty());
+            assert!(state.cancelled_by_flags(&bob, USER_ACTIVE | USER_FROZEN | USER_ADMIN).is_empty());
+            assert_eq!(state.cancelled_by_flags(&carol, USER_ACTIVE | USER_FROZEN).len(), 2);
+        });
+    }
+
+    // The log indexes Committed lets through, and how many it skipped
+    fn committed(entries: &[LogEntry]) -> (Vec<u64>, usize) {
+        let mut groups = Committed::default();
+        let mut applied = Vec::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            groups.push(idx as u64, *entry, |leg_idx, _| applied.push(leg_idx));
+        }
+        (applied, groups.finish())
+    }
+
+    #[test]
+    fn only_whole_groups_are_applied() {
+        let bare = LogEntry::new(1, ActionType::Deposit, 0, 0, 100);
+        let leg = |user_id| LogEntry { flags: LOG_FLAG_IN_TXN, ..LogEntry::new(user_id, ActionType::Transfer, 0, 0, 5) };
+        let begin = |id| LogEntry::txn_marker(ActionType::TxnBegin, id, 0);
+        let commit = |id, legs| LogEntry::txn_marker(ActionType::TxnCommit, id, legs);
+
+        // 1. Committed: its legs come out at the commit, with their own indexes
+        assert_eq!(committed(&[bare, begin(5), leg(1), leg(2), commit(5, 2), bare]), (vec![0, 2, 3, 5], 0));
+        // 2. No TxnCommit before the end of the log
+        assert_eq!(committed(&[bare, begin(5), leg(1), leg(2)]), (vec![0], 2));
+        // 3. A commit that counts a different number of legs, or closes another group
+        assert_eq!(committed(&[begin(5), leg(1), leg(2), commit(5, 3), bare]), (vec![4], 2));
+        assert_eq!(committed(&[begin(5), leg(1), commit(6, 1)]), (vec![], 1));
+        // 4. A leg without a group, and a group cut short by the next entry
+        assert_eq!(committed(&[leg(1), bare]), (vec![1], 1));
+        assert_eq!(committed(&[begin(5), leg(1), bare, begin(6), leg(2), commit(6, 1)]), (vec![2, 4], 1));
+    }
+
+    #[test]
+    fn retried_requests_get_their_receipt() {
+        format::in_data_dir("state-receipts", || {
+            let (mut state, mut rx) = test_state();
+            fund(&mut state, 1, 1_000, 0);
+            fund(&mut state, 2, 1_000, 0);
+            let key = state.request_key(Some("pay-rent"));
+            let rent = Transfer { to: 2, symbol_id: 0, is_cash: true, amount: 300 };
+
+            // 1. The retry answers like the first time, and moves nothing
+            assert_eq!(state.transfer(1, key, rent), Ok(rent));
+            let first = journaled(&mut rx).len();
+            assert_eq!(state.transfer(1, key, rent), Ok(rent));
+            assert!(journaled(&mut rx).is_empty());
+            assert_eq!((balances(&mut state, 1).0, balances(&mut state, 2).0), (700, 1_300));
+            // The same key for something else is an error, not a second transfer
+            let more = Transfer { amount: 400, ..rent };
+            assert_eq!(state.transfer(1, key, more), Err("Request ID reused"));
+
+            // 2. Another user's key of the same name is theirs alone
+            let back = Transfer { to: 1, ..rent };
+            assert_eq!(state.transfer(2, key, back), Ok(back));
+            assert_eq!(journaled(&mut rx).len(), first);
+            assert_eq!(state.receipt(1, key), Some(&Receipt::Transfer(rent)));
+            assert_eq!(state.receipt(2, key), Some(&Receipt::Transfer(back)));
+        });
+    }
+
+    #[test]
+    fn receipts_expire_after_their_ttl() {
+        let mut receipts = Receipts::new(60);
+        receipts.insert(1, 7, 100, Receipt::Cash);
+        receipts.insert(2, 7, 130, Receipt::Cash);
+        assert!(receipts.get(1, 7, 159).is_some());
+        assert!(receipts.get(1, 7, 160).is_none());
+
+        receipts.expire(160);
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts.get(2, 7, 160).is_some());
+
+        // A key used again after it expired keeps the new receipt
+        receipts.insert(2, 7, 195, Receipt::Cash);
+        receipts.expire(190);
+        assert!(receipts.get(2, 7, 250).is_some());
+        assert_eq!(receipts.iter().map(|(user_id, _, made_at, _)| (user_id, made_at)).collect::<Vec<_>>(), vec![(2, 195)]);
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use crate::consts::{WalFileHeader, WalKind, WalRecordHeader, WAL_MAGIC, WAL_VERSION};
+
+// --- TREE WRITE-AHEAD LOG ---
+// Every change to a tree page is described here before the page itself may reach
+// the disk (the pager enforces that). Records are buffered in RAM and written
+// out by flush(), which a Commit and every page write-back force.
+
+pub struct WalRecord {
+    pub header: WalRecordHeader,
+    pub payload: Vec<u8>,
+}
+
+impl WalRecord {
+    pub fn kind(&self) -> Option<WalKind> {
+        WalKind::from_u8(self.header.kind)
+    }
+
+    /// Update: the bytes the page held before the change.
+    pub fn before(&self) -> &[u8] {
+        &self.payload[..self.header.len as usize]
+    }
+
+    /// Update: the bytes written. CLR: the bytes restored.
+    pub fn after(&self) -> &[u8] {
+        &self.payload[self.payload.len() - self.header.len as usize..]
+    }
+}
+
+pub struct Wal {
+    file: File,
+    buffer: Vec<u8>,  // Appended records not yet written
+    next_lsn: u64,
+    flushed_lsn: u64, // Every record with lsn <= this is durable
+}
+
+impl Wal {
+    /// Opens (or creates) the log and returns every intact record in it, for recovery.
+    /// A torn record at the tail (crash mid-write) is cut off.
+    pub fn open(filename: &str) -> io::Result<(Self, Vec<WalRecord>)> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(filename)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let header_size = size_of::<WalFileHeader>();
+        let header: Option<WalFileHeader> = (bytes.len() >= header_size)
+            .then(|| bytemuck::pod_read_unaligned(&bytes[..header_size]))
+            .filter(|h: &WalFileHeader| h.magic == WAL_MAGIC && h.version == WAL_VERSION);
+
+        let Some(header) = header else {
+            // Brand new (or unreadable) log
+            let mut wal = Self { file, buffer: Vec::new(), next_lsn: 1, flushed_lsn: 0 };
+            wal.truncate(1)?;
+            return Ok((wal, Vec::new()));
+        };
+
+        // 1. Parse records until the first short or corrupt one
+        let mut records = Vec::new();
+        let mut pos = header_size;
+        let mut next_lsn = header.base_lsn;
+        while let Some((record, size)) = parse_record(&bytes[pos..]) {
+            next_lsn = record.header.lsn + 1;
+            records.push(record);
+            pos += size;
+        }
+
+        // 2. Drop the torn tail so new records follow the last good one
+        if pos < bytes.len() {
+            println!("[WAL] {}: dropping {} torn bytes at offset {}", filename, bytes.len() - pos, pos);
+            file.set_len(pos as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let wal = Self { file, buffer: Vec::new(), next_lsn, flushed_lsn: next_lsn - 1 };
+        Ok((wal, records))
+    }
+
+    /// Buffers a record and returns its LSN. Nothing is durable until flush().
+    pub fn append(&mut self, mut header: WalRecordHeader, payload: &[u8]) -> u64 {
+        header.lsn = self.next_lsn;
+        header.crc = 0;
+        header.crc = checksum(&header, payload);
+        self.next_lsn += 1;
+
+        self.buffer.extend_from_slice(bytemuck::bytes_of(&header));
+        self.buffer.extend_from_slice(payload);
+        header.lsn
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.flushed_lsn + 1 == self.next_lsn {
+            return Ok(());
+        }
+        self.file.write_all(&self.buffer)?;
+        self.file.sync_data()?;
+        self.buffer.clear();
+        self.flushed_lsn = self.next_lsn - 1;
+        Ok(())
+    }
+
+    /// The WAL rule: a page stamped with `lsn` may only be written once its records are durable.
+    pub fn flush_to(&mut self, lsn: u64) -> io::Result<()> {
+        if lsn > self.flushed_lsn {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Empties the log. Only safe once every page it describes is on disk.
+    /// LSNs keep counting up from `base_lsn`, since old ones are still stamped on pages.
+    pub fn truncate(&mut self, base_lsn: u64) -> io::Result<()> {
+        let header = WalFileHeader {
+            magic: WAL_MAGIC,
+            version: WAL_VERSION,
+            _padding: [0; 2],
+            base_lsn,
+        };
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(bytemuck::bytes_of(&header))?;
+        self.file.sync_data()?;
+
+        self.buffer.clear();
+        self.next_lsn = base_lsn;
+        self.flushed_lsn = base_lsn - 1;
+        Ok(())
+    }
+
+    pub fn next_lsn(&self) -> u64 {
+        self.next_lsn
+    }
+}
+
+fn checksum(header: &WalRecordHeader, payload: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(bytemuck::bytes_of(header));
+    crc32c::crc32c_append(crc, payload)
+}
+
+// One record from the front of `bytes`, plus its size. None = torn or corrupt.
+fn parse_record(bytes: &[u8]) -> Option<(WalRecord, usize)> {
+    let header_size = size_of::<WalRecordHeader>();
+    if bytes.len() < header_size {
+        return None;
+    }
+    let header: WalRecordHeader = bytemuck::pod_read_unaligned(&bytes[..header_size]);
+
+    let payload_len = match WalKind::from_u8(header.kind)? {
+        WalKind::Update => 2 * header.len as usize,
+        WalKind::Clr => header.len as usize,
+        _ => 0,
+    };
+    let size = header_size + payload_len;
+    if bytes.len() < size {
+        return None;
+    }
+    let payload = bytes[header_size..size].to_vec();
+
+    let stored_crc = header.crc;
+    if checksum(&WalRecordHeader { crc: 0, ..header }, &payload) != stored_crc {
+        return None;
+    }
+    Some((WalRecord { header, payload }, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(kind: WalKind, payload: &[u8]) -> WalRecordHeader {
+        WalRecordHeader {
+            lsn: 0,
+            prev_lsn: 0,
+            txn_id: 1,
+            undo_next: 0,
+            page_id: 3,
+            offset: 100,
+            len: if kind == WalKind::Update { payload.len() as u16 / 2 } else { payload.len() as u16 },
+            kind: kind as u8,
+            _padding: [0; 3],
+            crc: 0,
+        }
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let dir = std::env::temp_dir().join(format!("jdb-wal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("tree.idx.wal").to_str().unwrap().to_string();
+
+        // 1. Three durable records, then half of a fourth (a crash mid-write)
+        let (mut wal, records) = Wal::open(&file).unwrap();
+        assert!(records.is_empty());
+        wal.append(record(WalKind::Begin, &[]), &[]);
+        wal.append(record(WalKind::Update, b"oldnew"), b"oldnew");
+        wal.append(record(WalKind::Commit, &[]), &[]);
+        wal.flush().unwrap();
+        let good_len = fs::metadata(&file).unwrap().len();
+        wal.append(record(WalKind::Clr, b"restored"), b"restored");
+        let torn = wal.buffer.len() / 2;
+        wal.buffer.truncate(torn);
+        wal.flush().unwrap();
+        drop(wal);
+
+        // 2. Reopened: the three, the file cut back to them, and the next LSN follows the last one
+        let (mut wal, records) = Wal::open(&file).unwrap();
+        assert_eq!(records.iter().map(|r| r.header.lsn).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!((records[1].before(), records[1].after()), (&b"old"[..], &b"new"[..]));
+        assert_eq!(fs::metadata(&file).unwrap().len(), good_len);
+        assert_eq!(wal.append(record(WalKind::Begin, &[]), &[]), 4);
+        wal.flush().unwrap();
+        drop(wal);
+
+        // 3. A damaged record ends the log just the same, whatever follows it
+        let mut bytes = fs::read(&file).unwrap();
+        let second = size_of::<WalFileHeader>() + size_of::<WalRecordHeader>();
+        bytes[second + size_of::<WalRecordHeader>()] ^= 0xFF; // The Update's payload
+        fs::write(&file, &bytes).unwrap();
+        let (_, records) = Wal::open(&file).unwrap();
+        assert_eq!(records.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
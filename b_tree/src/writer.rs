@@ -1,54 +1,87 @@
 use std::fs::{OpenOptions, File};
 use std::io::{self, Write};
 use std::mem::size_of;
+use std::sync::Arc;
 // use std::slice;
-use crate::consts::{UserMeta, LogEntry};
+use crate::consts::{sealed_size, UserMeta, LogEntry, FILE_HEADER_SIZE};
+use crate::crypto::Keyring;
+use crate::segments::{Retire, Segment, SegmentWriter};
 
+// Every record is sealed (encrypted + authenticated) on its way to disk, see crypto.rs
 pub struct DatabaseWriter {
     user_file: File,
-    log_file: File,
+    log: SegmentWriter, // The journal: pre-allocated segments (segments.rs)
+    keys: Arc<Keyring>,
+    num_users: u64, // Records in users.bin, i.e. the index of the next one
 }
 
 impl DatabaseWriter {
-    pub fn new() -> io::Result<Self> {
+    pub fn new(keys: Arc<Keyring>, engine_id: [u8; 16]) -> io::Result<Self> {
         // Open with options that allow Append
         let user_file = OpenOptions::new()
-            .read(true).write(true).create(true).append(true)
+            .read(true).create(true).append(true)
             .open("users.bin")?;
 
-        let log_file = OpenOptions::new()
-            .read(true).write(true).create(true).append(true)
-            .open("history.bin")?;
+        let log = SegmentWriter::open(keys.clone(), engine_id)?;
 
-        Ok(Self { user_file, log_file })
+        let mut writer = Self { user_file, log, keys, num_users: 0 };
+        writer.recount()?;
+        Ok(writer)
     }
 
-    /// Writes a User struct directly to disk byte-wise
-    pub fn append_user(&mut self, user: &UserMeta) -> io::Result<u64> {
-        // 1. Convert Struct to Byte Slice (Unsafe but Fast)
-        let bytes: &[u8] = bytemuck::bytes_of(user);
+    /// Counts the records again (after a failed batch, the files are
+    /// the only thing that knows how much of it stayed)
+    pub fn recount(&mut self) -> io::Result<()> {
+        // users.bin starts with a file header (format.rs), written at startup
+        let header = FILE_HEADER_SIZE as u64;
+        self.num_users = self.user_file.metadata()?.len().saturating_sub(header) / sealed_size(size_of::<UserMeta>()) as u64;
+        self.log.recount()
+    }
 
-        // 2. Write to OS Buffer
-        self.user_file.write_all(bytes)?;
+    /// Largest batch of log entries append_logs() takes
+    pub fn max_batch(&self) -> usize {
+        self.log.max_batch()
+    }
 
-        // 3. FORCE DISK WRITE (Bypass Cache logic)
-        // This forces the OS to flush buffers to the physical platter/NAND immediately.
-        self.user_file.sync_all()?; 
+    pub fn segments(&self) -> &[Segment] {
+        self.log.segments()
+    }
 
-        // Return the index (ID) of this user
-        let len = self.user_file.metadata()?.len();
-        Ok((len / size_of::<UserMeta>() as u64) - 1)
+    /// Retires the full segments the oldest kept snapshot covers
+    pub fn retire(&mut self, covered: u64, mode: Retire) -> io::Result<Vec<String>> {
+        self.log.retire(covered, mode)
     }
 
-    /// Writes a Log entry directly to disk
-    pub fn append_log(&mut self, entry: &LogEntry) -> io::Result<()> {
-        let bytes: &[u8] = bytemuck::bytes_of(entry);
-        self.log_file.write_all(bytes)?;
-        
-        // Critical for "Durability" - ensure it hits the disk
-        self.log_file.sync_data()?; 
+    /// Appends a batch of users, sealed: one write, one fsync
+    pub fn append_users(&mut self, users: &[UserMeta]) -> io::Result<()> {
+        // 1. Seal every record into one buffer
+        let mut bytes = Vec::with_capacity(users.len() * sealed_size(size_of::<UserMeta>()));
+        for (i, user) in users.iter().enumerate() {
+            bytes.extend_from_slice(&self.keys.seal_record("users.bin", self.num_users + i as u64, bytemuck::bytes_of(user)));
+        }
+
+        // 2. Write to OS Buffer, then FORCE DISK WRITE (Bypass Cache logic)
+        // This forces the OS to flush buffers to the physical platter/NAND immediately.
+        let written = self.user_file.write_all(&bytes).and_then(|_| self.user_file.sync_all());
+        undo_partial(&self.user_file, self.num_users, size_of::<UserMeta>(), written)?;
+
+        self.num_users += users.len() as u64;
         Ok(())
     }
+
+    /// Appends a batch of log entries, sealed: one write, one fsync (group commit)
+    pub fn append_logs(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        self.log.append(entries)
+    }
+}
+
+// A failed batch may have left some of itself in the file: cut it back to the
+// last whole record we know about, so the next batch doesn't land behind garbage
+fn undo_partial(file: &File, records: u64, size: usize, written: io::Result<()>) -> io::Result<()> {
+    if written.is_err() {
+        let _ = file.set_len(FILE_HEADER_SIZE as u64 + records * sealed_size(size) as u64);
+    }
+    written
 }
 
 // Helper to create the Fixed-Size Byte Arrays
@@ -11,18 +11,35 @@ const API_URL = "http://localhost:3000";
 function App() {
   // State
   const [user, setUser] = useState(localStorage.getItem('hft_user'));
+  const [token, setToken] = useState(localStorage.getItem('hft_token'));
   const [balance, setBalance] = useState(null);
   
   // Form Inputs
   const [symbolId, setSymbolId] = useState(1);
   const [amount, setAmount] = useState(10);
   const [usernameInput, setUsernameInput] = useState("");
+  const [passwordInput, setPasswordInput] = useState("");
   const [statusMsg, setStatusMsg] = useState("");
 
+  // The session token says who we are; the server ignores usernames in requests
+  const authHeaders = () => ({
+    "Content-Type": "application/json",
+    "Authorization": `Bearer ${token}`,
+  });
+
+  const clearSession = () => {
+    setUser(null);
+    setToken(null);
+    localStorage.removeItem('hft_user');
+    localStorage.removeItem('hft_token');
+    setBalance(null);
+  };
+
   const fetchBalance = async () => {
-    if (!user) return;
+    if (!user || !token) return;
     try {
-      const res = await fetch(`${API_URL}/balance/${user}`);
+      const res = await fetch(`${API_URL}/balance`, { headers: authHeaders() });
+      if (res.status === 401) return clearSession(); // Expired or revoked
       const data = await res.json();
       if (!data.error) setBalance(data);
     } catch (e) {
@@ -35,7 +52,7 @@ function App() {
     if (user) fetchBalance();
     const interval = setInterval(fetchBalance, 2000); 
     return () => clearInterval(interval);
-  }, [user]);
+  }, [user, token]);
 
   const handleAuth = async (isLogin) => {
     const endpoint = isLogin ? "/login" : "/register";
@@ -43,7 +60,7 @@ function App() {
       const res = await fetch(`${API_URL}${endpoint}`, {
         method: "POST",
         headers: { "Content-Type": "application/json" },
-        body: JSON.stringify({ username: usernameInput, password: "password", email: "" }),
+        body: JSON.stringify({ username: usernameInput, password: passwordInput, email: "" }),
       });
       const data = await res.json();
       
@@ -51,7 +68,10 @@ function App() {
         setStatusMsg(data.error);
       } else {
         setUser(usernameInput);
+        setToken(data.token);
+        setPasswordInput("");
         localStorage.setItem('hft_user', usernameInput);
+        localStorage.setItem('hft_token', data.token);
         setStatusMsg(`Success! ID: ${data.user_id}`);
       }
     } catch (e) {
@@ -60,10 +80,9 @@ function App() {
   };
 
   const executeTrade = async (action) => {
-    if (!user) return;
+    if (!user || !token) return;
 
     let payload = {
-      username: user,
       symbol_id: Number(symbolId),
       amount: Number(amount),
       is_cash: false
@@ -92,7 +111,7 @@ function App() {
     try {
       const res = await fetch(`${API_URL}/trade`, {
         method: "POST",
-        headers: { "Content-Type": "application/json" },
+        headers: authHeaders(),
         body: JSON.stringify(payload),
       });
       const data = await res.json();
@@ -103,10 +122,13 @@ function App() {
     }
   };
 
-  const logout = () => {
-    setUser(null);
-    localStorage.removeItem('hft_user');
-    setBalance(null);
+  const logout = async () => {
+    try {
+      await fetch(`${API_URL}/logout`, { method: "POST", headers: authHeaders() });
+    } catch (e) {
+      console.error("Failed to revoke session", e);
+    }
+    clearSession();
   };
 
   return (
@@ -118,7 +140,7 @@ function App() {
           <h1 className="text-2xl font-bold flex items-center gap-2 text-terminal-green">
             <Activity /> HFT::ENGINE_V1
           </h1>
-          {user && (
+          {user && token && (
             <button onClick={logout} className="text-xs hover:text-red-500 flex items-center gap-1">
               LOGOUT <LogOut size={14}/>
             </button>
@@ -126,7 +148,7 @@ function App() {
         </div>
 
         {/* Auth Screen */}
-        {!user ? (
+        {!(user && token) ? (
           <div className="max-w-sm mx-auto bg-terminal-dark p-6 rounded border border-zinc-800 shadow-xl">
             <h2 className="text-lg mb-4 text-center">ACCESS TERMINAL</h2>
             <input 
@@ -135,6 +157,13 @@ function App() {
               value={usernameInput}
               onChange={e => setUsernameInput(e.target.value)}
             />
+            <input 
+              type="password"
+              className="w-full bg-black border border-zinc-700 p-2 mb-4 text-center focus:outline-none focus:border-terminal-green text-white"
+              placeholder="PASSWORD"
+              value={passwordInput}
+              onChange={e => setPasswordInput(e.target.value)}
+            />
             <div className="flex gap-2">
               <button onClick={() => handleAuth(true)} className="flex-1 bg-zinc-800 hover:bg-zinc-700 py-2 text-sm text-white">LOGIN</button>
               <button onClick={() => handleAuth(false)} className="flex-1 bg-zinc-800 hover:bg-zinc-700 py-2 text-sm text-white">REGISTER</button>